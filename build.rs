@@ -0,0 +1,48 @@
+//! Records what `--version --json` reports about the build
+
+use std::path::Path;
+use std::process::Command;
+
+/// The version of a package in Cargo.lock
+fn locked_version(lock: &str, package: &str) -> Option<String> {
+    let name = format!("name = \"{package}\"");
+    let mut lines = lock.lines().skip_while(|line| *line != name);
+    lines.next()?;
+    let version = lines.next()?.strip_prefix("version = \"")?;
+    Some(version.trim_end_matches('"').to_string())
+}
+
+fn git_sha() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn main() {
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let lock =
+        std::fs::read_to_string(Path::new(&manifest_dir).join("Cargo.lock")).unwrap_or_default();
+
+    let kanidm_proto = locked_version(&lock, "kanidm_proto");
+    let git_sha = git_sha();
+    let target = std::env::var("TARGET").ok();
+    for (name, value) in [
+        ("KANIDM_PROTO_VERSION", kanidm_proto),
+        ("GIT_SHA", git_sha),
+        ("BUILD_TARGET", target),
+    ] {
+        println!(
+            "cargo:rustc-env={name}={}",
+            value.as_deref().unwrap_or("unknown")
+        );
+    }
+
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -0,0 +1,75 @@
+//! An append-only log of what happened to the managed blocks, with `--audit-log`
+//!
+//! Every rewrite of a managed block, and every change made to one out-of-band since it was last
+//! written, is appended to the audit log as one JSON object per line, with when and on which host
+//! it happened, so it can be shipped to a SIEM next to sshd's own logs.
+
+use std::io::Write;
+use std::path::Path;
+
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+use crate::Cli;
+use crate::diagnostic::Error;
+
+/// The line recorded for `event` on `file`, with the fields of `details` added
+fn line(event: &str, file: &Path, details: serde_json::Value, now: OffsetDateTime) -> String {
+    let mut line = serde_json::json!({
+        "time": now.format(&Rfc3339).unwrap_or_default(),
+        "host": crate::config::hostname(),
+        "event": event,
+        "file": file.display().to_string(),
+    });
+    if let (Some(line), serde_json::Value::Object(details)) = (line.as_object_mut(), details) {
+        line.extend(details);
+    }
+    line.to_string()
+}
+
+/// Append `event` on `file` to the audit log, if one is configured
+///
+/// An entry that can't be written is only warned about, the file was changed all the same.
+pub fn record(args: &Cli, event: &str, file: &Path, details: serde_json::Value) {
+    let Some(path) = &args.audit_log else {
+        return;
+    };
+    let line = line(event, file, details, OffsetDateTime::now_utc());
+
+    let mut options = std::fs::OpenOptions::new();
+    options.append(true).create(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let written = options
+        .open(path)
+        .and_then(|mut log| log.write_all(format!("{line}\n").as_bytes()));
+    if let Err(e) = written {
+        Error::new("audit::write", "Failed to append to the audit log")
+            .file(path)
+            .with("event", event)
+            .cause(e)
+            .warn();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_the_event_with_its_details() {
+        let now = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let line = line(
+            "drift",
+            Path::new("/home/alice/.ssh/authorized_keys"),
+            serde_json::json!({ "expected": "abc", "actual": "def" }),
+            now,
+        );
+        let line: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(line["time"], "2023-11-14T22:13:20Z");
+        assert_eq!(line["event"], "drift");
+        assert_eq!(line["file"], "/home/alice/.ssh/authorized_keys");
+        assert_eq!(line["expected"], "abc");
+        assert_eq!(line["actual"], "def");
+    }
+}
@@ -0,0 +1,1323 @@
+use std::ffi::OsStr;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+use nix::unistd::{Gid, Uid};
+use tracing::debug;
+
+use crate::diagnostic::Error;
+use crate::dir::Dir;
+use crate::{Cli, SymlinkPolicy, TamperPolicy, backup, state};
+
+pub const MANAGED_KEYS_START: &str = "# Managed Keys by kanidm_sshkey_fetcher";
+pub const MANAGED_KEYS_END: &str = "# End of Managed Keys by kanidm_sshkey_fetcher";
+
+/// The prefix of lines that were disabled because they belonged to a broken managed block
+pub const QUARANTINE_PREFIX: &str = "# Quarantined by kanidm_sshkey_fetcher: ";
+
+/// Whether a line (including its line ending) is the given marker
+fn is_marker(line: &[u8], marker: &str) -> bool {
+    line.trim_ascii() == marker.as_bytes()
+}
+
+/// Where the managed block and the broken blocks of a file are, found by feeding it line by
+/// line, so large files need not be held in memory
+///
+/// The first complete block is the managed one. Everything that looks like a broken block is
+/// commented out with [`QUARANTINE_PREFIX`] instead, so neither stale keys nor stray markers
+/// stay active:
+///
+/// - an end marker without a preceding start marker,
+/// - a start marker without a following end marker, up to the next start marker or the end of
+///   the file,
+/// - every complete block after the first one.
+#[derive(Debug, Default)]
+struct Markers {
+    /// How many lines were fed
+    lines: usize,
+    /// How many bytes they had together
+    bytes: u64,
+    /// Whether any line ends with CRLF
+    crlf: bool,
+    /// Whether the last line ends with a line ending
+    ends_with_nl: bool,
+    /// The start and end marker lines of the managed block
+    managed: Option<(usize, usize)>,
+    /// The lines to comment out, ascending and disjoint
+    quarantined: Vec<std::ops::Range<usize>>,
+    /// Those of them that are complete blocks after the managed one
+    duplicates: Vec<std::ops::Range<usize>>,
+    /// How many lines earlier runs quarantined
+    leftovers: usize,
+    problems: Vec<String>,
+    /// The start marker line of the block being read, and whether it is a duplicate
+    open: Option<(usize, bool)>,
+}
+
+impl Markers {
+    fn line(&mut self, line: &[u8]) {
+        let i = self.lines;
+        self.lines += 1;
+        self.bytes += line.len() as u64;
+        self.crlf |= line.ends_with(b"\r\n");
+        self.ends_with_nl = line.ends_with(b"\n");
+        if line.starts_with(QUARANTINE_PREFIX.as_bytes()) {
+            self.leftovers += 1;
+        }
+
+        if is_marker(line, MANAGED_KEYS_START) {
+            if let Some((start, _)) = self.open {
+                self.problems.push(format!(
+                    "line {}: start marker without end marker",
+                    start + 1
+                ));
+                self.quarantined.push(start..i);
+            }
+            self.open = Some((i, self.managed.is_some()));
+        } else if is_marker(line, MANAGED_KEYS_END) {
+            match self.open.take() {
+                None => {
+                    self.problems
+                        .push(format!("line {}: end marker without start marker", i + 1));
+                    self.quarantined.push(i..i + 1);
+                }
+                Some((start, false)) => self.managed = Some((start, i)),
+                Some((start, true)) => {
+                    self.problems.push(format!(
+                        "lines {}-{}: duplicate managed block",
+                        start + 1,
+                        i + 1
+                    ));
+                    self.quarantined.push(start..i + 1);
+                    self.duplicates.push(start..i + 1);
+                }
+            }
+        }
+    }
+
+    /// Quarantine the block still open at the end of the file
+    fn finish(mut self) -> Markers {
+        if let Some((start, _)) = self.open.take() {
+            self.problems.push(format!(
+                "line {}: start marker without end marker",
+                start + 1
+            ));
+            self.quarantined.push(start..self.lines);
+        }
+        self
+    }
+
+    fn is_quarantined(&self, line: usize) -> bool {
+        let next = self.quarantined.partition_point(|range| range.end <= line);
+        self.quarantined
+            .get(next)
+            .is_some_and(|range| range.contains(&line))
+    }
+
+    fn is_duplicate(&self, line: usize) -> bool {
+        self.duplicates.iter().any(|range| range.contains(&line))
+    }
+
+    fn quarantined_lines(&self) -> usize {
+        self.quarantined.iter().map(|range| range.len()).sum()
+    }
+
+    fn nl(&self) -> &'static str {
+        if self.crlf { "\r\n" } else { "\n" }
+    }
+}
+
+/// The prefix of the footer of a managed block, followed by the checksum of the lines between
+/// the start marker and the footer
+pub const CHECKSUM_PREFIX: &str = "# Checksum of the managed keys: sha256:";
+
+/// The lines of a managed block holding `keys`, markers included
+fn render_block(keys: &[String], nl: &str) -> Vec<u8> {
+    let mut content = nl.as_bytes().to_vec();
+    for key in keys {
+        content.extend(format!("{key}{nl}").as_bytes());
+    }
+    content.extend(nl.as_bytes());
+
+    let mut block = format!("{MANAGED_KEYS_START}{nl}").into_bytes();
+    block.extend(&content);
+    block.extend(
+        format!(
+            "{CHECKSUM_PREFIX}{}{nl}{MANAGED_KEYS_END}{nl}",
+            state::checksum(&content)
+        )
+        .as_bytes(),
+    );
+    block
+}
+
+/// The checksum the footer of the content of a managed block records, and the one the lines
+/// above it have
+///
+/// None for blocks without a footer, as written by versions before it was added.
+fn footer_checksums(content: &[u8]) -> Option<(String, String)> {
+    let start = content[..content.len().saturating_sub(1)]
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |i| i + 1);
+    let footer = std::str::from_utf8(content[start..].trim_ascii()).ok()?;
+    let recorded = footer.strip_prefix(CHECKSUM_PREFIX)?;
+    Some((recorded.to_string(), state::checksum(&content[..start])))
+}
+
+/// The content between the markers of a block from [`render_block`]
+fn block_content(block: &[u8]) -> &[u8] {
+    let start = block.iter().position(|b| *b == b'\n').map_or(0, |i| i + 1);
+    let end = block[..block.len().saturating_sub(1)]
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(block.len(), |i| i + 1);
+    &block[start..end.max(start)]
+}
+
+/// A file scanned by [`scan`], without its content
+#[derive(Debug)]
+struct Scanned {
+    markers: Markers,
+    /// The lines of the managed block, markers included
+    block: Option<Vec<u8>>,
+}
+
+impl Scanned {
+    /// The content between the markers of the managed block
+    fn managed(&self) -> Option<&[u8]> {
+        self.block.as_deref().map(block_content)
+    }
+}
+
+/// Read the lines of a file once for its markers, keeping only the managed block
+fn scan(mut reader: impl std::io::BufRead) -> std::io::Result<Scanned> {
+    let mut markers = Markers::default();
+    let mut line = Vec::new();
+    // The lines since the start marker of what may become the managed block
+    let mut candidate: Option<Vec<u8>> = None;
+    let mut block = None;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        let had_block = markers.managed.is_some();
+        markers.line(&line);
+        match markers.open {
+            Some((start, false)) if start + 1 == markers.lines => candidate = Some(Vec::new()),
+            Some((_, false)) => {}
+            _ => {
+                if !had_block && markers.managed.is_some() {
+                    let mut lines = candidate.take().unwrap_or_default();
+                    lines.extend(&line);
+                    block = Some(lines);
+                }
+                candidate = None;
+            }
+        }
+        if let Some(candidate) = &mut candidate {
+            candidate.extend(&line);
+        }
+    }
+    Ok(Scanned {
+        markers: markers.finish(),
+        block,
+    })
+}
+
+/// Copy the lines of a file scanned by [`scan`] to `out`, with `block` in place of the managed
+/// block, or appended if there is none, and broken blocks quarantined
+///
+/// Every other line is copied as it is, so content around the block survives byte for byte.
+fn rewrite(
+    mut reader: impl std::io::BufRead,
+    out: &mut impl Write,
+    markers: &Markers,
+    block: &[u8],
+) -> std::io::Result<()> {
+    let mut line = Vec::new();
+    let mut i = 0;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        match markers.managed {
+            Some((start, _)) if i == start => out.write_all(block)?,
+            Some((start, end)) if start < i && i <= end => {}
+            _ => {
+                if markers.is_quarantined(i) {
+                    out.write_all(QUARANTINE_PREFIX.as_bytes())?;
+                }
+                out.write_all(&line)?;
+            }
+        }
+        i += 1;
+    }
+    // The file changed since it was scanned, its markers may be elsewhere now
+    if i != markers.lines {
+        return Err(std::io::Error::other(
+            "the file changed while it was rewritten",
+        ));
+    }
+
+    if markers.managed.is_none() {
+        let nl = markers.nl().as_bytes();
+        if markers.lines > 0 {
+            if !markers.ends_with_nl {
+                out.write_all(nl)?;
+            }
+            out.write_all(nl)?;
+        }
+        out.write_all(block)?;
+    }
+    Ok(())
+}
+
+/// Copy the lines of a file scanned by [`scan`] to `out` like [`rewrite`], but with everything
+/// managed blocks left behind removed rather than quarantined
+///
+/// Complete blocks after the managed one, stray markers, and the lines of broken blocks and
+/// those earlier runs quarantined that are markers, footers, empty or in `block` all go. Lines of
+/// a block without an end marker can't be told apart from the user's own, so those that aren't
+/// fetched keys stay, quarantined for review. Returns how many lines were removed.
+fn repair(
+    mut reader: impl std::io::BufRead,
+    out: &mut impl Write,
+    markers: &Markers,
+    block: &[u8],
+) -> std::io::Result<usize> {
+    let fetched: std::collections::HashSet<&[u8]> = block
+        .split(|b| *b == b'\n')
+        .map(<[u8]>::trim_ascii)
+        .collect();
+    let ours = |line: &[u8]| {
+        let line = line.trim_ascii();
+        line.is_empty()
+            || line == MANAGED_KEYS_START.as_bytes()
+            || line == MANAGED_KEYS_END.as_bytes()
+            || line.starts_with(CHECKSUM_PREFIX.as_bytes())
+            || fetched.contains(line)
+    };
+
+    let mut line = Vec::new();
+    let mut i = 0;
+    let mut removed = 0;
+    // Whether the last line copied ends with a line ending
+    let mut last_nl = None;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        let quarantined = line.strip_prefix(QUARANTINE_PREFIX.as_bytes());
+        match markers.managed {
+            Some((start, _)) if i == start => out.write_all(block)?,
+            Some((start, end)) if start < i && i <= end => {}
+            _ if markers.is_duplicate(i) => removed += 1,
+            _ if markers.is_quarantined(i) && ours(&line) => removed += 1,
+            _ if markers.is_quarantined(i) => {
+                out.write_all(QUARANTINE_PREFIX.as_bytes())?;
+                out.write_all(&line)?;
+                last_nl = Some(line.ends_with(b"\n"));
+            }
+            _ if quarantined.is_some_and(ours) => removed += 1,
+            _ => {
+                out.write_all(&line)?;
+                last_nl = Some(line.ends_with(b"\n"));
+            }
+        }
+        i += 1;
+    }
+    if i != markers.lines {
+        return Err(std::io::Error::other(
+            "the file changed while it was rewritten",
+        ));
+    }
+
+    if markers.managed.is_none() {
+        let nl = markers.nl().as_bytes();
+        if let Some(ends_with_nl) = last_nl {
+            if !ends_with_nl {
+                out.write_all(nl)?;
+            }
+            out.write_all(nl)?;
+        }
+        out.write_all(block)?;
+    }
+    Ok(removed)
+}
+
+/// The content between the managed block markers, if the file has a managed block
+pub fn managed_block(content: &[u8]) -> Option<&[u8]> {
+    let mut markers = Markers::default();
+    // The byte offsets of the start of every line
+    let mut offsets = vec![0];
+    for line in content.split_inclusive(|b| *b == b'\n') {
+        offsets.push(offsets[offsets.len() - 1] + line.len());
+        markers.line(line);
+    }
+    let (start, end) = markers.managed?;
+    Some(&content[offsets[start + 1]..offsets[end]])
+}
+
+/// The authorized_keys file that is modified
+#[cfg(not(windows))]
+pub fn authorized_keys_path(args: &Cli) -> Result<PathBuf, ()> {
+    let ssh_config_dir = match (&args.home_dir, &args.user) {
+        (Some(home_dir), _) => home_dir.join(".ssh"),
+        (None, Some(name)) => crate::user::lookup(name)?.dir.join(".ssh"),
+        (None, None) => PathBuf::from(shellexpand::tilde(crate::SSH_CONFIG_DIR).into_owned()),
+    };
+    Ok(ssh_config_dir.join("authorized_keys"))
+}
+
+/// The authorized_keys file that is modified
+#[cfg(windows)]
+pub fn authorized_keys_path(args: &Cli) -> Result<PathBuf, ()> {
+    if args.user.is_some() {
+        Error::new("args::unsupported", "--user is not supported on Windows").report();
+        return Err(());
+    }
+    Ok(if args.administrators {
+        crate::windows::administrators_authorized_keys_path()
+    } else if let Some(home_dir) = &args.home_dir {
+        home_dir.join(".ssh").join("authorized_keys")
+    } else {
+        crate::windows::user_authorized_keys_path()
+    })
+}
+
+/// The mode sshd's StrictModes accepts for the directory of authorized_keys
+#[cfg(unix)]
+pub const DEFAULT_DIR_MODE: u32 = 0o700;
+/// The mode sshd's StrictModes accepts for authorized_keys
+#[cfg(unix)]
+pub const DEFAULT_FILE_MODE: u32 = 0o600;
+
+/// Set the mode of `file`, at `path`, if it differs from `mode`
+#[cfg(unix)]
+fn fix_mode(file: &std::fs::File, path: &Path, mode: u32) -> Result<(), ()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let current = file
+        .metadata()
+        .map_err(|e| {
+            Error::new("permissions::read", "Failed to read permissions")
+                .file(path)
+                .cause(e)
+                .report()
+        })?
+        .permissions()
+        .mode()
+        & 0o7777;
+    if current != mode {
+        tracing::info!("Fixing permissions of {path:?} -- {current:o} -> {mode:o}");
+        file.set_permissions(std::fs::Permissions::from_mode(mode))
+            .map_err(|e| {
+                Error::new("permissions::set", "Failed to set permissions")
+                    .file(path)
+                    .cause(e)
+                    .report()
+            })?;
+    }
+
+    Ok(())
+}
+
+/// The user authorized_keys at `path` is handed over to, if any
+///
+/// That is the `--user` if given, else the owner of the home directory when running as root,
+/// where files created in another user's home would otherwise belong to root.
+#[cfg(unix)]
+fn file_owner(path: &Path, args: &Cli) -> Result<Option<(Uid, Gid)>, ()> {
+    use std::os::unix::fs::MetadataExt;
+
+    if let Some(name) = &args.user {
+        let user = crate::user::lookup(name)?;
+        return Ok(Some((user.uid, user.gid)));
+    }
+    if !nix::unistd::geteuid().is_root() {
+        return Ok(None);
+    }
+    let Some(home) = path
+        .parent()
+        .and_then(Path::parent)
+        .filter(|home| !home.as_os_str().is_empty())
+    else {
+        return Ok(None);
+    };
+    let owner = match std::fs::metadata(home) {
+        Ok(owner) => owner,
+        // Created along with the directory of authorized_keys, by the user running
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            Error::new("owner::read", "Failed to read the owner")
+                .file(home)
+                .cause(e)
+                .report();
+            return Err(());
+        }
+    };
+    Ok(Some((
+        Uid::from_raw(owner.uid()),
+        Gid::from_raw(owner.gid()),
+    )))
+}
+
+/// Open the directory of authorized_keys at `path`, creating it if needed
+///
+/// Written for another user, the directory is untrusted and opened as [`Dir::open_for`] does,
+/// so a symlink planted in the home directory cannot redirect the write, nor the changes of
+/// owner and mode that follow it.
+pub fn open_dir(path: &Path, args: &Cli) -> Result<Dir, ()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+
+    #[cfg(unix)]
+    if let Some(owner) = file_owner(path, args)?
+        && owner.0 != nix::unistd::geteuid()
+    {
+        return Dir::open_for(dir, owner);
+    }
+    #[cfg(not(unix))]
+    let _ = args;
+
+    if !dir.exists() {
+        debug!("Creating ssh config directory -- {dir:?}");
+        std::fs::create_dir_all(dir).map_err(|e| {
+            Error::new(
+                "authorized_keys::create_dir",
+                "Failed to create ssh config directory",
+            )
+            .file(dir)
+            .cause(e)
+            .report()
+        })?;
+    }
+    Dir::open(dir).map_err(|e| {
+        Error::new(
+            "authorized_keys::create_dir",
+            "Failed to open ssh config directory",
+        )
+        .file(dir)
+        .cause(e)
+        .report()
+    })
+}
+
+/// Hand authorized_keys and its directory over to the user they belong to, see [`file_owner`],
+/// and give them modes sshd's StrictModes accepts
+///
+/// Both are changed through the open directory and file, never by path.
+#[cfg(unix)]
+fn secure_unix(dir: &Dir, name: &OsStr, args: &Cli) -> Result<(), ()> {
+    let path = dir.join(name);
+    let file = dir
+        .read(name)
+        .and_then(|file| file.ok_or_else(|| std::io::ErrorKind::NotFound.into()))
+        .map_err(|e| {
+            Error::new("permissions::read", "Failed to read permissions")
+                .file(&path)
+                .cause(e)
+                .report()
+        })?;
+
+    if let Some((uid, gid)) = file_owner(&path, args)? {
+        for (file, path) in [(dir.file(), dir.path()), (&file, path.as_path())] {
+            debug!("Changing owner of {path:?} -- {uid}:{gid}");
+            std::os::unix::fs::fchown(file, Some(uid.as_raw()), Some(gid.as_raw())).map_err(
+                |e| {
+                    Error::new("owner::set", "Failed to change the owner")
+                        .file(path)
+                        .cause(e)
+                        .report()
+                },
+            )?;
+        }
+    }
+
+    if !dir.path().as_os_str().is_empty() && dir.path() != Path::new(".") {
+        fix_mode(
+            dir.file(),
+            dir.path(),
+            args.dir_mode.unwrap_or(DEFAULT_DIR_MODE),
+        )?;
+    }
+    fix_mode(&file, &path, args.file_mode.unwrap_or(DEFAULT_FILE_MODE))
+}
+
+/// Give a written authorized_keys, `name` in `dir`, the ownership and permissions sshd expects
+pub fn secure(dir: &Dir, name: &OsStr, args: &Cli) -> Result<(), ()> {
+    #[cfg(windows)]
+    crate::windows::restrict_acl(&dir.join(name), args.administrators)?;
+
+    #[cfg(unix)]
+    secure_unix(dir, name, args)?;
+
+    Ok(())
+}
+
+/// Copy the extended attributes, which include POSIX ACLs, of the file being replaced
+///
+/// Attributes that cannot be copied, e.g. `security.*` ones without the privileges to set them,
+/// are skipped with a warning.
+#[cfg(unix)]
+fn copy_xattrs(from: &std::fs::File, path: &Path, to: &std::fs::File) {
+    use xattr::FileExt;
+
+    let names = match from.list_xattr() {
+        Ok(names) => names,
+        Err(e) => {
+            debug!("Failed to list extended attributes of {path:?} -- {:?}", e);
+            return;
+        }
+    };
+    for name in names {
+        let copied = from.get_xattr(&name).and_then(|value| match value {
+            Some(value) => to.set_xattr(&name, &value),
+            None => Ok(()),
+        });
+        if let Err(e) = copied {
+            Error::new("write::xattr", "Failed to keep extended attribute")
+                .file(path)
+                .with("attribute", name.to_string_lossy())
+                .cause(e)
+                .warn();
+        }
+    }
+}
+
+/// Atomically replace the content of `name` in `dir`, honoring the symlink policy
+///
+/// The content is written to a temporary file in the same directory, synced and renamed over
+/// the target, so sshd never reads a partially written file. The mode, ACLs and extended
+/// attributes of the existing file are kept.
+pub fn write_file(
+    dir: &Dir,
+    name: &OsStr,
+    content: &[u8],
+    symlinks: SymlinkPolicy,
+) -> Result<(), ()> {
+    write_file_with(
+        dir,
+        name,
+        symlinks,
+        |file| file.write_all(content),
+        |_| Ok(()),
+    )
+}
+
+/// Report that the symlink at `path`, in the directory of another user, is not written
+///
+/// Whatever the symlink policy, neither its target nor its content can be trusted there.
+fn symlink_refused(path: &Path) {
+    Error::new(
+        "write::symlink",
+        "Refusing to write a symlink in the directory of another user",
+    )
+    .file(path)
+    .help("replace it with a regular file")
+    .report();
+}
+
+/// [`write_file`] with the content written by `write_content`, which may stream it
+///
+/// `write_content` gets the temporary file buffered. Once it is complete, `check` may refuse to
+/// put it in place, reporting why, given the temporary file to read back. Either way an error
+/// leaves the target untouched.
+///
+/// In a [guarded](Dir::guarded) directory, a symlink is refused whatever the policy.
+fn write_file_with(
+    dir: &Dir,
+    name: &OsStr,
+    symlinks: SymlinkPolicy,
+    write_content: impl FnOnce(&mut std::io::BufWriter<&std::fs::File>) -> std::io::Result<()>,
+    check: impl FnOnce(&std::fs::File) -> Result<(), ()>,
+) -> Result<(), ()> {
+    let path = dir.join(name);
+    if dir.is_symlink(name) {
+        if dir.guarded() {
+            symlink_refused(&path);
+            return Err(());
+        }
+        match symlinks {
+            SymlinkPolicy::Follow => {
+                let target = std::fs::canonicalize(&path).map_err(|e| {
+                    Error::new("write::symlink", "Failed to resolve symlink")
+                        .file(&path)
+                        .cause(e)
+                        .report()
+                })?;
+                debug!("Following symlink {path:?} -- {target:?}");
+                let (Some(parent), Some(name)) = (target.parent(), target.file_name()) else {
+                    Error::new("write::path", "Invalid target file")
+                        .file(&target)
+                        .report();
+                    return Err(());
+                };
+                let parent = Dir::open(parent).map_err(|e| {
+                    Error::new("write::symlink", "Failed to resolve symlink")
+                        .file(&path)
+                        .cause(e)
+                        .report()
+                })?;
+                return write_file_with(
+                    &parent,
+                    name,
+                    SymlinkPolicy::Replace,
+                    write_content,
+                    check,
+                );
+            }
+            SymlinkPolicy::Refuse => {
+                Error::new("write::symlink", "Refusing to write a symlink")
+                    .file(&path)
+                    .help("pass --symlinks follow or --symlinks replace to write it anyway")
+                    .report();
+                return Err(());
+            }
+            SymlinkPolicy::Replace => {}
+        }
+    }
+
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(name);
+    tmp_name.push(".kanidm_sshkey_fetcher.tmp");
+
+    // A symlink being replaced has no content to keep the attributes of
+    let existing = if dir.is_symlink(name) {
+        None
+    } else {
+        dir.read(name).ok().flatten()
+    };
+    let write = || -> std::io::Result<std::fs::File> {
+        // Left behind by a run that was killed while writing
+        match dir.remove(&tmp_name) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let file = dir.create_new(&tmp_name)?;
+        if let Some(existing) = &existing {
+            file.set_permissions(existing.metadata()?.permissions())?;
+            #[cfg(unix)]
+            copy_xattrs(existing, &path, &file);
+        }
+        let mut buffered = std::io::BufWriter::new(&file);
+        write_content(&mut buffered)?;
+        buffered.flush()?;
+        drop(buffered);
+        file.sync_all()?;
+        Ok(file)
+    };
+    let write_error = |e: std::io::Error| {
+        Error::new("write::file", "Failed to write")
+            .file(&path)
+            .cause(e)
+            .report();
+        let _ = dir.remove(&tmp_name);
+    };
+    let written = write().map_err(write_error)?;
+    check(&written).map_err(|()| {
+        let _ = dir.remove(&tmp_name);
+    })?;
+    dir.rename(&tmp_name, name).map_err(write_error)?;
+
+    // Persist the rename itself
+    dir.sync();
+
+    #[cfg(target_os = "linux")]
+    crate::selinux::restore_context(&path);
+
+    Ok(())
+}
+
+/// How many times larger than the original a rewritten file may be, besides the new block
+const MAX_GROWTH: u64 = 2;
+
+/// Why the rewrite of a file scanned as `original` to one with `block` as the managed block,
+/// scanned as `written`, looks broken, if it does
+///
+/// Guards against bugs and corruption while writing: the markers must be balanced, every line
+/// of the block an empty line, a comment or an authorized key, and the size sane. The content
+/// around the block can only grow by the quarantine prefixes, so it is never smaller than the
+/// original without its block, and never more than [`MAX_GROWTH`] times as large.
+fn output_problem(
+    original: &Markers,
+    old_block: usize,
+    block: &[u8],
+    written: &Scanned,
+) -> Option<String> {
+    let markers = &written.markers;
+    if !markers.problems.is_empty() {
+        return Some(format!(
+            "the markers are unbalanced, {}",
+            markers.problems.join(", ")
+        ));
+    }
+    let Some((start, _)) = markers.managed else {
+        return Some("it has no managed block".to_string());
+    };
+    let content = written.managed().unwrap_or_default();
+    for (i, line) in String::from_utf8_lossy(content).lines().enumerate() {
+        if !is_authorized_line(line) {
+            return Some(format!("line {} is not an authorized key", start + i + 2));
+        }
+    }
+
+    let unmanaged = original.bytes.saturating_sub(old_block as u64);
+    let prefixes = (original.quarantined_lines() * QUARANTINE_PREFIX.len()) as u64;
+    // The blank line separating a block appended to a file without one
+    let separator = 2 * original.nl().len() as u64;
+    let max = MAX_GROWTH * original.bytes + prefixes + block.len() as u64 + separator;
+    if markers.bytes < unmanaged || markers.bytes > max {
+        return Some(format!(
+            "it has {} bytes, where {} to {} were expected",
+            markers.bytes, unmanaged, max
+        ));
+    }
+    None
+}
+
+/// Whether `line` may be in the managed block: empty, a comment or an authorized key that
+/// parses with its options
+fn is_authorized_line(line: &str) -> bool {
+    let line = line.trim();
+    line.is_empty()
+        || line.starts_with('#')
+        || line.parse::<ssh_key::authorized_keys::Entry>().is_ok()
+}
+
+/// `keys` without the lines that can't be in the managed block, each reported
+///
+/// One bad line of a `--source-file` or `--source-exec` shouldn't keep every other key out of
+/// the file, so it is left out rather than failing [`output_problem`] for the whole write.
+pub fn authorized_lines(keys: Vec<String>) -> Vec<String> {
+    keys.into_iter()
+        .filter(|line| {
+            let authorized = is_authorized_line(line);
+            if !authorized {
+                Error::new(
+                    "write::invalid_key",
+                    "Leaving out a line that is not an authorized key",
+                )
+                .with("line", line)
+                .help("check what the sources return for the line")
+                .warn();
+            }
+            authorized
+        })
+        .collect()
+}
+
+pub fn modify_authorized_keys(keys: Vec<String>, args: &Cli) -> Result<(), ()> {
+    debug!("Modifying authorized_keys file started");
+    write_authorized_keys(keys, args, false)
+}
+
+/// Rebuild the managed block of authorized_keys from `keys`, removing what broken blocks and
+/// earlier versions left behind, see [`repair`]
+///
+/// With `--dry-run` only reports what would be repaired.
+pub fn repair_authorized_keys(keys: Vec<String>, args: &Cli) -> Result<(), ()> {
+    debug!("Repairing authorized_keys file started");
+    write_authorized_keys(keys, args, true)
+}
+
+fn write_authorized_keys(keys: Vec<String>, args: &Cli, repairing: bool) -> Result<(), ()> {
+    let authorized_keys_file = authorized_keys_path(args)?;
+    let dir = open_dir(&authorized_keys_file, args)?;
+    let Some(name) = authorized_keys_file.file_name() else {
+        Error::new("write::path", "Invalid target file")
+            .file(&authorized_keys_file)
+            .report();
+        return Err(());
+    };
+    if dir.guarded() && dir.is_symlink(name) {
+        symlink_refused(&authorized_keys_file);
+        return Err(());
+    }
+
+    // Work on bytes, so content that isn't valid UTF-8 survives the rewrite, and line by line,
+    // so files of many megabytes are never held in memory as a whole
+    let open = || Ok::<_, std::io::Error>(dir.read(name)?.map(std::io::BufReader::new));
+    let read_error = |e: std::io::Error| {
+        Error::new(
+            "authorized_keys::read",
+            "Failed to read authorized_keys file",
+        )
+        .file(&authorized_keys_file)
+        .cause(e)
+        .report()
+    };
+    let scanned = match open().map_err(read_error)? {
+        Some(reader) => scan(reader).map_err(read_error)?,
+        None => Scanned {
+            markers: Markers::default(),
+            block: None,
+        },
+    };
+    let exists = dir.exists(name);
+    if repairing {
+        let markers = &scanned.markers;
+        for problem in &markers.problems {
+            tracing::info!("Repairing {authorized_keys_file:?} -- {problem}");
+        }
+        if markers.leftovers > 0 {
+            tracing::info!(
+                "Repairing {authorized_keys_file:?} -- {} lines quarantined by earlier runs",
+                markers.leftovers
+            );
+        }
+        if markers.problems.is_empty() && markers.leftovers == 0 {
+            tracing::info!("Nothing to repair in {authorized_keys_file:?}");
+        }
+        if args.dry_run {
+            return Ok(());
+        }
+    }
+    for problem in scanned.markers.problems.iter().filter(|_| !repairing) {
+        Error::new(
+            "authorized_keys::malformed",
+            "Malformed managed block, quarantining it",
+        )
+        .file(&authorized_keys_file)
+        .with("at", problem)
+        .warn();
+    }
+
+    // Compare the managed block with its footer and with what we wrote last time
+    let state_dir = state::state_dir(args);
+    let mut state = state::State::load(&state_dir);
+    let state_key = authorized_keys_file.to_string_lossy().into_owned();
+    let previous = scanned.managed().map(state::checksum);
+    let drift = match (
+        scanned.managed().and_then(footer_checksums),
+        &previous,
+        state.managed_checksums.get(&state_key),
+    ) {
+        (Some((expected, actual)), _, _) if expected != actual => {
+            Some(("footer", expected, actual))
+        }
+        (_, Some(actual), Some(expected)) if actual != expected => {
+            Some(("state", expected.clone(), actual.clone()))
+        }
+        _ => None,
+    };
+    // Repairing rebuilds the block whatever it holds
+    if let Some((detected_by, expected, actual)) = drift.filter(|_| !repairing) {
+        let policy = args.on_tamper.unwrap_or_default();
+        crate::audit::record(
+            args,
+            "drift",
+            &authorized_keys_file,
+            serde_json::json!({
+                "detected_by": detected_by,
+                "expected": expected,
+                "actual": actual,
+                "action": match policy {
+                    TamperPolicy::Repair => "overwrite",
+                    TamperPolicy::Warn => "leave",
+                },
+            }),
+        );
+        match policy {
+            TamperPolicy::Repair => Error::new(
+                "authorized_keys::tampered",
+                "The managed block was modified since it was last written, overwriting it",
+            )
+            .file(&authorized_keys_file)
+            .with("detected_by", detected_by)
+            .warn(),
+            TamperPolicy::Warn => {
+                Error::new(
+                    "authorized_keys::tampered",
+                    "The managed block was modified since it was last written, leaving it untouched",
+                )
+                .file(&authorized_keys_file)
+                .with("detected_by", detected_by)
+                .help("pass --on-tamper repair to overwrite it with the fetched keys")
+                .report();
+                return Err(());
+            }
+        }
+    } else if scanned.block.is_some() {
+        debug!(
+            "The managed block was not modified since it was last written -- {authorized_keys_file:?}"
+        );
+    }
+
+    let block = render_block(&keys, scanned.markers.nl());
+    let checksum = state::checksum(block_content(&block));
+
+    // Only the block changes unless broken blocks are quarantined
+    if exists
+        && scanned.markers.problems.is_empty()
+        && (!repairing || scanned.markers.leftovers == 0)
+        && scanned.block.as_ref() == Some(&block)
+    {
+        debug!("authorized_keys is up to date, not rewriting it -- {authorized_keys_file:?}");
+        return secure(&dir, name, args);
+    }
+
+    if let Some(current) = open().map_err(read_error)? {
+        backup::backup(
+            &state_dir,
+            &authorized_keys_file,
+            current,
+            args.keep_backups.unwrap_or(backup::DEFAULT_KEEP_BACKUPS),
+        )?;
+    }
+
+    // Write the updated content back to the file
+    write_file_with(
+        &dir,
+        name,
+        args.symlinks.unwrap_or_default(),
+        |out| match open()? {
+            Some(reader) if repairing => {
+                let removed = repair(reader, out, &scanned.markers, &block)?;
+                debug!("Removed {removed} lines left behind by broken blocks");
+                Ok(())
+            }
+            Some(reader) => rewrite(reader, out, &scanned.markers, &block),
+            None => out.write_all(&block),
+        },
+        |tmp| {
+            // Repairing may remove any line, so only the upper bound on the size holds
+            let old_block = if repairing {
+                scanned.markers.bytes as usize
+            } else {
+                scanned.block.as_ref().map_or(0, Vec::len)
+            };
+            let written = {
+                use std::io::Seek;
+                let mut tmp = tmp;
+                tmp.rewind().map(|()| std::io::BufReader::new(tmp))
+            };
+            let problem = match written.and_then(scan) {
+                Ok(written) => output_problem(&scanned.markers, old_block, &block, &written),
+                Err(e) => Some(e.to_string()),
+            };
+            let Some(problem) = problem else {
+                return Ok(());
+            };
+            Error::new(
+                "write::sanity",
+                "Refusing to write output that looks broken, leaving the file untouched",
+            )
+            .file(&authorized_keys_file)
+            .with("problem", problem)
+            .help("check what the sources return for the line, or report a bug with the file")
+            .report();
+            Err(())
+        },
+    )?;
+
+    secure(&dir, name, args)?;
+
+    crate::audit::record(
+        args,
+        if repairing { "repair" } else { "write" },
+        &authorized_keys_file,
+        serde_json::json!({ "checksum": checksum, "previous": previous }),
+    );
+    state.managed_checksums.insert(state_key, checksum);
+    state.save(&state_dir)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: &str = MANAGED_KEYS_START;
+    const END: &str = MANAGED_KEYS_END;
+
+    fn keys() -> Vec<String> {
+        vec![
+            "ssh-ed25519 AAAA alice@laptop".to_string(),
+            "ssh-rsa BBBB alice@desktop".to_string(),
+        ]
+    }
+
+    /// `content` rewritten with `keys` like [`modify_authorized_keys`] does, in memory
+    fn render(content: &[u8], keys: &[String]) -> Vec<u8> {
+        let scanned = scan(content).unwrap();
+        let block = render_block(keys, scanned.markers.nl());
+        let mut out = Vec::new();
+        rewrite(content, &mut out, &scanned.markers, &block).unwrap();
+        out
+    }
+
+    /// The footer of a block whose lines between the start marker and the footer are `lines`
+    fn footer(lines: &str) -> String {
+        format!("{CHECKSUM_PREFIX}{}", state::checksum(lines.as_bytes()))
+    }
+
+    fn with_block(before: &[u8], block: &str, after: &[u8]) -> Vec<u8> {
+        let mut content = before.to_vec();
+        content.extend(block.as_bytes());
+        content.extend(after);
+        content
+    }
+
+    /// Unmanaged content in all the shapes users leave it in
+    const UNMANAGED: &[&[u8]] = &[
+        b"",
+        b"ssh-ed25519 USER\n",
+        b"ssh-ed25519 USER",
+        b"ssh-ed25519 USER\n\n\n",
+        b"\n\n# a comment\n  \t\n# another comment   \n",
+        b"command=\"/bin/true\",no-pty ssh-ed25519 USER user@host\n",
+        b"ssh-ed25519 USER \xff\xfe not utf-8\n",
+        b"ssh-ed25519 USER\r\n# comment\r\n\r\n",
+    ];
+
+    #[test]
+    fn preserves_content_around_block() {
+        let block = format!("{START}\n\nssh-ed25519 OLD\n\n{END}\n");
+        for before in UNMANAGED {
+            for after in UNMANAGED {
+                // Content before the block always ends with a newline, otherwise the start
+                // marker would not be on its own line
+                if !before.is_empty() && !before.ends_with(b"\n") {
+                    continue;
+                }
+                let content = with_block(before, &block, after);
+                let rendered = render(&content, &keys());
+
+                assert!(rendered.starts_with(before), "before {before:?}");
+                assert!(rendered.ends_with(after), "after {after:?}");
+                assert!(!rendered.windows(3).any(|w| w == b"OLD"));
+            }
+        }
+    }
+
+    #[test]
+    fn appends_block_after_unmanaged_content() {
+        for before in UNMANAGED {
+            let rendered = render(before, &keys());
+
+            assert!(rendered.starts_with(before), "before {before:?}");
+            let markers = scan(&rendered[..]).unwrap().markers;
+            assert!(markers.problems.is_empty());
+            assert_eq!(markers.managed.map(|(_, end)| end + 1), Some(markers.lines));
+        }
+    }
+
+    #[test]
+    fn rewrite_is_idempotent() {
+        for before in UNMANAGED {
+            let once = render(before, &keys());
+            let twice = render(&once, &keys());
+            assert_eq!(once, twice, "before {before:?}");
+        }
+    }
+
+    #[test]
+    fn renders_keys_in_block() {
+        let rendered = render(b"ssh-ed25519 USER\n", &keys());
+        let keys_lines = "\nssh-ed25519 AAAA alice@laptop\nssh-rsa BBBB alice@desktop\n\n";
+        let expected = format!(
+            "ssh-ed25519 USER\n\n{START}\n{keys_lines}{}\n{END}\n",
+            footer(keys_lines)
+        );
+        assert_eq!(String::from_utf8_lossy(&rendered), expected);
+    }
+
+    #[test]
+    fn keeps_crlf_line_endings() {
+        let content = format!("ssh-ed25519 USER\r\n\r\n{START}\r\n\r\n{END}\r\n");
+        let rendered = render(content.as_bytes(), &keys());
+        let keys_lines = "\r\nssh-ed25519 AAAA alice@laptop\r\nssh-rsa BBBB alice@desktop\r\n\r\n";
+        let expected = format!(
+            "ssh-ed25519 USER\r\n\r\n{START}\r\n{keys_lines}{}\r\n{END}\r\n",
+            footer(keys_lines)
+        );
+        assert_eq!(String::from_utf8_lossy(&rendered), expected);
+    }
+
+    #[test]
+    fn quarantines_broken_blocks() {
+        let content = format!(
+            "{END}\nssh-ed25519 USER\n{START}\nssh-ed25519 OLD\n{END}\n{START}\nssh-ed25519 DUP\n{END}\n{START}\nssh-ed25519 TRUNCATED\n"
+        );
+        let scanned = scan(content.as_bytes()).unwrap();
+        assert_eq!(scanned.markers.problems.len(), 3);
+        assert_eq!(
+            scanned.managed(),
+            Some(&b"ssh-ed25519 OLD\n"[..]),
+            "the first complete block is the managed one"
+        );
+        assert_eq!(scanned.managed(), managed_block(content.as_bytes()));
+
+        let rendered = String::from_utf8_lossy(&render(content.as_bytes(), &keys())).into_owned();
+        let q = QUARANTINE_PREFIX;
+        let keys_lines = "\nssh-ed25519 AAAA alice@laptop\nssh-rsa BBBB alice@desktop\n\n";
+        let footer = footer(keys_lines);
+        let expected = format!(
+            "{q}{END}\nssh-ed25519 USER\n{START}\n{keys_lines}{footer}\n{END}\n{q}{START}\n{q}ssh-ed25519 DUP\n{q}{END}\n{q}{START}\n{q}ssh-ed25519 TRUNCATED\n"
+        );
+        assert_eq!(rendered, expected);
+
+        // The quarantined lines are inert on the next run
+        let rescanned = scan(rendered.as_bytes()).unwrap();
+        assert!(rescanned.markers.problems.is_empty());
+    }
+
+    #[test]
+    fn detects_blocks_edited_since_they_were_written() {
+        let content = render(b"ssh-ed25519 USER\n", &keys());
+        let scanned = scan(&content[..]).unwrap();
+        let (recorded, actual) = footer_checksums(scanned.managed().unwrap()).unwrap();
+        assert_eq!(recorded, actual);
+
+        let edited = String::from_utf8_lossy(&content).replace("BBBB", "EVIL");
+        let scanned = scan(edited.as_bytes()).unwrap();
+        let (recorded, actual) = footer_checksums(scanned.managed().unwrap()).unwrap();
+        assert_ne!(recorded, actual);
+
+        // Blocks written before the footer was added have nothing to check
+        let old = format!("{START}\n\nssh-ed25519 OLD\n\n{END}\n");
+        let scanned = scan(old.as_bytes()).unwrap();
+        assert_eq!(footer_checksums(scanned.managed().unwrap()), None);
+    }
+
+    #[test]
+    fn repairs_broken_blocks() {
+        let q = QUARANTINE_PREFIX;
+        let content = format!(
+            "{END}\nssh-ed25519 USER\n{START}\nssh-ed25519 OLD\n{END}\n{START}\nssh-ed25519 DUP\n{END}\n{q}{START}\n{q}ssh-ed25519 AAAA alice@laptop\n{q}ssh-ed25519 STALE\n{START}\nssh-ed25519 AAAA alice@laptop\nssh-ed25519 MINE\n"
+        );
+        let scanned = scan(content.as_bytes()).unwrap();
+        assert_eq!(scanned.markers.leftovers, 3);
+        let block = render_block(&keys(), "\n");
+        let mut repaired = Vec::new();
+        let removed = repair(content.as_bytes(), &mut repaired, &scanned.markers, &block).unwrap();
+        assert_eq!(removed, 8);
+
+        let block = String::from_utf8_lossy(&block);
+        let expected =
+            format!("ssh-ed25519 USER\n{block}{q}ssh-ed25519 STALE\n{q}ssh-ed25519 MINE\n");
+        assert_eq!(String::from_utf8_lossy(&repaired), expected);
+
+        // What is left is clean
+        let rescanned = scan(&repaired[..]).unwrap();
+        assert!(rescanned.markers.problems.is_empty());
+        assert_eq!(rescanned.markers.leftovers, 2);
+
+        // Without a managed block the new one is appended
+        let content = format!("ssh-ed25519 USER\n{START}\nssh-ed25519 AAAA alice@laptop\n");
+        let scanned = scan(content.as_bytes()).unwrap();
+        let mut repaired = Vec::new();
+        repair(
+            content.as_bytes(),
+            &mut repaired,
+            &scanned.markers,
+            block.as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&repaired),
+            format!("ssh-ed25519 USER\n\n{block}")
+        );
+    }
+
+    #[test]
+    fn refuses_files_that_changed_since_they_were_scanned() {
+        let content = format!("ssh-ed25519 USER\n{START}\n\n{END}\n");
+        let scanned = scan(content.as_bytes()).unwrap();
+        let block = render_block(&keys(), "\n");
+        let changed = format!("ssh-ed25519 USER\nssh-ed25519 NEW\n{START}\n\n{END}\n");
+        assert!(
+            rewrite(
+                changed.as_bytes(),
+                &mut Vec::new(),
+                &scanned.markers,
+                &block
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn leaves_out_lines_that_are_not_keys() {
+        const KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIGTlyP9U6voqkUKDgU2cO9gviPO33QDYXnZaEG8f0Flf alice";
+        let lines = [
+            "# From command get-legacy-keys alice",
+            KEY,
+            "not a key",
+            "ssh-ed25519 AAAA truncated",
+            "",
+            &format!("restrict,command=\"uptime\" {KEY}"),
+        ];
+        assert_eq!(
+            authorized_lines(lines.iter().map(|line| line.to_string()).collect()),
+            [lines[0], lines[1], lines[4], lines[5]]
+        );
+    }
+
+    #[test]
+    fn refuses_output_that_looks_broken() {
+        let keys = vec![
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIGTlyP9U6voqkUKDgU2cO9gviPO33QDYXnZaEG8f0Flf alice"
+                .to_string(),
+        ];
+        let user = "ssh-ed25519 USER\n".repeat(20);
+        let content = format!("{user}{START}\n\nssh-ed25519 OLD\n\n{END}\n# after\n");
+        let original = scan(content.as_bytes()).unwrap();
+        let old_block = original.block.as_ref().map_or(0, Vec::len);
+        let block = render_block(&keys, "\n");
+        let problem = |written: &[u8]| {
+            output_problem(
+                &original.markers,
+                old_block,
+                &block,
+                &scan(written).unwrap(),
+            )
+        };
+
+        let rendered = render(content.as_bytes(), &keys);
+        assert_eq!(problem(&rendered), None);
+
+        // Truncated to the block, or with the content around it duplicated
+        let max = 2 * content.len() + block.len() + 2;
+        assert_eq!(
+            problem(&block),
+            Some(format!(
+                "it has {} bytes, where 348 to {max} were expected",
+                block.len()
+            ))
+        );
+        let grown = [user.repeat(3).as_bytes(), &rendered].concat();
+        assert_eq!(
+            problem(&grown),
+            Some(format!(
+                "it has {} bytes, where 348 to {max} were expected",
+                grown.len()
+            ))
+        );
+
+        assert_eq!(
+            problem(format!("{START}\n\nssh-ed25519 USER\n").as_bytes()),
+            Some("the markers are unbalanced, line 1: start marker without end marker".to_string())
+        );
+        let not_a_key = format!("{user}{START}\n\nnot a key\n\n{END}\n# after\n");
+        assert_eq!(
+            problem(not_a_key.as_bytes()),
+            Some("line 23 is not an authorized key".to_string())
+        );
+        assert_eq!(
+            problem(user.as_bytes()),
+            Some("it has no managed block".to_string())
+        );
+    }
+}
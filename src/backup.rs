@@ -0,0 +1,209 @@
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use time::{OffsetDateTime, format_description::BorrowedFormatItem, macros::format_description};
+use tracing::{debug, info};
+
+use crate::diagnostic::Error;
+use crate::{authorized_keys, state};
+
+/// How many backups are kept per file if `keep_backups` is not configured
+pub const DEFAULT_KEEP_BACKUPS: usize = 10;
+
+/// Backups are named after when they were taken, to the microsecond so they sort and several
+/// taken in a second don't replace each other
+const TIMESTAMP_FORMAT: &[BorrowedFormatItem<'static>] =
+    format_description!("[year][month][day]T[hour][minute][second].[subsecond digits:6]Z");
+
+/// How often a backup is retried under a new timestamp if one by that name already exists
+const NAME_ATTEMPTS: usize = 10;
+
+#[derive(Debug, Clone, Args)]
+pub struct RestoreArgs {
+    /// The backup to restore, either a timestamp as shown by --list or `latest`
+    #[arg(long = "from", default_value = "latest")]
+    from: String,
+
+    /// List the available backups instead of restoring one
+    #[arg(short, long, default_value_t = false)]
+    list: bool,
+}
+
+/// The directory backups of a file are kept in, named after the full path of the file
+fn backup_dir(state_dir: &Path, target: &Path) -> PathBuf {
+    let name = target.to_string_lossy().replace(['/', '\\', ':'], "_");
+    state_dir.join("backups").join(name)
+}
+
+/// The timestamps of the available backups, oldest first
+fn list_backups(dir: &Path) -> Vec<String> {
+    let mut backups: Vec<String> = std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter_map(|e| e.file_name().to_str().map(String::from))
+                .filter(|name| !name.starts_with('.'))
+                .collect()
+        })
+        .unwrap_or_default();
+    backups.sort();
+    backups
+}
+
+/// Copy `content`, the current content of `target`, into the backup directory, keeping the
+/// newest `keep`
+///
+/// The content is read from the file opened by the caller rather than copied by path, so a
+/// file swapped in after it was checked is not backed up.
+pub fn backup(
+    state_dir: &Path,
+    target: &Path,
+    mut content: impl std::io::Read,
+    keep: usize,
+) -> Result<(), ()> {
+    if keep == 0 {
+        return Ok(());
+    }
+
+    let dir = backup_dir(state_dir, target);
+    std::fs::create_dir_all(&dir).map_err(|e| {
+        Error::new("backup::create_dir", "Failed to create backup directory")
+            .file(&dir)
+            .cause(e)
+            .report()
+    })?;
+
+    let mut attempts = 0;
+    let (backup_path, mut file) = loop {
+        let timestamp = OffsetDateTime::now_utc()
+            .format(TIMESTAMP_FORMAT)
+            .map_err(|e| {
+                Error::new("backup::timestamp", "Failed to format backup timestamp")
+                    .cause(e)
+                    .report()
+            })?;
+        let backup_path = dir.join(&timestamp);
+        attempts += 1;
+        match std::fs::File::create_new(&backup_path) {
+            Ok(file) => break (backup_path, file),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && attempts < NAME_ATTEMPTS => {
+                debug!("Backup already exists, retrying -- {backup_path:?}");
+            }
+            Err(e) => {
+                Error::new("backup::copy", "Failed to back up")
+                    .file(&backup_path)
+                    .cause(e)
+                    .report();
+                return Err(());
+            }
+        }
+    };
+    debug!("Backing up {target:?} -- {backup_path:?}");
+    std::io::copy(&mut content, &mut file).map_err(|e| {
+        Error::new("backup::copy", "Failed to back up")
+            .file(target)
+            .cause(e)
+            .report()
+    })?;
+
+    let backups = list_backups(&dir);
+    for old in &backups[..backups.len().saturating_sub(keep)] {
+        debug!("Removing old backup -- {old}");
+        let _ = std::fs::remove_file(dir.join(old));
+    }
+
+    Ok(())
+}
+
+pub fn restore(args: &crate::Cli, restore: &RestoreArgs) -> Result<(), ()> {
+    let state_dir = state::state_dir(args);
+    let target = authorized_keys::authorized_keys_path(args)?;
+    let dir = backup_dir(&state_dir, &target);
+    let backups = list_backups(&dir);
+
+    if restore.list {
+        backups.iter().for_each(|b| println!("{}", b));
+        return Ok(());
+    }
+
+    let timestamp = match restore.from.as_str() {
+        "latest" => backups.last(),
+        from => backups.iter().find(|b| *b == from),
+    }
+    .ok_or_else(|| {
+        Error::new(
+            "backup::not_found",
+            format!("No backup {} found", restore.from),
+        )
+        .file(&target)
+        .help("list the backups with `restore --list`")
+        .report()
+    })?;
+
+    let content = std::fs::read(dir.join(timestamp)).map_err(|e| {
+        Error::new("backup::read", "Failed to read backup")
+            .file(dir.join(timestamp))
+            .cause(e)
+            .report()
+    })?;
+
+    let target_dir = authorized_keys::open_dir(&target, args)?;
+    let Some(name) = target.file_name() else {
+        Error::new("write::path", "Invalid target file")
+            .file(&target)
+            .report();
+        return Err(());
+    };
+    authorized_keys::write_file(
+        &target_dir,
+        name,
+        &content,
+        args.symlinks.unwrap_or_default(),
+    )?;
+
+    authorized_keys::secure(&target_dir, name, args)?;
+
+    // The restored block is the new reference for tamper detection
+    let mut state = state::State::load(&state_dir);
+    let state_key = target.to_string_lossy().into_owned();
+    match authorized_keys::managed_block(&content) {
+        Some(block) => {
+            state
+                .managed_checksums
+                .insert(state_key, state::checksum(block));
+        }
+        None => {
+            state.managed_checksums.remove(&state_key);
+        }
+    }
+    state.save(&state_dir)?;
+
+    info!("Restored {target:?} from backup {}", timestamp);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_backups_taken_in_the_same_second() {
+        let state_dir = std::env::temp_dir().join(format!(
+            "kanidm_sshkey_fetcher-backup-{}",
+            std::process::id()
+        ));
+        let target = Path::new("/home/alice/.ssh/authorized_keys");
+
+        for content in ["first", "second", "third"] {
+            backup(&state_dir, target, content.as_bytes(), 2).unwrap();
+        }
+        let dir = backup_dir(&state_dir, target);
+        let backups: Vec<String> = list_backups(&dir)
+            .iter()
+            .map(|name| std::fs::read_to_string(dir.join(name)).unwrap())
+            .collect();
+        let _ = std::fs::remove_dir_all(&state_dir);
+
+        assert_eq!(backups, ["second", "third"]);
+    }
+}
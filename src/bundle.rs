@@ -0,0 +1,388 @@
+//! Signed bundles of fetched keys, for hosts with no route to the kanidm server
+//!
+//! A bundle is a JSON file holding the keys of every account as a JSON payload, and an SSH
+//! signature of that payload in the `kanidm_sshkey_fetcher-bundle` namespace, the same
+//! signature `ssh-keygen -Y sign` makes:
+//!
+//! ```json
+//! {"payload": "{\"created\":1760000000,\"accounts\":[[\"alice\",[\"ssh-ed25519 AAAA...\"]]]}",
+//!  "signature": "-----BEGIN SSH SIGNATURE-----\n..."}
+//! ```
+//!
+//! `import` only writes a bundle signed by one of the trusted keys, configured with
+//! `bundle_trusted_keys`, and never one older than the last it imported, so an old bundle
+//! can't bring back keys removed since.
+
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use ssh_key::{HashAlg, LineEnding, PrivateKey, PublicKey, SshSig};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+use tracing::info;
+
+use crate::Cli;
+use crate::diagnostic::Error;
+
+/// The namespace bundles are signed in, so no other SSH signature passes for one
+pub const NAMESPACE: &str = "kanidm_sshkey_fetcher-bundle";
+
+#[derive(Debug, Clone, Args)]
+pub struct ImportArgs {
+    /// The bundle to write the keys of
+    #[arg(long, value_parser)]
+    bundle: PathBuf,
+
+    /// A file of OpenSSH public keys trusted to sign bundles, one per line, in addition to
+    /// --bundle-trusted-key
+    #[arg(long, value_parser)]
+    trusted_keys: Option<PathBuf>,
+}
+
+/// A bundle as written to disk
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignedBundle {
+    /// The [`Bundle`] as JSON, kept as the exact bytes that were signed
+    pub payload: String,
+    /// The armored SSH signature of the payload
+    pub signature: String,
+}
+
+/// What a bundle carries
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bundle {
+    /// When the keys were fetched, in seconds since the epoch
+    pub created: i64,
+    /// The kanidm server the keys were fetched from
+    #[serde(default)]
+    pub server: Option<String>,
+    /// The keys of each account
+    pub accounts: Vec<(String, Vec<String>)>,
+}
+
+/// Why a bundle was refused
+#[derive(Debug, PartialEq, Eq)]
+enum Refused {
+    Malformed(String),
+    /// Signed for something else than a bundle, in this namespace
+    WrongNamespace(String),
+    /// Signed by a key that is not trusted, with this fingerprint
+    Untrusted(String),
+    /// Signed by a trusted key, but the payload is not what was signed
+    Tampered,
+}
+
+/// The bundle in `content` if it was signed by one of `trusted`
+fn verify(content: &str, trusted: &[PublicKey]) -> Result<Bundle, Refused> {
+    let signed: SignedBundle =
+        serde_json::from_str(content).map_err(|e| Refused::Malformed(e.to_string()))?;
+    let signature =
+        SshSig::from_pem(&signed.signature).map_err(|e| Refused::Malformed(e.to_string()))?;
+
+    if signature.namespace() != NAMESPACE {
+        return Err(Refused::WrongNamespace(signature.namespace().to_string()));
+    }
+    let Some(signer) = trusted
+        .iter()
+        .find(|key| key.key_data() == signature.public_key())
+    else {
+        let fingerprint = signature.public_key().fingerprint(HashAlg::Sha256);
+        return Err(Refused::Untrusted(fingerprint.to_string()));
+    };
+    // Nothing of the payload is looked at before its signature is checked
+    signer
+        .verify(NAMESPACE, signed.payload.as_bytes(), &signature)
+        .map_err(|_| Refused::Tampered)?;
+    serde_json::from_str(&signed.payload).map_err(|e| Refused::Malformed(e.to_string()))
+}
+
+/// Sign a bundle with the OpenSSH private key at `signing_key` and write it to `path`
+pub fn write(path: &Path, signing_key: &Path, bundle: &Bundle) -> Result<(), ()> {
+    let key = PrivateKey::read_openssh_file(signing_key).map_err(|e| {
+        Error::new("bundle::signing_key", "Failed to read the signing key")
+            .file(signing_key)
+            .cause(e)
+            .report()
+    })?;
+    if key.is_encrypted() {
+        Error::new("bundle::signing_key", "The signing key is encrypted")
+            .file(signing_key)
+            .help("sign with a key without a passphrase, kept off the network like the bundle")
+            .report();
+        return Err(());
+    }
+
+    let payload = serde_json::to_string(bundle).map_err(|e| {
+        Error::new("bundle::write", "Failed to serialize the bundle")
+            .cause(e)
+            .report()
+    })?;
+    let signature = key
+        .sign(NAMESPACE, HashAlg::Sha512, payload.as_bytes())
+        .and_then(|signature| signature.to_pem(LineEnding::LF))
+        .map_err(|e| {
+            Error::new("bundle::sign", "Failed to sign the bundle")
+                .file(signing_key)
+                .cause(e)
+                .report()
+        })?;
+    let content =
+        serde_json::to_string_pretty(&SignedBundle { payload, signature }).map_err(|e| {
+            Error::new("bundle::write", "Failed to serialize the bundle")
+                .cause(e)
+                .report()
+        })?;
+
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, content + "\n").map_err(|e| {
+        Error::new("bundle::write", "Failed to write the bundle")
+            .file(&tmp_path)
+            .cause(e)
+            .report()
+    })?;
+    std::fs::rename(&tmp_path, path).map_err(|e| {
+        Error::new("bundle::write", "Failed to move the bundle into place")
+            .file(path)
+            .cause(e)
+            .report()
+    })
+}
+
+/// The public keys in a trusted keys file, skipping empty lines and `#` comments
+fn read_trusted_keys(path: &Path) -> Result<Vec<PublicKey>, ()> {
+    let content = std::fs::read_to_string(path).map_err(|e| {
+        Error::new("bundle::trusted_keys", "Failed to read the trusted keys")
+            .file(path)
+            .cause(e)
+            .report()
+    })?;
+    crate::source::key_lines(&content)
+        .map(|line| {
+            PublicKey::from_openssh(&line).map_err(|e| {
+                Error::new("bundle::trusted_keys", "Invalid trusted key")
+                    .file(path)
+                    .with("key", &line)
+                    .cause(e)
+                    .report()
+            })
+        })
+        .collect()
+}
+
+/// The keys of `--bundle-trusted-key` and of the trusted keys file, if any
+fn trusted_keys(args: &Cli, import: &ImportArgs) -> Result<Vec<PublicKey>, ()> {
+    let mut trusted = args
+        .bundle_trusted_keys
+        .iter()
+        .map(|line| {
+            PublicKey::from_openssh(line).map_err(|e| {
+                Error::new("bundle::trusted_keys", "Invalid trusted key")
+                    .with("key", line)
+                    .cause(e)
+                    .report()
+            })
+        })
+        .collect::<Result<Vec<_>, ()>>()?;
+    if let Some(path) = &import.trusted_keys {
+        trusted.extend(read_trusted_keys(path)?);
+    }
+
+    if trusted.is_empty() {
+        Error::new(
+            "bundle::trusted_keys",
+            "No keys are trusted to sign bundles",
+        )
+        .help("pass --trusted-keys <FILE> or set bundle_trusted_keys in the config")
+        .report();
+        return Err(());
+    }
+    Ok(trusted)
+}
+
+/// Read a bundle, verifying its signature against the trusted keys
+fn read(args: &Cli, import: &ImportArgs) -> Result<Bundle, ()> {
+    let trusted = trusted_keys(args, import)?;
+    let content = std::fs::read_to_string(&import.bundle).map_err(|e| {
+        Error::new("bundle::read", "Failed to read the bundle")
+            .file(&import.bundle)
+            .cause(e)
+            .report()
+    })?;
+
+    verify(&content, &trusted).map_err(|refused| {
+        let error = match refused {
+            Refused::Malformed(problem) => {
+                Error::new("bundle::parse", "Failed to parse the bundle").cause(problem)
+            }
+            Refused::WrongNamespace(namespace) => Error::new(
+                "bundle::signature",
+                "The signature is not one of a bundle, refusing to import",
+            )
+            .with("namespace", namespace)
+            .help(format!("bundles are signed in the {NAMESPACE} namespace")),
+            Refused::Untrusted(fingerprint) => Error::new(
+                "bundle::signature",
+                "The bundle is signed by a key that is not trusted, refusing to import",
+            )
+            .with("signer", fingerprint)
+            .help("add the signer's public key to the trusted keys if it should be trusted"),
+            Refused::Tampered => Error::new(
+                "bundle::tampered",
+                "The bundle was modified after it was signed, refusing to import",
+            )
+            .help("export the bundle again and check how it was copied"),
+        };
+        error.file(&import.bundle).report()
+    })
+}
+
+/// Write the keys of a bundle to the configured destinations, as a fetch would
+pub fn import(args: &Cli, import: &ImportArgs, started: Instant) -> Result<(), ()> {
+    let bundle = read(args, import)?;
+    let created = OffsetDateTime::from_unix_timestamp(bundle.created)
+        .map_err(time::error::Format::from)
+        .and_then(|created| created.format(&Rfc3339))
+        .map_err(|e| {
+            Error::new("bundle::parse", "The bundle has an invalid timestamp")
+                .file(&import.bundle)
+                .cause(e)
+                .report()
+        })?;
+
+    let state_dir = crate::state::state_dir(args);
+    let state = crate::state::State::load(&state_dir);
+    if let Some(last) = state.last_bundle
+        && bundle.created < last
+    {
+        Error::new(
+            "bundle::rollback",
+            "The bundle is older than the one last imported",
+        )
+        .file(&import.bundle)
+        .with("created", created)
+        .help("export a new bundle, an old one could bring back removed keys")
+        .report();
+        return Err(());
+    }
+    info!(
+        "Importing the keys of {} accounts fetched at {}",
+        bundle.accounts.len(),
+        created
+    );
+
+    let origin = crate::source::Origin {
+        kind: crate::source::OriginKind::Bundle,
+        from: Some(import.bundle.display().to_string()),
+    };
+    let results = crate::source::Fetched {
+        origins: bundle
+            .accounts
+            .iter()
+            .map(|(id, _)| (id.clone(), origin.clone()))
+            .collect(),
+        fetched: bundle
+            .accounts
+            .into_iter()
+            .map(|(id, keys)| (id, Some(keys)))
+            .collect(),
+        complete: true,
+        static_keys: crate::source::static_keys(args),
+        posix_ids: Default::default(),
+    };
+    if !args.modify && args.key_dir.is_none() {
+        let keys = results.fetched.iter().filter_map(|(_, keys)| keys.as_ref());
+        keys.flatten()
+            .chain(&results.static_keys)
+            .for_each(|key| println!("{key}"));
+        return Ok(());
+    }
+    crate::write_results(args, &results, started)?;
+
+    // Loaded again, writing the results updated the checksums
+    let mut state = crate::state::State::load(&state_dir);
+    state.last_bundle = Some(bundle.created);
+    state.save(&state_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ssh_key::Algorithm;
+    use ssh_key::rand_core::OsRng;
+
+    fn signed(key: &PrivateKey, payload: &str) -> String {
+        let signature = key
+            .sign(NAMESPACE, HashAlg::Sha512, payload.as_bytes())
+            .and_then(|signature| signature.to_pem(LineEnding::LF))
+            .expect("signing works");
+        serde_json::to_string(&SignedBundle {
+            payload: payload.to_string(),
+            signature,
+        })
+        .expect("the bundle serializes")
+    }
+
+    #[test]
+    fn only_accepts_bundles_signed_by_a_trusted_key() {
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).expect("a key");
+        let other = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).expect("a key");
+        let trusted = [key.public_key().clone()];
+        let payload = r#"{"created":1760000000,"accounts":[["alice",["ssh-ed25519 AAAA"]]]}"#;
+
+        assert_eq!(
+            verify(&signed(&key, payload), &trusted),
+            Ok(Bundle {
+                created: 1760000000,
+                server: None,
+                accounts: vec![("alice".to_string(), vec!["ssh-ed25519 AAAA".to_string()])],
+            })
+        );
+        let fingerprint = other.public_key().fingerprint(HashAlg::Sha256).to_string();
+        assert_eq!(
+            verify(&signed(&other, payload), &trusted),
+            Err(Refused::Untrusted(fingerprint))
+        );
+
+        let tampered = signed(&key, payload).replace("alice", "mallory");
+        assert_eq!(verify(&tampered, &trusted), Err(Refused::Tampered));
+
+        let signature = key
+            .sign("file", HashAlg::Sha512, payload.as_bytes())
+            .and_then(|signature| signature.to_pem(LineEnding::LF))
+            .unwrap();
+        let other_namespace = serde_json::json!({"payload": payload, "signature": signature});
+        assert_eq!(
+            verify(&other_namespace.to_string(), &trusted),
+            Err(Refused::WrongNamespace("file".to_string()))
+        );
+        assert!(matches!(
+            verify("not json", &trusted),
+            Err(Refused::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn reads_back_written_bundles() {
+        let dir = std::env::temp_dir().join(format!(
+            "kanidm_sshkey_fetcher-bundle-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).expect("a key");
+        key.write_openssh_file(&dir.join("signer"), LineEnding::LF)
+            .unwrap();
+        let bundle = Bundle {
+            created: 1760000000,
+            server: Some("https://idm.example.com/".to_string()),
+            accounts: vec![("alice".to_string(), vec!["ssh-ed25519 AAAA".to_string()])],
+        };
+
+        write(&dir.join("keys.bundle"), &dir.join("signer"), &bundle).unwrap();
+        let content = std::fs::read_to_string(dir.join("keys.bundle")).unwrap();
+        assert_eq!(verify(&content, &[key.public_key().clone()]), Ok(bundle));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
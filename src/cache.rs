@@ -0,0 +1,994 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use clap::{Args, Subcommand};
+use hmac::{Hmac, Mac};
+use rusqlite::{Connection, OptionalExtension, params};
+use sha2::Sha256;
+use tracing::{debug, info};
+
+use crate::diagnostic::Error;
+use crate::table::print_table;
+
+/// How long a second process waits for a lock held by another one, e.g. a daemon and a one-shot
+/// run writing at the same time
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many seconds cached keys are used for if `cache_ttl` is not configured
+pub const DEFAULT_TTL: u64 = 300;
+
+/// How many seconds an account that was not found is remembered for if `negative_cache_ttl` is
+/// not configured
+pub const DEFAULT_NEGATIVE_TTL: u64 = 60;
+
+/// How many seconds old cached keys may be to be served with `--on-server-failure open` if
+/// `max_staleness` is not configured
+pub const DEFAULT_MAX_STALENESS: u64 = 86400;
+
+/// What is appended to the cache path to name the key generated when encryption is enabled
+/// without a key file
+const GENERATED_KEY_SUFFIX: &str = ".key";
+
+#[derive(Debug, Clone, Args)]
+pub struct CacheArgs {
+    #[command(subcommand)]
+    action: CacheAction,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum CacheAction {
+    /// Show the cached accounts and the cache hit statistics
+    Stats,
+    /// Remove every cached entry
+    Clear,
+    /// Remove the cached entry of an account, forcing the next lookup to hit the server
+    Invalidate {
+        /// The account id to invalidate
+        account_id: String,
+    },
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// Encrypts cached keys and hides account names when the cache is encrypted
+pub struct Cipher {
+    aead: Aes256Gcm,
+    mac_key: [u8; 32],
+}
+
+impl Cipher {
+    /// Derive the encryption and account name keys from a secret
+    pub fn from_secret(secret: &[u8]) -> Self {
+        let hkdf = hkdf::Hkdf::<Sha256>::new(Some(b"kanidm_sshkey_fetcher cache"), secret);
+        let mut enc_key = [0u8; 32];
+        let mut mac_key = [0u8; 32];
+        // 32 bytes are always a valid output length for HKDF-SHA256
+        let _ = hkdf.expand(b"encryption", &mut enc_key);
+        let _ = hkdf.expand(b"account", &mut mac_key);
+
+        Cipher {
+            aead: Aes256Gcm::new(&enc_key.into()),
+            mac_key,
+        }
+    }
+
+    /// Read the secret from a file, e.g. a key file or a generated key
+    pub fn from_file(path: &Path) -> Result<Self, ()> {
+        let secret = std::fs::read(path).map_err(|e| {
+            Error::new("cache::key", "Failed to read cache key")
+                .file(path)
+                .cause(e)
+                .report()
+        })?;
+        if secret.iter().all(|b| b.is_ascii_whitespace()) {
+            Error::new("cache::key", "Cache key is empty")
+                .file(path)
+                .report();
+            return Err(());
+        }
+        Ok(Cipher::from_secret(&secret))
+    }
+
+    /// Read the key generated next to the cache, creating a random one readable only by the owner
+    /// on first use
+    ///
+    /// This keeps the cache from other local users, but not from whoever gets hold of the disk.
+    /// The key is written to a temporary file first and only linked into place once complete, so
+    /// a concurrent first run reads either no key or the whole key that won.
+    pub fn generated(path: &Path) -> Result<Self, ()> {
+        if !path.exists() {
+            Self::generate(path)?;
+        }
+        Cipher::from_file(path)
+    }
+
+    /// Write a random key to `path` unless another run got there first
+    fn generate(path: &Path) -> Result<(), ()> {
+        // Unique to this run, so runs racing to generate the key never share a temporary file
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(format!(
+            ".{:016x}.tmp",
+            aes_gcm::aead::rand_core::RngCore::next_u64(&mut OsRng)
+        ));
+        let tmp_path = path.with_file_name(tmp_name);
+        let error = |message: &'static str| {
+            move |e: std::io::Error| {
+                Error::new("cache::key", message)
+                    .file(path)
+                    .cause(e)
+                    .help("pass --cache-key-file <PATH> to use a key provisioned elsewhere")
+                    .report()
+            }
+        };
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let secret = BASE64.encode(Aes256Gcm::generate_key(&mut OsRng));
+        let written = options.open(&tmp_path).and_then(|mut file| {
+            std::io::Write::write_all(&mut file, secret.as_bytes())?;
+            file.sync_all()
+        });
+        let linked = written
+            .map_err(error("Failed to write generated cache key"))
+            .and_then(|()| match std::fs::hard_link(&tmp_path, path) {
+                Ok(()) => {
+                    info!("Generated cache key {}", path.display());
+                    Ok(())
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    debug!("Another run generated the cache key -- {path:?}");
+                    Ok(())
+                }
+                Err(e) => Err(e).map_err(error("Failed to create cache key")),
+            });
+        let _ = std::fs::remove_file(&tmp_path);
+        linked
+    }
+
+    /// The name an account is stored under, so the cache doesn't list who may log in
+    fn account(&self, account: &str) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.mac_key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(account.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Encrypt `plaintext`, bound to `account`, the name it is stored under, so an entry copied
+    /// to another account doesn't decrypt
+    fn seal(&self, account: &str, plaintext: &str) -> Result<String, ()> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plaintext.as_bytes(),
+            aad: account.as_bytes(),
+        };
+        let ciphertext = self.aead.encrypt(&nonce, payload).map_err(|e| {
+            Error::new("cache::encrypt", "Failed to encrypt cache entry")
+                .cause(e)
+                .report()
+        })?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(BASE64.encode(sealed))
+    }
+
+    /// Decrypt what `seal` encrypted for `account`, the name it is stored under
+    fn open(&self, account: &str, sealed: &str) -> Option<String> {
+        let sealed = BASE64.decode(sealed).ok()?;
+        if sealed.len() < 12 {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(12);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: account.as_bytes(),
+        };
+        let plaintext = self.aead.decrypt(Nonce::from_slice(nonce), payload).ok()?;
+        String::from_utf8(plaintext).ok()
+    }
+}
+
+/// A cache of fetched keys keyed by account id, stored in a SQLite database
+pub struct Cache {
+    conn: Connection,
+    ttl: u64,
+    negative_ttl: u64,
+    cipher: Option<Cipher>,
+}
+
+impl Cache {
+    /// Open (and create if needed) the cache database
+    ///
+    /// New entries expire after `ttl` seconds, accounts that were not found after `negative_ttl`.
+    /// With a `cipher` the cached keys are encrypted and account names are replaced by a MAC.
+    pub fn open(
+        path: &Path,
+        ttl: u64,
+        negative_ttl: u64,
+        cipher: Option<Cipher>,
+    ) -> Result<Self, ()> {
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+            && !parent.exists()
+        {
+            std::fs::create_dir_all(parent).map_err(|e| {
+                Error::new("cache::create_dir", "Failed to create cache directory")
+                    .file(parent)
+                    .cause(e)
+                    .report()
+            })?;
+        }
+
+        // Created readable only by the owner, with the journal and WAL files sqlite gives the
+        // same mode, rather than with whatever the umask allows
+        if path != Path::new(":memory:") {
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create(true).truncate(false);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options.open(path).map_err(|e| {
+                Error::new("cache::open", "Failed to create cache")
+                    .file(path)
+                    .cause(e)
+                    .report()
+            })?;
+        }
+
+        debug!("Opening cache -- {path:?}");
+        let conn = Connection::open(path).map_err(|e| {
+            Error::new("cache::open", "Failed to open cache")
+                .file(path)
+                .cause(e)
+                .report()
+        })?;
+        conn.busy_timeout(BUSY_TIMEOUT).map_err(|e| {
+            Error::new("cache::open", "Failed to configure cache")
+                .file(path)
+                .cause(e)
+                .report()
+        })?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS keys (
+                account TEXT PRIMARY KEY,
+                keys TEXT NOT NULL,
+                fetched_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS missing (
+                account TEXT PRIMARY KEY,
+                expires_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS stats (
+                name TEXT PRIMARY KEY,
+                value INTEGER NOT NULL
+            );",
+        )
+        .map_err(|e| {
+            Error::new("cache::open", "Failed to initialize cache")
+                .file(path)
+                .cause(e)
+                .report()
+        })?;
+
+        Ok(Cache {
+            conn,
+            ttl,
+            negative_ttl,
+            cipher,
+        })
+    }
+
+    /// The name an account is stored under
+    fn account(&self, account: &str) -> String {
+        match &self.cipher {
+            Some(cipher) => cipher.account(account),
+            None => account.to_string(),
+        }
+    }
+
+    fn bump(&self, name: &str) {
+        let r = self.conn.execute(
+            "INSERT INTO stats (name, value) VALUES (?1, 1)
+            ON CONFLICT (name) DO UPDATE SET value = value + 1",
+            params![name],
+        );
+        if let Err(e) = r {
+            debug!("Failed to update cache statistics -- {:?}", e);
+        }
+    }
+
+    /// Get the cached keys of an account if the entry has not expired yet
+    pub fn get(&self, account: &str) -> Option<Vec<String>> {
+        match self.fresh(account) {
+            Some(keys) => {
+                debug!("Cache hit for account {}", account);
+                self.bump("hits");
+                Some(keys)
+            }
+            None => {
+                debug!("Cache miss for account {}", account);
+                self.bump("misses");
+                None
+            }
+        }
+    }
+
+    /// Whether the cache alone can answer for an account, without counting as a hit or miss
+    pub fn covers(&self, account: &str) -> bool {
+        self.fresh(account).is_some() || self.missing(account)
+    }
+
+    fn fresh(&self, account: &str) -> Option<Vec<String>> {
+        let keys: Option<String> = self
+            .conn
+            .query_row(
+                "SELECT keys FROM keys WHERE account = ?1 AND expires_at > ?2",
+                params![self.account(account), now()],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| {
+                Error::new("cache::read", "Failed to read cache")
+                    .cause(e)
+                    .report()
+            })
+            .ok()
+            .flatten();
+        self.unseal(account, keys)
+    }
+
+    /// Get the cached keys of an account fetched at most `max_age` seconds ago, expired or not,
+    /// and how many seconds ago they were fetched
+    ///
+    /// For answering while the server fails, see `--on-server-failure`.
+    pub fn get_stale(&self, account: &str, max_age: u64) -> Option<(Vec<String>, u64)> {
+        let now = now();
+        let (keys, fetched_at): (String, i64) = self
+            .conn
+            .query_row(
+                "SELECT keys, fetched_at FROM keys WHERE account = ?1 AND fetched_at >= ?2",
+                params![self.account(account), now - max_age as i64],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(|e| {
+                Error::new("cache::read", "Failed to read cache")
+                    .cause(e)
+                    .report()
+            })
+            .ok()
+            .flatten()?;
+
+        let keys = self.unseal(account, Some(keys))?;
+        self.bump("stale_hits");
+        Some((keys, (now - fetched_at).max(0) as u64))
+    }
+
+    /// The keys of a cache entry, decrypted if the cache is encrypted
+    fn unseal(&self, account: &str, keys: Option<String>) -> Option<Vec<String>> {
+        let keys = match (&self.cipher, keys) {
+            (Some(cipher), Some(sealed)) => {
+                let keys = cipher.open(&cipher.account(account), &sealed);
+                if keys.is_none() {
+                    debug!("Failed to decrypt cached keys of account {}", account);
+                }
+                keys
+            }
+            (_, keys) => keys,
+        };
+        keys.map(|keys| keys.lines().map(String::from).collect())
+    }
+
+    /// Store freshly fetched keys of an account
+    pub fn put(&self, account: &str, keys: &[String]) -> Result<(), ()> {
+        let now = now();
+        let keys = match &self.cipher {
+            Some(cipher) => cipher.seal(&cipher.account(account), &keys.join("\n"))?,
+            None => keys.join("\n"),
+        };
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO keys (account, keys, fetched_at, expires_at)
+                VALUES (?1, ?2, ?3, ?4)",
+                params![self.account(account), keys, now, now + self.ttl as i64],
+            )
+            .map_err(|e| {
+                Error::new("cache::write", "Failed to write cache")
+                    .cause(e)
+                    .report()
+            })?;
+        Ok(())
+    }
+
+    /// Whether the account was recently not found on the server
+    pub fn is_missing(&self, account: &str) -> bool {
+        let missing = self.missing(account);
+        if missing {
+            debug!("Negative cache hit for account {}", account);
+            self.bump("negative_hits");
+        }
+        missing
+    }
+
+    fn missing(&self, account: &str) -> bool {
+        self.conn
+            .query_row(
+                "SELECT 1 FROM missing WHERE account = ?1 AND expires_at > ?2",
+                params![self.account(account), now()],
+                |_| Ok(()),
+            )
+            .optional()
+            .map_err(|e| {
+                Error::new("cache::read", "Failed to read cache")
+                    .cause(e)
+                    .report()
+            })
+            .ok()
+            .flatten()
+            .is_some()
+    }
+
+    /// Remember that an account was not found on the server
+    pub fn put_missing(&self, account: &str) -> Result<(), ()> {
+        if self.negative_ttl == 0 {
+            return Ok(());
+        }
+
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO missing (account, expires_at) VALUES (?1, ?2)",
+                params![self.account(account), now() + self.negative_ttl as i64],
+            )
+            .map_err(|e| {
+                Error::new("cache::write", "Failed to write cache")
+                    .cause(e)
+                    .report()
+            })?;
+        Ok(())
+    }
+
+    /// Remove the cached entry of an account, returning whether there was one
+    pub fn invalidate(&self, account: &str) -> Result<bool, ()> {
+        let mut removed = 0;
+        for table in ["keys", "missing"] {
+            removed += self
+                .conn
+                .execute(
+                    &format!("DELETE FROM {table} WHERE account = ?1"),
+                    params![self.account(account)],
+                )
+                .map_err(|e| {
+                    Error::new("cache::write", "Failed to invalidate cache entry")
+                        .account(account)
+                        .cause(e)
+                        .report()
+                })?;
+        }
+        Ok(removed > 0)
+    }
+
+    /// Remove every cached entry, returning how many there were
+    pub fn clear(&self) -> Result<usize, ()> {
+        let mut removed = 0;
+        for table in ["keys", "missing"] {
+            removed += self
+                .conn
+                .execute(&format!("DELETE FROM {table}"), [])
+                .map_err(|e| {
+                    Error::new("cache::write", "Failed to clear cache")
+                        .cause(e)
+                        .report()
+                })?;
+        }
+        Ok(removed)
+    }
+
+    /// The cached entries and the hit counters
+    fn stats(&self) -> Result<Stats, ()> {
+        let now = now();
+        let counter = |name: &str| -> i64 {
+            self.conn
+                .query_row(
+                    "SELECT value FROM stats WHERE name = ?1",
+                    params![name],
+                    |row| row.get(0),
+                )
+                .optional()
+                .ok()
+                .flatten()
+                .unwrap_or(0)
+        };
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT account, keys, fetched_at, expires_at FROM keys ORDER BY account")
+            .map_err(|e| {
+                Error::new("cache::read", "Failed to read cache")
+                    .cause(e)
+                    .report()
+            })?;
+        let entries: Vec<(String, String, i64, i64)> = stmt
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .and_then(|rows| rows.collect())
+            .map_err(|e| {
+                Error::new("cache::read", "Failed to read cache")
+                    .cause(e)
+                    .report()
+            })?;
+        let missing: i64 = self
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM missing WHERE expires_at > ?1",
+                params![now],
+                |row| row.get(0),
+            )
+            .map_err(|e| {
+                Error::new("cache::read", "Failed to read cache")
+                    .cause(e)
+                    .report()
+            })?;
+
+        let entries = entries
+            .into_iter()
+            .map(|(account, keys, fetched_at, expires_at)| StatsEntry {
+                keys: match &self.cipher {
+                    Some(cipher) => cipher.open(&account, &keys).map(|k| k.lines().count()),
+                    None => Some(keys.lines().count()),
+                },
+                account,
+                age: now - fetched_at,
+                expires_in: (expires_at > now).then_some(expires_at - now),
+            })
+            .collect();
+        Ok(Stats {
+            entries,
+            missing,
+            hits: counter("hits"),
+            misses: counter("misses"),
+            negative_hits: counter("negative_hits"),
+            stale_hits: counter("stale_hits"),
+        })
+    }
+}
+
+/// What `cache stats` shows
+#[derive(Debug)]
+struct Stats {
+    entries: Vec<StatsEntry>,
+    /// How many accounts are remembered as not found
+    missing: i64,
+    hits: i64,
+    misses: i64,
+    negative_hits: i64,
+    stale_hits: i64,
+}
+
+#[derive(Debug)]
+struct StatsEntry {
+    account: String,
+    /// How many keys are cached, `None` if they can't be decrypted
+    keys: Option<usize>,
+    /// How many seconds ago the keys were fetched
+    age: i64,
+    /// In how many seconds the entry expires, `None` if it has
+    expires_in: Option<i64>,
+}
+
+impl Stats {
+    fn print(&self) {
+        let total = self.entries.len();
+        let fresh = self
+            .entries
+            .iter()
+            .filter(|e| e.expires_in.is_some())
+            .count();
+        println!(
+            "Entries:     {} ({} fresh, {} expired)",
+            total,
+            fresh,
+            total - fresh
+        );
+        println!("Not found:   {}", self.missing);
+        println!("Hits:        {}", self.hits);
+        println!("Misses:      {}", self.misses);
+        println!("Negative:    {} hits", self.negative_hits);
+        println!("Stale:       {} served", self.stale_hits);
+        if self.hits + self.misses > 0 {
+            println!(
+                "Hit rate:    {:.1}%",
+                self.hits as f64 * 100.0 / (self.hits + self.misses) as f64
+            );
+        }
+
+        if !self.entries.is_empty() {
+            println!();
+            let mut rows = vec![
+                ["ACCOUNT", "KEYS", "AGE", "EXPIRES IN"]
+                    .map(String::from)
+                    .to_vec(),
+            ];
+            for entry in &self.entries {
+                rows.push(vec![
+                    entry.account.clone(),
+                    entry.keys.map_or("?".to_string(), |keys| keys.to_string()),
+                    format!("{}s", entry.age),
+                    entry
+                        .expires_in
+                        .map_or("expired".to_string(), |secs| format!("{secs}s")),
+                ]);
+            }
+            print_table(&rows, &[1, 2]);
+        }
+    }
+}
+
+/// Keep the keys of an account in its file below `dir`, see `--fallback-dir`
+///
+/// The file is touched even if the keys didn't change, its modification time is when the keys
+/// were last fetched.
+pub fn save_fallback(dir: &Path, account: &str, keys: &[String]) -> Result<(), ()> {
+    std::fs::create_dir_all(dir).map_err(|e| {
+        Error::new(
+            "fallback::create_dir",
+            "Failed to create fallback directory",
+        )
+        .file(dir)
+        .cause(e)
+        .report()
+    })?;
+    crate::export::write_key_file(dir, crate::export::local_name(account), keys)?;
+
+    let path = dir.join(crate::export::local_name(account));
+    std::fs::File::options()
+        .append(true)
+        .open(&path)
+        .and_then(|file| file.set_modified(SystemTime::now()))
+        .map_err(|e| {
+            Error::new("fallback::write", "Failed to touch fallback file")
+                .file(&path)
+                .cause(e)
+                .report()
+        })
+}
+
+/// The keys in the fallback file of an account if they were fetched at most `max_age` seconds
+/// ago, with the path and how many seconds ago they were fetched
+pub fn load_fallback(
+    dir: &Path,
+    account: &str,
+    max_age: u64,
+) -> Option<(Vec<String>, PathBuf, u64)> {
+    let path = dir.join(crate::export::local_name(account));
+    let age = std::fs::metadata(&path)
+        .and_then(|metadata| metadata.modified())
+        .ok()?
+        .elapsed()
+        .map_or(0, |age| age.as_secs());
+    if age > max_age {
+        debug!("Fallback file is {age}s old, older than allowed -- {path:?}");
+        return None;
+    }
+
+    let content = std::fs::read_to_string(&path)
+        .map_err(|e| {
+            Error::new("fallback::read", "Failed to read fallback file")
+                .file(&path)
+                .cause(e)
+                .report()
+        })
+        .ok()?;
+    Some((crate::source::key_lines(&content).collect(), path, age))
+}
+
+/// Open the cache configured by `cache_path`, `cache_ttl` and `negative_cache_ttl`, if any
+pub fn open_configured(args: &crate::Cli) -> Result<Option<Cache>, ()> {
+    let Some(path) = &args.cache_path else {
+        return Ok(None);
+    };
+    let path = PathBuf::from(shellexpand::tilde(&path.to_string_lossy()).into_owned());
+
+    let cipher = match &args.cache_key_file {
+        Some(key_file) => Some(Cipher::from_file(key_file)?),
+        None if args.encrypt_cache => {
+            let mut key_file = path.clone().into_os_string();
+            key_file.push(GENERATED_KEY_SUFFIX);
+            Some(Cipher::generated(Path::new(&key_file))?)
+        }
+        None => None,
+    };
+
+    Cache::open(
+        &path,
+        args.cache_ttl.unwrap_or(DEFAULT_TTL),
+        args.negative_cache_ttl.unwrap_or(DEFAULT_NEGATIVE_TTL),
+        cipher,
+    )
+    .map(Some)
+}
+
+pub fn cache(args: &crate::Cli, cache: &CacheArgs) -> Result<(), ()> {
+    let cache_db = open_configured(args)?.ok_or_else(|| {
+        Error::new("cache::not_configured", "No cache configured")
+            .help("pass --cache <PATH> or set cache_path in the config")
+            .report()
+    })?;
+
+    match &cache.action {
+        CacheAction::Stats => {
+            cache_db.stats()?.print();
+            Ok(())
+        }
+        CacheAction::Clear => {
+            let removed = cache_db.clear()?;
+            info!("Removed {} cached entries", removed);
+            Ok(())
+        }
+        CacheAction::Invalidate { account_id } => {
+            if cache_db.invalidate(account_id)? {
+                info!("Invalidated cached keys of account {}", account_id);
+            } else {
+                info!("Account {} was not cached", account_id);
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: [&str; 2] = [
+        "ssh-ed25519 AAAA alice@laptop",
+        "ssh-rsa BBBB alice@desktop",
+    ];
+
+    fn keys() -> Vec<String> {
+        KEYS.map(String::from).to_vec()
+    }
+
+    fn cache(ttl: u64) -> Cache {
+        Cache::open(Path::new(":memory:"), ttl, DEFAULT_NEGATIVE_TTL, None).unwrap()
+    }
+
+    /// Pretend every entry was written `secs` seconds earlier
+    fn age(cache: &Cache, secs: i64) {
+        cache
+            .conn
+            .execute_batch(&format!(
+                "UPDATE keys SET fetched_at = fetched_at - {secs}, expires_at = expires_at - {secs};
+                UPDATE missing SET expires_at = expires_at - {secs};"
+            ))
+            .unwrap();
+    }
+
+    #[test]
+    fn serves_keys_until_they_expire() {
+        let cache = cache(300);
+        assert_eq!(cache.get("alice"), None);
+        assert!(!cache.covers("alice"));
+
+        cache.put("alice", &keys()).unwrap();
+        assert_eq!(cache.get("alice"), Some(keys()));
+        assert!(cache.covers("alice"));
+
+        age(&cache, 290);
+        assert_eq!(cache.get("alice"), Some(keys()), "inside the TTL");
+        age(&cache, 10);
+        assert_eq!(cache.get("alice"), None, "past the TTL");
+        assert!(!cache.covers("alice"));
+    }
+
+    #[test]
+    fn serves_stale_keys_up_to_max_staleness() {
+        let cache = cache(300);
+        cache.put("alice", &keys()).unwrap();
+        age(&cache, 1000);
+        assert_eq!(cache.get("alice"), None);
+
+        let (stale, age_secs) = cache.get_stale("alice", 1100).unwrap();
+        assert_eq!(stale, keys());
+        assert!((1000..1100).contains(&age_secs));
+        assert_eq!(cache.get_stale("alice", 900), None);
+        assert_eq!(cache.get_stale("bob", 1100), None);
+    }
+
+    #[test]
+    fn remembers_missing_accounts_for_the_negative_ttl() {
+        let cache = Cache::open(Path::new(":memory:"), 300, 60, None).unwrap();
+        assert!(!cache.is_missing("ghost"));
+        cache.put_missing("ghost").unwrap();
+        assert!(cache.is_missing("ghost"));
+        assert!(cache.covers("ghost"));
+
+        age(&cache, 50);
+        assert!(cache.is_missing("ghost"), "inside the negative TTL");
+        age(&cache, 10);
+        assert!(
+            !cache.is_missing("ghost"),
+            "fetched again after the negative TTL"
+        );
+        assert!(!cache.covers("ghost"));
+
+        let cache = Cache::open(Path::new(":memory:"), 300, 0, None).unwrap();
+        cache.put_missing("ghost").unwrap();
+        assert!(!cache.is_missing("ghost"), "0 turns the negative cache off");
+    }
+
+    #[test]
+    fn counts_entries_hits_and_removals() {
+        let cache = cache(300);
+        cache.put("alice", &keys()).unwrap();
+        cache.put("bob", &keys()[..1]).unwrap();
+        cache.put_missing("carol").unwrap();
+        cache.get("alice");
+        cache.get("alice");
+        cache.get("dave");
+        cache.is_missing("carol");
+
+        let stats = cache.stats().unwrap();
+        assert_eq!(stats.entries.len(), 2);
+        assert_eq!(stats.entries[0].account, "alice");
+        assert_eq!(stats.entries[0].keys, Some(2));
+        assert_eq!(stats.entries[1].keys, Some(1));
+        assert!(stats.entries.iter().all(|e| e.expires_in.is_some()));
+        assert_eq!(
+            (stats.missing, stats.hits, stats.misses, stats.negative_hits),
+            (1, 2, 1, 1)
+        );
+
+        assert!(cache.invalidate("alice").unwrap());
+        assert!(!cache.invalidate("alice").unwrap());
+        assert!(cache.invalidate("carol").unwrap());
+        assert_eq!(cache.stats().unwrap().entries.len(), 1);
+
+        cache.put_missing("erin").unwrap();
+        assert_eq!(cache.clear().unwrap(), 2);
+        assert_eq!(cache.clear().unwrap(), 0);
+        let stats = cache.stats().unwrap();
+        assert_eq!((stats.entries.len(), stats.missing), (0, 0));
+    }
+
+    fn encrypted(secret: &[u8]) -> Cache {
+        let cipher = Cipher::from_secret(secret);
+        Cache::open(Path::new(":memory:"), 300, 60, Some(cipher)).unwrap()
+    }
+
+    #[test]
+    fn encrypts_keys_and_hides_account_names() {
+        let cache = encrypted(b"secret");
+        cache.put("alice", &keys()).unwrap();
+        cache.put_missing("ghost").unwrap();
+        assert_eq!(cache.get("alice"), Some(keys()));
+        assert!(cache.is_missing("ghost"));
+        assert_eq!(cache.stats().unwrap().entries[0].keys, Some(2));
+
+        let (account, sealed): (String, String) = cache
+            .conn
+            .query_row("SELECT account, keys FROM keys", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        let missing: String = cache
+            .conn
+            .query_row("SELECT account FROM missing", [], |row| row.get(0))
+            .unwrap();
+        assert!(!account.contains("alice") && !missing.contains("ghost"));
+        assert!(KEYS.iter().all(|key| !sealed.contains(key)));
+    }
+
+    #[test]
+    fn treats_entries_it_cannot_decrypt_as_misses() {
+        let cache = encrypted(b"secret");
+        cache.put("alice", &keys()).unwrap();
+        cache.put("bob", &keys()[..1]).unwrap();
+
+        let other = Cipher::from_secret(b"another secret");
+        let cache = Cache {
+            cipher: Some(other),
+            ..cache
+        };
+        assert_eq!(cache.get("alice"), None, "a different key");
+        let cache = Cache {
+            cipher: Some(Cipher::from_secret(b"secret")),
+            ..cache
+        };
+        assert_eq!(cache.get("alice"), Some(keys()));
+
+        // Bob's entry copied over Alice's is bound to Bob's name
+        let cipher = Cipher::from_secret(b"secret");
+        cache
+            .conn
+            .execute(
+                "UPDATE keys SET keys = (SELECT keys FROM keys WHERE account = ?1)
+                WHERE account = ?2",
+                params![cipher.account("bob"), cipher.account("alice")],
+            )
+            .unwrap();
+        assert_eq!(cache.get("alice"), None, "another account's entry");
+        assert_eq!(cache.get("bob"), Some(keys()[..1].to_vec()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn creates_the_database_readable_only_by_the_owner() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!(
+            "kanidm_sshkey_fetcher-cache-db-{}",
+            std::process::id()
+        ));
+        let path = dir.join("cache.db");
+
+        let cache = Cache::open(&path, 300, 60, None).unwrap();
+        cache.put("alice", &keys()).unwrap();
+        let modes: Vec<u32> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().permissions().mode() & 0o777)
+            .collect();
+        drop(cache);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(!modes.is_empty());
+        assert!(modes.iter().all(|&mode| mode == 0o600), "{modes:?}");
+    }
+
+    #[test]
+    fn generates_a_private_key_once() {
+        let dir = std::env::temp_dir().join(format!(
+            "kanidm_sshkey_fetcher-cache-key-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cache.db.key");
+        let _ = std::fs::remove_file(&path);
+
+        let first = Cipher::generated(&path).unwrap();
+        let secret = std::fs::read(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let second = Cipher::generated(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), secret);
+        assert_eq!(first.account("alice"), second.account("alice"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_runs_agree_on_the_generated_key() {
+        let dir = std::env::temp_dir().join(format!(
+            "kanidm_sshkey_fetcher-cache-key-race-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cache.db.key");
+
+        let accounts: Vec<String> = std::thread::scope(|scope| {
+            let runs: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| Cipher::generated(&path).unwrap().account("alice")))
+                .collect();
+            runs.into_iter().map(|run| run.join().unwrap()).collect()
+        });
+        let left = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(accounts.iter().all(|account| *account == accounts[0]));
+        assert_eq!(left, 1, "only the key is left behind");
+    }
+}
@@ -0,0 +1,236 @@
+//! Answering like the tools this one replaces when installed under their name
+//!
+//! Installed or symlinked as `kanidm_ssh_authorizedkeys` or `kanidm_ssh_authorizedkeys_direct`,
+//! existing `AuthorizedKeysCommand` lines and automation keep working unchanged. Both take the
+//! account as the only positional argument, and `-d`, `-H`, `-C` and `-D` like upstream.
+//!
+//! - `kanidm_ssh_authorizedkeys` asks kanidm-unixd, at the `sock_path` of `/etc/kanidm/unixd`,
+//!   and only the server if `-H` is given.
+//! - `kanidm_ssh_authorizedkeys_direct` asks the server in `/etc/kanidm/config`, which is read
+//!   anyway.
+//!
+//! As `sss_ssh_authorizedkeys`, for sites migrating from FreeIPA or SSSD, it takes the user and
+//! `-d`/`--domain` like SSSD's tool does, see [`sss_args`], and prints nothing but the keys.
+//!
+//! None of the tools have subcommands, so an account named like one of this tool's, e.g. `list`
+//! or `doctor`, is looked up like any other, see [`without_subcommands`].
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use clap::Command;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::Cli;
+use crate::diagnostic::Error;
+
+/// The configuration kanidm-unixd and its tools read
+const UNIXD_CONFIG: &str = "/etc/kanidm/unixd";
+
+/// A tool this one can stand in for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compat {
+    /// kanidm-unixd's client, `kanidm_ssh_authorizedkeys`
+    KanidmUnixd,
+    /// The client of the server, `kanidm_ssh_authorizedkeys_direct`
+    KanidmDirect,
+    /// SSSD's `sss_ssh_authorizedkeys`
+    Sss,
+}
+
+impl Compat {
+    /// The tool the program was invoked as, if any
+    pub fn invoked_as() -> Option<Compat> {
+        let program = std::env::args_os().next()?;
+        Compat::from_program(Path::new(&program))
+    }
+
+    fn from_program(program: &Path) -> Option<Compat> {
+        match program.file_stem()?.to_str()? {
+            "kanidm_ssh_authorizedkeys" => Some(Compat::KanidmUnixd),
+            "kanidm_ssh_authorizedkeys_direct" => Some(Compat::KanidmDirect),
+            "sss_ssh_authorizedkeys" => Some(Compat::Sss),
+            _ => None,
+        }
+    }
+
+    /// Fill in what the tool would do that the arguments don't say otherwise
+    pub fn apply(self, args: &mut Cli) {
+        debug!("Invoked as {:?}", self);
+        if let Some(name) = &args.name
+            && name != "anonymous"
+        {
+            Error::new(
+                "compat::name",
+                "Only anonymous and token logins are supported, ignoring -D",
+            )
+            .with("name", name)
+            .help("pass an API token of the account with --token instead")
+            .warn();
+        }
+
+        if self == Compat::Sss {
+            crate::diagnostic::silence();
+        }
+        if self == Compat::KanidmUnixd {
+            if args.unixd_socket.is_none() {
+                args.unixd_socket = Some(unixd_socket(Path::new(UNIXD_CONFIG)));
+            }
+            args.unixd_only = args.unixd_only || args.addr.is_none();
+        }
+    }
+}
+
+/// The arguments of `sss_ssh_authorizedkeys [-d DOMAIN] USER` as this tool's
+///
+/// `-d` picks the domain rather than enabling debug output, so it is taken out and the user is
+/// qualified with it instead, as kanidm knows accounts by `name@domain`. Everything else is
+/// passed on unchanged.
+pub fn sss_args(args: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+    let mut args = args.into_iter();
+    let mut domain = None;
+    let mut rest = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-d" | "--domain") => domain = args.next(),
+            Some(arg) if arg.starts_with("--domain=") => {
+                domain = Some(arg["--domain=".len()..].into());
+            }
+            Some(arg) if arg.starts_with("-d") => domain = Some(arg["-d".len()..].into()),
+            _ => rest.push(arg),
+        }
+    }
+
+    let Some(domain) = domain else {
+        return rest;
+    };
+    rest.into_iter()
+        .enumerate()
+        .map(|(i, arg)| match arg.to_str() {
+            // The program itself, options, and users that are already qualified stay as they are
+            Some(user) if i > 0 && !user.starts_with('-') && !user.contains('@') => {
+                let mut user = OsString::from(user);
+                user.push("@");
+                user.push(&domain);
+                user
+            }
+            _ => arg,
+        })
+        .collect()
+}
+
+/// The command line of this tool without its subcommands, to parse the arguments of a tool it
+/// stands in for
+///
+/// sshd passes the account as the only argument, so it must never be taken for a subcommand.
+pub fn without_subcommands(command: &Command) -> Command {
+    let without = Command::new(command.get_name().to_string())
+        .disable_version_flag(true)
+        .args(command.get_arguments().cloned());
+    command
+        .get_groups()
+        .cloned()
+        .fold(without, |without, group| without.group(group))
+}
+
+/// The `sock_path` of kanidm-unixd's configuration, or its default
+fn unixd_socket(config: &Path) -> PathBuf {
+    #[derive(Deserialize)]
+    struct UnixdConfig {
+        sock_path: Option<PathBuf>,
+    }
+
+    let default = PathBuf::from(crate::unixd::DEFAULT_SOCKET);
+    let Ok(content) = std::fs::read_to_string(config) else {
+        return default;
+    };
+    match toml::from_str::<UnixdConfig>(&content) {
+        Ok(config) => config.sock_path.unwrap_or(default),
+        Err(e) => {
+            Error::new(
+                "compat::unixd_config",
+                "Failed to parse kanidm-unixd's configuration",
+            )
+            .file(config)
+            .cause(e)
+            .warn();
+            default
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, FromArgMatches};
+
+    use super::*;
+
+    #[test]
+    fn recognizes_the_tools_it_replaces() {
+        assert_eq!(
+            Compat::from_program(Path::new("/usr/sbin/kanidm_ssh_authorizedkeys")),
+            Some(Compat::KanidmUnixd)
+        );
+        assert_eq!(
+            Compat::from_program(Path::new("kanidm_ssh_authorizedkeys_direct")),
+            Some(Compat::KanidmDirect)
+        );
+        assert_eq!(
+            Compat::from_program(Path::new("/usr/bin/sss_ssh_authorizedkeys")),
+            Some(Compat::Sss)
+        );
+        assert_eq!(
+            Compat::from_program(Path::new("/usr/bin/kanidm_sshkey_fetcher")),
+            None
+        );
+    }
+
+    #[test]
+    fn qualifies_sss_users_with_the_domain() {
+        let sss = |args: &[&str]| {
+            sss_args(args.iter().map(OsString::from))
+                .into_iter()
+                .map(|arg| arg.into_string().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(sss(&["sss", "alice"]), ["sss", "alice"]);
+        assert_eq!(
+            sss(&["sss", "-d", "idm.example.com", "alice"]),
+            ["sss", "alice@idm.example.com"]
+        );
+        assert_eq!(
+            sss(&["sss", "--domain=idm.example.com", "alice"]),
+            ["sss", "alice@idm.example.com"]
+        );
+        assert_eq!(
+            sss(&["sss", "-didm.example.com", "bob@other.example.com"]),
+            ["sss", "bob@other.example.com"]
+        );
+    }
+
+    #[test]
+    fn takes_accounts_named_like_subcommands_as_accounts() {
+        let parse = |args: &[&str]| {
+            let mut matches = without_subcommands(&Cli::command())
+                .try_get_matches_from(args)
+                .unwrap();
+            Cli::from_arg_matches_mut(&mut matches).unwrap()
+        };
+
+        let args = parse(&["sss_ssh_authorizedkeys", "list"]);
+        assert!(args.command.is_none());
+        assert_eq!(args.account_ids, ["list"]);
+
+        let args = parse(&[
+            "kanidm_ssh_authorizedkeys",
+            "-H",
+            "https://idm.example.com",
+            "doctor",
+        ]);
+        assert!(args.command.is_none());
+        assert_eq!(args.account_ids, ["doctor"]);
+        assert_eq!(args.addr.as_deref(), Some("https://idm.example.com"));
+    }
+}
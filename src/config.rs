@@ -0,0 +1,450 @@
+//! Reading the configuration file
+//!
+//! The file holds the same options as the command line, in TOML, or in YAML or JSON if its name
+//! ends in `.yaml`, `.yml` or `.json`. Sections named after hostname patterns
+//! apply to matching hosts only, and take precedence over the rest of the file:
+//!
+//! ```toml
+//! account_ids = ["admin"]
+//!
+//! [host."web-*"]
+//! account_ids = ["deploy"]
+//! ```
+//!
+//! String values may refer to `${HOSTNAME}` and to environment variables as `${ENV:NAME}`, `$${`
+//! is a literal `${`.
+//!
+//! Without any file, every option can also be set from the environment, see [`parse_args`].
+
+use std::ffi::OsString;
+use std::ops::Range;
+use std::path::Path;
+
+use clap::{CommandFactory, FromArgMatches};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+
+use crate::Cli;
+use crate::compat::Compat;
+use crate::diagnostic::Error;
+
+/// The prefix of the environment variables options can be set with
+pub const ENV_PREFIX: &str = "KANIDM_SSHKEY_FETCHER_";
+
+/// Parse the command line, taking the options it doesn't set from the environment
+///
+/// Each option is read from `KANIDM_SSHKEY_FETCHER_<NAME>`, where `<NAME>` is its long name in
+/// upper case with `_` for `-`, e.g. `KANIDM_SSHKEY_FETCHER_URL`, or `ACCOUNT_IDS` for the
+/// accounts. Accounts and groups are separated by commas. Invoked as one of the tools this one
+/// replaces, there are no subcommands, and as `sss_ssh_authorizedkeys` its arguments are
+/// translated first, see [`crate::compat::sss_args`].
+pub fn parse_args() -> Cli {
+    let mut command = Cli::command().mut_args(|arg| {
+        let name = arg
+            .get_long()
+            .map_or_else(|| arg.get_id().to_string(), str::to_string);
+        if name == "help" {
+            return arg;
+        }
+        let list = matches!(arg.get_id().as_str(), "account_ids" | "groups");
+        arg.env(format!(
+            "{ENV_PREFIX}{}",
+            name.to_uppercase().replace('-', "_")
+        ))
+        .hide_env(true)
+        .value_delimiter(list.then_some(','))
+    });
+    let compat = Compat::invoked_as();
+    if compat.is_some() {
+        command = crate::compat::without_subcommands(&command);
+    }
+    let args: Vec<OsString> = match compat {
+        Some(Compat::Sss) => crate::compat::sss_args(std::env::args_os()),
+        _ => std::env::args_os().collect(),
+    };
+    let mut matches = command
+        .try_get_matches_from_mut(args)
+        .unwrap_or_else(|e| e.exit());
+    Cli::from_arg_matches_mut(&mut matches).unwrap_or_else(|e| e.format(&mut command).exit())
+}
+
+/// Read a secret from a file, e.g. one mounted from a Docker or Kubernetes secret
+///
+/// A file others can write to is refused, as the secret could be swapped, and one others can
+/// read is reported. Surrounding whitespace is trimmed.
+pub fn read_secret(path: &Path) -> Result<String, ()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let metadata = std::fs::metadata(path).map_err(|e| {
+            Error::new("secret::read", "Failed to read secret file")
+                .file(path)
+                .cause(e)
+                .report()
+        })?;
+        let mode = metadata.permissions().mode() & 0o777;
+        match secret_problem(mode) {
+            Some(SecretProblem::Writable) => {
+                Error::new("secret::permissions", "Secret file is writable by others")
+                    .file(path)
+                    .with("mode", format_args!("{mode:o}"))
+                    .help(format!("run `chmod go-w {}`", path.display()))
+                    .report();
+                return Err(());
+            }
+            Some(SecretProblem::Readable) => {
+                Error::new("secret::permissions", "Secret file is readable by everyone")
+                    .file(path)
+                    .with("mode", format_args!("{mode:o}"))
+                    .help(format!("run `chmod o-r {}`", path.display()))
+                    .warn();
+            }
+            None => {}
+        }
+    }
+
+    let secret = std::fs::read_to_string(path).map_err(|e| {
+        Error::new("secret::read", "Failed to read secret file")
+            .file(path)
+            .cause(e)
+            .report()
+    })?;
+    Ok(secret.trim().to_string())
+}
+
+/// What is wrong with the permissions of a secret file
+#[cfg(unix)]
+#[derive(Debug, PartialEq, Eq)]
+enum SecretProblem {
+    Writable,
+    Readable,
+}
+
+/// What is wrong with the mode of a secret file, if anything
+#[cfg(unix)]
+fn secret_problem(mode: u32) -> Option<SecretProblem> {
+    if mode & 0o022 != 0 {
+        Some(SecretProblem::Writable)
+    } else if mode & 0o004 != 0 {
+        Some(SecretProblem::Readable)
+    } else {
+        None
+    }
+}
+
+/// Read the configuration file, with the sections of the hosts this host matches applied
+pub fn load(path: &Path) -> Result<Cli, ()> {
+    let content = std::fs::read_to_string(path).map_err(|e| {
+        Error::new("config::read", "Failed to read config file")
+            .file(path)
+            .cause(e)
+            .report()
+    })?;
+
+    let format = Format::of(path);
+    let parse_error = |(message, span): (String, Option<Range<usize>>)| {
+        let error = Error::new("config::parse", "Failed to parse config file").file(path);
+        match span {
+            Some(span) => error.span(path.display().to_string(), &content, span, message),
+            None => error.cause(format_args!("{message}")),
+        }
+        .report()
+    };
+
+    let mut value: toml::Value = format.parse(&content).map_err(parse_error)?;
+    expand_value(&mut value, &lookup_variable).map_err(|variable| {
+        Error::new("config::template", "Unknown variable in config file")
+            .file(path)
+            .with("variable", &variable)
+            .help("set the environment variable, or write `$${` for a literal `${`")
+            .report()
+    })?;
+    let mut config = Cli::deserialize(value).map_err(|e| {
+        // The expanded values have no position, the file itself may show where the error is
+        match format.parse::<Cli>(&content) {
+            Err(original @ (_, Some(_))) => parse_error(original),
+            _ => parse_error((e.message().to_string(), None)),
+        }
+    })?;
+
+    if !config.host.is_empty() {
+        let hostname = hostname().ok_or_else(|| {
+            Error::new("config::hostname", "Failed to get the hostname")
+                .file(path)
+                .help("remove the [host] sections or set a hostname")
+                .report()
+        })?;
+        config = apply_host_sections(config, &hostname);
+    }
+    Ok(config)
+}
+
+/// The formats the configuration file can be written in, told apart by its extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Format {
+    /// `.yaml` and `.yml` files are YAML, `.json` files JSON, and anything else TOML
+    fn of(path: &Path) -> Format {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml" | "yml") => Format::Yaml,
+            Some("json") => Format::Json,
+            _ => Format::Toml,
+        }
+    }
+
+    /// Parse a configuration, or return why it is invalid and where, if known
+    fn parse<T: DeserializeOwned>(
+        self,
+        content: &str,
+    ) -> Result<T, (String, Option<Range<usize>>)> {
+        match self {
+            Format::Toml => {
+                toml::from_str(content).map_err(|e| (e.message().to_string(), e.span()))
+            }
+            Format::Yaml => serde_yaml_ng::from_str(content).map_err(|e| {
+                let span = e
+                    .location()
+                    .map(|location| location.index()..location.index());
+                (e.to_string(), span)
+            }),
+            Format::Json => serde_json::from_str(content).map_err(|e| {
+                let index = offset(content, e.line(), e.column());
+                (e.to_string(), index.map(|index| index..index))
+            }),
+        }
+    }
+}
+
+/// The byte offset of a 1-based line and column, `None` for line 0, which means unknown
+fn offset(content: &str, line: usize, column: usize) -> Option<usize> {
+    let start: usize = content
+        .split_inclusive('\n')
+        .take(line.checked_sub(1)?)
+        .map(str::len)
+        .sum();
+    Some((start + column.saturating_sub(1)).min(content.len()))
+}
+
+/// The value of a `${...}` variable, `None` if it is not known
+fn lookup_variable(name: &str) -> Option<String> {
+    match name.split_once(':') {
+        Some(("ENV", variable)) => std::env::var(variable).ok(),
+        None if name == "HOSTNAME" => hostname(),
+        _ => None,
+    }
+}
+
+/// Replace the variables in every string of a value, or return the first unknown one
+fn expand_value(
+    value: &mut toml::Value,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> Result<(), String> {
+    match value {
+        toml::Value::String(text) => *text = expand(text, lookup)?,
+        toml::Value::Array(values) => {
+            for value in values {
+                expand_value(value, lookup)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, value) in table.iter_mut() {
+                expand_value(value, lookup)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replace the `${...}` variables in a string, or return the first unknown one
+fn expand(text: &str, lookup: &impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            expanded.push_str("${");
+            rest = after;
+        } else if let Some((name, after)) = rest
+            .strip_prefix("${")
+            .and_then(|after| after.split_once('}'))
+        {
+            expanded.push_str(&lookup(name).ok_or_else(|| name.to_string())?);
+            rest = after;
+        } else {
+            expanded.push('$');
+            rest = &rest[1..];
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// The configuration with the sections whose pattern matches `hostname` merged in, in name order
+fn apply_host_sections(mut config: Cli, hostname: &str) -> Cli {
+    let sections = std::mem::take(&mut config.host);
+    let mut merged = None::<Cli>;
+    for (pattern, section) in sections {
+        if !glob_match(
+            &pattern.to_ascii_lowercase(),
+            &hostname.to_ascii_lowercase(),
+        ) {
+            continue;
+        }
+        tracing::debug!("Applying the config section of hosts {:?}", pattern);
+        match &mut merged {
+            Some(merged) => merged.or(&section),
+            None => merged = Some(section),
+        }
+    }
+
+    match merged {
+        Some(mut merged) => {
+            merged.or(&config);
+            merged
+        }
+        None => config,
+    }
+}
+
+/// The name of this host
+pub fn hostname() -> Option<String> {
+    #[cfg(unix)]
+    return nix::unistd::gethostname()
+        .ok()
+        .and_then(|name| name.into_string().ok());
+    #[cfg(windows)]
+    return std::env::var("COMPUTERNAME").ok();
+}
+
+/// Whether `text` matches a pattern where `*` matches any run of characters and `?` any one
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), text.chars().collect());
+    // Where the last `*` was, and the text position it matched up to
+    let mut star = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_match("web-*", "web-01"));
+        assert!(glob_match("web-??", "web-01"));
+        assert!(glob_match("*.example.com", "db.example.com"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("web-*", "db-01"));
+        assert!(!glob_match("web-?", "web-01"));
+        assert!(!glob_match("a*b", "aXbY"));
+    }
+
+    #[test]
+    fn reads_every_format() {
+        let toml = "account_ids = [\"alice\"]\n[source]\nmerge = \"first-match\"\n";
+        let yaml = "account_ids:\n  - alice\nsource:\n  merge: first-match\n";
+        let json = r#"{"account_ids": ["alice"], "source": {"merge": "first-match"}}"#;
+
+        for (format, content) in [
+            (Format::Toml, toml),
+            (Format::Yaml, yaml),
+            (Format::Json, json),
+        ] {
+            let value: toml::Value = format.parse(content).expect("the config is valid");
+            let config = Cli::deserialize(value).expect("the config is valid");
+            assert_eq!(config.account_ids, ["alice"], "{format:?}");
+        }
+        assert_eq!(Format::of(Path::new("/etc/fetcher.yml")), Format::Yaml);
+        assert_eq!(Format::of(Path::new("config.toml")), Format::Toml);
+    }
+
+    #[test]
+    fn points_at_json_errors() {
+        let (_, span) = Format::Json
+            .parse::<toml::Value>("{\n  \"a\": 1,\n  oops\n}")
+            .unwrap_err();
+        assert_eq!(span, Some(14..14));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn checks_secret_permissions() {
+        assert_eq!(secret_problem(0o600), None);
+        assert_eq!(secret_problem(0o640), None);
+        assert_eq!(secret_problem(0o644), Some(SecretProblem::Readable));
+        assert_eq!(secret_problem(0o660), Some(SecretProblem::Writable));
+        assert_eq!(secret_problem(0o606), Some(SecretProblem::Writable));
+    }
+
+    #[test]
+    fn expands_variables() {
+        let lookup = |name: &str| match name {
+            "HOSTNAME" => Some("web01".to_string()),
+            "ENV:SITE" => Some("fra".to_string()),
+            _ => None,
+        };
+
+        assert_eq!(
+            expand("https://idm-${ENV:SITE}.example.com", &lookup).as_deref(),
+            Ok("https://idm-fra.example.com")
+        );
+        assert_eq!(
+            expand("/var/lib/${HOSTNAME}/$${HOME} costs $5", &lookup).as_deref(),
+            Ok("/var/lib/web01/${HOME} costs $5")
+        );
+        assert_eq!(expand("${ENV:NOPE}", &lookup), Err("ENV:NOPE".to_string()));
+        assert_eq!(
+            expand("${unterminated", &lookup).as_deref(),
+            Ok("${unterminated")
+        );
+    }
+
+    #[test]
+    fn applies_matching_host_sections() {
+        let config: Cli = toml::from_str(
+            r#"
+            account_ids = ["admin"]
+            cache_ttl = 60
+
+            [host."web-*"]
+            account_ids = ["deploy"]
+            cache_ttl = 30
+
+            [host."db-*"]
+            account_ids = ["dba"]
+            "#,
+        )
+        .expect("the config is valid");
+
+        let web = apply_host_sections(config, "WEB-01");
+        assert_eq!(web.account_ids, ["deploy", "admin"]);
+        assert_eq!(web.cache_ttl, Some(30));
+        assert!(web.host.is_empty());
+    }
+}
@@ -0,0 +1,272 @@
+//! Running as a service that keeps the keys in sync
+
+use std::io::Write;
+use std::time::Duration;
+
+use ssh_key::rand_core::{OsRng, RngCore};
+use tracing::{debug, info};
+
+use crate::Cli;
+use crate::cache::Cache;
+use crate::diagnostic::Error;
+use crate::source::KeySource;
+
+/// How many seconds to wait between syncs if `interval` is not configured
+pub const DEFAULT_INTERVAL: u64 = 300;
+
+/// Resolves once the service is asked to stop
+///
+/// The signal streams are created once, so a signal received while not waiting for it, e.g.
+/// during a write, is not lost.
+struct Shutdown {
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    interrupt: tokio::signal::unix::Signal,
+    #[cfg(windows)]
+    ctrl_c: tokio::signal::windows::CtrlC,
+}
+
+impl Shutdown {
+    #[cfg(unix)]
+    fn new() -> Result<Shutdown, ()> {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let terminate = signal(SignalKind::terminate()).map_err(|e| {
+            Error::new("daemon::signal", "Failed to handle SIGTERM")
+                .cause(e)
+                .report()
+        })?;
+        let interrupt = signal(SignalKind::interrupt()).map_err(|e| {
+            Error::new("daemon::signal", "Failed to handle SIGINT")
+                .cause(e)
+                .report()
+        })?;
+        Ok(Shutdown {
+            terminate,
+            interrupt,
+        })
+    }
+
+    #[cfg(windows)]
+    fn new() -> Result<Shutdown, ()> {
+        let ctrl_c = tokio::signal::windows::ctrl_c().map_err(|e| {
+            Error::new("daemon::signal", "Failed to handle Ctrl-C")
+                .cause(e)
+                .report()
+        })?;
+        Ok(Shutdown { ctrl_c })
+    }
+
+    #[cfg(unix)]
+    async fn recv(&mut self) {
+        tokio::select! {
+            _ = self.terminate.recv() => info!("Received SIGTERM"),
+            _ = self.interrupt.recv() => info!("Received SIGINT"),
+        }
+    }
+
+    #[cfg(windows)]
+    async fn recv(&mut self) {
+        self.ctrl_c.recv().await;
+        info!("Received Ctrl-C");
+    }
+}
+
+/// Pings the systemd watchdog if `WATCHDOG_USEC` is set for the service
+///
+/// The pings are sent from the sync loop itself, so systemd restarts the service if the loop
+/// gets stuck. A fetch only keeps them going for as long as the watchdog timeout, one that runs
+/// longer is taken for hung.
+struct Watchdog {
+    interval: Option<tokio::time::Interval>,
+    timeout: Option<Duration>,
+}
+
+impl Watchdog {
+    fn from_env() -> Watchdog {
+        #[cfg(unix)]
+        let timeout = sd_notify::watchdog_enabled();
+        #[cfg(not(unix))]
+        let timeout: Option<Duration> = None;
+
+        // Ping twice per timeout, as recommended by systemd
+        Watchdog {
+            interval: timeout.map(|timeout| {
+                info!(
+                    "systemd watchdog enabled, timeout {}ms",
+                    timeout.as_millis()
+                );
+                tokio::time::interval(timeout / 2)
+            }),
+            timeout,
+        }
+    }
+
+    /// Whether a fetch running for `running` should be given up on, and the pings stopped
+    fn hung(&self, running: Duration) -> bool {
+        self.timeout.is_some_and(|timeout| running >= timeout)
+    }
+
+    /// Resolves when the next ping is due, never if the watchdog is disabled
+    async fn tick(&mut self) {
+        match &mut self.interval {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    fn ping(&self) {
+        notify_systemd(NotifyState::Watchdog);
+    }
+}
+
+/// The few service states reported to systemd
+enum NotifyState {
+    Ready,
+    Stopping,
+    Watchdog,
+}
+
+/// Report the service state to systemd, if started by it with `Type=notify`
+#[cfg(unix)]
+fn notify_systemd(state: NotifyState) {
+    let state = match state {
+        NotifyState::Ready => sd_notify::NotifyState::Ready,
+        NotifyState::Stopping => sd_notify::NotifyState::Stopping,
+        NotifyState::Watchdog => sd_notify::NotifyState::Watchdog,
+    };
+    if let Err(e) = sd_notify::notify(&[state]) {
+        debug!("Failed to notify systemd -- {:?}", e);
+    }
+}
+
+#[cfg(not(unix))]
+fn notify_systemd(_state: NotifyState) {}
+
+/// Create `--ready-file` after a successful sync, remove it after a failed one
+///
+/// A file that can't be created is only logged, the keys were synced all the same.
+fn mark_ready(args: &Cli, ready: bool) {
+    let Some(path) = &args.ready_file else {
+        return;
+    };
+
+    if ready {
+        if let Err(e) = std::fs::write(path, b"") {
+            Error::new("daemon::ready_file", "Failed to create the ready file")
+                .file(path)
+                .cause(e)
+                .warn();
+        }
+        return;
+    }
+    match std::fs::remove_file(path) {
+        Ok(()) => debug!("Removed the ready file {}", path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => Error::new("daemon::ready_file", "Failed to remove the ready file")
+            .file(path)
+            .cause(e)
+            .warn(),
+    }
+}
+
+/// The interval plus a random splay of up to `splay` seconds
+fn next_delay(interval: Duration, splay: u64) -> Duration {
+    if splay == 0 {
+        return interval;
+    }
+    interval + Duration::from_secs(OsRng.next_u64() % (splay + 1))
+}
+
+/// Sync the keys every `interval` seconds, plus the splay, until SIGTERM or SIGINT
+///
+/// A signal during a fetch aborts it before anything is written. Writing itself is never
+/// interrupted, a signal received meanwhile stops the service once the write is done. Either
+/// way it stops well inside the grace period Kubernetes gives a pod.
+///
+/// Every sync goes through the same sources, so the session authenticated at startup and its
+/// pooled keep-alive connection are reused instead of paying for a new login and TLS handshake
+/// on every poll.
+pub async fn run(source: &impl KeySource, args: &Cli, cache: Option<&Cache>) -> Result<(), ()> {
+    let interval = Duration::from_secs(args.interval.unwrap_or(DEFAULT_INTERVAL));
+    let splay = args.splay.unwrap_or(0);
+    let mut shutdown = Shutdown::new()?;
+    let mut watchdog = Watchdog::from_env();
+    info!(
+        "Syncing keys every {}s with a splay of up to {splay}s",
+        interval.as_secs()
+    );
+    notify_systemd(NotifyState::Ready);
+
+    'sync: loop {
+        let started = std::time::Instant::now();
+        let fetch = crate::source::fetch_all(source, args, cache, |_, _| {});
+        tokio::pin!(fetch);
+        let mut hung = false;
+        let (mut fetched, complete) = loop {
+            tokio::select! {
+                results = &mut fetch => break results,
+                () = shutdown.recv() => {
+                    info!("Aborting the sync in progress, nothing was written");
+                    break 'sync;
+                }
+                () = watchdog.tick(), if !hung => {
+                    hung = watchdog.hung(started.elapsed());
+                    if hung {
+                        Error::new(
+                            "daemon::watchdog",
+                            "The fetch is taking longer than the watchdog timeout, \
+                            no longer pinging it",
+                        )
+                        .with("running", format_args!("{}s", started.elapsed().as_secs()))
+                        .help("systemd restarts the service unless the fetch completes")
+                        .warn();
+                    } else {
+                        watchdog.ping();
+                    }
+                }
+            }
+        };
+
+        let results = crate::source::Fetched {
+            posix_ids: crate::source::posix_ids(source, args, &mut fetched).await,
+            origins: crate::source::origins(source, &fetched),
+            fetched,
+            complete,
+            static_keys: crate::source::static_keys(args),
+        };
+        let synced = crate::write_results(args, &results, started).is_ok();
+        if !synced {
+            Error::new(
+                "daemon::sync",
+                "Failed to sync keys, retrying at the next interval",
+            )
+            .report();
+        }
+        mark_ready(args, synced);
+        crate::health::record(args, synced);
+        crate::report_outcome(args, synced, started);
+
+        let delay = next_delay(interval, splay);
+        debug!("Next sync in {}s", delay.as_secs());
+        let sleep = tokio::time::sleep(delay);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                () = &mut sleep => break,
+                () = shutdown.recv() => break 'sync,
+                () = watchdog.tick() => watchdog.ping(),
+            }
+        }
+    }
+
+    notify_systemd(NotifyState::Stopping);
+    mark_ready(args, false);
+    let _ = std::io::stdout().flush();
+    info!("Shut down");
+
+    Ok(())
+}
@@ -0,0 +1,526 @@
+//! Errors with a stable code and the context they happened in
+//!
+//! A failure is reported once, where it happens, with [`Error::report`]. On a terminal it is
+//! rendered as a miette diagnostic, otherwise logged as one terse line starting with its code, e.g.
+//!
+//! ```text
+//! [authorized_keys::read] Failed to read authorized_keys file (file: /root/.ssh/authorized_keys) -- Os { code: 13, .. }
+//! ```
+//!
+//! Codes are `<area>::<what>` and don't change between releases, so they can be matched on.
+//! Warnings, reported with [`Error::warn`], work the same way. With `--errors json` both are
+//! printed as one JSON object per line on stderr instead, see [`ErrorFormat::Json`].
+//!
+//! Diagnostics, log lines and the changes `--dry-run` prints are colored following
+//! [`ColorChoice`].
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use clap::ValueEnum;
+use miette::{
+    Diagnostic, GraphicalReportHandler, GraphicalTheme, LabeledSpan, NamedSource, Severity,
+    SourceCode,
+};
+use serde::{Deserialize, Serialize};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::{LevelFilter, filter_fn};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+
+/// How errors and warnings are printed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorFormat {
+    /// Pretty on a terminal, one line starting with the code otherwise
+    #[default]
+    Text,
+    /// One JSON object per line on stderr, with the level, code, account and message
+    Json,
+}
+
+/// Whether [`ErrorFormat::Json`] was chosen
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Print errors and warnings in `format` from now on
+pub fn set_format(format: ErrorFormat) {
+    JSON.store(format == ErrorFormat::Json, Ordering::Relaxed);
+}
+
+/// How many of the reported problems are kept, see [`take_reported`]
+const MAX_REPORTED: usize = 20;
+
+/// The errors and warnings reported since the last [`take_reported`]
+#[derive(Debug, Default)]
+pub struct Reported {
+    pub errors: usize,
+    pub warnings: usize,
+    /// The first [`MAX_REPORTED`] of them, as their JSON lines without the context
+    pub first: Vec<serde_json::Value>,
+}
+
+static REPORTED: Mutex<Reported> = Mutex::new(Reported {
+    errors: 0,
+    warnings: 0,
+    first: Vec::new(),
+});
+
+/// The errors and warnings reported since the last call, e.g. for the status of a run
+pub fn take_reported() -> Reported {
+    REPORTED
+        .lock()
+        .map(|mut reported| std::mem::take(&mut *reported))
+        .unwrap_or_default()
+}
+
+/// Whether nothing but the keys is printed, see [`silence`]
+static SILENT: AtomicBool = AtomicBool::new(false);
+
+/// Print no errors, warnings or log lines from now on, as tools sshd calls are expected to
+pub fn silence() {
+    SILENT.store(true, Ordering::Relaxed);
+}
+
+/// When output is colored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChoice {
+    /// On a terminal, unless `NO_COLOR` is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// The [`ColorChoice`] chosen, as its discriminant
+static COLOR: AtomicU8 = AtomicU8::new(ColorChoice::Auto as u8);
+
+/// Color the output following `color` from now on
+pub fn set_color(color: ColorChoice) {
+    COLOR.store(color as u8, Ordering::Relaxed);
+}
+
+fn color_choice() -> ColorChoice {
+    match COLOR.load(Ordering::Relaxed) {
+        c if c == ColorChoice::Always as u8 => ColorChoice::Always,
+        c if c == ColorChoice::Never as u8 => ColorChoice::Never,
+        _ => ColorChoice::Auto,
+    }
+}
+
+/// Whether to color what is written to a stream, `terminal` if it is one
+pub fn colored(terminal: bool) -> bool {
+    match color_choice() {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => terminal && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()),
+    }
+}
+
+/// Set up tracing, sending the warnings and errors logged by dependencies through `format` too
+pub fn init_tracing(format: ErrorFormat) {
+    set_format(format);
+    if SILENT.load(Ordering::Relaxed) {
+        return;
+    }
+    let ansi = colored(std::io::stdout().is_terminal());
+    if format == ErrorFormat::Text {
+        tracing_subscriber::fmt().with_ansi(ansi).init();
+        return;
+    }
+
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(ansi)
+                .with_filter(LevelFilter::INFO)
+                .with_filter(filter_fn(|metadata| *metadata.level() > Level::WARN)),
+        )
+        .with(JsonLayer.with_filter(LevelFilter::WARN))
+        .init();
+}
+
+/// Set up tracing for a sidecar, printing every line as JSON like [`ErrorFormat::Json`] does
+/// for warnings and errors
+pub fn init_json_tracing() {
+    set_format(ErrorFormat::Json);
+    tracing_subscriber::registry()
+        .with(JsonLayer.with_filter(LevelFilter::INFO))
+        .init();
+}
+
+/// The JSON line of a warning or error, with `null` for what is not known
+fn json_line(
+    level: &str,
+    code: Option<&str>,
+    account: Option<&str>,
+    message: &str,
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut line = serde_json::Map::new();
+    line.insert("level".to_string(), level.into());
+    line.insert("code".to_string(), code.into());
+    line.insert("account".to_string(), account.into());
+    line.insert("message".to_string(), message.into());
+    line
+}
+
+/// Prints the warnings and errors of dependencies, which have no code, as JSON lines
+///
+/// For a sidecar, it prints the info lines of the fetcher itself too.
+struct JsonLayer;
+
+/// The message and the account, if any, of an event
+#[derive(Default)]
+struct EventFields {
+    message: String,
+    account: Option<String>,
+}
+
+impl Visit for EventFields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{value:?}"),
+            "account" | "account_id" => self.account = Some(format!("{value:?}")),
+            _ => {}
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            "account" | "account_id" => self.account = Some(value.to_string()),
+            _ => {}
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for JsonLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut fields = EventFields::default();
+        event.record(&mut fields);
+        let level = event.metadata().level().as_str().to_lowercase();
+        let line = json_line(&level, None, fields.account.as_deref(), &fields.message);
+        eprintln!("{}", serde_json::Value::Object(line));
+    }
+}
+
+/// What caused an error, kept as the `{:?}` of the underlying error
+#[derive(Debug)]
+struct Cause(String);
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Cause {}
+
+#[derive(Debug)]
+pub struct Error {
+    code: &'static str,
+    message: String,
+    context: Vec<(&'static str, String)>,
+    cause: Option<Cause>,
+    help: Option<String>,
+    warning: bool,
+    source_code: Option<NamedSource<String>>,
+    label: Option<LabeledSpan>,
+}
+
+impl Error {
+    pub fn new(code: &'static str, message: impl Into<String>) -> Error {
+        Error {
+            code,
+            message: message.into(),
+            context: Vec::new(),
+            cause: None,
+            help: None,
+            warning: false,
+            source_code: None,
+            label: None,
+        }
+    }
+
+    /// The file the error is about
+    pub fn file(self, path: impl AsRef<Path>) -> Error {
+        self.with("file", path.as_ref().display())
+    }
+
+    /// The account the error is about
+    pub fn account(self, account_id: impl fmt::Display) -> Error {
+        self.with("account", account_id)
+    }
+
+    /// The URL the error is about
+    pub fn url(self, url: impl fmt::Display) -> Error {
+        self.with("url", url)
+    }
+
+    /// Any other context, shown as `name: value`
+    pub fn with(mut self, name: &'static str, value: impl fmt::Display) -> Error {
+        self.context.push((name, value.to_string()));
+        self
+    }
+
+    pub fn cause(mut self, cause: impl fmt::Debug) -> Error {
+        self.cause = Some(Cause(format!("{:?}", cause)));
+        self
+    }
+
+    /// What the user can do about the error
+    pub fn help(mut self, help: impl Into<String>) -> Error {
+        self.help = Some(help.into());
+        self
+    }
+
+    /// Like [`Error::help`], for help only some causes come with
+    pub fn maybe_help(mut self, help: Option<impl Into<String>>) -> Error {
+        self.help = help.map(Into::into).or(self.help);
+        self
+    }
+
+    /// Point at the part of a file that caused the error, e.g. a syntax error in the config
+    pub fn span(
+        mut self,
+        name: impl AsRef<str>,
+        content: &str,
+        span: std::ops::Range<usize>,
+        label: impl Into<String>,
+    ) -> Error {
+        self.source_code = Some(NamedSource::new(name, content.to_string()));
+        self.label = Some(LabeledSpan::new_with_span(Some(label.into()), span));
+        self
+    }
+
+    /// Print the error to stderr, pretty on a terminal and terse otherwise
+    pub fn report(self) {
+        self.print();
+    }
+
+    /// Print the error to stderr as a warning, for failures the run continues after
+    pub fn warn(mut self) {
+        self.warning = true;
+        self.print();
+    }
+
+    fn print(self) {
+        self.keep();
+        if SILENT.load(Ordering::Relaxed) {
+            return;
+        }
+        if JSON.load(Ordering::Relaxed) {
+            eprintln!("{}", self.json());
+            return;
+        }
+
+        if std::io::stderr().is_terminal() {
+            let mut rendered = String::new();
+            let handler = match color_choice() {
+                ColorChoice::Auto => GraphicalReportHandler::new(),
+                ColorChoice::Always => {
+                    GraphicalReportHandler::new_themed(GraphicalTheme::unicode())
+                }
+                ColorChoice::Never => {
+                    GraphicalReportHandler::new_themed(GraphicalTheme::unicode_nocolor())
+                }
+            };
+            if handler.render_report(&mut rendered, &self).is_ok() {
+                eprint!("{rendered}");
+                return;
+            }
+        }
+
+        // Before tracing is set up, e.g. while reading the configuration
+        match (tracing::dispatcher::has_been_set(), self.warning) {
+            (true, false) => tracing::error!("{}", self.terse()),
+            (true, true) => tracing::warn!("{}", self.terse()),
+            (false, false) => eprintln!("error: {}", self.terse()),
+            (false, true) => eprintln!("warning: {}", self.terse()),
+        }
+    }
+
+    /// The code, account and message, and the rest of the context, cause and help
+    /// Count the error for [`take_reported`], and send errors to Sentry, also when nothing is
+    /// printed
+    fn keep(&self) {
+        #[cfg(feature = "sentry")]
+        if !self.warning {
+            crate::sentry::capture_error(&self.json());
+        }
+        let Ok(mut reported) = REPORTED.lock() else {
+            return;
+        };
+        if self.warning {
+            reported.warnings += 1;
+        } else {
+            reported.errors += 1;
+        }
+        if reported.first.len() < MAX_REPORTED {
+            let level = if self.warning { "warn" } else { "error" };
+            let account = self
+                .context
+                .iter()
+                .find(|(name, _)| *name == "account")
+                .map(|(_, account)| account.as_str());
+            reported
+                .first
+                .push(json_line(level, Some(self.code), account, &self.message).into());
+        }
+    }
+
+    fn json(&self) -> serde_json::Value {
+        let account = self
+            .context
+            .iter()
+            .find(|(name, _)| *name == "account")
+            .map(|(_, account)| account.as_str());
+        let context: BTreeMap<&str, &str> = self
+            .context
+            .iter()
+            .filter(|(name, _)| *name != "account")
+            .map(|(name, value)| (*name, value.as_str()))
+            .collect();
+        // Syntax errors only describe themselves in the label
+        let cause = self
+            .cause
+            .as_ref()
+            .map(|cause| cause.0.as_str())
+            .or_else(|| self.label.as_ref().and_then(|label| label.label()));
+
+        let level = if self.warning { "warn" } else { "error" };
+        let mut line = json_line(level, Some(self.code), account, &self.message);
+        line.insert("context".to_string(), serde_json::json!(context));
+        line.insert("cause".to_string(), cause.into());
+        line.insert("help".to_string(), self.help.as_deref().into());
+        serde_json::Value::Object(line)
+    }
+
+    /// The code, message, context, cause and help on one line
+    fn terse(&self) -> String {
+        let mut line = format!("[{}] {}", self.code, self);
+        if let Some(cause) = &self.cause {
+            line.push_str(" -- ");
+            line.push_str(&cause.0);
+        }
+        if let Some(help) = &self.help {
+            line.push_str(" -- help: ");
+            line.push_str(help);
+        }
+        line
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)?;
+        if !self.context.is_empty() {
+            let context: Vec<String> = self
+                .context
+                .iter()
+                .map(|(name, value)| format!("{name}: {value}"))
+                .collect();
+            write!(f, " ({})", context.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.cause
+            .as_ref()
+            .map(|cause| cause as &(dyn std::error::Error + 'static))
+    }
+}
+
+impl Diagnostic for Error {
+    fn code<'a>(&'a self) -> Option<Box<dyn fmt::Display + 'a>> {
+        Some(Box::new(self.code))
+    }
+
+    fn severity(&self) -> Option<Severity> {
+        Some(if self.warning {
+            Severity::Warning
+        } else {
+            Severity::Error
+        })
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn fmt::Display + 'a>> {
+        self.help
+            .as_ref()
+            .map(|help| Box::new(help) as Box<dyn fmt::Display>)
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        self.source_code
+            .as_ref()
+            .map(|source| source as &dyn SourceCode)
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+        self.label
+            .clone()
+            .map(|label| Box::new(std::iter::once(label)) as Box<dyn Iterator<Item = LabeledSpan>>)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn terse_lines_lead_with_the_code() {
+        let error = Error::new("cache::open", "Failed to open cache")
+            .file("/var/cache/keys.db")
+            .account("alice")
+            .cause(std::io::ErrorKind::PermissionDenied);
+
+        assert_eq!(
+            error.terse(),
+            "[cache::open] Failed to open cache (file: /var/cache/keys.db, account: alice) -- PermissionDenied"
+        );
+        assert_eq!(
+            Error::new("args::conflict", "Bad").terse(),
+            "[args::conflict] Bad"
+        );
+        assert_eq!(
+            Error::new("auth::anonymous", "Refused")
+                .maybe_help(Some("supply --token"))
+                .terse(),
+            "[auth::anonymous] Refused -- help: supply --token"
+        );
+    }
+
+    #[test]
+    fn json_lines_carry_the_code_and_account() {
+        let error = Error::new("fetch::account", "Failed to get ssh keys")
+            .account("alice")
+            .url("https://idm.example.com/")
+            .cause("unreachable");
+
+        assert_eq!(
+            error.json(),
+            serde_json::json!({
+                "level": "error",
+                "code": "fetch::account",
+                "account": "alice",
+                "message": "Failed to get ssh keys",
+                "context": { "url": "https://idm.example.com/" },
+                "cause": "\"unreachable\"",
+                "help": null,
+            })
+        );
+
+        let mut warning = Error::new("sandbox::landlock", "Not supported");
+        warning.warning = true;
+        assert_eq!(warning.json()["level"], "warn");
+        assert_eq!(warning.json()["account"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,321 @@
+//! Reading and writing the files of one directory through a handle to it
+//!
+//! authorized_keys lives in a directory its user controls. A path is resolved anew on every
+//! access, so when writing for someone else, as root or the write helper, a `.ssh` swapped for
+//! a symlink to `/etc` between two accesses would redirect the writes, and the changes of owner
+//! and mode, there. The directory is therefore opened once, and every file in it is reached
+//! relative to the open directory. Opened for another user with [`Dir::open_for`], neither the
+//! directory nor the files in it are followed if they are symlinks.
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+use nix::fcntl::{OFlag, openat, renameat};
+#[cfg(unix)]
+use nix::sys::stat::{Mode, SFlag, fstatat, mkdirat};
+#[cfg(unix)]
+use nix::unistd::{Gid, Uid, UnlinkatFlags, unlinkat};
+
+use crate::diagnostic::Error;
+
+/// An open directory, see the [module documentation](self)
+pub struct Dir {
+    path: PathBuf,
+    /// Whether it belongs to another user, so symlinks in it are refused
+    guarded: bool,
+    #[cfg(unix)]
+    fd: File,
+}
+
+impl Dir {
+    /// Open the directory at `path`, following symlinks, for files of the user running
+    pub fn open(path: &Path) -> io::Result<Dir> {
+        #[cfg(unix)]
+        let fd = File::from(openat(
+            nix::fcntl::AT_FDCWD,
+            path,
+            OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )?);
+        #[cfg(not(unix))]
+        if !path.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                "not a directory",
+            ));
+        }
+        Ok(Dir {
+            path: path.to_path_buf(),
+            guarded: false,
+            #[cfg(unix)]
+            fd,
+        })
+    }
+
+    /// Open the directory at `path` for writing files of the user `owner`
+    ///
+    /// Its parent, e.g. the home directory from the passwd database, is trusted, the directory
+    /// itself is not: it is opened without following a symlink, created for `owner` if it is
+    /// missing, and must belong to `owner` or to the user running.
+    #[cfg(unix)]
+    pub fn open_for(path: &Path, owner: (Uid, Gid)) -> Result<Dir, ()> {
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            Error::new("write::dir", "Invalid directory")
+                .file(path)
+                .report();
+            return Err(());
+        };
+        let error = |message: &'static str| {
+            move |e: nix::Error| {
+                Error::new("write::dir", message)
+                    .file(path)
+                    .cause(e)
+                    .report()
+            }
+        };
+        let parent = Dir::open(parent).map_err(|e| {
+            Error::new("write::dir", "Failed to open home directory")
+                .file(parent)
+                .cause(e)
+                .report()
+        })?;
+        let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+        let fd = match openat(&parent.fd, name, flags, Mode::empty()) {
+            Err(nix::Error::ENOENT) => {
+                tracing::debug!("Creating ssh config directory -- {path:?}");
+                mkdirat(&parent.fd, name, Mode::S_IRWXU)
+                    .map_err(error("Failed to create ssh config directory"))?;
+                let fd = File::from(
+                    openat(&parent.fd, name, flags, Mode::empty())
+                        .map_err(error("Failed to open ssh config directory"))?,
+                );
+                std::os::unix::fs::fchown(&fd, Some(owner.0.as_raw()), Some(owner.1.as_raw()))
+                    .map_err(|e| {
+                        Error::new("owner::set", "Failed to change the owner")
+                            .file(path)
+                            .cause(e)
+                            .report()
+                    })?;
+                fd
+            }
+            Err(nix::Error::ELOOP | nix::Error::ENOTDIR) => {
+                Error::new(
+                    "write::dir",
+                    "Refusing to write into a directory that is a symlink or not a directory",
+                )
+                .file(path)
+                .help("replace it with a directory owned by the user")
+                .report();
+                return Err(());
+            }
+            fd => File::from(fd.map_err(error("Failed to open ssh config directory"))?),
+        };
+
+        use std::os::unix::fs::MetadataExt;
+        let uid = fd
+            .metadata()
+            .map_err(|e| {
+                Error::new("owner::read", "Failed to read the owner")
+                    .file(path)
+                    .cause(e)
+                    .report()
+            })?
+            .uid();
+        if uid != owner.0.as_raw() && uid != nix::unistd::geteuid().as_raw() {
+            Error::new(
+                "write::dir",
+                "Refusing to write into a directory of someone else",
+            )
+            .file(path)
+            .with("owner", uid)
+            .with("expected", owner.0)
+            .report();
+            return Err(());
+        }
+
+        Ok(Dir {
+            path: path.to_path_buf(),
+            guarded: true,
+            fd,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the directory belongs to another user, and symlinks in it are refused
+    pub fn guarded(&self) -> bool {
+        self.guarded
+    }
+
+    /// The path of `name` in the directory, for messages and file names
+    pub fn join(&self, name: &OsStr) -> PathBuf {
+        self.path.join(name)
+    }
+
+    /// The open directory, e.g. to change its owner or mode
+    #[cfg(unix)]
+    pub fn file(&self) -> &File {
+        &self.fd
+    }
+
+    /// Open `name` for reading, `None` if it doesn't exist
+    ///
+    /// In a guarded directory only regular files are opened, a symlink is refused.
+    pub fn read(&self, name: &OsStr) -> io::Result<Option<File>> {
+        #[cfg(unix)]
+        let file = {
+            let mut flags = OFlag::O_RDONLY | OFlag::O_CLOEXEC;
+            if self.guarded {
+                // Neither follow a symlink nor wait for a FIFO a user planted
+                flags |= OFlag::O_NOFOLLOW | OFlag::O_NONBLOCK;
+            }
+            openat(&self.fd, name, flags, Mode::empty())
+                .map(File::from)
+                .map_err(io::Error::from)
+        };
+        #[cfg(not(unix))]
+        let file = File::open(self.join(name));
+        match file {
+            Ok(file) if self.guarded && !file.metadata()?.is_file() => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a regular file",
+            )),
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether `name` exists, as a symlink or anything else
+    pub fn exists(&self, name: &OsStr) -> bool {
+        #[cfg(unix)]
+        return fstatat(&self.fd, name, nix::fcntl::AtFlags::AT_SYMLINK_NOFOLLOW).is_ok();
+        #[cfg(not(unix))]
+        return self.join(name).symlink_metadata().is_ok();
+    }
+
+    /// Whether `name` is a symlink
+    pub fn is_symlink(&self, name: &OsStr) -> bool {
+        #[cfg(unix)]
+        return fstatat(&self.fd, name, nix::fcntl::AtFlags::AT_SYMLINK_NOFOLLOW).is_ok_and(
+            |stat| SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFLNK,
+        );
+        #[cfg(not(unix))]
+        return self
+            .join(name)
+            .symlink_metadata()
+            .is_ok_and(|m| m.file_type().is_symlink());
+    }
+
+    /// Create `name`, readable and writable only by its owner, failing if it exists
+    pub fn create_new(&self, name: &OsStr) -> io::Result<File> {
+        #[cfg(unix)]
+        return openat(
+            &self.fd,
+            name,
+            OFlag::O_RDWR | OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+            Mode::S_IRUSR | Mode::S_IWUSR,
+        )
+        .map(File::from)
+        .map_err(io::Error::from);
+        #[cfg(not(unix))]
+        return std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(self.join(name));
+    }
+
+    /// Rename `from` to `to`, replacing it
+    pub fn rename(&self, from: &OsStr, to: &OsStr) -> io::Result<()> {
+        #[cfg(unix)]
+        return renameat(&self.fd, from, &self.fd, to).map_err(io::Error::from);
+        #[cfg(not(unix))]
+        return std::fs::rename(self.join(from), self.join(to));
+    }
+
+    /// Remove the file `name`
+    pub fn remove(&self, name: &OsStr) -> io::Result<()> {
+        #[cfg(unix)]
+        return unlinkat(&self.fd, name, UnlinkatFlags::NoRemoveDir).map_err(io::Error::from);
+        #[cfg(not(unix))]
+        return std::fs::remove_file(self.join(name));
+    }
+
+    /// Persist the renames in the directory
+    pub fn sync(&self) {
+        #[cfg(unix)]
+        let _ = self.fd.sync_all();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "kanidm_sshkey_fetcher-dir-{name}-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("home")).unwrap();
+        dir
+    }
+
+    fn current_user() -> (Uid, Gid) {
+        (nix::unistd::geteuid(), nix::unistd::getegid())
+    }
+
+    #[test]
+    fn refuses_a_symlinked_directory_for_another_user() {
+        let dir = temp_dir("symlink");
+        std::fs::create_dir(dir.join("elsewhere")).unwrap();
+        std::os::unix::fs::symlink(dir.join("elsewhere"), dir.join("home/.ssh")).unwrap();
+
+        let opened = Dir::open_for(&dir.join("home/.ssh"), current_user());
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(opened.is_err());
+    }
+
+    #[test]
+    fn creates_a_missing_directory_for_another_user() {
+        let dir = temp_dir("create");
+
+        let opened = Dir::open_for(&dir.join("home/.ssh"), current_user()).unwrap();
+        let created = opened.create_new(OsStr::new("authorized_keys")).is_ok();
+        let exists = dir.join("home/.ssh/authorized_keys").is_file();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(opened.guarded());
+        assert!(created);
+        assert!(exists);
+    }
+
+    #[test]
+    fn reads_symlinks_only_in_unguarded_directories() {
+        let dir = temp_dir("read");
+        std::fs::create_dir(dir.join("home/.ssh")).unwrap();
+        std::fs::write(dir.join("secret"), "secret").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret"), dir.join("home/.ssh/authorized_keys"))
+            .unwrap();
+        let name = OsStr::new("authorized_keys");
+
+        let unguarded = Dir::open(&dir.join("home/.ssh")).unwrap();
+        let guarded = Dir::open_for(&dir.join("home/.ssh"), current_user()).unwrap();
+        let followed = unguarded.read(name).map(|file| file.is_some());
+        let refused = guarded.read(name).is_err();
+        let is_symlink = guarded.is_symlink(name);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(followed.unwrap());
+        assert!(refused);
+        assert!(is_symlink);
+    }
+}
@@ -0,0 +1,447 @@
+//! Checks of everything a sync depends on, with a hint on how to fix each failure
+
+use std::net::ToSocketAddrs;
+#[cfg(unix)]
+use std::path::Path;
+
+use kanidm_client::{ClientError, KanidmClient};
+
+use crate::Cli;
+use crate::source::{KeySource, SourceError, Sources};
+use crate::table::print_table;
+
+/// Where sshd reads its configuration from
+#[cfg(unix)]
+const SSHD_CONFIG: &str = "/etc/ssh/sshd_config";
+
+/// What sshd uses if `AuthorizedKeysFile` is not set
+#[cfg(unix)]
+const DEFAULT_AUTHORIZED_KEYS_FILE: &str = ".ssh/authorized_keys .ssh/authorized_keys2";
+
+enum Outcome {
+    Pass(String),
+    Fail { detail: String, hint: String },
+    Skip(String),
+}
+
+fn fail(detail: impl Into<String>, hint: impl Into<String>) -> Outcome {
+    Outcome::Fail {
+        detail: detail.into(),
+        hint: hint.into(),
+    }
+}
+
+/// The innermost cause of an error, e.g. `Connection refused` rather than the whole chain
+fn root_cause(e: &dyn std::error::Error) -> String {
+    let mut cause = e;
+    while let Some(source) = cause.source() {
+        cause = source;
+    }
+    cause.to_string()
+}
+
+fn describe(e: &ClientError) -> String {
+    match e {
+        ClientError::Transport(e) => root_cause(e),
+        ClientError::Http(status, _, _) => format!("http {status}"),
+        e => format!("{:?}", e),
+    }
+}
+
+fn check_config(args: &Cli) -> Outcome {
+    if args.account_ids.is_empty() && args.groups.is_empty() {
+        return fail(
+            "no accounts or groups configured",
+            "list account ids as arguments or in `account_ids`, or groups with --group",
+        );
+    }
+
+    let configured = format!(
+        "{} accounts and {} groups",
+        args.account_ids.len(),
+        args.groups.len()
+    );
+    Outcome::Pass(match &args.config_path {
+        Some(path) => format!("{} parsed, {configured}", path.display()),
+        None => format!("no config file, {configured}"),
+    })
+}
+
+fn check_dns(client: &KanidmClient) -> Outcome {
+    let url = client.get_url();
+    let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+        return fail(
+            format!("{url} has no host"),
+            "pass the server address with --url, e.g. https://idm.example.com",
+        );
+    };
+
+    match (host, port).to_socket_addrs() {
+        Ok(addrs) => {
+            let addrs: Vec<String> = addrs.map(|addr| addr.ip().to_string()).collect();
+            Outcome::Pass(format!("{host} resolves to {}", addrs.join(", ")))
+        }
+        Err(e) => fail(
+            format!("{host} -- {e}"),
+            "check the host name in --url and the resolver configuration",
+        ),
+    }
+}
+
+/// Whether the server answers, checking the TLS handshake for https
+async fn check_tls(client: &KanidmClient) -> Outcome {
+    let url = client.make_url("/status");
+    let https = url.scheme() == "https";
+
+    match client.client().get(url).send().await {
+        Ok(response) if https => {
+            Outcome::Pass(format!("handshake ok, /status is {}", response.status()))
+        }
+        Ok(response) => Outcome::Skip(format!("not https, /status is {}", response.status())),
+        Err(e) if e.is_connect() && https => fail(
+            root_cause(&e),
+            "make sure the server is up and its certificate is trusted, pass its ca with --ca",
+        ),
+        Err(e) if e.is_connect() => fail(root_cause(&e), "make sure the server is up"),
+        Err(e) => fail(
+            root_cause(&e),
+            "check that --url points to the kanidm server itself and not a proxy",
+        ),
+    }
+}
+
+async fn check_version(client: &KanidmClient) -> Outcome {
+    let client_version = crate::version::client_version();
+    match crate::version::server_version(client).await {
+        Ok(Some(version)) if crate::version::is_supported(&version) => {
+            Outcome::Pass(format!("server {version}, client {client_version}"))
+        }
+        Ok(Some(version)) => fail(
+            format!("server {version}, client {client_version}"),
+            "use a release of this program built for the server's kanidm version",
+        ),
+        Ok(None) => Outcome::Skip("the server did not report its version".to_string()),
+        Err(e) => fail(root_cause(&e), "see the tls check"),
+    }
+}
+
+async fn check_auth(client: &KanidmClient, args: &Cli) -> Outcome {
+    if args.ldap_only || args.unixd_only {
+        return Outcome::Skip("--ldap-only and --unixd-only do not use the HTTPS API".to_string());
+    }
+
+    let Some(token) = &args.token else {
+        return match client.auth_anonymous().await {
+            Ok(()) => Outcome::Pass("anonymous".to_string()),
+            Err(e) => fail(
+                format!("anonymous -- {}", describe(&e)),
+                crate::auth_hint(&e, false)
+                    .unwrap_or("check that the server is reachable, see the checks above"),
+            ),
+        };
+    };
+
+    client.set_token(token.clone()).await;
+    match client.whoami().await {
+        Ok(Some(_)) => Outcome::Pass("token accepted".to_string()),
+        Ok(None) | Err(ClientError::Unauthorized | ClientError::SessionExpired) => fail(
+            "the token was rejected",
+            "the token is invalid or expired, issue a new one and pass it with --token",
+        ),
+        Err(e) => fail(
+            describe(&e),
+            "check that the server is reachable, see the checks above",
+        ),
+    }
+}
+
+async fn check_fetch(client: &KanidmClient, args: &Cli) -> Outcome {
+    let Ok(sources) = Sources::new(client, args) else {
+        return fail(
+            "no source to fetch from",
+            "pass --ldap-url with --ldap-only",
+        );
+    };
+    let (account_ids, _) = crate::source::resolve_account_ids(&sources, args).await;
+    let Some(account_id) = account_ids.first() else {
+        return Outcome::Skip("no account to fetch".to_string());
+    };
+
+    match sources.account_keys(account_id).await {
+        Ok(keys) => Outcome::Pass(format!("{account_id} has {} keys", keys.len())),
+        Err(SourceError::NotFound) => fail(
+            format!("{account_id} was not found"),
+            "check the account id, and that it is readable with the configured credentials",
+        ),
+        Err(e) => fail(
+            format!("{account_id} -- {e}"),
+            "see the checks above, or run with --debug for details",
+        ),
+    }
+}
+
+/// Whether `path`, or the directory it would be created in, is writable and not writable by
+/// anyone else
+#[cfg(unix)]
+fn check_writable(path: &Path, strict: bool) -> Result<(), Outcome> {
+    use std::os::unix::fs::PermissionsExt;
+
+    use nix::unistd::{AccessFlags, access};
+
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .unwrap_or(Path::new("/"));
+    if access(existing, AccessFlags::W_OK).is_err() {
+        return Err(fail(
+            format!("{} is not writable", existing.display()),
+            "run as the owner of the file or as root, or use --write-helper",
+        ));
+    }
+
+    if strict {
+        for p in [Some(path), path.parent()].into_iter().flatten() {
+            let Ok(metadata) = std::fs::metadata(p) else {
+                continue;
+            };
+            if metadata.permissions().mode() & 0o022 != 0 {
+                return Err(fail(
+                    format!("{} is writable by group or others", p.display()),
+                    "sshd's StrictModes ignores such files, run once with --modify to fix the modes",
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn check_permissions(args: &Cli) -> Outcome {
+    let mut checked = Vec::new();
+
+    if args.modify && args.write_helper.is_none() {
+        let Ok(path) = crate::authorized_keys::authorized_keys_path(args) else {
+            return fail(
+                "the home directory cannot be resolved",
+                "check --user, or pass --home-dir",
+            );
+        };
+        if let Err(outcome) = check_writable(&path, true) {
+            return outcome;
+        }
+        checked.push(path);
+    }
+    for dir in [&args.key_dir, &args.fallback_dir].into_iter().flatten() {
+        if let Err(outcome) = check_writable(dir, false) {
+            return outcome;
+        }
+        checked.push(dir.clone());
+    }
+
+    if checked.is_empty() {
+        return Outcome::Skip("nothing is written locally".to_string());
+    }
+    let checked: Vec<String> = checked.iter().map(|p| p.display().to_string()).collect();
+    Outcome::Pass(checked.join(", "))
+}
+
+/// The value of the first `keyword` line in the global section of an sshd_config
+///
+/// Like sshd, the first occurrence wins. `Match` blocks and `Include`d files are not considered.
+#[cfg(unix)]
+fn sshd_option<'a>(config: &'a str, keyword: &str) -> Option<&'a str> {
+    config
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.split_once(|c: char| c.is_whitespace() || c == '=')
+                .map(|(k, v)| {
+                    (
+                        k,
+                        v.trim_start_matches(|c: char| c.is_whitespace() || c == '='),
+                    )
+                })
+                .unwrap_or((line, ""))
+        })
+        .take_while(|(k, _)| !k.eq_ignore_ascii_case("match"))
+        .find(|(k, _)| k.eq_ignore_ascii_case(keyword))
+        .map(|(_, v)| v.trim())
+}
+
+/// Whether an sshd_config makes sshd read the files this program writes, see
+/// [`check_sshd_config`]
+#[cfg(unix)]
+fn sshd_config_problem(config: &str, args: &Cli) -> Option<Outcome> {
+    if args.modify {
+        let files =
+            sshd_option(config, "AuthorizedKeysFile").unwrap_or(DEFAULT_AUTHORIZED_KEYS_FILE);
+        let read = files.split_whitespace().any(|file| {
+            file.trim_start_matches("%h/")
+                .trim_start_matches("~/")
+                .ends_with(".ssh/authorized_keys")
+        });
+        if !read {
+            return Some(fail(
+                format!("AuthorizedKeysFile is {files}"),
+                "add .ssh/authorized_keys to AuthorizedKeysFile",
+            ));
+        }
+    }
+
+    if let Some(key_dir) = &args.key_dir {
+        let key_dir = key_dir.display().to_string();
+        match sshd_option(config, "AuthorizedKeysCommand") {
+            Some(command) if command.contains(&key_dir) => {
+                if sshd_option(config, "AuthorizedKeysCommandUser").is_none() {
+                    return Some(fail(
+                        "AuthorizedKeysCommandUser is not set",
+                        "sshd ignores AuthorizedKeysCommand without it, e.g. set it to nobody",
+                    ));
+                }
+            }
+            command => {
+                return Some(fail(
+                    format!("AuthorizedKeysCommand is {}", command.unwrap_or("not set")),
+                    format!("set `AuthorizedKeysCommand /bin/cat {key_dir}/%u`"),
+                ));
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(unix)]
+fn check_sshd_config(args: &Cli) -> Outcome {
+    if !args.modify && args.key_dir.is_none() {
+        return Outcome::Skip("neither --modify nor --key-dir is set".to_string());
+    }
+
+    let config = match std::fs::read_to_string(SSHD_CONFIG) {
+        Ok(config) => config,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Outcome::Skip(format!("{SSHD_CONFIG} does not exist"));
+        }
+        Err(e) => {
+            return fail(
+                format!("{SSHD_CONFIG} -- {e}"),
+                "run as root to check the sshd configuration",
+            );
+        }
+    };
+
+    sshd_config_problem(&config, args)
+        .unwrap_or_else(|| Outcome::Pass(format!("{SSHD_CONFIG} reads the written keys")))
+}
+
+/// Run every check and print a report, fails if any check failed
+pub async fn doctor(args: &Cli) -> Result<(), ()> {
+    let mut checks = vec![("config", check_config(args))];
+
+    match crate::build_configured_client(args) {
+        Ok(client) => {
+            checks.push(("client", Outcome::Pass(client.get_url().to_string())));
+            checks.push(("dns", check_dns(&client)));
+            let tls = check_tls(&client).await;
+            let reachable = !matches!(tls, Outcome::Fail { .. });
+            checks.push(("tls", tls));
+            if reachable || args.ldap_only || args.unixd_only {
+                checks.push(("version", check_version(&client).await));
+                checks.push(("auth", check_auth(&client, args).await));
+                checks.push(("fetch", check_fetch(&client, args).await));
+            } else {
+                let unreachable = || Outcome::Skip("the server cannot be reached".to_string());
+                checks.push(("version", unreachable()));
+                checks.push(("auth", unreachable()));
+                checks.push(("fetch", unreachable()));
+            }
+        }
+        Err(()) => checks.push((
+            "client",
+            fail(
+                "the client cannot be built",
+                "check --url, --ca and /etc/kanidm/config, the error is logged above",
+            ),
+        )),
+    }
+
+    #[cfg(unix)]
+    {
+        checks.push(("permissions", check_permissions(args)));
+        checks.push(("sshd_config", check_sshd_config(args)));
+    }
+
+    let mut rows = Vec::new();
+    let mut failed = false;
+    for (name, outcome) in checks {
+        match outcome {
+            Outcome::Pass(detail) => rows.push(vec!["ok".to_string(), name.to_string(), detail]),
+            Outcome::Skip(detail) => rows.push(vec!["skip".to_string(), name.to_string(), detail]),
+            Outcome::Fail { detail, hint } => {
+                failed = true;
+                rows.push(vec!["FAIL".to_string(), name.to_string(), detail]);
+                rows.push(vec![String::new(), String::new(), format!("hint: {hint}")]);
+            }
+        }
+    }
+    print_table(&rows, &[]);
+
+    if failed { Err(()) } else { Ok(()) }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::parse_from(std::iter::once("kanidm_sshkey_fetcher").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn reads_the_global_section_only() {
+        let config = "\
+# AuthorizedKeysFile /nowhere
+AuthorizedKeysFile=.ssh/authorized_keys
+authorizedkeyscommand /bin/cat /etc/ssh/keys/%u
+Match User git
+    AuthorizedKeysCommandUser git
+";
+        assert_eq!(
+            sshd_option(config, "AuthorizedKeysFile"),
+            Some(".ssh/authorized_keys")
+        );
+        assert_eq!(
+            sshd_option(config, "AuthorizedKeysCommand"),
+            Some("/bin/cat /etc/ssh/keys/%u")
+        );
+        assert_eq!(sshd_option(config, "AuthorizedKeysCommandUser"), None);
+    }
+
+    #[test]
+    fn checks_sshd_reads_the_written_keys() {
+        let modify = cli(&["--modify", "alice"]);
+        assert!(sshd_config_problem("", &modify).is_none());
+        assert!(
+            sshd_config_problem("AuthorizedKeysFile %h/.ssh/authorized_keys", &modify).is_none()
+        );
+        assert!(sshd_config_problem("AuthorizedKeysFile /etc/ssh/keys/%u", &modify).is_some());
+
+        let key_dir = cli(&["--key-dir", "/etc/ssh/keys", "alice"]);
+        assert!(sshd_config_problem("", &key_dir).is_some());
+        assert!(
+            sshd_config_problem("AuthorizedKeysCommand /bin/cat /etc/ssh/keys/%u", &key_dir)
+                .is_some()
+        );
+        assert!(
+            sshd_config_problem(
+                "AuthorizedKeysCommand /bin/cat /etc/ssh/keys/%u\nAuthorizedKeysCommandUser nobody",
+                &key_dir
+            )
+            .is_none()
+        );
+    }
+}
@@ -0,0 +1,66 @@
+//! Keys printed by external commands, to bridge legacy systems during a migration to kanidm
+
+use std::process::Stdio;
+use std::time::Duration;
+
+use tokio::process::Command;
+use tracing::debug;
+
+use crate::diagnostic::Error;
+
+/// How long a command may run before its account counts as failed
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The program a command runs, i.e. its first word
+pub fn program(command: &str) -> Option<&str> {
+    command.split_whitespace().next()
+}
+
+/// Run `command` for an account and collect the keys it prints, one per line
+///
+/// `%a` is replaced by the account id. The command is split on whitespace and run without a
+/// shell, so an account id can't inject anything. Empty lines and `#` comments are skipped.
+pub async fn keys(command: &str, account_id: &str) -> Result<Vec<String>, ()> {
+    let mut argv = command
+        .split_whitespace()
+        .map(|arg| arg.replace("%a", account_id));
+    let Some(program) = argv.next() else {
+        Error::new("exec::empty", "Exec source is empty")
+            .account(account_id)
+            .report();
+        return Err(());
+    };
+
+    debug!("Running exec source {} for account {}", program, account_id);
+    let output = Command::new(&program)
+        .args(argv)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .output();
+    let output = tokio::time::timeout(TIMEOUT, output)
+        .await
+        .map_err(|_| {
+            Error::new("exec::timeout", "Exec source timed out")
+                .with("program", &program)
+                .account(account_id)
+                .report()
+        })?
+        .map_err(|e| {
+            Error::new("exec::run", "Failed to run exec source")
+                .with("program", &program)
+                .account(account_id)
+                .cause(e)
+                .report()
+        })?;
+    if !output.status.success() {
+        Error::new("exec::failed", "Exec source failed")
+            .with("program", &program)
+            .account(account_id)
+            .with("status", output.status)
+            .report();
+        return Err(());
+    }
+
+    Ok(crate::source::key_lines(&String::from_utf8_lossy(&output.stdout)).collect())
+}
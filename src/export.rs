@@ -0,0 +1,213 @@
+use std::path::{Path, PathBuf};
+
+use clap::Args;
+use kanidm_client::KanidmClient;
+use tracing::{debug, info};
+
+use crate::diagnostic::Error;
+use crate::keys::normalize_key;
+use crate::usermap::UserMap;
+
+#[derive(Debug, Clone, Args)]
+pub struct ExportArgs {
+    /// The directory to write one key file per account into
+    #[arg(short, long, value_parser, default_value = "keys")]
+    output: PathBuf,
+
+    /// Write a signed bundle of every account's keys to this file instead, for `import`
+    #[arg(long, value_parser)]
+    bundle: Option<PathBuf>,
+
+    /// The OpenSSH private key to sign the bundle with, overriding --bundle-signing-key
+    #[arg(long, value_parser, requires = "bundle")]
+    signing_key: Option<PathBuf>,
+}
+
+/// Write the keys of an account to `<dir>/<name>`, replacing the file atomically
+///
+/// A file that already has the same content is left alone.
+pub fn write_key_file(dir: &Path, name: &str, keys: &[String]) -> Result<(), ()> {
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        Error::new(
+            "key_dir::unsafe_name",
+            "Refusing to write key file for unsafe account name",
+        )
+        .account(format!("{:?}", name))
+        .report();
+        return Err(());
+    }
+
+    let path = dir.join(name);
+    let tmp_path = dir.join(format!(".{}.tmp", name));
+
+    let mut content = String::new();
+    for key in keys {
+        content.push_str(&format!("{}\n", key));
+    }
+
+    if std::fs::read(&path).is_ok_and(|existing| existing == content.as_bytes()) {
+        debug!("Key file is up to date -- {path:?}");
+        return Ok(());
+    }
+
+    debug!("Writing key file -- {path:?}");
+    std::fs::write(&tmp_path, content).map_err(|e| {
+        Error::new("key_dir::write", "Failed to write key file")
+            .file(&tmp_path)
+            .cause(e)
+            .report()
+    })?;
+    std::fs::rename(&tmp_path, &path).map_err(|e| {
+        Error::new("key_dir::write", "Failed to move key file into place")
+            .file(&path)
+            .cause(e)
+            .report()
+    })?;
+
+    Ok(())
+}
+
+/// The local user name an account's key file is named after, i.e. the account without `@domain`
+pub fn local_name(account_id: &str) -> &str {
+    account_id
+        .split_once('@')
+        .map_or(account_id, |(name, _)| name)
+}
+
+/// Bring a per-account key directory in line with the fetched keys
+///
+/// Each file is named after the local user of its account, see [`UserMap`]. Accounts that failed
+/// to fetch keep their previous file. Files of accounts that are no longer configured are only
+/// removed when `prune` is set, i.e. when every account resolved.
+pub fn sync_key_dir(
+    dir: &Path,
+    fetched: &[(String, Option<Vec<String>>)],
+    users: &UserMap,
+    prune: bool,
+) -> Result<(), ()> {
+    std::fs::create_dir_all(dir).map_err(|e| {
+        Error::new("key_dir::create", "Failed to create key directory")
+            .file(dir)
+            .cause(e)
+            .report()
+    })?;
+
+    let mut failed = false;
+    for (id, keys) in fetched {
+        match keys {
+            Some(keys) => failed |= write_key_file(dir, users.local_user(id), keys).is_err(),
+            None => {
+                debug!("Keeping the previous key file of account {}", id);
+                failed = true;
+            }
+        }
+    }
+
+    if !prune || failed {
+        debug!("Skipping removal of stale key files as not every account resolved");
+        return Ok(());
+    }
+
+    let names: Vec<&str> = fetched.iter().map(|(id, _)| users.local_user(id)).collect();
+    let entries = std::fs::read_dir(dir).map_err(|e| {
+        Error::new("key_dir::read", "Failed to read key directory")
+            .file(dir)
+            .cause(e)
+            .report()
+    })?;
+    for entry in entries.flatten() {
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+        if file_name.starts_with('.') || names.contains(&file_name) {
+            continue;
+        }
+
+        debug!("Removing stale key file -- {:?}", entry.path());
+        std::fs::remove_file(entry.path()).map_err(|e| {
+            Error::new("key_dir::remove", "Failed to remove stale key file")
+                .file(entry.path())
+                .cause(e)
+                .report()
+        })?;
+    }
+
+    Ok(())
+}
+
+pub async fn export(
+    client: &KanidmClient,
+    args: &crate::Cli,
+    export: &ExportArgs,
+) -> Result<(), ()> {
+    let signing_key = export
+        .signing_key
+        .as_ref()
+        .or(args.bundle_signing_key.as_ref());
+    if export.bundle.is_some() && signing_key.is_none() {
+        Error::new("bundle::signing_key", "No key to sign the bundle with")
+            .help("pass --signing-key <FILE> or set bundle_signing_key in the config")
+            .report();
+        return Err(());
+    }
+
+    // A group that couldn't be resolved leaves its members out, reported by the resolution
+    let (account_ids, complete) = crate::source::resolve_account_ids(client, args).await;
+    let mut accounts = Vec::new();
+    let mut failed = !complete;
+    for id in &account_ids {
+        match client.idm_account_get_ssh_pubkeys(id).await {
+            Ok(keys) => {
+                let keys: Vec<String> = keys.iter().map(|k| normalize_key(k)).collect();
+                accounts.push((id.clone(), keys));
+            }
+            Err(e) => {
+                Error::new("fetch::account", "Failed to get ssh pubkeys")
+                    .account(id)
+                    .maybe_help(crate::auth_hint(&e, args.token.is_some()))
+                    .cause(e)
+                    .report();
+                failed = true;
+            }
+        }
+    }
+
+    // A bundle missing accounts would remove their keys wherever it is imported
+    if let (Some(bundle), Some(signing_key)) = (&export.bundle, signing_key) {
+        if failed {
+            return Err(());
+        }
+        let bundle_content = crate::bundle::Bundle {
+            created: time::OffsetDateTime::now_utc().unix_timestamp(),
+            server: Some(client.get_url().to_string()),
+            accounts,
+        };
+        crate::bundle::write(bundle, signing_key, &bundle_content)?;
+        info!(
+            "Exported the keys of {} accounts to {:?}",
+            bundle_content.accounts.len(),
+            bundle
+        );
+        return Ok(());
+    }
+
+    std::fs::create_dir_all(&export.output).map_err(|e| {
+        Error::new("export::create_dir", "Failed to create output directory")
+            .file(&export.output)
+            .cause(e)
+            .report()
+    })?;
+    for (id, keys) in &accounts {
+        if write_key_file(&export.output, id, keys).is_err() {
+            failed = true;
+        }
+    }
+
+    if failed {
+        return Err(());
+    }
+
+    info!("Exported keys to {:?}", export.output);
+    Ok(())
+}
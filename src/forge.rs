@@ -0,0 +1,88 @@
+//! Keys published on code forges, for people who don't have a kanidm account yet
+
+use std::time::Duration;
+
+use tracing::debug;
+
+use crate::diagnostic::Error;
+
+/// Where GitHub publishes the keys of its users
+pub const GITHUB_URL: &str = "https://github.com";
+
+/// The GitLab instance used if `source.gitlab_url` is not configured
+pub const DEFAULT_GITLAB_URL: &str = "https://gitlab.com";
+
+/// How long a forge may take to answer
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+pub fn client() -> Result<reqwest::Client, ()> {
+    reqwest::Client::builder()
+        .timeout(TIMEOUT)
+        .user_agent(concat!(
+            env!("CARGO_PKG_NAME"),
+            "/",
+            env!("CARGO_PKG_VERSION")
+        ))
+        .build()
+        .map_err(|e| {
+            Error::new("forge::client", "Failed to build the forge client")
+                .cause(e)
+                .report()
+        })
+}
+
+/// Where the configured GitLab instance publishes the keys of its users
+pub fn gitlab_users_url(args: &crate::Cli) -> String {
+    let url = args
+        .source
+        .gitlab_url
+        .as_deref()
+        .unwrap_or(DEFAULT_GITLAB_URL);
+    format!("{}/users", url.trim_end_matches('/'))
+}
+
+/// Whether a forge user name is safe to put into a URL
+fn is_valid_user(user: &str) -> bool {
+    !user.is_empty()
+        && !user.starts_with('.')
+        && user
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// The keys `user` published on the forge at `url`, i.e. `<url>/<user>.keys`
+pub async fn keys(client: &reqwest::Client, url: &str, user: &str) -> Result<Vec<String>, ()> {
+    if !is_valid_user(user) {
+        Error::new(
+            "forge::unsafe_user",
+            "Refusing to fetch keys of unsafe forge user name",
+        )
+        .with("user", format!("{:?}", user))
+        .report();
+        return Err(());
+    }
+
+    let url = format!("{}/{user}.keys", url.trim_end_matches('/'));
+    debug!("Fetching forge keys -- {url}");
+    let content = client
+        .get(&url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| {
+            Error::new("forge::fetch", "Failed to fetch forge keys")
+                .url(&url)
+                .cause(e)
+                .report()
+        })?
+        .text()
+        .await
+        .map_err(|e| {
+            Error::new("forge::fetch", "Failed to read forge keys")
+                .url(&url)
+                .cause(e)
+                .report()
+        })?;
+
+    Ok(crate::source::key_lines(&content).collect())
+}
@@ -0,0 +1,164 @@
+//! Whether the daemon is keeping the keys in sync, for a container `HEALTHCHECK`
+//!
+//! After every sync the daemon records when it ran and whether it succeeded in the state
+//! directory. `health` only reads that record, so it answers in milliseconds without talking
+//! to the server.
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::Cli;
+use crate::diagnostic::Error;
+
+const STATUS_FILE: &str = "last_sync.json";
+
+#[derive(Debug, Clone, Args)]
+pub struct HealthArgs {
+    /// Fail if the last successful sync is older than this many seconds, defaults to twice the
+    /// interval plus the splay
+    #[arg(long)]
+    max_age: Option<u64>,
+}
+
+/// What the daemon records after every sync
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LastSync {
+    /// When the last sync finished, in seconds since the epoch
+    pub finished: u64,
+    /// Whether the last sync succeeded
+    pub success: bool,
+    /// When the last successful sync finished, if any did
+    pub last_success: Option<u64>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+impl LastSync {
+    /// The record after a sync that finished at `now`
+    fn after(self, success: bool, now: u64) -> LastSync {
+        LastSync {
+            finished: now,
+            success,
+            last_success: if success {
+                Some(now)
+            } else {
+                self.last_success
+            },
+        }
+    }
+
+    /// Why the record is unhealthy at `now`, if it is
+    fn problem(&self, max_age: u64, now: u64) -> Option<String> {
+        let Some(last_success) = self.last_success else {
+            return Some("no sync has succeeded yet".to_string());
+        };
+        let age = now.saturating_sub(last_success);
+        (age > max_age)
+            .then(|| format!("the last successful sync was {age}s ago, more than {max_age}s"))
+    }
+}
+
+fn read(dir: &Path) -> Result<Option<LastSync>, ()> {
+    let path = dir.join(STATUS_FILE);
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            Error::new("health::read", "Failed to read the sync status")
+                .file(&path)
+                .cause(e)
+                .report();
+            return Err(());
+        }
+    };
+    serde_json::from_str(&content).map(Some).map_err(|e| {
+        Error::new("health::read", "Failed to parse the sync status")
+            .file(&path)
+            .cause(e)
+            .report()
+    })
+}
+
+/// Record the outcome of a sync in the state directory
+///
+/// A record that can't be written is only logged, the keys were synced all the same.
+pub fn record(args: &Cli, success: bool) {
+    let dir = crate::state::state_dir(args);
+    let previous = read(&dir).ok().flatten().unwrap_or_default();
+    let status = previous.after(success, now());
+
+    let path = dir.join(STATUS_FILE);
+    let tmp_path = dir.join(format!(".{STATUS_FILE}.tmp"));
+    let written = serde_json::to_string(&status)
+        .map_err(std::io::Error::other)
+        .and_then(|content| {
+            std::fs::create_dir_all(&dir)?;
+            std::fs::write(&tmp_path, content)?;
+            std::fs::rename(&tmp_path, &path)
+        });
+    match written {
+        Ok(()) => debug!("Recorded the sync status in {}", path.display()),
+        Err(e) => Error::new("health::write", "Failed to record the sync status")
+            .file(&path)
+            .cause(e)
+            .warn(),
+    }
+}
+
+/// Exit successfully if the daemon synced recently enough
+pub fn health(args: &Cli, health: &HealthArgs) -> Result<(), ()> {
+    let max_age = health.max_age.unwrap_or_else(|| {
+        2 * args.interval.unwrap_or(crate::daemon::DEFAULT_INTERVAL) + args.splay.unwrap_or(0)
+    });
+
+    let dir = crate::state::state_dir(args);
+    let problem = match read(&dir)? {
+        Some(status) => status.problem(max_age, now()),
+        None => Some("no sync was recorded yet".to_string()),
+    };
+    match problem {
+        Some(problem) => {
+            Error::new("health::unhealthy", format!("Unhealthy, {problem}"))
+                .file(dir.join(STATUS_FILE))
+                .report();
+            Err(())
+        }
+        None => {
+            println!("Healthy");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tolerates_failures_until_max_age() {
+        let status = LastSync::default().after(true, 1000);
+        assert_eq!(status.problem(600, 1300), None);
+
+        let status = status.after(false, 1300);
+        assert!(!status.success);
+        assert_eq!(status.problem(600, 1300), None);
+        assert_eq!(
+            status.problem(600, 1700),
+            Some("the last successful sync was 700s ago, more than 600s".to_string())
+        );
+
+        let status = LastSync::default().after(false, 1000);
+        assert_eq!(
+            status.problem(600, 1000),
+            Some("no sync has succeeded yet".to_string())
+        );
+    }
+}
@@ -0,0 +1,239 @@
+//! The write helper, writing authorized_keys on behalf of an unprivileged fetcher
+//!
+//! A copy of the binary installed with `cap_chown,cap_dac_override,cap_fowner` or setuid root
+//! is invoked as `write-helper` by the fetcher, which sends the fetched keys as JSON on stdin.
+//! The network facing code then never runs privileged, even when writing for many users.
+//!
+//! As the caller is not trusted, the helper reads no configuration, resolves the target only
+//! through the passwd database and refuses to write for root. It writes through the opened
+//! `.ssh` directory, which must be a real directory of the user, never follows a symlink and
+//! always enforces the default modes. A caller may only write their own authorized_keys, unless
+//! they are a member of the group named in [`HELPER_GROUP_FILE`], a file only root may write.
+
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use clap::{Parser, ValueEnum};
+use nix::unistd::{Gid, Group, Uid};
+use tracing::debug;
+
+use crate::diagnostic::Error;
+use crate::source::Fetched;
+use crate::{Cli, SymlinkPolicy};
+
+/// Where the helper keeps its state, independent of the caller's environment
+const HELPER_STATE_DIR: &str = "/var/lib/kanidm_sshkey_fetcher";
+
+/// The file naming the group whose members may write the authorized_keys of any user
+const HELPER_GROUP_FILE: &str = "/etc/kanidm_sshkey_fetcher/write-helper-group";
+
+/// The real ids of whoever ran the helper, which a setuid binary can't be fooled about
+struct Caller {
+    uid: Uid,
+    gids: Vec<Gid>,
+}
+
+impl Caller {
+    fn current() -> Caller {
+        let mut gids = nix::unistd::getgroups().unwrap_or_default();
+        gids.push(nix::unistd::getgid());
+        Caller {
+            uid: nix::unistd::getuid(),
+            gids,
+        }
+    }
+
+    /// Whether the caller may write the authorized_keys of the user `target`, members of
+    /// `trusted` writing those of anyone
+    fn may_write(&self, target: Uid, trusted: Option<Gid>) -> bool {
+        self.uid.is_root()
+            || self.uid == target
+            || trusted.is_some_and(|gid| self.gids.contains(&gid))
+    }
+}
+
+/// The group named in [`HELPER_GROUP_FILE`], if the file exists and only root can change it
+fn trusted_group() -> Option<Gid> {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = Path::new(HELPER_GROUP_FILE);
+    let metadata = std::fs::symlink_metadata(path).ok()?;
+    if metadata.uid() != 0 || metadata.mode() & 0o022 != 0 || !metadata.is_file() {
+        Error::new(
+            "helper::group_file",
+            "Ignoring the write helper group file, it is not owned and only writable by root",
+        )
+        .file(path)
+        .warn();
+        return None;
+    }
+    let name = std::fs::read_to_string(path).ok()?;
+    match Group::from_name(name.trim()) {
+        Ok(Some(group)) => Some(group.gid),
+        _ => {
+            Error::new(
+                "helper::group_file",
+                "The write helper group does not exist",
+            )
+            .file(path)
+            .with("group", name.trim())
+            .warn();
+            None
+        }
+    }
+}
+
+/// Build the options the helper writes with from the few the caller may choose
+fn sanitize(args: &Cli) -> Result<Cli, ()> {
+    let name = args
+        .user
+        .as_ref()
+        .ok_or_else(|| Error::new("helper::args", "The write helper requires --user").report())?;
+    let user = crate::user::lookup(name)?;
+    if user.uid.is_root() {
+        Error::new(
+            "helper::root",
+            "The write helper refuses to write the authorized_keys of root",
+        )
+        .report();
+        return Err(());
+    }
+    let caller = Caller::current();
+    if !caller.may_write(user.uid, trusted_group()) {
+        Error::new(
+            "helper::unauthorized",
+            "The caller may not write the authorized_keys of this user",
+        )
+        .with("user", name)
+        .with("caller", caller.uid)
+        .help(format!(
+            "only the user themselves, root and members of the group named in {HELPER_GROUP_FILE} may"
+        ))
+        .report();
+        return Err(());
+    }
+
+    let mut helper_args = Cli::parse_from([env!("CARGO_PKG_NAME")]);
+    helper_args.modify = true;
+    helper_args.user = Some(name.clone());
+    helper_args.symlinks = Some(SymlinkPolicy::Refuse);
+    helper_args.state_dir = Some(PathBuf::from(HELPER_STATE_DIR));
+    helper_args.on_tamper = args.on_tamper;
+    helper_args.errors = args.errors;
+    helper_args.json = args.json;
+    helper_args.keep_backups = args.keep_backups;
+
+    Ok(helper_args)
+}
+
+/// Read the request of the fetcher, returning the options to write with and the keys
+pub fn request(args: &Cli) -> Result<(Cli, Fetched), ()> {
+    let helper_args = sanitize(args)?;
+
+    let mut content = Vec::new();
+    std::io::stdin().read_to_end(&mut content).map_err(|e| {
+        Error::new("helper::read", "Failed to read the fetched keys")
+            .cause(e)
+            .report()
+    })?;
+    let fetched = serde_json::from_slice(&content).map_err(|e| {
+        Error::new("helper::read", "Failed to parse the fetched keys")
+            .cause(e)
+            .report()
+    })?;
+
+    Ok((helper_args, fetched))
+}
+
+/// Hand the fetched keys to the write helper at `helper`
+pub fn invoke(helper: &Path, args: &Cli, fetched: &Fetched) -> Result<(), ()> {
+    let user = args
+        .user
+        .as_ref()
+        .ok_or_else(|| Error::new("args::conflict", "--write-helper requires --user").report())?;
+
+    let mut command = Command::new(helper);
+    command.arg("--user").arg(user);
+    if let Some(on_tamper) = args.on_tamper.and_then(|p| p.to_possible_value()) {
+        command.arg("--on-tamper").arg(on_tamper.get_name());
+    }
+    if args.json {
+        command.arg("--json");
+    }
+    if let Some(errors) = args.errors.and_then(|f| f.to_possible_value()) {
+        command.arg("--errors").arg(errors.get_name());
+    }
+    if let Some(keep_backups) = args.keep_backups {
+        command.arg("--keep-backups").arg(keep_backups.to_string());
+    }
+    command.arg("write-helper").stdin(Stdio::piped());
+
+    let content = serde_json::to_vec(fetched).map_err(|e| {
+        Error::new("helper::send", "Failed to encode the fetched keys")
+            .cause(e)
+            .report()
+    })?;
+
+    debug!("Invoking the write helper -- {command:?}");
+    let mut child = command.spawn().map_err(|e| {
+        Error::new("helper::run", "Failed to run the write helper")
+            .file(helper)
+            .cause(e)
+            .report()
+    })?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(&content).map_err(|e| {
+            Error::new(
+                "helper::send",
+                "Failed to send the fetched keys to the write helper",
+            )
+            .file(helper)
+            .cause(e)
+            .report()
+        })?;
+    }
+    let status = child.wait().map_err(|e| {
+        Error::new("helper::run", "Failed to wait for the write helper")
+            .file(helper)
+            .cause(e)
+            .report()
+    })?;
+    if !status.success() {
+        Error::new("helper::failed", "The write helper failed")
+            .file(helper)
+            .with("status", status)
+            .report();
+        return Err(());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_callers_writing_for_others() {
+        let (alice, bob) = (Uid::from_raw(1000), Uid::from_raw(1001));
+        let fetchers = Gid::from_raw(900);
+        let caller = Caller {
+            uid: bob,
+            gids: vec![Gid::from_raw(1001)],
+        };
+        assert!(!caller.may_write(alice, None));
+        assert!(!caller.may_write(alice, Some(fetchers)));
+        assert!(
+            caller.may_write(bob, None),
+            "a caller may write their own file"
+        );
+
+        let fetcher = Caller {
+            uid: Uid::from_raw(999),
+            gids: vec![fetchers],
+        };
+        assert!(fetcher.may_write(alice, Some(fetchers)));
+        assert!(!fetcher.may_write(alice, None));
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use clap::{Args, Subcommand};
+use kanidm_client::{ClientError, KanidmClient};
+use kanidm_proto::constants::{ATTR_NAME, ATTR_SPN, ATTR_SSH_PUBLICKEY, ATTR_UUID};
+use ssh_key::{HashAlg, Mpint, PublicKey, public::KeyData};
+use tracing::info;
+
+use crate::diagnostic::Error;
+
+#[derive(Debug, Clone, Args)]
+pub struct KeysArgs {
+    #[command(subcommand)]
+    action: KeysAction,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum KeysAction {
+    /// List the tagged ssh keys stored for an account
+    List {
+        /// The account id to list keys for
+        account_id: String,
+    },
+    /// Add an ssh public key to an account under a tag
+    Add {
+        /// The account id to add the key to
+        account_id: String,
+
+        /// The tag to store the key under
+        tag: String,
+
+        /// The public key, e.g. `ssh-ed25519 AAAA... comment`
+        #[arg(required_unless_present = "file", conflicts_with = "file")]
+        key: Option<String>,
+
+        /// Read the public key from a file instead, e.g. ~/.ssh/id_ed25519.pub
+        #[arg(short, long, value_parser)]
+        file: Option<PathBuf>,
+    },
+    /// Remove the ssh key stored under a tag from an account
+    Remove {
+        /// The account id to remove the key from
+        account_id: String,
+
+        /// The tag of the key to remove
+        tag: String,
+    },
+}
+
+/// Split a `tag: key` value of the `ssh_publickey` attribute into its tag and key
+pub fn parse_tagged_key(value: &str) -> (&str, &str) {
+    match value.split_once(": ") {
+        Some((tag, key)) => (tag, key),
+        None => ("", value),
+    }
+}
+
+/// A key in the canonical `algorithm base64 comment` form, whatever whitespace it was pasted with
+///
+/// Runs of whitespace become one space, and a base64 blob that was wrapped over several lines is
+/// joined again. Text that isn't a public key is returned with its whitespace collapsed.
+pub fn normalize_key(key: &str) -> String {
+    let fields: Vec<&str> = key.split_whitespace().collect();
+    for end in 2..=fields.len() {
+        let blob = fields[1..end].concat();
+        if let Ok(mut public_key) = PublicKey::from_openssh(&format!("{} {}", fields[0], blob)) {
+            public_key.set_comment(fields[end..].join(" "));
+            if let Ok(normalized) = public_key.to_openssh() {
+                return normalized;
+            }
+        }
+    }
+    fields.join(" ")
+}
+
+/// The properties of a public key that are interesting when inspecting it
+pub struct KeyInfo {
+    pub algorithm: String,
+    pub bits: Option<usize>,
+    pub fingerprint: String,
+    pub comment: String,
+}
+
+fn mpint_bits(mpint: &Mpint) -> Option<usize> {
+    let bytes = mpint.as_positive_bytes()?;
+    let first = bytes.first()?;
+    Some(bytes.len() * 8 - first.leading_zeros() as usize)
+}
+
+/// Parse an openssh formatted public key and describe it
+pub fn describe_key(key: &str) -> Result<KeyInfo, ssh_key::Error> {
+    let public_key = PublicKey::from_openssh(key)?;
+
+    let bits = match public_key.key_data() {
+        KeyData::Dsa(dsa) => mpint_bits(&dsa.p),
+        KeyData::Ecdsa(ecdsa) => Some(match ecdsa.curve() {
+            ssh_key::EcdsaCurve::NistP256 => 256,
+            ssh_key::EcdsaCurve::NistP384 => 384,
+            ssh_key::EcdsaCurve::NistP521 => 521,
+        }),
+        KeyData::Ed25519(_) | KeyData::SkEd25519(_) => Some(256),
+        KeyData::Rsa(rsa) => mpint_bits(&rsa.n),
+        KeyData::SkEcdsaSha2NistP256(_) => Some(256),
+        _ => None,
+    };
+
+    Ok(KeyInfo {
+        algorithm: public_key.algorithm().to_string(),
+        bits,
+        fingerprint: public_key.fingerprint(HashAlg::Sha256).to_string(),
+        comment: public_key.comment().to_string(),
+    })
+}
+
+/// Fetch the `(tag, key)` pairs stored for an account
+pub async fn get_tagged_keys(
+    client: &KanidmClient,
+    account_id: &str,
+) -> Result<Vec<(String, String)>, ()> {
+    let values = client
+        .idm_person_account_get_attr(account_id, ATTR_SSH_PUBLICKEY)
+        .await
+        .map_err(|e| {
+            Error::new("fetch::account", "Failed to get ssh keys")
+                .account(account_id)
+                .cause(e)
+                .report()
+        })?
+        .unwrap_or_default();
+
+    Ok(values
+        .iter()
+        .map(|value| {
+            let (tag, key) = parse_tagged_key(value);
+            (tag.to_string(), key.to_string())
+        })
+        .collect())
+}
+
+/// Fetch the keys of every person in one request, by name, spn and uuid
+///
+/// Persons whose `ssh_publickey` attribute is not visible are left out, so they are fetched
+/// individually instead of being mistaken for having no keys.
+pub async fn get_all_person_keys(
+    client: &KanidmClient,
+) -> Result<HashMap<String, Vec<String>>, ClientError> {
+    let mut all_keys = HashMap::new();
+    for entry in client.idm_person_account_list().await? {
+        let Some(values) = entry.attrs.get(ATTR_SSH_PUBLICKEY) else {
+            continue;
+        };
+        let keys: Vec<String> = values
+            .iter()
+            .map(|value| parse_tagged_key(value).1.to_string())
+            .collect();
+
+        for attr in [ATTR_NAME, ATTR_SPN, ATTR_UUID] {
+            for id in entry.attrs.get(attr).into_iter().flatten() {
+                all_keys.insert(id.clone(), keys.clone());
+            }
+        }
+    }
+
+    Ok(all_keys)
+}
+
+pub async fn keys(client: &KanidmClient, args: &KeysArgs) -> Result<(), ()> {
+    match &args.action {
+        KeysAction::List { account_id } => {
+            for (tag, key) in get_tagged_keys(client, account_id).await? {
+                println!("{}: {}", tag, key);
+            }
+        }
+        KeysAction::Add {
+            account_id,
+            tag,
+            key,
+            file,
+        } => {
+            let key = match (key, file) {
+                (Some(key), _) => key.clone(),
+                (None, Some(file)) => std::fs::read_to_string(file).map_err(|e| {
+                    Error::new("keys::read", "Failed to read public key file")
+                        .file(file)
+                        .cause(e)
+                        .report()
+                })?,
+                (None, None) => unreachable!("clap requires either a key or a file"),
+            };
+
+            client
+                .idm_person_account_post_ssh_pubkey(account_id, tag, key.trim())
+                .await
+                .map_err(|e| {
+                    Error::new("keys::add", "Failed to add key")
+                        .with("tag", tag)
+                        .account(account_id)
+                        .cause(e)
+                        .report()
+                })?;
+            info!("Added key {} to account {}", tag, account_id);
+        }
+        KeysAction::Remove { account_id, tag } => {
+            client
+                .idm_person_account_delete_ssh_pubkey(account_id, tag)
+                .await
+                .map_err(|e| {
+                    Error::new("keys::remove", "Failed to remove key")
+                        .with("tag", tag)
+                        .account(account_id)
+                        .cause(e)
+                        .report()
+                })?;
+            info!("Removed key {} from account {}", tag, account_id);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_pasted_keys() {
+        const KEY: &str =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKhtiB5ZKct7eqPkIZBIkubYHjEVoOhM7ufiAisize+o";
+        let normalized = format!("{KEY} alice laptop");
+
+        assert_eq!(
+            normalize_key(&format!("  {KEY}   alice\tlaptop \n")),
+            normalized
+        );
+        assert_eq!(
+            normalize_key(&format!("{}\n{} alice laptop", &KEY[..40], &KEY[40..])),
+            normalized
+        );
+        assert_eq!(normalize_key(KEY), KEY);
+        assert_eq!(normalize_key(" not  a key "), "not a key");
+    }
+}
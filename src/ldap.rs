@@ -0,0 +1,238 @@
+//! Reading keys over kanidm's LDAP interface
+//!
+//! Some edge hosts may only reach kanidm over LDAPS, this source reads `sshPublicKey` from there
+//! instead of the HTTPS API.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use ldap3::{Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry, ldap_escape};
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::pem::PemObject;
+use tokio::sync::OnceCell;
+use tracing::debug;
+
+use crate::Cli;
+use crate::diagnostic::Error;
+use crate::source::{KeySource, SourceError};
+
+const ATTR_SSH_PUBLICKEY: &str = "sshpublickey";
+const ATTR_MEMBER: &str = "member";
+const ATTR_UID_NUMBER: &str = "uidnumber";
+const ID_ATTRS: [&str; 3] = ["name", "spn", "uuid"];
+
+/// The port an LDAP URL points to, explicit or the scheme's default
+pub fn port(url: &str) -> Option<u16> {
+    let (scheme, rest) = url.split_once("://")?;
+    let host = rest.split('/').next()?;
+    match host.rsplit_once(':') {
+        Some((_, port)) if !port.ends_with(']') => port.parse().ok(),
+        _ => match scheme {
+            "ldaps" => Some(636),
+            "ldap" => Some(389),
+            _ => None,
+        },
+    }
+}
+
+/// A connection to kanidm's LDAP interface, opened on first use
+pub struct LdapSource {
+    url: String,
+    base_dn: Option<String>,
+    token: Option<String>,
+    settings: LdapConnSettings,
+    conn: OnceCell<(Ldap, String)>,
+}
+
+fn ldap_error(e: ldap3::LdapError) -> SourceError {
+    SourceError::Other(e.to_string())
+}
+
+/// The values of an attribute, whatever case the server spells it in
+fn values<'a>(entry: &'a SearchEntry, attr: &str) -> impl Iterator<Item = &'a String> {
+    entry
+        .attrs
+        .iter()
+        .filter(move |(name, _)| name.eq_ignore_ascii_case(attr))
+        .flat_map(|(_, values)| values)
+}
+
+fn entry_keys(entry: &SearchEntry) -> Vec<String> {
+    values(entry, ATTR_SSH_PUBLICKEY)
+        .map(|value| crate::keys::parse_tagged_key(value).1.to_string())
+        .collect()
+}
+
+/// The filter matching an entry of `class` by any of its ids
+fn id_filter(class: &str, id: &str) -> String {
+    let id = ldap_escape(id);
+    let ids: String = ID_ATTRS
+        .iter()
+        .map(|attr| format!("({attr}={id})"))
+        .collect();
+    format!("(&(objectclass={class})(|{ids}))")
+}
+
+impl LdapSource {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The LDAP source configured with `--ldap-url`, if any
+    pub fn from_args(args: &Cli) -> Result<Option<LdapSource>, ()> {
+        let Some(url) = &args.ldap_url else {
+            return Ok(None);
+        };
+
+        let mut settings = LdapConnSettings::new();
+        if let Some(ca_path) = &args.ca_path {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(ca_path).map_err(|e| {
+                Error::new("ldap::ca", "Failed to read ca certificate")
+                    .file(ca_path)
+                    .cause(e)
+                    .report()
+            })? {
+                let cert = cert.map_err(|e| {
+                    Error::new("ldap::ca", "Failed to parse ca certificate")
+                        .file(ca_path)
+                        .cause(e)
+                        .report()
+                })?;
+                roots.add(cert).map_err(|e| {
+                    Error::new("ldap::ca", "Failed to add ca certificate")
+                        .file(ca_path)
+                        .cause(e)
+                        .report()
+                })?;
+            }
+            let config = rustls::ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            settings = settings.set_config(Arc::new(config));
+        }
+
+        Ok(Some(LdapSource {
+            url: url.clone(),
+            base_dn: args.ldap_base_dn.clone(),
+            token: args.token.clone(),
+            settings,
+            conn: OnceCell::new(),
+        }))
+    }
+
+    /// The connection and the base DN to search below
+    async fn connect(&self) -> Result<(Ldap, String), SourceError> {
+        let (ldap, base_dn) = self
+            .conn
+            .get_or_try_init(|| async {
+                debug!("Connecting to {}", self.url);
+                let (conn, mut ldap) =
+                    LdapConnAsync::with_settings(self.settings.clone(), &self.url)
+                        .await
+                        .map_err(ldap_error)?;
+                ldap3::drive!(conn);
+
+                // kanidm accepts API tokens as the password of the `dn=token` bind
+                if let Some(token) = &self.token {
+                    ldap.simple_bind("dn=token", token)
+                        .await
+                        .and_then(|r| r.success())
+                        .map_err(ldap_error)?;
+                }
+
+                let base_dn = match &self.base_dn {
+                    Some(base_dn) => base_dn.clone(),
+                    None => naming_context(&mut ldap).await?,
+                };
+                debug!("Searching LDAP below {}", base_dn);
+                Ok::<_, SourceError>((ldap, base_dn))
+            })
+            .await?;
+
+        Ok((ldap.clone(), base_dn.clone()))
+    }
+
+    async fn search(&self, filter: &str, attrs: &[&str]) -> Result<Vec<SearchEntry>, SourceError> {
+        let (mut ldap, base_dn) = self.connect().await?;
+        let (entries, _) = ldap
+            .search(&base_dn, Scope::Subtree, filter, attrs)
+            .await
+            .and_then(|r| r.success())
+            .map_err(ldap_error)?;
+        Ok(entries.into_iter().map(SearchEntry::construct).collect())
+    }
+}
+
+/// The base DN advertised in the root DSE
+async fn naming_context(ldap: &mut Ldap) -> Result<String, SourceError> {
+    let (entries, _) = ldap
+        .search("", Scope::Base, "(objectclass=*)", ["namingcontexts"])
+        .await
+        .and_then(|r| r.success())
+        .map_err(ldap_error)?;
+
+    entries
+        .into_iter()
+        .map(SearchEntry::construct)
+        .find_map(|entry| values(&entry, "namingcontexts").next().cloned())
+        .ok_or_else(|| SourceError::Other("the server advertises no naming context".to_string()))
+}
+
+impl KeySource for LdapSource {
+    async fn account_keys(&self, account_id: &str) -> Result<Vec<String>, SourceError> {
+        let entries = self
+            .search(&id_filter("account", account_id), &[ATTR_SSH_PUBLICKEY])
+            .await?;
+        entries.first().map(entry_keys).ok_or(SourceError::NotFound)
+    }
+
+    async fn all_account_keys(&self) -> Result<HashMap<String, Vec<String>>, SourceError> {
+        let filter = format!("(&(objectclass=person)({ATTR_SSH_PUBLICKEY}=*))");
+        let mut attrs = ID_ATTRS.to_vec();
+        attrs.push(ATTR_SSH_PUBLICKEY);
+
+        let mut all_keys = HashMap::new();
+        for entry in self.search(&filter, &attrs).await? {
+            let keys = entry_keys(&entry);
+            for attr in ID_ATTRS {
+                for id in values(&entry, attr) {
+                    all_keys.insert(id.clone(), keys.clone());
+                }
+            }
+        }
+
+        Ok(all_keys)
+    }
+
+    async fn group_members(&self, group: &str) -> Result<Option<Vec<String>>, SourceError> {
+        let entries = self
+            .search(&id_filter("group", group), &[ATTR_MEMBER])
+            .await?;
+        let entry = entries.first().ok_or(SourceError::NotFound)?;
+
+        // Members are DNs like `spn=alice@idm.example.com,dc=idm,dc=example,dc=com`
+        let members: Vec<String> = values(entry, ATTR_MEMBER)
+            .filter_map(|dn| dn.split(',').next()?.split_once('='))
+            .map(|(_, id)| id.to_string())
+            .collect();
+
+        Ok((!members.is_empty()).then_some(members))
+    }
+
+    async fn posix_id(&self, account_id: &str) -> Result<Option<u32>, SourceError> {
+        let entries = self
+            .search(&id_filter("account", account_id), &[ATTR_UID_NUMBER])
+            .await?;
+        let entry = entries.first().ok_or(SourceError::NotFound)?;
+        Ok(values(entry, ATTR_UID_NUMBER).find_map(|gid| gid.parse().ok()))
+    }
+
+    async fn tagged_keys(&self, account_id: &str) -> Result<Vec<(String, String)>, SourceError> {
+        let entries = self
+            .search(&id_filter("account", account_id), &[ATTR_SSH_PUBLICKEY])
+            .await?;
+        let entry = entries.first().ok_or(SourceError::NotFound)?;
+        Ok(crate::source::tagged(values(entry, ATTR_SSH_PUBLICKEY)))
+    }
+}
@@ -0,0 +1,111 @@
+use kanidm_client::{ClientError, KanidmClient};
+use kanidm_proto::constants::{ATTR_LAST_MODIFIED_CID, ATTR_SSH_PUBLICKEY};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use tracing::debug;
+
+use crate::keys::parse_tagged_key;
+use crate::table::print_table;
+
+struct Row {
+    account_id: String,
+    keys: String,
+    tags: String,
+    last_modified: String,
+    status: String,
+}
+
+/// When a `last_modified_cid` value was recorded
+///
+/// A cid is formatted as `<nanoseconds since epoch>-<server uuid>`.
+pub fn cid_time(cid: &str) -> Option<OffsetDateTime> {
+    cid.split_once('-')
+        .and_then(|(ts, _)| ts.parse::<i128>().ok())
+        .and_then(|ts| OffsetDateTime::from_unix_timestamp_nanos(ts).ok())
+}
+
+/// Render a `last_modified_cid` value as a timestamp, the raw value if it cannot be parsed
+fn format_cid(cid: &str) -> String {
+    cid_time(cid)
+        .and_then(|ts| ts.format(&Rfc3339).ok())
+        .unwrap_or_else(|| cid.to_string())
+}
+
+/// A short description of a fetch failure that fits in a table cell
+fn format_error(e: &ClientError) -> String {
+    match e {
+        ClientError::Transport(_) => "error: unreachable".to_string(),
+        ClientError::Http(status, _, _) => format!("error: http {}", status.as_u16()),
+        ClientError::Unauthorized | ClientError::SessionExpired => {
+            "error: unauthorized".to_string()
+        }
+        _ => "error".to_string(),
+    }
+}
+
+async fn fetch_row(client: &KanidmClient, account_id: &str) -> Row {
+    let mut row = Row {
+        account_id: account_id.to_string(),
+        keys: "-".to_string(),
+        tags: "-".to_string(),
+        last_modified: "-".to_string(),
+        status: "ok".to_string(),
+    };
+
+    match client.idm_person_account_get(account_id).await {
+        Ok(Some(entry)) => {
+            let values = entry
+                .attrs
+                .get(ATTR_SSH_PUBLICKEY)
+                .cloned()
+                .unwrap_or_default();
+            let tags: Vec<&str> = values.iter().map(|v| parse_tagged_key(v).0).collect();
+
+            row.keys = values.len().to_string();
+            if !tags.is_empty() {
+                row.tags = tags.join(",");
+            }
+            if let Some(cid) = entry
+                .attrs
+                .get(ATTR_LAST_MODIFIED_CID)
+                .and_then(|v| v.first())
+            {
+                row.last_modified = format_cid(cid);
+            }
+        }
+        Ok(None) => row.status = "not found".to_string(),
+        Err(e) => {
+            debug!("Failed to get account {} -- {:?}", account_id, e);
+            row.status = format_error(&e);
+        }
+    }
+
+    row
+}
+
+impl Row {
+    fn into_cells(self) -> Vec<String> {
+        vec![
+            self.account_id,
+            self.keys,
+            self.tags,
+            self.last_modified,
+            self.status,
+        ]
+    }
+}
+
+pub async fn list(client: &KanidmClient, args: &crate::Cli) -> Result<(), ()> {
+    let mut rows = vec![
+        ["ACCOUNT", "KEYS", "TAGS", "LAST MODIFIED", "STATUS"]
+            .map(String::from)
+            .to_vec(),
+    ];
+
+    for id in &crate::source::resolve_account_ids(client, args).await.0 {
+        rows.push(fetch_row(client, id).await.into_cells());
+    }
+
+    print_table(&rows, &[1]);
+
+    Ok(())
+}
@@ -1,14 +1,89 @@
+#![allow(clippy::result_unit_err)]
+
+use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::time::Instant;
 
-use clap::Parser;
-use kanidm_client::{ClientError, KanidmClient, KanidmClientBuilder};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use kanidm_client::{ClientError, KanidmClient, KanidmClientBuilder, StatusCode};
 use serde::{Deserialize, Serialize};
-use tracing::{debug, error};
+use tracing::debug;
+
+use crate::diagnostic::{ColorChoice, Error, ErrorFormat};
+use crate::metrics::StatsdFormat;
+
+mod audit;
+mod authorized_keys;
+mod backup;
+mod bundle;
+mod cache;
+mod compat;
+mod config;
+mod daemon;
+mod diagnostic;
+mod dir;
+mod doctor;
+mod exec;
+mod export;
+mod forge;
+mod health;
+#[cfg(unix)]
+mod helper;
+mod keys;
+mod ldap;
+mod list;
+mod metrics;
+#[cfg(all(test, feature = "mock-server"))]
+mod mock_server;
+mod ping;
+mod plan;
+#[cfg(unix)]
+mod privileges;
+mod report;
+mod rotate;
+#[cfg(target_os = "linux")]
+mod sandbox;
+mod search;
+#[cfg(target_os = "linux")]
+mod selinux;
+#[cfg(feature = "sentry")]
+mod sentry;
+mod session;
+mod show;
+mod source;
+mod state;
+mod status;
+mod summary;
+mod table;
+mod timings;
+mod unixd;
+#[cfg(unix)]
+mod user;
+mod usermap;
+mod version;
+#[cfg(windows)]
+mod windows;
 
 const SSH_CONFIG_DIR: &str = "~/.ssh";
 
-#[derive(Debug, Parser, Serialize, Deserialize)]
-#[command(version, about)]
+/// What to do when the managed block was edited by hand since it was last written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TamperPolicy {
+    /// Report the edit and overwrite the block with the fetched keys
+    #[default]
+    Repair,
+    /// Report the edit and leave the file untouched
+    Warn,
+}
+
+#[derive(Debug, Clone, Parser, Serialize, Deserialize)]
+#[command(
+    version,
+    about,
+    subcommand_precedence_over_arg = true,
+    disable_version_flag = true
+)]
 pub struct Cli {
     #[arg(short, long)]
     #[serde(default)]
@@ -22,6 +97,11 @@ pub struct Cli {
     #[arg(short = 'C', long = "ca", value_parser)]
     ca_path: Option<PathBuf>,
 
+    /// The account to log in as, accepted for compatibility with kanidm_ssh_authorizedkeys_direct
+    #[arg(short = 'D', long, hide = true)]
+    #[serde(skip)]
+    name: Option<String>,
+
     /// The configuration file to use
     #[arg(short = 'c', long = "config", value_parser)]
     config_path: Option<PathBuf>,
@@ -30,12 +110,462 @@ pub struct Cli {
     #[serde(default)]
     account_ids: Vec<String>,
 
+    /// The groups whose members' keys should be fetched, can be repeated
+    #[arg(short, long = "group")]
+    #[serde(default)]
+    groups: Vec<String>,
+
     /// Whether to modify the authorized_keys file
     ///
     /// If true, the program will try to update ~/.ssh/authorized_keys
     #[arg(short, long, default_value_t = false)]
     #[serde(default)]
     modify: bool,
+
+    /// Maintain a directory with one key file per account
+    ///
+    /// Each file is named after the account without its domain, or the user --user-map maps it
+    /// to, so that sshd can read it with `AuthorizedKeysCommand /bin/cat <dir>/%u`
+    #[arg(short = 'k', long, value_parser)]
+    key_dir: Option<PathBuf>,
+
+    /// A file of `account -> user` lines naming the key files of some accounts after another
+    /// local user
+    #[arg(long, value_parser)]
+    user_map: Option<PathBuf>,
+
+    /// Name the key files of accounts with a POSIX uid after the local user with that uid
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    map_by_uid: bool,
+
+    /// Also fetch the keys of the local users that exist in kanidm, with --modify writing each
+    /// to their own authorized_keys
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    local_users: bool,
+
+    /// The lowest uid of the users of --local-users, defaults to 1000
+    #[arg(long)]
+    local_uid_min: Option<u32>,
+
+    /// The highest uid of the users of --local-users, defaults to 60000
+    #[arg(long)]
+    local_uid_max: Option<u32>,
+
+    /// The SQLite database to cache fetched keys in, caching is disabled if unset
+    #[arg(long = "cache", value_parser)]
+    cache_path: Option<PathBuf>,
+
+    /// How many seconds cached keys are used before they are fetched again, defaults to 300
+    #[arg(long)]
+    cache_ttl: Option<u64>,
+
+    /// How many seconds an account that was not found is remembered for, defaults to 60
+    ///
+    /// Lookups of such accounts don't hit the server until then, 0 disables this
+    #[arg(long)]
+    negative_cache_ttl: Option<u64>,
+
+    /// What to return for an account when the server fails, defaults to closed
+    ///
+    /// Meant for AuthorizedKeysCommand, where open lets users log in with their cached keys
+    /// while the server is down, and closed refuses them until it is back
+    #[arg(long, value_enum)]
+    on_server_failure: Option<FailurePolicy>,
+
+    /// How many seconds old cached keys may be to be returned with --on-server-failure open,
+    /// defaults to 86400
+    #[arg(long)]
+    max_staleness: Option<u64>,
+
+    /// Keep the keys of each fetched account in a file named after it in this directory
+    ///
+    /// With --on-server-failure open, they are returned when neither the server nor the cache
+    /// can answer, as long as they are at most --max-staleness old
+    #[arg(long, value_parser)]
+    fallback_dir: Option<PathBuf>,
+
+    /// Fetch all persons in one request once this many accounts need fetching, defaults to 10
+    ///
+    /// 0 always fetches accounts one by one
+    #[arg(long)]
+    batch_threshold: Option<usize>,
+
+    /// Log the accounts that took longest to fetch, this many of them, at the end of the fetch
+    #[arg(long, value_name = "N")]
+    slowest_accounts: Option<usize>,
+
+    /// Encrypt the cache with a key generated next to it, <CACHE_PATH>.key
+    ///
+    /// The key only keeps the cache from other local users, not from whoever has the disk.
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    encrypt_cache: bool,
+
+    /// Encrypt the cache with a key derived from the contents of this file
+    #[arg(long, value_parser)]
+    cache_key_file: Option<PathBuf>,
+
+    /// Manage %ProgramData%\ssh\administrators_authorized_keys instead of the user's file
+    ///
+    /// Only has an effect on Windows, where sshd reads this file for members of the
+    /// Administrators group.
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    administrators: bool,
+
+    /// What to do when the managed block in authorized_keys was edited by hand
+    #[arg(long, value_enum)]
+    on_tamper: Option<TamperPolicy>,
+
+    /// Append every rewrite of a managed block, and every modification made to one by hand, to
+    /// this file as a JSON line
+    #[arg(long, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+
+    /// Manage the authorized_keys of this user instead of the current one
+    ///
+    /// The home directory is resolved from the passwd database
+    #[arg(long)]
+    user: Option<String>,
+
+    /// The home directory containing .ssh/authorized_keys, overriding $HOME and --user
+    ///
+    /// Useful in chroots, containers and image builds where neither reflects the final layout
+    #[arg(long, value_parser)]
+    home_dir: Option<PathBuf>,
+
+    /// What to do when authorized_keys is a symlink, defaults to follow
+    #[arg(long, value_enum)]
+    symlinks: Option<SymlinkPolicy>,
+
+    /// The octal mode to enforce on the directory of authorized_keys, defaults to 700
+    #[arg(long, value_parser = parse_mode)]
+    dir_mode: Option<u32>,
+
+    /// The octal mode to enforce on authorized_keys, defaults to 600
+    #[arg(long, value_parser = parse_mode)]
+    file_mode: Option<u32>,
+
+    /// How many backups of authorized_keys to keep, defaults to 10, 0 disables backups
+    #[arg(long)]
+    keep_backups: Option<usize>,
+
+    /// The directory to keep state between runs in, defaults to $STATE_DIRECTORY, then
+    /// $XDG_STATE_HOME/kanidm_sshkey_fetcher, then ~/.local/state/kanidm_sshkey_fetcher
+    #[arg(long, value_parser)]
+    state_dir: Option<PathBuf>,
+
+    /// The file that keeps overlapping runs apart, defaults to `lock` in the state directory
+    #[arg(long, value_parser)]
+    lock_file: Option<PathBuf>,
+
+    /// Fetch the keys and print what writing them would change, without writing anything
+    #[arg(long, default_value_t = false)]
+    #[serde(skip)]
+    dry_run: bool,
+
+    /// Show what writing the keys would change and ask before writing them
+    #[arg(short, long, default_value_t = false)]
+    #[serde(skip)]
+    interactive: bool,
+
+    /// Keep running and sync the keys every --interval seconds
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    daemon: bool,
+
+    /// How many seconds to wait between syncs in daemon mode, defaults to 300
+    #[arg(long)]
+    interval: Option<u64>,
+
+    /// Add a random delay of up to this many seconds to each interval in daemon mode
+    ///
+    /// Keeps many hosts started at the same time from syncing in the same second
+    #[arg(long)]
+    splay: Option<u64>,
+
+    /// Create this file after each successful sync in daemon mode and remove it otherwise
+    ///
+    /// Meant for a readiness probe, the file is also removed when the daemon stops
+    #[arg(long, value_parser)]
+    ready_file: Option<PathBuf>,
+
+    /// Run as a Kubernetes sidecar, i.e. --daemon with every log line printed as JSON
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    sidecar: bool,
+
+    /// Wait for another running instance to finish instead of exiting
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    wait_for_lock: bool,
+
+    /// Fetch keys as this unprivileged user when running as root
+    ///
+    /// Root is kept only for writing the fetched keys, in a separate process
+    #[arg(long)]
+    drop_privileges: Option<String>,
+
+    /// Write authorized_keys through this privileged helper instead of directly, requires --user
+    #[arg(long, value_parser)]
+    write_helper: Option<PathBuf>,
+
+    /// Restrict the filesystem, network and syscalls available to a fetch run
+    ///
+    /// Uses Landlock and seccomp, only available on Linux
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    sandbox: bool,
+
+    /// The API or session token to authenticate with instead of anonymous
+    #[arg(short = 'T', long)]
+    token: Option<String>,
+
+    /// Read the token from this file instead, e.g. a mounted secret
+    #[arg(long, value_parser)]
+    token_file: Option<PathBuf>,
+
+    /// Refuse to read from the server anonymously, e.g. when every read must be attributable to
+    /// a service account
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    require_auth: bool,
+
+    /// Write the outcome of every run to this file as JSON, for monitoring to alarm on stale or
+    /// failing syncs
+    #[arg(long, value_name = "PATH")]
+    status_file: Option<PathBuf>,
+
+    /// Send the duration and outcome of every run, and the keys it wrote, to this statsd server
+    /// over UDP
+    #[arg(long, value_name = "HOST:PORT")]
+    statsd: Option<String>,
+
+    /// The dialect to send metrics in, defaults to statsd
+    #[arg(long, value_enum)]
+    statsd_format: Option<StatsdFormat>,
+
+    /// A tag to add to every metric with --statsd-format dogstatsd, e.g. `env:prod`, can be
+    /// repeated
+    #[arg(long = "statsd-tag", value_name = "TAG")]
+    #[serde(default)]
+    statsd_tags: Vec<String>,
+
+    /// Print how long loading the configuration, building the client, authenticating, fetching
+    /// and writing took at the end of the run
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    timings: bool,
+
+    /// Report errors and panics to the Sentry project of this DSN, requires the `sentry` feature
+    #[arg(long, value_name = "DSN")]
+    sentry_dsn: Option<String>,
+
+    /// Save the anonymous session in the state directory and reuse it until it expires
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    persist_session: bool,
+
+    /// Fail instead of warning when the server runs a kanidm release the client doesn't support
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    strict_version: bool,
+
+    /// Read keys over kanidm's LDAP interface at this URL when the HTTPS API can't be reached
+    ///
+    /// e.g. ldaps://idm.example.com:636, the token, if any, is used to bind
+    #[arg(long)]
+    ldap_url: Option<String>,
+
+    /// The base DN to search below, defaults to the naming context advertised by the server
+    #[arg(long)]
+    ldap_base_dn: Option<String>,
+
+    /// Only read keys over LDAP, never from the HTTPS API, requires --ldap-url
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    ldap_only: bool,
+
+    /// Ask kanidm-unixd for keys over its socket before the server, at /var/run/kanidm-unixd/sock
+    /// if no path is given
+    #[arg(long, value_parser, num_args = 0..=1, default_missing_value = unixd::DEFAULT_SOCKET)]
+    unixd_socket: Option<PathBuf>,
+
+    /// Only read keys from kanidm-unixd, never from the server, requires --unixd-socket
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    unixd_only: bool,
+
+    /// What to do with the comments of fetched keys, defaults to keep
+    #[arg(long, value_enum)]
+    key_comments: Option<CommentPolicy>,
+
+    /// Only use the kanidm keys tagged `host:<pattern>` with a pattern matching this host's name
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    host_tags: bool,
+
+    /// Only use this many keys of each account, those tagged --key-priority first, then in the
+    /// order its sources return them
+    #[arg(long)]
+    max_keys_per_account: Option<usize>,
+
+    /// With --max-keys-per-account, keep the kanidm keys tagged with a tag matching this pattern
+    /// first, can be repeated, earlier patterns win
+    #[arg(long = "key-priority", value_name = "TAG")]
+    #[serde(default)]
+    key_priority: Vec<String>,
+
+    /// Warn about accounts given by id that exist but have no ssh keys
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    warn_empty: bool,
+
+    /// Like --warn-empty, but also exit with an error, after writing the keys of the others
+    #[arg(long, default_value_t = false)]
+    #[serde(default)]
+    fail_empty: bool,
+
+    #[command(flatten)]
+    #[serde(default)]
+    source: source::SourceArgs,
+
+    /// Print version
+    #[arg(short = 'V', long, default_value_t = false)]
+    #[serde(skip)]
+    version: bool,
+
+    /// Print machine-readable JSON instead of text, for --version, the summary of a sync and
+    /// the changes of fetch and --dry-run
+    #[arg(long, default_value_t = false)]
+    #[serde(skip)]
+    json: bool,
+
+    /// How to print warnings and errors, defaults to text
+    ///
+    /// json prints one object per line on stderr, with the level, code, account and message
+    #[arg(long, value_enum)]
+    errors: Option<ErrorFormat>,
+
+    /// When to color the output, defaults to auto, i.e. on a terminal unless NO_COLOR is set
+    #[arg(long, value_enum)]
+    color: Option<ColorChoice>,
+
+    /// An OpenSSH public key trusted to sign the bundles `import` writes, can be repeated
+    #[arg(long = "bundle-trusted-key", value_name = "KEY")]
+    #[serde(default)]
+    bundle_trusted_keys: Vec<String>,
+
+    /// The OpenSSH private key `export --bundle` signs bundles with
+    #[arg(long, value_parser)]
+    bundle_signing_key: Option<PathBuf>,
+
+    /// The sections of the configuration file for the hosts matching a pattern, see [`config`]
+    #[arg(skip)]
+    #[serde(default)]
+    host: BTreeMap<String, Cli>,
+
+    #[command(subcommand)]
+    #[serde(skip)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Generate a new ed25519 keypair and register the public key in kanidm
+    Rotate(rotate::RotateArgs),
+    /// Manage the ssh keys stored in kanidm for an account
+    Keys(keys::KeysArgs),
+    /// Show a table of the configured accounts and their keys
+    List,
+    /// Show the keys of an account in detail
+    Show {
+        /// The account id to show
+        account_id: String,
+    },
+    /// Search for accounts whose name matches a filter
+    Search(search::SearchArgs),
+    /// Write the keys of every configured account to one file per account
+    Export(export::ExportArgs),
+    /// Report keys and accounts of the configured accounts that are worth a look
+    Report(report::ReportArgs),
+    /// Inspect or flush the local key cache
+    Cache(cache::CacheArgs),
+    /// Fetch the keys and stage what writing them would change for review, without writing it
+    Fetch(plan::FetchArgs),
+    /// Write the keys staged by fetch, if the files are unchanged since
+    Apply(plan::ApplyArgs),
+    /// Put a backup of authorized_keys taken before a modification back in place
+    Restore(backup::RestoreArgs),
+    /// Rebuild a broken managed block in authorized_keys from a fresh fetch, keeping the rest
+    Repair,
+    /// Write the keys of a signed bundle, e.g. on hosts that can't reach the server
+    Import(bundle::ImportArgs),
+    /// Check that the server is reachable and the credentials are accepted
+    Ping,
+    /// Check the configuration, the connection to the server and the files written
+    Doctor,
+    /// Exit successfully if the daemon synced recently, e.g. for a container HEALTHCHECK
+    Health(health::HealthArgs),
+    /// Print the shell completion script for a shell
+    Completions {
+        /// The shell to complete in
+        shell: clap_complete::Shell,
+    },
+    /// Print the man page in roff format
+    Mangen,
+    /// Write keys sent as JSON on stdin by an unprivileged fetcher, see --write-helper
+    #[cfg(unix)]
+    #[command(hide = true)]
+    WriteHelper,
+}
+
+/// What to return for an account when the server fails to answer for it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Return no keys, locking the account out until the server answers again
+    #[default]
+    Closed,
+    /// Return the cached keys, or those in --fallback-dir, if they are at most --max-staleness old
+    Open,
+}
+
+/// What to do with the comment field of fetched keys
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CommentPolicy {
+    /// Keep the comment the key was uploaded with
+    #[default]
+    Keep,
+    /// Remove the comment
+    Strip,
+    /// Replace the comment with `<account>@kanidm`
+    Account,
+}
+
+/// What to do when authorized_keys is a symlink
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    /// Write to the file the symlink points to, keeping the symlink
+    #[default]
+    Follow,
+    /// Fail without writing anything
+    Refuse,
+    /// Replace the symlink with a regular file
+    Replace,
+}
+
+/// Parse an octal file mode like `600` or `0o600`
+fn parse_mode(value: &str) -> Result<u32, String> {
+    let digits = value.strip_prefix("0o").unwrap_or(value);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("invalid octal mode `{value}`")),
+    }
 }
 
 impl Cli {
@@ -44,7 +574,78 @@ impl Cli {
         self.addr = self.addr.clone().or(other.addr.clone());
         self.ca_path = self.ca_path.clone().or(other.ca_path.clone());
         self.account_ids.extend(other.account_ids.clone());
+        self.groups.extend(other.groups.clone());
         self.modify = self.modify || other.modify;
+        self.key_dir = self.key_dir.clone().or(other.key_dir.clone());
+        self.user_map = self.user_map.clone().or(other.user_map.clone());
+        self.map_by_uid = self.map_by_uid || other.map_by_uid;
+        self.local_users = self.local_users || other.local_users;
+        self.local_uid_min = self.local_uid_min.or(other.local_uid_min);
+        self.local_uid_max = self.local_uid_max.or(other.local_uid_max);
+        self.cache_path = self.cache_path.clone().or(other.cache_path.clone());
+        self.cache_ttl = self.cache_ttl.or(other.cache_ttl);
+        self.on_server_failure = self.on_server_failure.or(other.on_server_failure);
+        self.max_staleness = self.max_staleness.or(other.max_staleness);
+        self.fallback_dir = self.fallback_dir.clone().or(other.fallback_dir.clone());
+        self.slowest_accounts = self.slowest_accounts.or(other.slowest_accounts);
+        self.batch_threshold = self.batch_threshold.or(other.batch_threshold);
+        self.negative_cache_ttl = self.negative_cache_ttl.or(other.negative_cache_ttl);
+        self.encrypt_cache = self.encrypt_cache || other.encrypt_cache;
+        self.cache_key_file = self.cache_key_file.clone().or(other.cache_key_file.clone());
+        self.administrators = self.administrators || other.administrators;
+        self.drop_privileges = self
+            .drop_privileges
+            .clone()
+            .or(other.drop_privileges.clone());
+        self.write_helper = self.write_helper.clone().or(other.write_helper.clone());
+        self.sandbox = self.sandbox || other.sandbox;
+        self.wait_for_lock = self.wait_for_lock || other.wait_for_lock;
+        self.daemon = self.daemon || other.daemon;
+        self.interval = self.interval.or(other.interval);
+        self.splay = self.splay.or(other.splay);
+        self.ready_file = self.ready_file.clone().or(other.ready_file.clone());
+        self.sidecar = self.sidecar || other.sidecar;
+        self.on_tamper = self.on_tamper.or(other.on_tamper);
+        self.audit_log = self.audit_log.clone().or(other.audit_log.clone());
+        self.user = self.user.clone().or(other.user.clone());
+        self.home_dir = self.home_dir.clone().or(other.home_dir.clone());
+        self.symlinks = self.symlinks.or(other.symlinks);
+        self.dir_mode = self.dir_mode.or(other.dir_mode);
+        self.file_mode = self.file_mode.or(other.file_mode);
+        self.keep_backups = self.keep_backups.or(other.keep_backups);
+        self.state_dir = self.state_dir.clone().or(other.state_dir.clone());
+        self.lock_file = self.lock_file.clone().or(other.lock_file.clone());
+        self.token = self.token.clone().or(other.token.clone());
+        self.token_file = self.token_file.clone().or(other.token_file.clone());
+        self.persist_session = self.persist_session || other.persist_session;
+        self.status_file = self.status_file.clone().or(other.status_file.clone());
+        self.statsd = self.statsd.clone().or(other.statsd.clone());
+        self.statsd_format = self.statsd_format.or(other.statsd_format);
+        self.statsd_tags.extend(other.statsd_tags.clone());
+        self.sentry_dsn = self.sentry_dsn.clone().or(other.sentry_dsn.clone());
+        self.timings = self.timings || other.timings;
+        self.require_auth = self.require_auth || other.require_auth;
+        self.strict_version = self.strict_version || other.strict_version;
+        self.ldap_url = self.ldap_url.clone().or(other.ldap_url.clone());
+        self.ldap_base_dn = self.ldap_base_dn.clone().or(other.ldap_base_dn.clone());
+        self.ldap_only = self.ldap_only || other.ldap_only;
+        self.unixd_socket = self.unixd_socket.clone().or(other.unixd_socket.clone());
+        self.unixd_only = self.unixd_only || other.unixd_only;
+        self.errors = self.errors.or(other.errors);
+        self.color = self.color.or(other.color);
+        self.key_comments = self.key_comments.or(other.key_comments);
+        self.max_keys_per_account = self.max_keys_per_account.or(other.max_keys_per_account);
+        self.key_priority.extend(other.key_priority.clone());
+        self.host_tags = self.host_tags || other.host_tags;
+        self.warn_empty = self.warn_empty || other.warn_empty;
+        self.fail_empty = self.fail_empty || other.fail_empty;
+        self.bundle_trusted_keys
+            .extend(other.bundle_trusted_keys.clone());
+        self.bundle_signing_key = self
+            .bundle_signing_key
+            .clone()
+            .or(other.bundle_signing_key.clone());
+        self.source.or(&other.source);
     }
 }
 
@@ -62,15 +663,25 @@ pub fn build_configured_client(args: &Cli) -> Result<KanidmClient, ()> {
             .read_options_from_optional_config(DEFAULT_CLIENT_CONFIG_PATH)
             .and_then(|cb| {
                 debug!("Attempting to use config {}", config_path);
-                cb.read_options_from_optional_config(config_path)
+                cb.read_options_from_optional_config(&config_path)
             })
             .map_err(|e| {
-                error!("Failed to parse config (if present) -- {:?}", e);
+                Error::new("client::config", "Failed to parse the kanidm client config")
+                    .with(
+                        "files",
+                        format!("{DEFAULT_CLIENT_CONFIG_PATH}, {config_path}"),
+                    )
+                    .cause(e)
+                    .report()
             })
     }?;
 
+    // --unixd-only never connects, but the sources are built around a client all the same
     let client_builder = match &args.addr {
         Some(addr) => client_builder.address(addr.to_string()),
+        None if args.unixd_only && args.command.is_none() => {
+            client_builder.address("https://localhost".to_string())
+        }
         None => client_builder,
     };
 
@@ -79,118 +690,649 @@ pub fn build_configured_client(args: &Cli) -> Result<KanidmClient, ()> {
         Some(ca_path) => client_builder
             .add_root_certificate_filepath(ca_path)
             .map_err(|e| {
-                error!("Failed to add ca certificate -- {:?}", e);
+                Error::new("client::ca", "Failed to add ca certificate")
+                    .file(ca_path)
+                    .cause(e)
+                    .report()
             })?,
         None => client_builder,
     };
 
     client_builder.build().map_err(|e| {
-        error!("Failed to build client -- {:?}", e);
+        let error = Error::new("client::build", "Failed to build client").cause(e);
+        match &args.addr {
+            Some(addr) => error.url(addr),
+            None => error,
+        }
+        .report()
     })
 }
 
-pub fn modify_authorized_keys(keys: Vec<String>) -> Result<(), ()> {
-    debug!("Modifying authorized_keys file started");
+/// What to do about the server refusing to authenticate, or refusing a request afterwards
+///
+/// `token` is whether a token was supplied, otherwise the client logged in anonymously.
+pub fn auth_hint(e: &ClientError, token: bool) -> Option<&'static str> {
+    let unauthorized = source::is_unauthorized(e);
+    let forbidden = matches!(e, ClientError::Http(StatusCode::FORBIDDEN, _, _));
 
-    let ssh_config_dir = PathBuf::from(shellexpand::tilde(SSH_CONFIG_DIR).into_owned());
-    if !ssh_config_dir.exists() {
-        debug!("Creating ssh config directory -- {ssh_config_dir:?}");
-
-        std::fs::create_dir(&ssh_config_dir)
-            .map_err(|e| error!("Failed to create ssh config directory -- {:?}", e))?;
+    match (token, unauthorized, forbidden) {
+        (false, true, _) => {
+            Some("anonymous account disabled on server; supply --token or enable anonymous reads")
+        }
+        (true, true, _) => Some(
+            "token expired or revoked; issue a new one with `kanidm service-account api-token generate` and pass it with --token",
+        ),
+        (false, _, true) => Some(
+            "anonymous may not read ssh keys on this server; supply --token or grant anonymous reads",
+        ),
+        (true, _, true) => {
+            Some("the token's account may not read ssh keys; grant it read access to ssh_publickey")
+        }
+        _ => None,
     }
+}
 
-    let authorized_keys_file = ssh_config_dir.join("authorized_keys");
-
-    let mut authorized_keys =
-        std::fs::read_to_string(&authorized_keys_file).unwrap_or_else(|_| String::new());
+pub async fn authenticate(client: &KanidmClient, args: &Cli) {
+    if let Some(token) = &args.token {
+        debug!("Using the provided token");
+        client.set_token(token.clone()).await;
+        return;
+    }
 
-    // Find `# Managed Keys by kanidm_sshkey_fetcher` and `# End of Managed Keys by kanidm_sshkey_fetcher`
-    const MANAGED_KEYS_START: &str = "# Managed Keys by kanidm_sshkey_fetcher";
-    const MANAGED_KEYS_END: &str = "# End of Managed Keys by kanidm_sshkey_fetcher";
-    let start_index = authorized_keys
-        .find(MANAGED_KEYS_START)
-        .unwrap_or(authorized_keys.len());
-    let end_index = authorized_keys
-        .find(MANAGED_KEYS_END)
-        .unwrap_or(authorized_keys.len());
+    let session = session::session_path(args);
+    if let Some(token) = session.as_deref().and_then(session::load) {
+        client.set_token(token).await;
+        return;
+    }
 
-    // Prepare the new content
-    let mut new_content = String::new();
-    for key in keys {
-        new_content.push_str(&format!("{}\n", key));
+    let r = client.auth_anonymous().await;
+    if r.is_ok()
+        && let Some(path) = &session
+    {
+        session::save(path, client).await;
+    }
+    if let Err(e) = r {
+        let url = client.get_url();
+        let error = match &e {
+            ClientError::Transport(_) => {
+                Error::new("auth::connect", "Failed to connect to kanidm server")
+            }
+            _ => Error::new("auth::anonymous", "Error during authentication phase"),
+        };
+        error
+            .url(url)
+            .maybe_help(auth_hint(&e, false))
+            .cause(e)
+            .report()
     }
+}
 
-    // Replace the managed keys section if it exists
-    if start_index < end_index {
-        let start_index = start_index + MANAGED_KEYS_START.len() + 2; // +2 for the newline
-        new_content.push('\n'); // Add a newline between the content and the end marker
-        authorized_keys.replace_range(start_index..end_index, &new_content);
-    } else {
-        // If the section doesn't exist, append the new content
-        authorized_keys.push_str(&format!(
-            "\n{}\n\n{}\n{}\n",
-            MANAGED_KEYS_START, new_content, MANAGED_KEYS_END
-        ));
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), ()> {
+    let started = Instant::now();
+    let mut args = config::parse_args();
+    let result = run(&mut args, started).await;
+    if reports_outcome(&args) {
+        report_outcome(&args, result.is_ok(), started);
     }
+    result
+}
 
-    // Write the updated content back to the file
-    std::fs::write(&authorized_keys_file, authorized_keys)
-        .map_err(|e| error!("Failed to write to authorized_keys file -- {:?}", e))?;
+/// Whether a run reports how it went once it ended, however it ended
+///
+/// The daemon reports every sync instead. The write helper doesn't at all: its options are its
+/// caller's, who must not choose where a privileged process writes or sends to. With
+/// `--drop-privileges` only the parent reports, once the child's results are written.
+fn reports_outcome(args: &Cli) -> bool {
+    #[cfg(unix)]
+    if matches!(args.command, Some(Command::WriteHelper)) || privileges::is_child() {
+        return false;
+    }
+    !args.daemon
+}
 
-    Ok(())
+/// Tell monitoring how a run, or a sync of the daemon, went
+pub fn report_outcome(args: &Cli, success: bool, started: Instant) {
+    if args.timings {
+        timings::print(started.elapsed(), args.json);
+    }
+    #[cfg(feature = "sentry")]
+    sentry::flush();
+    let summary = summary::take_last();
+    if let Some(addr) = &args.statsd {
+        metrics::send(
+            addr,
+            args.statsd_format.unwrap_or_default(),
+            &args.statsd_tags,
+            success,
+            started,
+            summary.as_ref(),
+        );
+    }
+    if let Some(path) = &args.status_file {
+        status::write(path, success, started, summary);
+    }
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), ()> {
-    let mut args = Cli::parse();
+/// Everything a run does, with the options merged into `args` as they are read
+async fn run(args: &mut Cli, started: Instant) -> Result<(), ()> {
+    // The write helper may run privileged, its caller's environment must not configure it
+    #[cfg(unix)]
+    if matches!(args.command, Some(Command::WriteHelper)) {
+        *args = Cli::parse();
+    }
+    diagnostic::set_format(args.errors.unwrap_or_default());
+    diagnostic::set_color(args.color.unwrap_or_default());
 
-    if let Some(config_path) = &args.config_path {
-        let config_content = std::fs::read_to_string(config_path)
-            .map_err(|e| eprintln!("Failed to read config file -- {:?}", e))?;
+    if args.version {
+        return version::print(args.json);
+    }
 
-        let args_file: Cli = toml::from_str(&config_content)
-            .map_err(|e| eprintln!("Failed to parse config file -- {:?}", e))?;
+    // The write helper may run privileged on behalf of anyone, so it must not read any
+    // configuration the caller points it to
+    #[cfg(unix)]
+    if matches!(args.command, Some(Command::WriteHelper)) {
+        diagnostic::init_tracing(args.errors.unwrap_or_default());
+        let (helper_args, results) = helper::request(args)?;
+        let _lock = state::lock(&state::lock_path(&helper_args), args.wait_for_lock)?;
+        return write_results(&helper_args, &results, started);
+    }
 
+    if let Some(config_path) = &args.config_path {
+        let args_file = config::load(config_path)?;
         args.or(&args_file);
     }
 
+    if let Some(compat) = compat::Compat::invoked_as() {
+        compat.apply(args);
+    }
+
     if args.debug {
         unsafe {
             std::env::set_var("RUST_LOG", "kanidm=debug,kanidm_client=debug");
         }
     }
-    tracing_subscriber::fmt::init();
+    diagnostic::set_color(args.color.unwrap_or_default());
+    if args.sidecar {
+        args.daemon = true;
+        diagnostic::init_json_tracing();
+    } else {
+        diagnostic::init_tracing(args.errors.unwrap_or_default());
+    }
+    timings::record("config", started.elapsed());
+    #[cfg(feature = "sentry")]
+    if let Some(dsn) = &args.sentry_dsn {
+        sentry::init(dsn)?;
+    }
+    #[cfg(not(feature = "sentry"))]
+    if args.sentry_dsn.is_some() {
+        Error::new(
+            "sentry::unsupported",
+            "Built without Sentry support, not reporting errors to Sentry",
+        )
+        .help("build with `--features sentry`")
+        .warn();
+    }
 
-    let client = build_configured_client(&args)?;
+    if args.token.is_none()
+        && let Some(path) = &args.token_file
+    {
+        args.token = Some(config::read_secret(path)?);
+    }
 
-    let r = client.auth_anonymous().await;
-    if let Err(e) = r {
-        match e {
-            ClientError::Transport(e) => {
-                error!("failed to connect to kanidm server: {}", e.to_string())
-            }
-            _ => error!("Error during authentication phase: {:?}", e),
+    // `fetch` runs like a normal run up to writing the results, which it stages instead
+    let staging = match args.command.take() {
+        Some(Command::Fetch(fetch_args)) => Some(fetch_args),
+        command => {
+            args.command = command;
+            None
         }
+    };
+
+    // `repair` runs like a normal run with -m, rebuilding the managed block instead of updating it
+    let repairing = matches!(args.command, Some(Command::Repair));
+    if repairing {
+        args.command = None;
+        args.modify = true;
+    }
+
+    // The keys go to stdout for sshd, unless the changes they make are printed instead
+    let print = staging.is_none() && !repairing && !args.dry_run && !args.interactive;
+
+    // Each local user gets their own authorized_keys
+    #[cfg(unix)]
+    if args.local_users
+        && (args.user.is_some() || args.home_dir.is_some() || args.write_helper.is_some())
+    {
+        Error::new(
+            "args::conflict",
+            "--local-users cannot be combined with --user, --home-dir or --write-helper",
+        )
+        .help("--local-users already writes the authorized_keys of each local user")
+        .report();
+        return Err(());
+    }
+    #[cfg(not(unix))]
+    if args.local_users {
+        Error::new(
+            "args::unsupported",
+            "--local-users is only supported on Unix",
+        )
+        .report();
+        return Err(());
     }
 
-    let mut keys = Vec::new();
+    // Under sudo, root's own authorized_keys are rarely the ones meant
+    #[cfg(unix)]
+    if args.user.is_none()
+        && !args.local_users
+        && args.home_dir.is_none()
+        && (args.modify || matches!(args.command, Some(Command::Restore(_))))
+    {
+        args.user = user::offer_sudo_user();
+    }
+
+    // Overlapping runs must not race on the same files
+    let writes = match &args.command {
+        None => (args.modify || args.key_dir.is_some()) && !args.dry_run,
+        Some(Command::Restore(_) | Command::Import(_) | Command::Apply(_)) => true,
+        _ => false,
+    };
+    let _lock = if writes {
+        Some(state::lock(&state::lock_path(args), args.wait_for_lock)?)
+    } else {
+        None
+    };
+
+    // Commands that only work on local state don't need a client
+    match &args.command {
+        Some(Command::Cache(cache_args)) => return cache::cache(args, cache_args),
+        Some(Command::Restore(restore_args)) => return backup::restore(args, restore_args),
+        Some(Command::Health(health_args)) => return health::health(args, health_args),
+        Some(Command::Import(import_args)) => {
+            return bundle::import(args, import_args, started);
+        }
+        Some(Command::Apply(apply_args)) => return plan::apply(args, apply_args, started),
+        // Builds and authenticates its own client to report failures instead of exiting
+        Some(Command::Doctor) => return doctor::doctor(args).await,
+        Some(Command::Completions { shell }) => {
+            let mut command = Cli::command();
+            clap_complete::generate(
+                *shell,
+                &mut command,
+                env!("CARGO_PKG_NAME"),
+                &mut std::io::stdout(),
+            );
+            return Ok(());
+        }
+        Some(Command::Mangen) => {
+            return clap_mangen::Man::new(Cli::command())
+                .render(&mut std::io::stdout())
+                .map_err(|e| {
+                    Error::new("mangen::write", "Failed to write the man page")
+                        .cause(e)
+                        .report()
+                });
+        }
+        _ => {}
+    }
+
+    if args.require_auth && args.token.is_none() {
+        Error::new(
+            "auth::required",
+            "Refusing to read anonymously with --require-auth",
+        )
+        .help("supply a service account's API token with --token or --token-file")
+        .report();
+        return Err(());
+    }
+
+    // A login the cache answers entirely doesn't need the server, nor the kanidm client
+    // configuration, CA and TLS setup that connecting to it takes
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut cache = match args.command {
+        None => cache::open_configured(args).ok().flatten(),
+        Some(_) => None,
+    };
+    if let Some(cache) = &cache
+        && !args.daemon
+        && source::answered_by_cache(args, cache)
+    {
+        debug!("The cache answers every account, not connecting to the server");
+        let results = fetch_and_print(&source::CacheOnly, args, Some(cache), print).await;
+        return finish(args, &results, started, staging.as_ref(), repairing);
+    }
 
-    for id in &args.account_ids {
-        match client.idm_account_get_ssh_pubkeys(id.as_str()).await {
-            Ok(pkeys) => {
-                keys.extend(pkeys.clone());
-                pkeys.iter().for_each(|pkey| println!("{}", pkey))
+    // Keep root only for writing the results, see --drop-privileges
+    #[cfg(unix)]
+    let mut unprivileged = None;
+    #[cfg(unix)]
+    if let Some(user) = &args.drop_privileges
+        && args.command.is_none()
+    {
+        if nix::unistd::geteuid().is_root() {
+            // The child opens its own connection, SQLite's can't be shared across a fork
+            let reopen = cache.take().is_some();
+            match privileges::split(user)? {
+                privileges::Split::Parent(parent) => {
+                    // Static key files may only be readable by root
+                    let mut results = parent.wait()?;
+                    results.static_keys = source::static_keys(args);
+                    return finish(args, &results, started, staging.as_ref(), repairing);
+                }
+                privileges::Split::Child(child) => {
+                    if reopen {
+                        cache = cache::open_configured(args).ok().flatten();
+                    }
+                    unprivileged = Some(child);
+                }
             }
-            // Err(e) => error!("Failed to get ssh pubkeys for account {} -- {:?}", id, e),
-            Err(_e) => {}
+        } else {
+            debug!("Not running as root, ignoring --drop-privileges");
         }
     }
 
+    // Loading the trusted certificates takes a while of its own
+    let building = Instant::now();
+    let client = build_configured_client(args)?;
+    timings::record("client", building.elapsed());
+
+    if args.sandbox && args.command.is_none() {
+        #[cfg(target_os = "linux")]
+        sandbox::apply(&client, args)?;
+        #[cfg(not(target_os = "linux"))]
+        Error::new(
+            "sandbox::unsupported",
+            "--sandbox is only supported on Linux, running unrestricted",
+        )
+        .warn();
+    }
+
+    if !(args.ldap_only || args.unixd_only) || args.command.is_some() {
+        // The version check opens the connection, so it is part of authenticating
+        let authenticating = Instant::now();
+        // A mismatch would otherwise surface as opaque protocol errors
+        version::check(&client, args.strict_version).await?;
+        authenticate(&client, args).await;
+        timings::record("auth", authenticating.elapsed());
+    }
+
+    match &args.command {
+        Some(Command::Rotate(rotate_args)) => return rotate::rotate(&client, rotate_args).await,
+        Some(Command::Keys(keys_args)) => return keys::keys(&client, keys_args).await,
+        Some(Command::List) => return list::list(&client, args).await,
+        Some(Command::Show { account_id }) => {
+            return show::show(&client, account_id, args.token.is_some()).await;
+        }
+        Some(Command::Search(search_args)) => return search::search(&client, search_args).await,
+        Some(Command::Ping) => return ping::ping(&client, args).await,
+        Some(Command::Export(export_args)) => {
+            return export::export(&client, args, export_args).await;
+        }
+        Some(Command::Report(report_args)) => {
+            return report::report(&client, args, report_args).await;
+        }
+        Some(
+            Command::Cache(_)
+            | Command::Restore(_)
+            | Command::Import(_)
+            | Command::Apply(_)
+            | Command::Doctor
+            | Command::Health(_)
+            | Command::Completions { .. }
+            | Command::Mangen,
+        ) => {
+            unreachable!("handled before connecting")
+        }
+        Some(Command::Fetch(_) | Command::Repair) => unreachable!("runs as a normal run"),
+        #[cfg(unix)]
+        Some(Command::WriteHelper) => unreachable!("handled before connecting"),
+        None => {}
+    }
+
+    let sources = source::Sources::new(&client, args)?;
+
+    if args.daemon {
+        if staging.is_some() || repairing || args.dry_run || args.interactive {
+            Error::new(
+                "args::conflict",
+                "fetch, repair, --dry-run and --interactive cannot be combined with --daemon",
+            )
+            .report();
+            return Err(());
+        }
+        #[cfg(unix)]
+        if unprivileged.is_some() {
+            Error::new(
+                "args::conflict",
+                "--daemon cannot be combined with --drop-privileges",
+            )
+            .help("use --write-helper to keep root out of the daemon")
+            .report();
+            return Err(());
+        }
+        return daemon::run(&sources, args, cache.as_ref()).await;
+    }
+
+    let results = fetch_and_print(&sources, args, cache.as_ref(), print).await;
+
+    #[cfg(unix)]
+    if let Some(child) = unprivileged {
+        return child.send(&results);
+    }
+
+    finish(args, &results, started, staging.as_ref(), repairing)
+}
+
+/// Fetch the keys of every configured account and print them, for `AuthorizedKeysCommand`
+///
+/// Nothing is printed unless `print` is set.
+async fn fetch_and_print(
+    source: &impl source::KeySource,
+    args: &Cli,
+    cache: Option<&cache::Cache>,
+    print: bool,
+) -> source::Fetched {
+    let (mut fetched, complete) = source::fetch_all(source, args, cache, |_, keys| {
+        keys.iter()
+            .filter(|_| print)
+            .for_each(|key| println!("{}", key));
+    })
+    .await;
+    let results = source::Fetched {
+        posix_ids: source::posix_ids(source, args, &mut fetched).await,
+        origins: source::origins(source, &fetched),
+        fetched,
+        complete,
+        static_keys: source::static_keys(args),
+    };
+    results
+        .static_keys
+        .iter()
+        .filter(|_| print)
+        .for_each(|key| println!("{}", key));
+    results
+}
+
+/// Write the fetched keys, stage them for `apply` if run as `fetch`, rebuild the managed blocks
+/// with them if run as `repair`, or with `--dry-run` only print what writing them would change
+fn finish(
+    args: &Cli,
+    results: &source::Fetched,
+    started: Instant,
+    staging: Option<&plan::FetchArgs>,
+    repairing: bool,
+) -> Result<(), ()> {
+    match staging {
+        Some(fetch) => plan::stage(args, fetch, results),
+        None if repairing => repair_results(args, results),
+        None if args.dry_run => plan::dry_run(args, results),
+        None if args.interactive => plan::confirm(args, results, started),
+        None => write_results(args, results, started),
+    }
+}
+
+/// Write the fetched keys to the configured destinations
+///
+/// A summary of what changed is printed once everything is written, the run having begun at
+/// `started`.
+pub fn write_results(args: &Cli, results: &source::Fetched, started: Instant) -> Result<(), ()> {
+    let writing = Instant::now();
+    let written = write_files(args, results, started);
+    timings::record("write", writing.elapsed());
+    written
+}
+
+/// [`write_results`], without timing it
+fn write_files(args: &Cli, results: &source::Fetched, started: Instant) -> Result<(), ()> {
+    let fetched = &results.fetched;
+    let empty = report_empty(args, fetched);
+
+    // Report which accounts changed since the last sync, unchanged ones cause no writes
+    let mut changes = None;
+    if args.modify || args.key_dir.is_some() {
+        let key_changes = state::State::load(&state::state_dir(args)).update_key_checksums(fetched);
+        if key_changes.accounts.is_empty() {
+            debug!("No keys changed since the last sync");
+        } else {
+            tracing::info!("Keys changed for {}", key_changes.accounts.join(", "));
+        }
+        changes = Some(key_changes);
+    }
+
+    // Maintain the per-account key files if requested
+    if let Some(key_dir) = &args.key_dir {
+        let users = usermap::UserMap::load(args, &results.posix_ids)?;
+        export::sync_key_dir(key_dir, fetched, &users, results.complete)?;
+    }
+
     // Modify the authorized_keys file if requested
-    if args.modify {
-        modify_authorized_keys(keys)?;
+    #[cfg(unix)]
+    if args.modify
+        && let Some(helper) = &args.write_helper
+    {
+        helper::invoke(helper, args, results)?;
+        if let Some(changes) = &changes {
+            save_key_checksums(args, fetched, changes)?;
+        }
+        return empty;
+    }
+    let mut failed = false;
+    for (target_args, keys) in authorized_keys_targets(args, results)? {
+        failed |= authorized_keys::modify_authorized_keys(keys, &target_args).is_err();
+    }
+    if failed {
+        return Err(());
+    }
+
+    if let Some(changes) = changes {
+        save_key_checksums(args, fetched, &changes)?;
+        let summary = summary::Summary::new(fetched, &changes, started.elapsed());
+        summary.print(args.json);
+        summary.keep();
+    }
+
+    empty
+}
+
+/// Remember the checksums of the fetched keys once they are written, if any `changes`
+///
+/// Saved only after every write succeeded, so a failed write is retried as a change by the next
+/// run rather than taken for keys that are up to date. The state is loaded again, as writing
+/// authorized_keys updates it too.
+fn save_key_checksums(
+    args: &Cli,
+    fetched: &[(String, Option<Vec<String>>)],
+    changes: &state::KeyChanges,
+) -> Result<(), ()> {
+    if changes.accounts.is_empty() && changes.added + changes.removed == 0 {
+        return Ok(());
     }
+    let state_dir = state::state_dir(args);
+    let mut state = state::State::load(&state_dir);
+    state.update_key_checksums(fetched);
+    state.save(&state_dir)
+}
 
+/// Rebuild the managed block of every authorized_keys file writing `results` modifies
+fn repair_results(args: &Cli, results: &source::Fetched) -> Result<(), ()> {
+    #[cfg(unix)]
+    if args.write_helper.is_some() {
+        Error::new(
+            "args::conflict",
+            "repair cannot be combined with --write-helper",
+        )
+        .help("run repair as the owner of the file, or as root")
+        .report();
+        return Err(());
+    }
+    let mut failed = false;
+    for (target_args, keys) in authorized_keys_targets(args, results)? {
+        failed |= authorized_keys::repair_authorized_keys(keys, &target_args).is_err();
+    }
+    if failed { Err(()) } else { Ok(()) }
+}
+
+/// The authorized_keys files writing `results` modifies, as the options to write each with and
+/// the keys it gets
+///
+/// With `--local-users` every user gets the keys of their account only, accounts that failed
+/// to fetch keep their previous keys. Lines that are not authorized keys are left out.
+pub fn authorized_keys_targets(
+    args: &Cli,
+    results: &source::Fetched,
+) -> Result<Vec<(Cli, Vec<String>)>, ()> {
+    if !args.modify {
+        return Ok(Vec::new());
+    }
+
+    #[cfg(unix)]
+    if args.local_users {
+        let users = usermap::UserMap::load(args, &results.posix_ids)?;
+        return Ok(results
+            .fetched
+            .iter()
+            .filter_map(|(id, keys)| {
+                let mut user_args = args.clone();
+                user_args.user = Some(users.local_user(id).to_string());
+                Some((user_args, authorized_keys::authorized_lines(keys.clone()?)))
+            })
+            .collect());
+    }
+
+    let keys = results
+        .fetched
+        .iter()
+        .filter_map(|(_, keys)| keys.clone())
+        .flatten()
+        .chain(results.static_keys.iter().cloned())
+        .collect();
+    Ok(vec![(
+        args.clone(),
+        authorized_keys::authorized_lines(keys),
+    )])
+}
+
+/// Report the accounts `--warn-empty` and `--fail-empty` are about, failing for the latter
+fn report_empty(args: &Cli, fetched: &[(String, Option<Vec<String>>)]) -> Result<(), ()> {
+    if !args.warn_empty && !args.fail_empty {
+        return Ok(());
+    }
+
+    let empty = source::empty_accounts(args, fetched);
+    for id in &empty {
+        let error = Error::new("fetch::empty", "Account has no ssh keys")
+            .account(id)
+            .help("the user may have to add one with `kanidm person ssh add-publickey`");
+        if args.fail_empty {
+            error.report();
+        } else {
+            error.warn();
+        }
+    }
+
+    if args.fail_empty && !empty.is_empty() {
+        return Err(());
+    }
     Ok(())
 }
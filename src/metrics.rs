@@ -0,0 +1,226 @@
+//! Sending metrics of every run to statsd or dogstatsd, with `--statsd`
+//!
+//! For metrics pipelines built on Datadog or statsd rather than Prometheus, every run, and
+//! every sync of the daemon, ends by sending its duration and outcome, and with a summary the
+//! accounts that failed and the keys written, over UDP. All metrics start with
+//! `kanidm_sshkey_fetcher.`:
+//!
+//! - `run.duration`, a timer in milliseconds
+//! - `run.success` or `run.failure`, counted once per run
+//! - `accounts` and `accounts.failed`, gauges of the accounts fetched and those that failed
+//! - `account.failed`, counted per failed account, as `account.failed.<account>` for statsd and
+//!   tagged `account:<account>` for dogstatsd
+//! - `keys.added` and `keys.removed`, counters of the changes written
+//! - `keys.written`, a gauge of the keys in the files written
+//!
+//! UDP doesn't tell whether anyone listens, metrics that can't be sent are only logged.
+
+use std::net::UdpSocket;
+use std::time::Instant;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::diagnostic::Error;
+use crate::summary::Summary;
+
+const PREFIX: &str = "kanidm_sshkey_fetcher";
+
+/// The size statsd servers expect datagrams to fit in on common networks
+const MAX_PACKET: usize = 1432;
+
+/// The dialect of statsd to speak
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsdFormat {
+    /// Plain statsd, the account is part of the metric name
+    #[default]
+    Statsd,
+    /// Datadog's dogstatsd, with the account and --statsd-tag as tags
+    Dogstatsd,
+}
+
+/// The lines of the metrics of a run
+fn lines(
+    format: StatsdFormat,
+    tags: &[String],
+    success: bool,
+    duration_ms: u128,
+    summary: Option<&Summary>,
+) -> Vec<String> {
+    let line = |name: &str, value: String, kind: &str, tags: &[String]| match format {
+        StatsdFormat::Dogstatsd if !tags.is_empty() => {
+            format!("{PREFIX}.{name}:{value}|{kind}|#{}", tags.join(","))
+        }
+        _ => format!("{PREFIX}.{name}:{value}|{kind}"),
+    };
+
+    let outcome = if success {
+        "run.success"
+    } else {
+        "run.failure"
+    };
+    let mut lines = vec![
+        line("run.duration", duration_ms.to_string(), "ms", tags),
+        line(outcome, "1".to_string(), "c", tags),
+    ];
+    let Some(summary) = summary else {
+        return lines;
+    };
+    lines.push(line("accounts", summary.accounts.to_string(), "g", tags));
+    lines.push(line(
+        "accounts.failed",
+        summary.failed.to_string(),
+        "g",
+        tags,
+    ));
+    for account in &summary.failed_accounts {
+        lines.push(match format {
+            StatsdFormat::Statsd => line(
+                &format!(
+                    "account.failed.{}",
+                    sanitize(account, &['.', ':', '|', '@', '#'])
+                ),
+                "1".to_string(),
+                "c",
+                tags,
+            ),
+            StatsdFormat::Dogstatsd => {
+                let mut tags = tags.to_vec();
+                tags.push(format!("account:{}", sanitize(account, &[',', '|', '#'])));
+                line("account.failed", "1".to_string(), "c", &tags)
+            }
+        });
+    }
+    lines.push(line(
+        "keys.added",
+        summary.keys_added.to_string(),
+        "c",
+        tags,
+    ));
+    lines.push(line(
+        "keys.removed",
+        summary.keys_removed.to_string(),
+        "c",
+        tags,
+    ));
+    lines.push(line(
+        "keys.written",
+        (summary.keys_added + summary.keys_unchanged).to_string(),
+        "g",
+        tags,
+    ));
+    lines
+}
+
+/// `name` with the characters that separate the parts of a line replaced
+fn sanitize(name: &str, separators: &[char]) -> String {
+    name.replace(separators, "_")
+}
+
+/// Join `lines` into as few datagrams as fit in [`MAX_PACKET`]
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut packets: Vec<String> = Vec::new();
+    for line in lines {
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= MAX_PACKET => {
+                packet.push('\n');
+                packet.push_str(line);
+            }
+            _ => packets.push(line.clone()),
+        }
+    }
+    packets
+}
+
+/// Send the metrics of the run that started at `started` to the statsd server at `addr`
+pub fn send(
+    addr: &str,
+    format: StatsdFormat,
+    tags: &[String],
+    success: bool,
+    started: Instant,
+    summary: Option<&Summary>,
+) {
+    let lines = lines(
+        format,
+        tags,
+        success,
+        started.elapsed().as_millis(),
+        summary,
+    );
+    let sent = UdpSocket::bind("[::]:0")
+        .or_else(|_| UdpSocket::bind("0.0.0.0:0"))
+        .and_then(|socket| {
+            socket.connect(addr)?;
+            packets(&lines)
+                .iter()
+                .try_for_each(|packet| socket.send(packet.as_bytes()).map(|_| ()))
+        });
+    match sent {
+        Ok(()) => debug!("Sent {} metrics to {}", lines.len(), addr),
+        Err(e) => Error::new("metrics::send", "Failed to send metrics to statsd")
+            .with("address", addr)
+            .cause(e)
+            .warn(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary() -> Summary {
+        Summary {
+            accounts: 3,
+            failed: 1,
+            failed_accounts: vec!["bob@idm.example.com".to_string()],
+            keys_added: 2,
+            keys_removed: 1,
+            keys_unchanged: 4,
+            duration_ms: 40,
+        }
+    }
+
+    #[test]
+    fn names_failed_accounts_per_format() {
+        let summary = summary();
+        let statsd = lines(StatsdFormat::Statsd, &[], true, 42, Some(&summary));
+        assert_eq!(
+            statsd,
+            [
+                "kanidm_sshkey_fetcher.run.duration:42|ms",
+                "kanidm_sshkey_fetcher.run.success:1|c",
+                "kanidm_sshkey_fetcher.accounts:3|g",
+                "kanidm_sshkey_fetcher.accounts.failed:1|g",
+                "kanidm_sshkey_fetcher.account.failed.bob_idm_example_com:1|c",
+                "kanidm_sshkey_fetcher.keys.added:2|c",
+                "kanidm_sshkey_fetcher.keys.removed:1|c",
+                "kanidm_sshkey_fetcher.keys.written:6|g",
+            ]
+        );
+
+        let tags = ["env:prod".to_string()];
+        let dogstatsd = lines(StatsdFormat::Dogstatsd, &tags, false, 42, Some(&summary));
+        assert_eq!(
+            dogstatsd[1],
+            "kanidm_sshkey_fetcher.run.failure:1|c|#env:prod"
+        );
+        assert_eq!(
+            dogstatsd[4],
+            "kanidm_sshkey_fetcher.account.failed:1|c|#env:prod,account:bob@idm.example.com"
+        );
+
+        assert_eq!(lines(StatsdFormat::Statsd, &[], true, 42, None).len(), 2);
+    }
+
+    #[test]
+    fn splits_packets_that_would_be_too_large() {
+        let lines: Vec<String> = (0..100).map(|i| format!("metric.{i:03}:1|c")).collect();
+        let packets = packets(&lines);
+        assert!(packets.len() > 1);
+        assert!(packets.iter().all(|packet| packet.len() <= MAX_PACKET));
+        assert_eq!(packets.join("\n"), lines.join("\n"));
+    }
+}
@@ -0,0 +1,853 @@
+//! A stub kanidm server for end to end tests, enabled by the `mock-server` feature
+//!
+//! It speaks just enough of the kanidm HTTP API to authenticate anonymously and serve ssh keys,
+//! group members, the person list and `whoami`, and can be told to fail requests to exercise the error
+//! paths. Run the tests with `cargo test --features mock-server`.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use kanidm_client::{KanidmClientBuilder, StatusCode};
+use kanidm_proto::internal::OperationError;
+use kanidm_proto::v1::{AuthAllowed, AuthCredential, AuthMech, AuthRequest, AuthState, AuthStep};
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// The bearer token handed out on a successful login
+const TOKEN: &str = "mock-session-token";
+
+/// How the server misbehaves for a request
+#[derive(Debug, Clone, Copy)]
+pub enum Failure {
+    /// Answer with this status code
+    Status(u16),
+    /// Close the connection without answering
+    Disconnect,
+}
+
+#[derive(Default)]
+struct State {
+    accounts: BTreeMap<String, Vec<String>>,
+    groups: HashMap<String, Vec<String>>,
+    deny_anonymous: bool,
+    failures: VecDeque<Failure>,
+    requests: Vec<String>,
+}
+
+/// What to answer a request with
+enum Reply {
+    Json(StatusCode, Vec<u8>),
+    Close,
+}
+
+fn json(status: StatusCode, value: &impl Serialize) -> Reply {
+    Reply::Json(
+        status,
+        serde_json::to_vec(value).expect("replies serialize"),
+    )
+}
+
+fn not_found() -> Reply {
+    json(StatusCode::NOT_FOUND, &OperationError::NoMatchingEntries)
+}
+
+impl State {
+    fn reply(&mut self, method: &str, path: &str, token: Option<&str>, body: &[u8]) -> Reply {
+        self.requests.push(format!("{method} {path}"));
+
+        match self.failures.pop_front() {
+            Some(Failure::Status(status)) => {
+                let status = StatusCode::from_u16(status).expect("failures use valid statuses");
+                return json(status, &());
+            }
+            Some(Failure::Disconnect) => return Reply::Close,
+            None => {}
+        }
+
+        if (method, path) == ("POST", "/v1/auth") {
+            return self.auth(body);
+        }
+        if method != "GET" {
+            return json(StatusCode::METHOD_NOT_ALLOWED, &());
+        }
+        if path == "/status" {
+            return json(StatusCode::OK, &true);
+        }
+        if token != Some(TOKEN) {
+            return json(StatusCode::UNAUTHORIZED, &OperationError::NotAuthenticated);
+        }
+
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        match segments.as_slice() {
+            ["v1", "account", id, "_ssh_pubkeys"] => match self.accounts.get(*id) {
+                Some(keys) => json(StatusCode::OK, keys),
+                None => not_found(),
+            },
+            ["v1", "group", id, "_attr", "member"] => match self.groups.get(*id) {
+                Some(members) => json(StatusCode::OK, &Some(members)),
+                None => not_found(),
+            },
+            ["v1", "self"] => json(
+                StatusCode::OK,
+                &serde_json::json!({
+                    "youare": { "attrs": { "spn": ["anonymous@idm.example.com"] } },
+                }),
+            ),
+            ["v1", "person"] => {
+                let entries: Vec<_> = self
+                    .accounts
+                    .iter()
+                    .map(|(id, keys)| {
+                        let tagged: Vec<String> = (1..)
+                            .zip(keys)
+                            .map(|(n, key)| format!("key{n}: {key}"))
+                            .collect();
+                        serde_json::json!({
+                            "attrs": { "name": [id], "ssh_publickey": tagged },
+                        })
+                    })
+                    .collect();
+                json(StatusCode::OK, &entries)
+            }
+            _ => not_found(),
+        }
+    }
+
+    /// The anonymous login: init, begin and a single credential step
+    fn auth(&self, body: &[u8]) -> Reply {
+        let Ok(request) = serde_json::from_slice::<AuthRequest>(body) else {
+            return json(StatusCode::BAD_REQUEST, &());
+        };
+
+        let state = match request.step {
+            AuthStep::Init(_) | AuthStep::Init2 { .. } if self.deny_anonymous => {
+                AuthState::Choose(vec![])
+            }
+            AuthStep::Init(_) | AuthStep::Init2 { .. } => {
+                AuthState::Choose(vec![AuthMech::Anonymous])
+            }
+            AuthStep::Begin(AuthMech::Anonymous) => {
+                AuthState::Continue(vec![AuthAllowed::Anonymous])
+            }
+            AuthStep::Cred(AuthCredential::Anonymous) => AuthState::Success(TOKEN.to_string()),
+            _ => AuthState::Denied("only anonymous logins are supported".to_string()),
+        };
+
+        json(
+            StatusCode::OK,
+            &serde_json::json!({
+                "sessionid": "00000000-0000-0000-0000-000000000000",
+                "state": state,
+            }),
+        )
+    }
+}
+
+/// A running stub server, stopped when the test's runtime shuts down
+pub struct MockServer {
+    url: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockServer {
+    /// Listen on a free local port
+    pub async fn start() -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("binding a local port");
+        let url = format!("http://{}", listener.local_addr().expect("bound address"));
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let accepting = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, accepting.clone()));
+            }
+        });
+
+        MockServer { url, state }
+    }
+
+    /// The address to point `--host` to
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Add an account with these ssh keys
+    pub fn add_account(&self, id: &str, keys: &[&str]) {
+        let keys = keys.iter().map(|k| k.to_string()).collect();
+        self.state
+            .lock()
+            .unwrap()
+            .accounts
+            .insert(id.to_string(), keys);
+    }
+
+    /// Add a group with these members
+    pub fn add_group(&self, group: &str, members: &[&str]) {
+        let members = members.iter().map(|m| m.to_string()).collect();
+        self.state
+            .lock()
+            .unwrap()
+            .groups
+            .insert(group.to_string(), members);
+    }
+
+    /// Stop offering anonymous logins
+    pub fn deny_anonymous(&self) {
+        self.state.lock().unwrap().deny_anonymous = true;
+    }
+
+    /// Fail the next request, failures queue up in order
+    pub fn fail_next(&self, failure: Failure) {
+        self.state.lock().unwrap().failures.push_back(failure);
+    }
+
+    /// The requests served so far, as `METHOD /path`
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+/// Answer the requests of one keep-alive connection
+async fn serve(stream: TcpStream, state: Arc<Mutex<State>>) {
+    let mut stream = BufReader::new(stream);
+    let version = KanidmClientBuilder::user_agent()
+        .split_once('/')
+        .map_or("", |(_, version)| version);
+
+    loop {
+        let mut request_line = String::new();
+        if stream.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+            return;
+        }
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let path = parts.next().unwrap_or_default().to_string();
+
+        let mut content_length = 0;
+        let mut token = None;
+        loop {
+            let mut header = String::new();
+            if stream.read_line(&mut header).await.unwrap_or(0) == 0 {
+                return;
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let Some((name, value)) = header.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.parse().unwrap_or(0),
+                "authorization" => token = value.strip_prefix("Bearer ").map(str::to_string),
+                _ => {}
+            }
+        }
+
+        let mut body = vec![0; content_length];
+        if stream.read_exact(&mut body).await.is_err() {
+            return;
+        }
+
+        let reply = state
+            .lock()
+            .unwrap()
+            .reply(&method, &path, token.as_deref(), &body);
+        let Reply::Json(status, body) = reply else {
+            return;
+        };
+
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+             X-KANIDM-VERSION: {version}\r\nX-KANIDM-OPID: mock\r\n\r\n",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or_default(),
+            body.len(),
+        );
+        let stream = stream.get_mut();
+        if stream.write_all(head.as_bytes()).await.is_err()
+            || stream.write_all(&body).await.is_err()
+        {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::Cli;
+
+    const ALICE: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKhtiB5ZKct7eqPkIZBIkubYHjEVoOhM7ufiAisize+o alice";
+    const BOB: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKhtiB5ZKct7eqPkIZBIkubYHjEVoOhM7ufiAisize+o bob";
+
+    fn cli(server: &MockServer, args: &[&str]) -> Cli {
+        Cli::parse_from(
+            ["kanidm_sshkey_fetcher", "-H", server.url()]
+                .into_iter()
+                .chain(args.iter().copied()),
+        )
+    }
+
+    /// Connect and log in like a normal run does
+    async fn run(args: &Cli) -> (Vec<(String, Option<Vec<String>>)>, bool) {
+        let client = crate::build_configured_client(args).expect("client builds");
+        crate::authenticate(&client, args).await;
+        crate::source::fetch_all(&client, args, None, |_, _| {}).await
+    }
+
+    #[tokio::test]
+    async fn fetches_keys_after_anonymous_login() {
+        let server = MockServer::start().await;
+        server.add_account("alice", &[ALICE]);
+
+        let (fetched, complete) = run(&cli(&server, &["alice", "carol"])).await;
+
+        assert!(complete);
+        assert_eq!(
+            fetched,
+            vec![
+                ("alice".to_string(), Some(vec![ALICE.to_string()])),
+                ("carol".to_string(), None),
+            ]
+        );
+        assert_eq!(
+            server
+                .requests()
+                .iter()
+                .filter(|r| *r == "POST /v1/auth")
+                .count(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_login_resolves_nothing() {
+        let server = MockServer::start().await;
+        server.add_account("alice", &[ALICE]);
+        server.deny_anonymous();
+
+        let (fetched, _) = run(&cli(&server, &["alice"])).await;
+
+        assert_eq!(fetched, vec![("alice".to_string(), None)]);
+    }
+
+    #[tokio::test]
+    async fn expands_groups() {
+        let server = MockServer::start().await;
+        server.add_account("alice", &[ALICE]);
+        server.add_account("bob", &[BOB]);
+        server.add_group("admins", &["alice", "bob"]);
+
+        let (fetched, complete) = run(&cli(&server, &["-g", "admins", "-g", "gone"])).await;
+
+        assert!(!complete);
+        let ids: Vec<&str> = fetched.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, ["alice", "bob"]);
+    }
+
+    #[tokio::test]
+    async fn batches_through_the_person_list() {
+        let server = MockServer::start().await;
+        server.add_account("alice", &[ALICE]);
+        server.add_account("bob", &[BOB]);
+
+        let args = cli(&server, &["alice", "bob", "--batch-threshold", "2"]);
+        let (fetched, _) = run(&args).await;
+
+        assert!(fetched.iter().all(|(_, keys)| keys.is_some()));
+        let requests = server.requests();
+        assert!(requests.contains(&"GET /v1/person".to_string()));
+        assert!(!requests.iter().any(|r| r.ends_with("_ssh_pubkeys")));
+    }
+
+    #[tokio::test]
+    async fn next_run_recovers_from_server_errors() {
+        let server = MockServer::start().await;
+        server.add_account("alice", &[ALICE]);
+        server.add_account("bob", &[BOB]);
+        let args = cli(&server, &["alice", "bob"]);
+        let client = crate::build_configured_client(&args).expect("client builds");
+        crate::authenticate(&client, &args).await;
+
+        server.fail_next(Failure::Status(500));
+        let (fetched, _) = crate::source::fetch_all(&client, &args, None, |_, _| {}).await;
+        assert_eq!(fetched[0].1, None);
+        assert!(fetched[1].1.is_some());
+
+        server.fail_next(Failure::Disconnect);
+        let (fetched, _) = crate::source::fetch_all(&client, &args, None, |_, _| {}).await;
+        assert_eq!(fetched[0].1, None);
+
+        let (fetched, _) = crate::source::fetch_all(&client, &args, None, |_, _| {}).await;
+        assert!(fetched.iter().all(|(_, keys)| keys.is_some()));
+    }
+
+    #[tokio::test]
+    async fn logs_in_again_when_the_session_expires() {
+        let server = MockServer::start().await;
+        server.add_account("alice", &[ALICE]);
+        server.add_account("bob", &[BOB]);
+        let args = cli(&server, &["alice", "bob"]);
+        let client = crate::build_configured_client(&args).expect("client builds");
+        crate::authenticate(&client, &args).await;
+        let sources = crate::source::Sources::new(&client, &args).expect("the API is a source");
+
+        server.fail_next(Failure::Status(401));
+        let (fetched, _) = crate::source::fetch_all(&sources, &args, None, |_, _| {}).await;
+        assert!(fetched.iter().all(|(_, keys)| keys.is_some()));
+        let logins = |server: &MockServer| {
+            server
+                .requests()
+                .iter()
+                .filter(|r| *r == "POST /v1/auth")
+                .count()
+        };
+        assert_eq!(logins(&server), 6);
+
+        // Without a login, only the request that was refused fails
+        server.deny_anonymous();
+        server.fail_next(Failure::Status(401));
+        server.fail_next(Failure::Status(401));
+        let (fetched, _) = crate::source::fetch_all(&sources, &args, None, |_, _| {}).await;
+        assert_eq!(fetched[0].1, None);
+        assert!(fetched[1].1.is_some());
+    }
+
+    #[tokio::test]
+    async fn reuses_the_persisted_session() {
+        let server = MockServer::start().await;
+        server.add_account("alice", &[ALICE]);
+        let state_dir = std::env::temp_dir().join(format!(
+            "kanidm_sshkey_fetcher-session-{}",
+            std::process::id()
+        ));
+        let args = cli(
+            &server,
+            &[
+                "alice",
+                "--persist-session",
+                "--state-dir",
+                state_dir.to_str().unwrap(),
+            ],
+        );
+        let logins = || {
+            server
+                .requests()
+                .iter()
+                .filter(|r| *r == "POST /v1/auth")
+                .count()
+        };
+
+        let (fetched, _) = run(&args).await;
+        assert!(fetched[0].1.is_some());
+        assert_eq!(logins(), 3);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(state_dir.join("session"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let (fetched, _) = run(&args).await;
+        assert!(fetched[0].1.is_some());
+        assert_eq!(logins(), 3);
+        let _ = std::fs::remove_dir_all(&state_dir);
+    }
+
+    #[tokio::test]
+    async fn answers_from_the_cache_alone() {
+        let server = MockServer::start().await;
+        server.add_account("alice", &[ALICE]);
+        let path = std::env::temp_dir().join(format!(
+            "kanidm_sshkey_fetcher-warm-{}.db",
+            std::process::id()
+        ));
+        let cache = crate::cache::Cache::open(&path, 300, 60, None).expect("cache opens");
+        let args = cli(&server, &["alice", "carol"]);
+
+        assert!(!crate::source::answered_by_cache(&args, &cache));
+        let client = crate::build_configured_client(&args).expect("client builds");
+        crate::authenticate(&client, &args).await;
+        crate::source::fetch_all(&client, &args, Some(&cache), |_, _| {}).await;
+
+        assert!(crate::source::answered_by_cache(&args, &cache));
+        assert!(!crate::source::answered_by_cache(
+            &cli(&server, &["alice", "-g", "admins"]),
+            &cache
+        ));
+        let (fetched, _) =
+            crate::source::fetch_all(&crate::source::CacheOnly, &args, Some(&cache), |_, _| {})
+                .await;
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(
+            fetched,
+            vec![
+                ("alice".to_string(), Some(vec![ALICE.to_string()])),
+                ("carol".to_string(), None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_open_with_cached_keys() {
+        let server = MockServer::start().await;
+        server.add_account("alice", &[ALICE]);
+        let path = std::env::temp_dir().join(format!(
+            "kanidm_sshkey_fetcher-stale-{}.db",
+            std::process::id()
+        ));
+        // Entries expire at once, so only the failure policy serves them
+        let cache = crate::cache::Cache::open(&path, 0, 0, None).expect("cache opens");
+        let closed = cli(&server, &["alice"]);
+        let open = cli(&server, &["alice", "--on-server-failure", "open"]);
+        let client = crate::build_configured_client(&closed).expect("client builds");
+        crate::authenticate(&client, &closed).await;
+
+        let (fetched, _) =
+            crate::source::fetch_all(&client, &closed, Some(&cache), |_, _| {}).await;
+        assert!(fetched[0].1.is_some());
+
+        server.fail_next(Failure::Status(500));
+        let (fetched, _) =
+            crate::source::fetch_all(&client, &closed, Some(&cache), |_, _| {}).await;
+        assert_eq!(fetched[0].1, None);
+
+        server.fail_next(Failure::Status(500));
+        let (fetched, _) = crate::source::fetch_all(&client, &open, Some(&cache), |_, _| {}).await;
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(fetched[0].1, Some(vec![ALICE.to_string()]));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_fallback_file() {
+        let server = MockServer::start().await;
+        server.add_account("alice@idm.example.com", &[ALICE]);
+        let dir = std::env::temp_dir().join(format!(
+            "kanidm_sshkey_fetcher-fallback-{}",
+            std::process::id()
+        ));
+        let dir_arg = dir.to_str().unwrap();
+        let args = cli(
+            &server,
+            &[
+                "alice@idm.example.com",
+                "--fallback-dir",
+                dir_arg,
+                "--on-server-failure",
+                "open",
+            ],
+        );
+        let client = crate::build_configured_client(&args).expect("client builds");
+        crate::authenticate(&client, &args).await;
+
+        crate::source::fetch_all(&client, &args, None, |_, _| {}).await;
+        assert!(dir.join("alice").exists());
+
+        server.fail_next(Failure::Disconnect);
+        let (fetched, _) = crate::source::fetch_all(&client, &args, None, |_, _| {}).await;
+        assert_eq!(fetched[0].1, Some(vec![ALICE.to_string()]));
+
+        let mut too_old = args;
+        too_old.max_staleness = Some(0);
+        std::fs::File::options()
+            .append(true)
+            .open(dir.join("alice"))
+            .and_then(|f| f.set_modified(std::time::SystemTime::UNIX_EPOCH))
+            .unwrap();
+        server.fail_next(Failure::Disconnect);
+        let (fetched, _) = crate::source::fetch_all(&client, &too_old, None, |_, _| {}).await;
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(fetched[0].1, None);
+    }
+
+    #[tokio::test]
+    async fn syncs_authorized_keys() {
+        let server = MockServer::start().await;
+        server.add_account("alice", &[ALICE]);
+        let home =
+            std::env::temp_dir().join(format!("kanidm_sshkey_fetcher-{}", std::process::id()));
+        let home_arg = home.to_str().unwrap();
+        let state_dir = home.join("state");
+
+        let args = cli(
+            &server,
+            &[
+                "alice",
+                "-m",
+                "--home-dir",
+                home_arg,
+                "--state-dir",
+                state_dir.to_str().unwrap(),
+            ],
+        );
+        let (fetched, complete) = run(&args).await;
+        let results = crate::source::Fetched {
+            fetched,
+            complete,
+            static_keys: vec![],
+            posix_ids: Default::default(),
+            origins: Default::default(),
+        };
+        crate::write_results(&args, &results, std::time::Instant::now()).expect("keys are written");
+
+        let written = std::fs::read_to_string(home.join(".ssh/authorized_keys")).unwrap();
+        let _ = std::fs::remove_dir_all(&home);
+        assert!(written.contains(ALICE));
+    }
+
+    #[tokio::test]
+    async fn remembers_keys_only_once_written() {
+        let server = MockServer::start().await;
+        server.add_account("alice", &[ALICE]);
+        let home = std::env::temp_dir().join(format!(
+            "kanidm_sshkey_fetcher-unwritten-{}",
+            std::process::id()
+        ));
+        let state_dir = home.join("state");
+        std::fs::create_dir_all(&home).unwrap();
+        // Not a directory, so authorized_keys can't be written into it
+        std::fs::write(home.join(".ssh"), "").unwrap();
+
+        let args = cli(
+            &server,
+            &[
+                "alice",
+                "-m",
+                "--home-dir",
+                home.to_str().unwrap(),
+                "--state-dir",
+                state_dir.to_str().unwrap(),
+            ],
+        );
+        let (fetched, complete) = run(&args).await;
+        let results = crate::source::Fetched {
+            fetched,
+            complete,
+            static_keys: vec![],
+            posix_ids: Default::default(),
+            origins: Default::default(),
+        };
+        let failed = crate::write_results(&args, &results, std::time::Instant::now());
+        let remembered_after_failure = crate::state::State::load(&state_dir)
+            .key_checksums
+            .contains_key("alice");
+
+        std::fs::remove_file(home.join(".ssh")).unwrap();
+        let written = crate::write_results(&args, &results, std::time::Instant::now());
+        let remembered = crate::state::State::load(&state_dir)
+            .key_checksums
+            .contains_key("alice");
+        let _ = std::fs::remove_dir_all(&home);
+
+        assert_eq!(failed, Err(()));
+        assert!(!remembered_after_failure);
+        assert_eq!(written, Ok(()));
+        assert!(remembered);
+    }
+
+    #[tokio::test]
+    async fn imports_exported_bundles() {
+        use ssh_key::rand_core::OsRng;
+        use ssh_key::{Algorithm, LineEnding, PrivateKey};
+
+        let server = MockServer::start().await;
+        server.add_account("alice", &[ALICE]);
+        let dir = std::env::temp_dir().join(format!(
+            "kanidm_sshkey_fetcher-import-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let signer = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        signer
+            .write_openssh_file(&dir.join("signer"), LineEnding::LF)
+            .unwrap();
+        std::fs::write(
+            dir.join("signer.pub"),
+            signer.public_key().to_openssh().unwrap(),
+        )
+        .unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+        let export = cli(
+            &server,
+            &[
+                "alice",
+                "export",
+                "--bundle",
+                &path("keys.bundle"),
+                "--signing-key",
+                &path("signer"),
+            ],
+        );
+        let Some(crate::Command::Export(export_args)) = &export.command else {
+            unreachable!("parsed as export")
+        };
+        let client = crate::build_configured_client(&export).expect("client builds");
+        crate::authenticate(&client, &export).await;
+        crate::export::export(&client, &export, export_args)
+            .await
+            .expect("the bundle is exported");
+
+        let import = Cli::parse_from([
+            "kanidm_sshkey_fetcher",
+            "--key-dir",
+            &path("keys"),
+            "--state-dir",
+            &path("state"),
+            "import",
+            "--bundle",
+            &path("keys.bundle"),
+            "--trusted-keys",
+            &path("signer.pub"),
+        ]);
+        let Some(crate::Command::Import(import_args)) = &import.command else {
+            unreachable!("parsed as import")
+        };
+        crate::bundle::import(&import, import_args, std::time::Instant::now())
+            .expect("the bundle is imported");
+
+        let written = std::fs::read_to_string(dir.join("keys/alice")).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(written, format!("{ALICE}\n"));
+    }
+
+    #[tokio::test]
+    async fn refuses_to_export_bundles_missing_groups() {
+        use ssh_key::rand_core::OsRng;
+        use ssh_key::{Algorithm, LineEnding, PrivateKey};
+
+        let server = MockServer::start().await;
+        server.add_account("alice", &[ALICE]);
+        server.add_group("admins", &["alice"]);
+        let dir = std::env::temp_dir().join(format!(
+            "kanidm_sshkey_fetcher-export-incomplete-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        PrivateKey::random(&mut OsRng, Algorithm::Ed25519)
+            .unwrap()
+            .write_openssh_file(&dir.join("signer"), LineEnding::LF)
+            .unwrap();
+        let bundle = dir.join("keys.bundle");
+
+        let export = cli(
+            &server,
+            &[
+                "-g",
+                "admins",
+                "-g",
+                "gone",
+                "export",
+                "--bundle",
+                bundle.to_str().unwrap(),
+                "--signing-key",
+                dir.join("signer").to_str().unwrap(),
+            ],
+        );
+        let Some(crate::Command::Export(export_args)) = &export.command else {
+            unreachable!("parsed as export")
+        };
+        let client = crate::build_configured_client(&export).expect("client builds");
+        crate::authenticate(&client, &export).await;
+        let exported = crate::export::export(&client, &export, export_args).await;
+
+        let written = bundle.exists();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(exported, Err(()));
+        assert!(!written);
+    }
+
+    #[tokio::test]
+    async fn applies_staged_plans() {
+        let server = MockServer::start().await;
+        server.add_account("alice", &[ALICE]);
+        let home =
+            std::env::temp_dir().join(format!("kanidm_sshkey_fetcher-plan-{}", std::process::id()));
+        let home_arg = home.to_str().unwrap();
+        let state_dir = home.join("state");
+        let authorized_keys = home.join(".ssh/authorized_keys");
+
+        let args = cli(
+            &server,
+            &[
+                "alice",
+                "-m",
+                "--home-dir",
+                home_arg,
+                "--state-dir",
+                state_dir.to_str().unwrap(),
+                "apply",
+            ],
+        );
+        let Some(crate::Command::Apply(apply_args)) = &args.command else {
+            unreachable!("parsed as apply")
+        };
+        let fetch_args = crate::plan::FetchArgs::default();
+        let stage = || async {
+            let (fetched, complete) = run(&args).await;
+            let results = crate::source::Fetched {
+                fetched,
+                complete,
+                static_keys: vec![],
+                posix_ids: Default::default(),
+                origins: Default::default(),
+            };
+            crate::plan::stage(&args, &fetch_args, &results).expect("the plan is staged");
+        };
+
+        stage().await;
+        assert!(!authorized_keys.exists());
+        crate::plan::apply(&args, apply_args, std::time::Instant::now())
+            .expect("the plan is applied");
+        assert!(
+            std::fs::read_to_string(&authorized_keys)
+                .unwrap()
+                .contains(ALICE)
+        );
+
+        // A plan reviewed against other keys than the file has now is refused
+        server.add_account("alice", &[BOB]);
+        stage().await;
+        std::fs::write(&authorized_keys, "").unwrap();
+        let applied = crate::plan::apply(&args, apply_args, std::time::Instant::now());
+        let _ = std::fs::remove_dir_all(&home);
+        assert!(applied.is_err());
+    }
+
+    #[tokio::test]
+    async fn doctor_checks_the_server() {
+        let server = MockServer::start().await;
+        server.add_account("alice", &[ALICE]);
+        let args = cli(&server, &["alice"]);
+
+        assert!(crate::doctor::doctor(&args).await.is_ok());
+
+        server.deny_anonymous();
+        assert!(crate::doctor::doctor(&args).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pings_the_server() {
+        let server = MockServer::start().await;
+        let args = cli(&server, &[]);
+        let client = crate::build_configured_client(&args).expect("client builds");
+
+        assert!(crate::ping::ping(&client, &args).await.is_err());
+
+        crate::authenticate(&client, &args).await;
+        assert!(crate::ping::ping(&client, &args).await.is_ok());
+        assert!(server.requests().contains(&"GET /v1/self".to_string()));
+    }
+}
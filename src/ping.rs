@@ -0,0 +1,56 @@
+use std::time::Instant;
+
+use kanidm_client::{ClientError, KanidmClient};
+use kanidm_proto::constants::ATTR_SPN;
+
+use crate::Cli;
+use crate::diagnostic::Error;
+
+/// Check that the server answers and the credentials are accepted
+///
+/// Authentication already happened before, `whoami` only succeeds if it worked.
+pub async fn ping(client: &KanidmClient, args: &Cli) -> Result<(), ()> {
+    let token = args.token.is_some();
+    let url = client.get_url();
+
+    let start = Instant::now();
+    let version = crate::version::server_version(client)
+        .await
+        .map_err(|e| {
+            Error::new("ping::connect", "Failed to reach the server")
+                .url(&url)
+                .cause(e)
+                .report()
+        })?
+        .unwrap_or_else(|| "unknown".to_string());
+    let latency = start.elapsed();
+
+    let entry = client
+        .whoami()
+        .await
+        .map_err(|e| {
+            Error::new("ping::auth", "Failed to authenticate")
+                .url(&url)
+                .maybe_help(crate::auth_hint(&e, token))
+                .cause(e)
+                .report()
+        })?
+        .ok_or_else(|| {
+            Error::new("ping::auth", "Not authenticated")
+                .url(&url)
+                .maybe_help(crate::auth_hint(&ClientError::Unauthorized, token))
+                .report()
+        })?;
+    let identity = entry
+        .attrs
+        .get(ATTR_SPN)
+        .and_then(|v| v.first())
+        .map_or("unknown", String::as_str);
+
+    println!("Server:   {}", url);
+    println!("Version:  {}", version);
+    println!("Latency:  {} ms", latency.as_millis());
+    println!("Identity: {}", identity);
+
+    Ok(())
+}
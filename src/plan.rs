@@ -0,0 +1,561 @@
+//! Staging what a run would write, so it can be reviewed before it is applied
+//!
+//! `fetch` fetches like a normal run, but only saves the results as a plan and prints the keys
+//! it would add to and remove from each file. `apply` later writes the plan as the normal run
+//! would have, refusing to if any of those files changed in the meantime, since the review
+//! would no longer hold. `--dry-run` prints the same changes without staging anything, and
+//! `--interactive` prints them and asks before writing.
+//!
+//! With `--json` the changes are printed as one JSON object for CI to attach to a change
+//! ticket, with the keys each account gains and loses in each file, their fingerprints, and
+//! the source each added key was fetched from:
+//!
+//! ```json
+//! {"files": [{"path": "/home/alice/.ssh/authorized_keys", "accounts": [{"account": "alice",
+//!   "added": [{"key": "ssh-ed25519 AAAA... alice@laptop", "fingerprint": "SHA256:...",
+//!   "source": {"kind": "kanidm", "from": "https://idm.example.com/"}}],
+//!   "removed": []}]}]}
+//! ```
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::Cli;
+use crate::diagnostic::Error;
+use crate::source::{Fetched, Origin, key_lines};
+use crate::state::State;
+
+/// The plan file in the state directory if `--plan` is not given
+const DEFAULT_PLAN_FILE: &str = "plan.json";
+
+#[derive(Debug, Clone, Default, Args)]
+pub struct FetchArgs {
+    /// Where to save the plan, defaults to plan.json in the state directory
+    #[arg(long, value_parser)]
+    plan: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ApplyArgs {
+    /// The plan to apply, defaults to plan.json in the state directory
+    #[arg(long, value_parser)]
+    plan: Option<PathBuf>,
+}
+
+/// What `fetch` saves for `apply`
+#[derive(Debug, Serialize, Deserialize)]
+struct Plan {
+    /// When the plan was staged, in seconds since the epoch
+    created: i64,
+    results: Fetched,
+    /// The checksum of the keys each file the plan changes had when it was staged
+    files: BTreeMap<String, String>,
+}
+
+/// The keys of a file before and after a write
+#[derive(Debug, PartialEq, Eq)]
+pub struct FileChange {
+    pub path: PathBuf,
+    /// The account all keys of the file belong to, e.g. for a file in `--key-dir`
+    pub account: Option<String>,
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+impl FileChange {
+    /// The change, `None` if the keys stay the same
+    fn new(
+        path: PathBuf,
+        account: Option<&str>,
+        before: Vec<String>,
+        after: Vec<String>,
+    ) -> Option<FileChange> {
+        (before != after).then(|| FileChange {
+            path,
+            account: account.map(String::from),
+            before,
+            after,
+        })
+    }
+
+    pub fn added(&self) -> impl Iterator<Item = &String> {
+        self.after.iter().filter(|key| !self.before.contains(key))
+    }
+
+    pub fn removed(&self) -> impl Iterator<Item = &String> {
+        self.before.iter().filter(|key| !self.after.contains(key))
+    }
+
+    /// The checksum of the keys before the write, to tell whether the file changed since
+    fn checksum(&self) -> String {
+        crate::state::checksum(self.before.join("\n").as_bytes())
+    }
+}
+
+/// The keys in a file, none if it doesn't exist
+fn read_keys(path: &Path, managed_block: bool) -> Result<Vec<String>, ()> {
+    let content = match std::fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            Error::new(
+                "plan::read_target",
+                "Failed to read a file the keys are written to",
+            )
+            .file(path)
+            .cause(e)
+            .report();
+            return Err(());
+        }
+    };
+    let content = if managed_block {
+        crate::authorized_keys::managed_block(&content).unwrap_or_default()
+    } else {
+        &content
+    };
+    Ok(key_lines(&String::from_utf8_lossy(content)).collect())
+}
+
+/// The files writing `results` would change, as [`crate::write_results`] writes them
+pub fn file_changes(args: &Cli, results: &Fetched) -> Result<Vec<FileChange>, ()> {
+    let mut changes = Vec::new();
+
+    for (target_args, keys) in crate::authorized_keys_targets(args, results)? {
+        let path = crate::authorized_keys::authorized_keys_path(&target_args)?;
+        let before = read_keys(&path, true)?;
+        let after = key_lines(&keys.join("\n")).collect();
+        changes.extend(FileChange::new(path, None, before, after));
+    }
+
+    if let Some(dir) = &args.key_dir {
+        let users = crate::usermap::UserMap::load(args, &results.posix_ids)?;
+        let mut names = Vec::new();
+        let mut failed = false;
+        for (id, keys) in &results.fetched {
+            let name = users.local_user(id);
+            names.push(name);
+            // Accounts that failed to fetch keep their previous file
+            let Some(keys) = keys else {
+                failed = true;
+                continue;
+            };
+            let path = dir.join(name);
+            let before = read_keys(&path, false)?;
+            changes.extend(FileChange::new(path, Some(id), before, keys.clone()));
+        }
+
+        // The files sync_key_dir prunes
+        let entries = std::fs::read_dir(dir).into_iter().flatten().flatten();
+        for entry in entries.filter(|_| results.complete && !failed) {
+            let file_name = entry.file_name();
+            let Some(file_name) = file_name.to_str() else {
+                continue;
+            };
+            if file_name.starts_with('.') || names.contains(&file_name) {
+                continue;
+            }
+            let before = read_keys(&entry.path(), false)?;
+            changes.extend(FileChange::new(entry.path(), None, before, Vec::new()));
+        }
+    }
+
+    Ok(changes)
+}
+
+/// A key in the JSON plan
+#[derive(Debug, Serialize)]
+struct PlannedKey<'a> {
+    key: &'a str,
+    /// The SHA256 fingerprint, `None` for a line that is not a valid public key
+    fingerprint: Option<String>,
+    /// Where an added key was fetched from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<Origin>,
+}
+
+impl<'a> PlannedKey<'a> {
+    fn new(key: &'a str, source: Option<Origin>) -> PlannedKey<'a> {
+        PlannedKey {
+            key,
+            fingerprint: crate::keys::describe_key(key)
+                .ok()
+                .map(|info| info.fingerprint),
+            source,
+        }
+    }
+}
+
+/// The keys an account gains and loses in a file, `account` is `None` for static keys and
+/// keys of accounts no longer known
+#[derive(Debug, Serialize)]
+struct AccountPlan<'a> {
+    account: Option<&'a str>,
+    added: Vec<PlannedKey<'a>>,
+    removed: Vec<PlannedKey<'a>>,
+}
+
+impl<'a> AccountPlan<'a> {
+    /// The plan of `account` in `accounts`, added if it has none yet
+    fn of<'m>(
+        accounts: &'m mut BTreeMap<Option<&'a str>, AccountPlan<'a>>,
+        account: Option<&'a str>,
+    ) -> &'m mut AccountPlan<'a> {
+        accounts.entry(account).or_insert_with(|| AccountPlan {
+            account,
+            added: Vec::new(),
+            removed: Vec::new(),
+        })
+    }
+}
+
+/// The JSON plan, what each file gains and loses by account
+///
+/// Added keys belong to the account they were fetched for, and name the source they were fetched
+/// from, removed keys belong to the account they were last fetched for according to the state.
+fn json_plan(changes: &[FileChange], results: &Fetched, state: &State) -> serde_json::Value {
+    let origins = results.key_origins();
+    let added_by = |key: &String| {
+        results
+            .fetched
+            .iter()
+            .find(|(_, keys)| keys.as_ref().is_some_and(|keys| keys.contains(key)))
+            .map(|(id, _)| id.as_str())
+    };
+    let removed_by = |key: &String| {
+        let checksum = crate::state::checksum(key.as_bytes());
+        state
+            .key_set_checksums
+            .iter()
+            .find(|(_, checksums)| checksums.contains(&checksum))
+            .map(|(id, _)| id.as_str())
+    };
+
+    let files: Vec<serde_json::Value> = changes
+        .iter()
+        .map(|change| {
+            let mut accounts: BTreeMap<Option<&str>, AccountPlan> = BTreeMap::new();
+            for key in change.added() {
+                let account = change.account.as_deref().or_else(|| added_by(key));
+                AccountPlan::of(&mut accounts, account)
+                    .added
+                    .push(PlannedKey::new(key, origins.get(key.as_str()).cloned()));
+            }
+            for key in change.removed() {
+                let account = change.account.as_deref().or_else(|| removed_by(key));
+                AccountPlan::of(&mut accounts, account)
+                    .removed
+                    .push(PlannedKey::new(key, None));
+            }
+            serde_json::json!({
+                "path": change.path.display().to_string(),
+                "accounts": accounts.into_values().collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+    serde_json::json!({ "files": files })
+}
+
+/// Print the keys each file gains and loses, as a JSON plan with `--json`
+fn print_changes(args: &Cli, changes: &[FileChange], results: &Fetched) {
+    if args.json {
+        let state = State::load(&crate::state::state_dir(args));
+        println!("{}", json_plan(changes, results, &state));
+        return;
+    }
+
+    if changes.is_empty() {
+        println!("No keys would change");
+        return;
+    }
+    let color = crate::diagnostic::colored(std::io::stdout().is_terminal());
+    for change in changes {
+        println!("{}", paint(color, BOLD, &change.path.display().to_string()));
+        change
+            .removed()
+            .for_each(|key| println!("{}", paint(color, RED, &format!("- {key}"))));
+        change
+            .added()
+            .for_each(|key| println!("{}", paint(color, GREEN, &format!("+ {key}"))));
+    }
+}
+
+const BOLD: &str = "1";
+const RED: &str = "31";
+const GREEN: &str = "32";
+
+/// `text` in the ANSI style `style` if `color`
+fn paint(color: bool, style: &str, text: &str) -> String {
+    if color {
+        format!("\x1b[{style}m{text}\x1b[0m")
+    } else {
+        text.to_string()
+    }
+}
+
+/// Fail if no file would be written, as with neither `-m` nor `--key-dir`
+fn check_writes(args: &Cli) -> Result<(), ()> {
+    if !args.modify && args.key_dir.is_none() {
+        Error::new("plan::nothing", "Nothing to plan, no file is written")
+            .help("pass -m or --key-dir like the run the plan is for")
+            .report();
+        return Err(());
+    }
+    Ok(())
+}
+
+/// Print what writing the results of `--dry-run` would change, writing nothing
+pub fn dry_run(args: &Cli, results: &Fetched) -> Result<(), ()> {
+    check_writes(args)?;
+    let changes = file_changes(args, results)?;
+    print_changes(args, &changes, results);
+    Ok(())
+}
+
+fn plan_path(args: &Cli, plan: Option<&PathBuf>) -> PathBuf {
+    plan.cloned()
+        .unwrap_or_else(|| crate::state::state_dir(args).join(DEFAULT_PLAN_FILE))
+}
+
+/// Print what writing the results would change and write them once confirmed on the terminal
+///
+/// Without a terminal to ask on nothing is written, rather than writing unconfirmed changes.
+pub fn confirm(args: &Cli, results: &Fetched, started: Instant) -> Result<(), ()> {
+    check_writes(args)?;
+    let changes = file_changes(args, results)?;
+    if changes.is_empty() {
+        return crate::write_results(args, results, started);
+    }
+    if !std::io::stdin().is_terminal() {
+        Error::new("plan::interactive", "Cannot ask whether to write the keys")
+            .help("run --interactive from a terminal, or review a staged plan with fetch")
+            .report();
+        return Err(());
+    }
+
+    print_changes(args, &changes, results);
+    eprint!("Write these changes? [y/N] ");
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    if let Err(e) = std::io::stdin().lock().read_line(&mut answer) {
+        Error::new("plan::interactive", "Failed to read the answer")
+            .cause(e)
+            .report();
+        return Err(());
+    }
+
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => crate::write_results(args, results, started),
+        _ => {
+            info!("Not writing the keys");
+            Ok(())
+        }
+    }
+}
+
+/// Save the results of `fetch` as a plan, printing what applying it would change
+pub fn stage(args: &Cli, fetch: &FetchArgs, results: &Fetched) -> Result<(), ()> {
+    check_writes(args)?;
+    let changes = file_changes(args, results)?;
+    print_changes(args, &changes, results);
+
+    let plan = Plan {
+        created: time::OffsetDateTime::now_utc().unix_timestamp(),
+        results: results.clone(),
+        files: changes
+            .iter()
+            .map(|change| (change.path.display().to_string(), change.checksum()))
+            .collect(),
+    };
+    let path = plan_path(args, fetch.plan.as_ref());
+    let content = serde_json::to_string_pretty(&plan).map_err(|e| {
+        Error::new("plan::write", "Failed to serialize the plan")
+            .cause(e)
+            .report()
+    })?;
+    if let Some(dir) = path.parent()
+        && !dir.as_os_str().is_empty()
+    {
+        std::fs::create_dir_all(dir).map_err(|e| {
+            Error::new("plan::write", "Failed to create the plan's directory")
+                .file(dir)
+                .cause(e)
+                .report()
+        })?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, content).map_err(|e| {
+        Error::new("plan::write", "Failed to write the plan")
+            .file(&tmp_path)
+            .cause(e)
+            .report()
+    })?;
+    std::fs::rename(&tmp_path, &path).map_err(|e| {
+        Error::new("plan::write", "Failed to move the plan into place")
+            .file(&path)
+            .cause(e)
+            .report()
+    })?;
+
+    info!(
+        "Staged the plan in {}, run `apply` to write it",
+        path.display()
+    );
+    Ok(())
+}
+
+/// Write the plan `fetch` staged, if the files it changes are as they were when it was staged
+pub fn apply(args: &Cli, apply: &ApplyArgs, started: Instant) -> Result<(), ()> {
+    let path = plan_path(args, apply.plan.as_ref());
+    let content = std::fs::read_to_string(&path).map_err(|e| {
+        Error::new("plan::read", "Failed to read the plan")
+            .file(&path)
+            .help("stage one with `fetch` first")
+            .cause(e)
+            .report()
+    })?;
+    let plan: Plan = serde_json::from_str(&content).map_err(|e| {
+        Error::new("plan::parse", "Failed to parse the plan")
+            .file(&path)
+            .cause(e)
+            .report()
+    })?;
+
+    for change in file_changes(args, &plan.results)? {
+        let staged = plan.files.get(&change.path.display().to_string());
+        if staged != Some(&change.checksum()) {
+            Error::new(
+                "plan::outdated",
+                "A file changed since the plan was staged, refusing to apply it",
+            )
+            .file(&change.path)
+            .help("stage and review a new plan with `fetch`")
+            .report();
+            return Err(());
+        }
+    }
+
+    crate::write_results(args, &plan.results, started)?;
+    if let Err(e) = std::fs::remove_file(&path) {
+        debug!("Failed to remove the applied plan {path:?} -- {:?}", e);
+    }
+    info!("Applied the plan staged in {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKhtiB5ZKct7eqPkIZBIkubYHjEVoOhM7ufiAisize+o alice";
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    #[test]
+    fn lists_added_and_removed_keys() {
+        let change = FileChange::new(
+            PathBuf::from("authorized_keys"),
+            None,
+            keys(&["ssh-ed25519 A", "ssh-ed25519 B"]),
+            keys(&["ssh-ed25519 B", "ssh-ed25519 C"]),
+        )
+        .expect("the keys changed");
+
+        assert_eq!(change.removed().collect::<Vec<_>>(), ["ssh-ed25519 A"]);
+        assert_eq!(change.added().collect::<Vec<_>>(), ["ssh-ed25519 C"]);
+        assert_eq!(
+            FileChange::new(
+                PathBuf::new(),
+                None,
+                keys(&["ssh-ed25519 A"]),
+                keys(&["ssh-ed25519 A"])
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn attributes_keys_to_accounts_in_the_json_plan() {
+        let changes = [FileChange::new(
+            PathBuf::from("authorized_keys"),
+            None,
+            keys(&["ssh-ed25519 OLD", "ssh-ed25519 STATIC"]),
+            keys(&[ALICE, "ssh-ed25519 LEGACY", "ssh-ed25519 BREAKGLASS"]),
+        )
+        .expect("the keys changed")];
+        let server = Origin {
+            kind: crate::source::OriginKind::Kanidm,
+            from: Some("https://idm.example.com/".to_string()),
+        };
+        let results = Fetched {
+            fetched: vec![(
+                "alice".to_string(),
+                Some(keys(&[
+                    ALICE,
+                    "# From command get-legacy-keys %a",
+                    "ssh-ed25519 LEGACY",
+                ])),
+            )],
+            complete: true,
+            static_keys: keys(&[
+                "# Static keys from /etc/ssh/breakglass.pub",
+                "ssh-ed25519 BREAKGLASS",
+            ]),
+            posix_ids: Default::default(),
+            origins: [("alice".to_string(), server)].into(),
+        };
+        let mut state = State::default();
+        state.key_set_checksums.insert(
+            "bob".to_string(),
+            [crate::state::checksum(b"ssh-ed25519 OLD")].into(),
+        );
+
+        let fingerprint = crate::keys::describe_key(ALICE).unwrap().fingerprint;
+        assert_eq!(
+            json_plan(&changes, &results, &state),
+            serde_json::json!({"files": [{
+                "path": "authorized_keys",
+                "accounts": [
+                    {
+                        "account": null,
+                        "added": [{
+                            "key": "ssh-ed25519 BREAKGLASS",
+                            "fingerprint": null,
+                            "source": {"kind": "file", "from": "/etc/ssh/breakglass.pub"},
+                        }],
+                        "removed": [{"key": "ssh-ed25519 STATIC", "fingerprint": null}],
+                    },
+                    {
+                        "account": "alice",
+                        "added": [
+                            {
+                                "key": ALICE,
+                                "fingerprint": fingerprint,
+                                "source": {"kind": "kanidm", "from": "https://idm.example.com/"},
+                            },
+                            {
+                                "key": "ssh-ed25519 LEGACY",
+                                "fingerprint": null,
+                                "source": {"kind": "exec", "from": "get-legacy-keys %a"},
+                            },
+                        ],
+                        "removed": [],
+                    },
+                    {
+                        "account": "bob",
+                        "added": [],
+                        "removed": [{"key": "ssh-ed25519 OLD", "fingerprint": null}],
+                    },
+                ],
+            }]})
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! Dropping root privileges for the network facing part of a run
+//!
+//! The process forks once the configuration is read. The child drops to an unprivileged user,
+//! fetches the keys and sends them back over a pipe, while the parent keeps root only to write
+//! the results.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use nix::sys::wait::{WaitStatus, waitpid};
+use nix::unistd::{ForkResult, Pid, fork, pipe, setgid, setgroups, setuid};
+use tracing::debug;
+
+use crate::diagnostic::Error;
+use crate::source::Fetched;
+
+/// Whether this process is the unprivileged child, see [`is_child`]
+static CHILD: AtomicBool = AtomicBool::new(false);
+
+/// Whether this process is the unprivileged child, which leaves reporting the run to its parent
+pub fn is_child() -> bool {
+    CHILD.load(Ordering::Relaxed)
+}
+
+/// The privileged side, waiting for the child to fetch the keys
+pub struct Parent {
+    child: Pid,
+    results: File,
+}
+
+/// The unprivileged side, sending the fetched keys to the parent
+pub struct Child {
+    results: File,
+}
+
+pub enum Split {
+    Parent(Parent),
+    Child(Child),
+}
+
+fn drop_to(user: &str) -> Result<(), ()> {
+    let user = crate::user::lookup(user)?;
+    setgroups(&[user.gid])
+        .and_then(|_| setgid(user.gid))
+        .and_then(|_| setuid(user.uid))
+        .map_err(|e| {
+            Error::new("privileges::drop", "Failed to drop privileges")
+                .with("user", &user.name)
+                .cause(e)
+                .report()
+        })?;
+
+    // Make sure root cannot be regained
+    if setuid(nix::unistd::Uid::from_raw(0)).is_ok() {
+        Error::new(
+            "privileges::drop",
+            "Still able to regain root after dropping privileges",
+        )
+        .report();
+        return Err(());
+    }
+    debug!("Dropped privileges to {} -- {}", user.name, user.uid);
+
+    Ok(())
+}
+
+/// Fork into a privileged parent and a child running as `user`
+///
+/// Must be called before any threads are spawned.
+pub fn split(user: &str) -> Result<Split, ()> {
+    let (read, write) = pipe().map_err(|e| {
+        Error::new("privileges::split", "Failed to create a pipe")
+            .cause(e)
+            .report()
+    })?;
+
+    // SAFETY: no other threads exist yet, the child continues the regular program
+    match unsafe { fork() }.map_err(|e| {
+        Error::new("privileges::split", "Failed to fork")
+            .cause(e)
+            .report()
+    })? {
+        ForkResult::Parent { child } => {
+            drop(write);
+            Ok(Split::Parent(Parent {
+                child,
+                results: File::from(read),
+            }))
+        }
+        ForkResult::Child => {
+            CHILD.store(true, Ordering::Relaxed);
+            drop(read);
+            drop_to(user)?;
+            Ok(Split::Child(Child {
+                results: File::from(write),
+            }))
+        }
+    }
+}
+
+impl Parent {
+    /// Wait for the child, only returning its results if it succeeded
+    pub fn wait(mut self) -> Result<Fetched, ()> {
+        let mut content = Vec::new();
+        let read = self.results.read_to_end(&mut content);
+
+        match waitpid(self.child, None) {
+            Ok(WaitStatus::Exited(_, 0)) => {}
+            Ok(status) => {
+                Error::new(
+                    "privileges::child",
+                    "The unprivileged fetch failed, not writing anything",
+                )
+                .cause(status)
+                .report();
+                return Err(());
+            }
+            Err(e) => {
+                Error::new(
+                    "privileges::child",
+                    "Failed to wait for the unprivileged fetch",
+                )
+                .cause(e)
+                .report();
+                return Err(());
+            }
+        }
+
+        read.map_err(|e| {
+            Error::new("privileges::read", "Failed to read the fetched keys")
+                .cause(e)
+                .report()
+        })?;
+        serde_json::from_slice(&content).map_err(|e| {
+            Error::new("privileges::read", "Failed to parse the fetched keys")
+                .cause(e)
+                .report()
+        })
+    }
+}
+
+impl Child {
+    pub fn send(mut self, fetched: &Fetched) -> Result<(), ()> {
+        let content = serde_json::to_vec(fetched).map_err(|e| {
+            Error::new("privileges::send", "Failed to encode the fetched keys")
+                .cause(e)
+                .report()
+        })?;
+        self.results.write_all(&content).map_err(|e| {
+            Error::new("privileges::send", "Failed to send the fetched keys")
+                .cause(e)
+                .report()
+        })
+    }
+}
@@ -0,0 +1,150 @@
+use clap::{Args, Subcommand};
+use kanidm_client::KanidmClient;
+use kanidm_proto::constants::{ATTR_LAST_MODIFIED_CID, ATTR_SSH_PUBLICKEY};
+use time::OffsetDateTime;
+
+use crate::diagnostic::Error;
+use crate::keys::{describe_key, parse_tagged_key};
+use crate::table::print_table;
+
+#[derive(Debug, Clone, Args)]
+pub struct ReportArgs {
+    #[command(subcommand)]
+    action: ReportAction,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum ReportAction {
+    /// Flag outdated keys and accounts whose keys haven't changed in years, e.g. to plan a
+    /// rotation campaign
+    Stale {
+        /// Flag RSA keys shorter than this many bits
+        #[arg(long, default_value_t = 3072)]
+        min_rsa_bits: usize,
+
+        /// Flag tags naming a year, and accounts unchanged, at least this many years ago
+        #[arg(long, default_value_t = 2)]
+        years: i32,
+    },
+}
+
+/// The year a tag like `laptop-2019` or `20190501` names, if any
+fn tag_year(tag: &str, this_year: i32) -> Option<i32> {
+    tag.split(|c: char| !c.is_ascii_digit())
+        .filter(|digits| digits.len() == 4 || digits.len() == 8)
+        .filter_map(|digits| digits[..4].parse().ok())
+        .find(|year| (1990..=this_year).contains(year))
+}
+
+/// What is outdated about a key stored under `tag`, if anything
+fn key_problem(tag: &str, key: &str, args: &ReportAction, this_year: i32) -> Option<String> {
+    let ReportAction::Stale {
+        min_rsa_bits,
+        years,
+    } = args;
+
+    let Ok(info) = describe_key(key) else {
+        return Some("not a valid public key".to_string());
+    };
+    match (info.algorithm.as_str(), info.bits) {
+        ("ssh-dss", _) => return Some("DSA, which OpenSSH no longer accepts".to_string()),
+        ("ssh-rsa", Some(bits)) if bits < *min_rsa_bits => {
+            return Some(format!("RSA with {bits} bits, fewer than {min_rsa_bits}"));
+        }
+        _ => {}
+    }
+
+    tag_year(tag, this_year)
+        .filter(|year| this_year - year >= *years)
+        .map(|year| format!("tagged with {year}"))
+}
+
+pub async fn report(
+    client: &KanidmClient,
+    args: &crate::Cli,
+    report: &ReportArgs,
+) -> Result<(), ()> {
+    let ReportAction::Stale { years, .. } = &report.action;
+    let now = OffsetDateTime::now_utc();
+
+    let mut rows = vec![
+        ["ACCOUNT", "TAG", "ALGORITHM", "BITS", "PROBLEM"]
+            .map(String::from)
+            .to_vec(),
+    ];
+    let mut failed = false;
+    for id in &crate::source::resolve_account_ids(client, args).await.0 {
+        let entry = match client.idm_person_account_get(id).await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => continue,
+            Err(e) => {
+                Error::new("fetch::account", "Failed to get account")
+                    .account(id)
+                    .maybe_help(crate::auth_hint(&e, args.token.is_some()))
+                    .cause(e)
+                    .report();
+                failed = true;
+                continue;
+            }
+        };
+
+        for value in entry.attrs.get(ATTR_SSH_PUBLICKEY).into_iter().flatten() {
+            let (tag, key) = parse_tagged_key(value);
+            let Some(problem) = key_problem(tag, key, &report.action, now.year()) else {
+                continue;
+            };
+            let (algorithm, bits) = describe_key(key)
+                .map_or(("-".to_string(), None), |info| (info.algorithm, info.bits));
+            rows.push(vec![
+                id.clone(),
+                tag.to_string(),
+                algorithm,
+                bits.map_or("-".to_string(), |bits| bits.to_string()),
+                problem,
+            ]);
+        }
+
+        // Any change to the entry counts, kanidm keeps no history of the keys themselves
+        let modified = entry
+            .attrs
+            .get(ATTR_LAST_MODIFIED_CID)
+            .and_then(|v| v.first())
+            .and_then(|cid| crate::list::cid_time(cid));
+        if let Some(modified) = modified
+            && (now - modified).whole_days() >= i64::from(*years) * 365
+        {
+            rows.push(vec![
+                id.clone(),
+                "-".to_string(),
+                "-".to_string(),
+                "-".to_string(),
+                format!("unchanged since {}", modified.date()),
+            ]);
+        }
+    }
+
+    if rows.len() == 1 {
+        println!("No stale keys found");
+    } else {
+        print_table(&rows, &[3]);
+    }
+
+    if failed {
+        return Err(());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_year_in_a_tag() {
+        assert_eq!(tag_year("laptop-2019", 2026), Some(2019));
+        assert_eq!(tag_year("20190501", 2026), Some(2019));
+        assert_eq!(tag_year("yubikey", 2026), None);
+        assert_eq!(tag_year("host-12345", 2026), None);
+        assert_eq!(tag_year("build-2099", 2026), None);
+    }
+}
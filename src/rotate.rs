@@ -0,0 +1,147 @@
+use std::path::PathBuf;
+
+use clap::Args;
+use kanidm_client::KanidmClient;
+use ssh_key::{Algorithm, LineEnding, PrivateKey, rand_core::OsRng};
+use tracing::{debug, info};
+
+use crate::diagnostic::Error;
+
+#[derive(Debug, Clone, Args)]
+pub struct RotateArgs {
+    /// The account id to register the new key for
+    account_id: String,
+
+    /// The tag to register the new public key under
+    #[arg(short, long)]
+    tag: String,
+
+    /// The tag of the old key to remove once the new key is registered
+    #[arg(short, long)]
+    retire: Option<String>,
+
+    /// Where to write the private key, defaults to ~/.ssh/id_ed25519_<tag>
+    ///
+    /// The public key is written next to it with a `.pub` suffix
+    #[arg(short, long, value_parser)]
+    output: Option<PathBuf>,
+
+    /// The comment of the new key, defaults to the account id
+    #[arg(long)]
+    comment: Option<String>,
+
+    /// Overwrite existing key files at the output path
+    #[arg(short, long, default_value_t = false)]
+    force: bool,
+}
+
+pub async fn rotate(client: &KanidmClient, args: &RotateArgs) -> Result<(), ()> {
+    let private_path = match &args.output {
+        Some(path) => path.clone(),
+        None => PathBuf::from(
+            shellexpand::tilde(&format!(
+                "{}/id_ed25519_{}",
+                crate::SSH_CONFIG_DIR,
+                args.tag
+            ))
+            .into_owned(),
+        ),
+    };
+    let mut public_path = private_path.clone().into_os_string();
+    public_path.push(".pub");
+    let public_path = PathBuf::from(public_path);
+
+    if !args.force && (private_path.exists() || public_path.exists()) {
+        Error::new("rotate::exists", "Refusing to overwrite existing key")
+            .file(&private_path)
+            .help("use --force to replace it")
+            .report();
+        return Err(());
+    }
+
+    debug!("Generating ed25519 keypair -- {private_path:?}");
+    let mut private_key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).map_err(|e| {
+        Error::new("rotate::generate", "Failed to generate keypair")
+            .cause(e)
+            .report()
+    })?;
+    private_key.set_comment(args.comment.as_deref().unwrap_or(&args.account_id));
+
+    let public_key = private_key.public_key().to_openssh().map_err(|e| {
+        Error::new("rotate::generate", "Failed to encode public key")
+            .cause(e)
+            .report()
+    })?;
+
+    if let Some(parent) = private_path.parent()
+        && !parent.as_os_str().is_empty()
+        && !parent.exists()
+    {
+        std::fs::create_dir_all(parent).map_err(|e| {
+            Error::new("rotate::write", "Failed to create key directory")
+                .file(parent)
+                .cause(e)
+                .report()
+        })?;
+    }
+
+    // `write_openssh_file` creates the private key with 0600 permissions on unix
+    if args.force && private_path.exists() {
+        std::fs::remove_file(&private_path).map_err(|e| {
+            Error::new("rotate::write", "Failed to remove existing private key")
+                .file(&private_path)
+                .cause(e)
+                .report()
+        })?;
+    }
+    private_key
+        .write_openssh_file(&private_path, LineEnding::LF)
+        .map_err(|e| {
+            Error::new("rotate::write", "Failed to write private key")
+                .file(&private_path)
+                .cause(e)
+                .report()
+        })?;
+    std::fs::write(&public_path, format!("{}\n", public_key)).map_err(|e| {
+        Error::new("rotate::write", "Failed to write public key")
+            .file(&public_path)
+            .cause(e)
+            .report()
+    })?;
+
+    client
+        .idm_person_account_post_ssh_pubkey(&args.account_id, &args.tag, &public_key)
+        .await
+        .map_err(|e| {
+            Error::new("rotate::register", "Failed to register the new key")
+                .account(&args.account_id)
+                .cause(e)
+                .help(format!(
+                    "the generated keypair is kept at {}",
+                    private_path.display()
+                ))
+                .report();
+        })?;
+    info!(
+        "Registered new key {} for account {}",
+        args.tag, args.account_id
+    );
+
+    if let Some(retire) = &args.retire {
+        client
+            .idm_person_account_delete_ssh_pubkey(&args.account_id, retire)
+            .await
+            .map_err(|e| {
+                Error::new("rotate::retire", "Failed to retire key")
+                    .with("tag", retire)
+                    .account(&args.account_id)
+                    .cause(e)
+                    .report()
+            })?;
+        info!("Retired key {} for account {}", retire, args.account_id);
+    }
+
+    println!("{}", public_key);
+
+    Ok(())
+}
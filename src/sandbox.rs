@@ -0,0 +1,314 @@
+//! Sandboxing of the one-shot run on Linux
+//!
+//! Once the client is configured, Landlock restricts the filesystem to the paths the run needs
+//! and TCP connections to the kanidm server's port, and a seccomp filter denies syscalls the
+//! fetcher never uses. A compromise of the HTTP or TLS stack then can't roam the host.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use kanidm_client::KanidmClient;
+use landlock::{
+    ABI, Access, AccessFs, AccessNet, NetPort, Ruleset, RulesetAttr, RulesetCreatedAttr,
+    RulesetStatus, path_beneath_rules,
+};
+use seccompiler::{BpfProgram, SeccompAction, SeccompFilter, TargetArch};
+use tracing::debug;
+
+use crate::diagnostic::Error;
+use crate::{Cli, authorized_keys, state};
+
+/// The newest Landlock ABI used, older kernels get what they support
+const LANDLOCK_ABI: ABI = ABI::V4;
+
+/// Read-only paths needed to resolve names and users, verify certificates and load NSS modules
+const SYSTEM_PATHS: &[&str] = &[
+    "/etc",
+    "/usr",
+    "/lib",
+    "/lib64",
+    "/dev/null",
+    "/dev/urandom",
+    "/proc/self",
+];
+
+/// The programs that may always be executed, see [`crate::selinux`]
+const EXECUTABLES: &[&str] = &["/usr/sbin/restorecon", "/sbin/restorecon"];
+
+/// Where the dynamic loader lives, which the kernel runs for every dynamically linked program
+const LOADER_PATHS: &[&str] = &["/lib", "/lib64", "/usr/lib", "/usr/lib64"];
+
+/// Where exec sources may find interpreters and the programs they run
+const EXEC_SOURCE_PATHS: &[&str] = &["/usr/bin", "/bin"];
+
+/// The port of GitHub's key endpoint
+const HTTPS_PORT: u16 = 443;
+
+/// DNS falls back to TCP for large responses
+const DNS_PORT: u16 = 53;
+
+/// Syscalls that have no use in fetching keys and writing files
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_acct,
+    libc::SYS_add_key,
+    libc::SYS_bpf,
+    libc::SYS_chroot,
+    libc::SYS_delete_module,
+    libc::SYS_finit_module,
+    libc::SYS_init_module,
+    libc::SYS_kexec_file_load,
+    libc::SYS_kexec_load,
+    libc::SYS_keyctl,
+    libc::SYS_mount,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_perf_event_open,
+    libc::SYS_pivot_root,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_ptrace,
+    libc::SYS_reboot,
+    libc::SYS_request_key,
+    libc::SYS_setns,
+    libc::SYS_swapoff,
+    libc::SYS_swapon,
+    libc::SYS_umount2,
+    libc::SYS_unshare,
+    libc::SYS_userfaultfd,
+];
+
+/// The closest existing directory a path is or will be created in
+fn existing_ancestor(path: &Path) -> Option<PathBuf> {
+    let path = std::path::absolute(path).ok()?;
+    path.ancestors().find(|p| p.exists()).map(Path::to_path_buf)
+}
+
+/// The directories the one-shot run writes to
+fn writable_paths(args: &Cli) -> Result<Vec<PathBuf>, ()> {
+    let mut paths = Vec::new();
+
+    if let Some(cache_path) = &args.cache_path {
+        let cache_path = PathBuf::from(shellexpand::tilde(&cache_path.to_string_lossy()).as_ref());
+        // SQLite keeps its journal next to the database
+        paths.extend(cache_path.parent().and_then(existing_ancestor));
+    }
+    if let Some(key_dir) = &args.key_dir {
+        paths.extend(existing_ancestor(key_dir));
+    }
+    // The daemon records every sync there, see `health`, and runs may save their session there
+    if args.daemon || crate::session::session_path(args).is_some() {
+        paths.extend(existing_ancestor(&state::state_dir(args)));
+    }
+    if let Some(ready_file) = &args.ready_file {
+        paths.extend(ready_file.parent().and_then(existing_ancestor));
+    }
+    if let Some(status_file) = &args.status_file {
+        paths.extend(status_file.parent().and_then(existing_ancestor));
+    }
+    if let Some(audit_log) = &args.audit_log {
+        paths.extend(audit_log.parent().and_then(existing_ancestor));
+    }
+    if let Some(fallback_dir) = &args.fallback_dir {
+        paths.extend(existing_ancestor(fallback_dir));
+    }
+    if args.modify && args.local_users {
+        for name in crate::usermap::local_users(args)? {
+            paths.extend(existing_ancestor(
+                &crate::user::lookup(&name)?.dir.join(".ssh"),
+            ));
+        }
+        paths.extend(existing_ancestor(&state::state_dir(args)));
+    } else if args.modify {
+        let authorized_keys_file = authorized_keys::authorized_keys_path(args)?;
+        paths.extend(authorized_keys_file.parent().and_then(existing_ancestor));
+        paths.extend(existing_ancestor(&state::state_dir(args)));
+    }
+
+    Ok(paths)
+}
+
+fn landlock(client: &KanidmClient, args: &Cli) -> Result<(), ()> {
+    let mut read = AccessFs::from_read(LANDLOCK_ABI);
+    read.remove(AccessFs::Execute);
+    let write = read | AccessFs::from_write(LANDLOCK_ABI);
+    let execute = AccessFs::from_read(LANDLOCK_ABI);
+
+    let mut read_paths: Vec<PathBuf> = SYSTEM_PATHS.iter().map(PathBuf::from).collect();
+    read_paths.extend(args.ca_path.clone());
+    read_paths.extend(args.cache_key_file.clone());
+    read_paths.extend(args.source.file.clone());
+    read_paths.extend(args.user_map.clone());
+    let write_paths = writable_paths(args)?;
+    let mut executables: Vec<PathBuf> = EXECUTABLES
+        .iter()
+        .chain(LOADER_PATHS)
+        .map(PathBuf::from)
+        .collect();
+    if !args.source.exec.is_empty() {
+        executables.extend(
+            args.source
+                .exec
+                .iter()
+                .filter_map(|c| crate::exec::program(c))
+                .map(PathBuf::from),
+        );
+        executables.extend(EXEC_SOURCE_PATHS.iter().map(PathBuf::from));
+    }
+    debug!("Sandbox paths -- read {read_paths:?}, write {write_paths:?}");
+
+    let mut ports = vec![DNS_PORT];
+    let mut restrict_net = true;
+    match client.get_url().port_or_known_default() {
+        Some(port) => ports.push(port),
+        None => {
+            Error::new(
+                "sandbox::port",
+                "Failed to determine the port of the kanidm server, not restricting it",
+            )
+            .warn();
+            restrict_net = false;
+        }
+    }
+    if !args.source.github.is_empty() {
+        ports.push(HTTPS_PORT);
+    }
+    if !args.source.gitlab.is_empty() {
+        let url = crate::forge::gitlab_users_url(args);
+        match reqwest::Url::parse(&url)
+            .ok()
+            .and_then(|u| u.port_or_known_default())
+        {
+            Some(port) => ports.push(port),
+            None => {
+                Error::new(
+                    "sandbox::port",
+                    "Failed to determine the port of the GitLab instance, not restricting it",
+                )
+                .warn();
+                restrict_net = false;
+            }
+        }
+    }
+    if let Some(url) = &args.ldap_url {
+        match crate::ldap::port(url) {
+            Some(port) => ports.push(port),
+            None => {
+                Error::new(
+                    "sandbox::port",
+                    "Failed to determine the port of the LDAP server, not restricting it",
+                )
+                .warn();
+                restrict_net = false;
+            }
+        }
+    }
+
+    #[cfg(feature = "sentry")]
+    if let Some(dsn) = &args.sentry_dsn {
+        match crate::sentry::port(dsn) {
+            Some(port) => ports.push(port),
+            None => {
+                Error::new(
+                    "sandbox::port",
+                    "Failed to determine the port of Sentry, not restricting it",
+                )
+                .warn();
+                restrict_net = false;
+            }
+        }
+    }
+
+    // Without the servers' ports, only restrict the filesystem
+    let ruleset = Ruleset::default()
+        .handle_access(AccessFs::from_all(LANDLOCK_ABI))
+        .and_then(|r| {
+            if restrict_net {
+                r.handle_access(AccessNet::from_all(LANDLOCK_ABI))
+            } else {
+                Ok(r)
+            }
+        })
+        .and_then(|r| r.create())
+        .and_then(|r| r.add_rules(path_beneath_rules(&read_paths, read)))
+        .and_then(|r| r.add_rules(path_beneath_rules(&write_paths, write)))
+        .and_then(|r| r.add_rules(path_beneath_rules(&executables, execute)))
+        .and_then(|r| {
+            ports
+                .iter()
+                .filter(|_| restrict_net)
+                .try_fold(r, |r, port| {
+                    r.add_rule(NetPort::new(*port, AccessNet::ConnectTcp))
+                })
+        })
+        .map_err(|e| {
+            Error::new("sandbox::landlock", "Failed to set up the Landlock ruleset")
+                .cause(e)
+                .report()
+        })?;
+
+    let status = ruleset.restrict_self().map_err(|e| {
+        Error::new("sandbox::landlock", "Failed to enable Landlock")
+            .cause(e)
+            .report()
+    })?;
+    match status.ruleset {
+        RulesetStatus::FullyEnforced => debug!("Landlock is fully enforced"),
+        RulesetStatus::PartiallyEnforced => Error::new(
+            "sandbox::landlock",
+            "Landlock is only partially enforced, the kernel lacks some features",
+        )
+        .warn(),
+        RulesetStatus::NotEnforced => Error::new(
+            "sandbox::landlock",
+            "Landlock is not supported by the kernel",
+        )
+        .warn(),
+    }
+
+    Ok(())
+}
+
+fn seccomp() -> Result<(), ()> {
+    let arch = TargetArch::try_from(std::env::consts::ARCH).map_err(|e| {
+        Error::new(
+            "sandbox::seccomp",
+            "Seccomp is not supported on this architecture",
+        )
+        .cause(e)
+        .report()
+    })?;
+    let rules = DENIED_SYSCALLS
+        .iter()
+        .map(|syscall| (*syscall, Vec::new()))
+        .collect::<BTreeMap<_, _>>();
+
+    let program: BpfProgram = SeccompFilter::new(
+        rules,
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::EPERM as u32),
+        arch,
+    )
+    .and_then(TryInto::try_into)
+    .map_err(|e| {
+        Error::new("sandbox::seccomp", "Failed to build the seccomp filter")
+            .cause(e)
+            .report()
+    })?;
+
+    seccompiler::apply_filter(&program).map_err(|e| {
+        Error::new("sandbox::seccomp", "Failed to apply the seccomp filter")
+            .cause(e)
+            .report()
+    })?;
+    debug!("Seccomp filter applied");
+
+    Ok(())
+}
+
+/// Restrict the process to what the one-shot run needs
+///
+/// Must be called before any threads are spawned, as both only apply to the calling thread and
+/// the threads it creates afterwards.
+pub fn apply(client: &KanidmClient, args: &Cli) -> Result<(), ()> {
+    landlock(client, args)?;
+    seccomp()
+}
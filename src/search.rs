@@ -0,0 +1,57 @@
+use clap::Args;
+use kanidm_client::KanidmClient;
+use kanidm_proto::constants::{ATTR_DISPLAYNAME, ATTR_NAME, ATTR_SPN, ATTR_SSH_PUBLICKEY};
+
+use crate::diagnostic::Error;
+use crate::table::print_table;
+
+#[derive(Debug, Clone, Args)]
+pub struct SearchArgs {
+    /// The substring to match against account names, display names and spns
+    filter: String,
+
+    /// Only show accounts that have at least one ssh key
+    #[arg(short = 'k', long, default_value_t = false)]
+    with_keys: bool,
+}
+
+pub async fn search(client: &KanidmClient, args: &SearchArgs) -> Result<(), ()> {
+    let entries = client.idm_person_search(&args.filter).await.map_err(|e| {
+        Error::new("search::fetch", "Failed to search accounts")
+            .with("filter", &args.filter)
+            .cause(e)
+            .report()
+    })?;
+
+    let mut rows = vec![
+        ["NAME", "SPN", "DISPLAY NAME", "KEYS"]
+            .map(String::from)
+            .to_vec(),
+    ];
+
+    for entry in &entries {
+        let attr = |name: &str| {
+            entry
+                .attrs
+                .get(name)
+                .and_then(|v| v.first())
+                .cloned()
+                .unwrap_or_else(|| "-".to_string())
+        };
+        let keys = entry.attrs.get(ATTR_SSH_PUBLICKEY).map_or(0, |v| v.len());
+        if args.with_keys && keys == 0 {
+            continue;
+        }
+
+        rows.push(vec![
+            attr(ATTR_NAME),
+            attr(ATTR_SPN),
+            attr(ATTR_DISPLAYNAME),
+            keys.to_string(),
+        ]);
+    }
+
+    print_table(&rows, &[3]);
+
+    Ok(())
+}
@@ -0,0 +1,44 @@
+//! Support for hosts running SELinux
+
+use std::path::Path;
+use std::process::Command;
+
+use tracing::debug;
+
+use crate::diagnostic::Error;
+
+/// Present when the kernel has SELinux enabled
+const SELINUX_ENFORCE: &str = "/sys/fs/selinux/enforce";
+
+/// Reset the SELinux context of a written file to the policy default
+///
+/// A file created through a temporary file may end up with the context of its parent directory
+/// instead of `ssh_home_t`, in which case sshd silently ignores it when SELinux is enforcing.
+/// Failing to relabel is only reported, as the file has been written at that point.
+pub fn restore_context(path: &Path) {
+    if !Path::new(SELINUX_ENFORCE).exists() {
+        return;
+    }
+
+    let mut command = Command::new("restorecon");
+    command.arg(path);
+
+    debug!("Restoring SELinux context of {path:?} -- {command:?}");
+    match command.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => Error::new(
+            "selinux::restorecon",
+            "restorecon failed to restore the SELinux context, sshd may ignore the file",
+        )
+        .file(path)
+        .cause(format_args!("{status}"))
+        .warn(),
+        Err(e) => Error::new(
+            "selinux::restorecon",
+            "Failed to run restorecon, the SELinux context may be wrong",
+        )
+        .file(path)
+        .cause(e)
+        .warn(),
+    }
+}
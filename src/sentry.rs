@@ -0,0 +1,287 @@
+//! Reporting errors and panics to Sentry, with the `sentry` feature and `--sentry-dsn`
+//!
+//! Across a fleet, failures that no one reads the logs of surface in Sentry instead. Every
+//! error reported, not the warnings, becomes an event tagged with its code, the account and
+//! the host, and grouped by its code. Events are sent at the end of every run, and of every
+//! sync of the daemon, while a panic is sent right away.
+//!
+//! Events are posted to the envelope endpoint of the DSN with the HTTP client the fetcher
+//! already has, so the feature adds no dependencies.
+
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use reqwest::Url;
+use ssh_key::rand_core::{OsRng, RngCore};
+use tracing::debug;
+
+use crate::diagnostic::Error;
+
+/// How long Sentry may take to accept the events
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many events are sent per run, the first errors are most telling
+const MAX_EVENTS: usize = 10;
+
+/// Where and how to send events, parsed from a DSN like `https://<key>@<host>/<project>`
+#[derive(Debug, PartialEq, Eq)]
+struct Dsn {
+    dsn: String,
+    key: String,
+    envelope_url: String,
+}
+
+impl Dsn {
+    fn parse(dsn: &str) -> Option<Dsn> {
+        let url = Url::parse(dsn).ok()?;
+        let key = url.username();
+        let (prefix, project) = url.path().trim_end_matches('/').rsplit_once('/')?;
+        if key.is_empty() || project.is_empty() {
+            return None;
+        }
+        let port = url
+            .port()
+            .map(|port| format!(":{port}"))
+            .unwrap_or_default();
+        Some(Dsn {
+            dsn: dsn.to_string(),
+            key: key.to_string(),
+            envelope_url: format!(
+                "{}://{}{port}{prefix}/api/{project}/envelope/",
+                url.scheme(),
+                url.host_str()?
+            ),
+        })
+    }
+
+    /// An envelope carrying one event
+    fn envelope(&self, event: &serde_json::Value) -> String {
+        let header = serde_json::json!({ "event_id": event["event_id"], "dsn": self.dsn });
+        format!(
+            "{header}\n{}\n{event}\n",
+            serde_json::json!({ "type": "event" })
+        )
+    }
+}
+
+static DSN: OnceLock<Dsn> = OnceLock::new();
+
+/// The events captured since they were last sent
+static EVENTS: Mutex<Vec<serde_json::Value>> = Mutex::new(Vec::new());
+
+/// The TCP port the DSN is served on, for the sandbox
+pub fn port(dsn: &str) -> Option<u16> {
+    Url::parse(dsn).ok()?.port_or_known_default()
+}
+
+/// Report errors and panics to the Sentry project of `dsn` from now on
+pub fn init(dsn: &str) -> Result<(), ()> {
+    let Some(dsn) = Dsn::parse(dsn) else {
+        Error::new("sentry::dsn", "Failed to parse the Sentry DSN")
+            .help("the DSN looks like https://<key>@<host>/<project>, see the project's settings")
+            .report();
+        return Err(());
+    };
+    debug!("Reporting errors to Sentry at {}", dsn.envelope_url);
+    let _ = DSN.set(dsn);
+
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        previous(info);
+        let message = info
+            .payload()
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| info.payload().downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        let location = info
+            .location()
+            .map(|l| format!("{}:{}", l.file(), l.line()));
+        capture(panic_event(&message, location.as_deref()));
+        flush();
+    }));
+    Ok(())
+}
+
+/// The fields every event has, over those of `event`
+fn event(level: &str, mut event: serde_json::Value) -> serde_json::Value {
+    let mut id = [0; 16];
+    OsRng.fill_bytes(&mut id);
+    let timestamp = time::OffsetDateTime::now_utc().unix_timestamp_nanos() as f64 / 1e9;
+    let common = serde_json::json!({
+        "event_id": hex::encode(id),
+        "timestamp": timestamp,
+        "platform": "other",
+        "level": level,
+        "server_name": crate::config::hostname(),
+        "release": concat!(env!("CARGO_PKG_NAME"), "@", env!("CARGO_PKG_VERSION")),
+    });
+    if let (Some(event), serde_json::Value::Object(common)) = (event.as_object_mut(), common) {
+        event.extend(common);
+    }
+    event
+}
+
+/// The event of a reported error, from its JSON line
+fn error_event(error: &serde_json::Value) -> serde_json::Value {
+    let mut tags = serde_json::Map::new();
+    tags.insert("code".to_string(), error["code"].clone());
+    if !error["account"].is_null() {
+        tags.insert("account".to_string(), error["account"].clone());
+    }
+    event(
+        "error",
+        serde_json::json!({
+            "logger": error["code"],
+            "message": { "formatted": error["message"] },
+            "tags": tags,
+            "extra": {
+                "context": error["context"],
+                "cause": error["cause"],
+                "help": error["help"],
+            },
+            "fingerprint": [error["code"]],
+        }),
+    )
+}
+
+fn panic_event(message: &str, location: Option<&str>) -> serde_json::Value {
+    event(
+        "fatal",
+        serde_json::json!({
+            "exception": { "values": [{ "type": "panic", "value": message }] },
+            "tags": { "code": "panic" },
+            "extra": { "location": location },
+            "fingerprint": ["panic", location],
+        }),
+    )
+}
+
+/// Queue an event for the reported error `error`, given as its JSON line
+///
+/// Does nothing unless [`init`] was called.
+pub fn capture_error(error: &serde_json::Value) {
+    if DSN.get().is_some() {
+        capture(error_event(error));
+    }
+}
+
+fn capture(event: serde_json::Value) {
+    if let Ok(mut events) = EVENTS.lock()
+        && events.len() < MAX_EVENTS
+    {
+        events.push(event);
+    }
+}
+
+/// Send the queued events
+///
+/// Events that can't be sent are only logged, the run succeeded or failed all the same. They
+/// are sent from a thread of their own, so this works in the panic hook and in async code
+/// alike.
+pub fn flush() {
+    let Some(dsn) = DSN.get() else {
+        return;
+    };
+    let events = EVENTS.lock().map(|mut e| std::mem::take(&mut *e));
+    let events = events.unwrap_or_default();
+    if events.is_empty() {
+        return;
+    }
+
+    let sent = std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .map_err(|e| e.to_string())?;
+                runtime.block_on(send(dsn, &events))
+            })
+            .join()
+            .unwrap_or_else(|_| Err("the sending thread panicked".to_string()))
+    });
+    match sent {
+        Ok(()) => debug!("Sent {} events to Sentry", events.len()),
+        Err(e) => Error::new("sentry::send", "Failed to send errors to Sentry")
+            .url(&dsn.envelope_url)
+            .cause(e)
+            .warn(),
+    }
+}
+
+async fn send(dsn: &Dsn, events: &[serde_json::Value]) -> Result<(), String> {
+    let client = reqwest::Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let auth = format!(
+        "Sentry sentry_version=7, sentry_client={}/{}, sentry_key={}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        dsn.key
+    );
+    for event in events {
+        client
+            .post(&dsn.envelope_url)
+            .header("X-Sentry-Auth", &auth)
+            .header("Content-Type", "application/x-sentry-envelope")
+            .body(dsn.envelope(event))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn posts_to_the_envelope_endpoint_of_the_dsn() {
+        let dsn = Dsn::parse("https://abc123@o1.ingest.sentry.io/42").unwrap();
+        assert_eq!(dsn.key, "abc123");
+        assert_eq!(
+            dsn.envelope_url,
+            "https://o1.ingest.sentry.io/api/42/envelope/"
+        );
+
+        let dsn = Dsn::parse("http://abc123@sentry.example.com:9000/sentry/7").unwrap();
+        assert_eq!(
+            dsn.envelope_url,
+            "http://sentry.example.com:9000/sentry/api/7/envelope/"
+        );
+
+        assert_eq!(Dsn::parse("https://sentry.example.com/7"), None);
+        assert_eq!(Dsn::parse("https://abc123@sentry.example.com/"), None);
+    }
+
+    #[test]
+    fn tags_errors_with_their_code_and_account() {
+        let error = serde_json::json!({
+            "level": "error",
+            "code": "fetch::account",
+            "account": "alice",
+            "message": "Failed to get ssh keys",
+            "context": {},
+            "cause": "connection refused",
+            "help": null,
+        });
+        let event = error_event(&error);
+        assert_eq!(event["level"], "error");
+        assert_eq!(event["tags"]["code"], "fetch::account");
+        assert_eq!(event["tags"]["account"], "alice");
+        assert_eq!(event["fingerprint"][0], "fetch::account");
+        assert_eq!(event["extra"]["cause"], "connection refused");
+        assert_eq!(event["event_id"].as_str().unwrap().len(), 32);
+
+        let dsn = Dsn::parse("https://abc123@sentry.example.com/7").unwrap();
+        let envelope = dsn.envelope(&event);
+        let lines: Vec<&str> = envelope.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains(event["event_id"].as_str().unwrap()));
+        assert_eq!(lines[1], r#"{"type":"event"}"#);
+    }
+}
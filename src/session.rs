@@ -0,0 +1,126 @@
+//! Keeping the anonymous session between runs, with `--persist-session`
+//!
+//! Logging in anonymously takes three requests. With `--persist-session` a run saves its
+//! session in `session` in the state directory, readable only by its owner, and the next ones
+//! reuse it until it expires. A session the server refuses anyway is replaced by logging in
+//! again, see [`crate::source::Sources`].
+
+use std::path::{Path, PathBuf};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use kanidm_client::KanidmClient;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::Cli;
+use crate::diagnostic::Error;
+
+const SESSION_FILE: &str = "session";
+
+/// Sessions expiring within this many seconds are not reused
+const EXPIRY_MARGIN: i64 = 60;
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Session {
+    token: String,
+    /// When the session expires, in seconds since the epoch, `None` if the token doesn't say
+    expiry: Option<i64>,
+}
+
+impl Session {
+    fn new(token: String) -> Session {
+        Session {
+            expiry: token_expiry(&token),
+            token,
+        }
+    }
+
+    fn usable(&self, now: i64) -> bool {
+        self.expiry
+            .is_none_or(|expiry| expiry > now + EXPIRY_MARGIN)
+    }
+}
+
+/// When a session token expires, read from the `expiry` of the JWS payload kanidm signs
+fn token_expiry(token: &str) -> Option<i64> {
+    let payload = URL_SAFE_NO_PAD.decode(token.split('.').nth(1)?).ok()?;
+    let payload: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+    payload.get("expiry")?.as_i64()
+}
+
+/// Where the session is kept, `None` unless an anonymous session is to be persisted
+pub fn session_path(args: &Cli) -> Option<PathBuf> {
+    (args.persist_session && args.token.is_none())
+        .then(|| crate::state::state_dir(args).join(SESSION_FILE))
+}
+
+/// The session a previous run saved in `path`, if it is still usable
+pub fn load(path: &Path) -> Option<String> {
+    if !path.exists() {
+        return None;
+    }
+    let content = crate::config::read_secret(path).ok()?;
+    let session: Session = serde_json::from_str(&content)
+        .map_err(|e| {
+            Error::new("session::read", "Failed to parse the saved session")
+                .file(path)
+                .cause(e)
+                .warn()
+        })
+        .ok()?;
+    if !session.usable(time::OffsetDateTime::now_utc().unix_timestamp()) {
+        debug!("The saved session expires, logging in again");
+        return None;
+    }
+    debug!("Reusing the session saved in {}", path.display());
+    Some(session.token)
+}
+
+/// Save the session of `client` in `path` for the next runs
+///
+/// A session that can't be saved is only logged, the next run logs in again.
+pub async fn save(path: &Path, client: &KanidmClient) {
+    let Some(token) = client.get_token().await else {
+        return;
+    };
+    let tmp_path = path.with_extension("tmp");
+    let written = serde_json::to_string(&Session::new(token))
+        .map_err(std::io::Error::other)
+        .and_then(|content| {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            std::io::Write::write_all(&mut options.open(&tmp_path)?, content.as_bytes())?;
+            std::fs::rename(&tmp_path, path)
+        });
+    match written {
+        Ok(()) => debug!("Saved the session in {}", path.display()),
+        Err(e) => Error::new("session::write", "Failed to save the session")
+            .file(path)
+            .cause(e)
+            .warn(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_sessions_until_they_expire() {
+        let payload = URL_SAFE_NO_PAD.encode(r#"{"session_id":"00000000","expiry":1000}"#);
+        let session = Session::new(format!("eyJhbGciOiJFUzI1NiJ9.{payload}.c2lnbmF0dXJl"));
+        assert_eq!(session.expiry, Some(1000));
+        assert!(session.usable(900));
+        assert!(!session.usable(950));
+
+        let session = Session::new("opaque".to_string());
+        assert_eq!(session.expiry, None);
+        assert!(session.usable(i64::MAX - EXPIRY_MARGIN));
+    }
+}
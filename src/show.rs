@@ -0,0 +1,63 @@
+use kanidm_client::KanidmClient;
+use kanidm_proto::constants::{ATTR_DISPLAYNAME, ATTR_SPN, ATTR_SSH_PUBLICKEY};
+
+use crate::diagnostic::Error;
+use crate::keys::{describe_key, parse_tagged_key};
+
+/// `token` is whether a token was supplied, for the hint if the server refuses
+pub async fn show(client: &KanidmClient, account_id: &str, token: bool) -> Result<(), ()> {
+    let entry = client
+        .idm_person_account_get(account_id)
+        .await
+        .map_err(|e| {
+            Error::new("fetch::account", "Failed to get account")
+                .account(account_id)
+                .maybe_help(crate::auth_hint(&e, token))
+                .cause(e)
+                .report()
+        })?
+        .ok_or_else(|| {
+            Error::new("fetch::not_found", "Account not found")
+                .account(account_id)
+                .report()
+        })?;
+
+    let attr = |name: &str| entry.attrs.get(name).and_then(|v| v.first()).cloned();
+    let values = entry
+        .attrs
+        .get(ATTR_SSH_PUBLICKEY)
+        .cloned()
+        .unwrap_or_default();
+
+    match attr(ATTR_DISPLAYNAME) {
+        Some(displayname) => println!("Account:     {} ({})", account_id, displayname),
+        None => println!("Account:     {}", account_id),
+    }
+    if let Some(spn) = attr(ATTR_SPN) {
+        println!("SPN:         {}", spn);
+    }
+    println!("Keys:        {}", values.len());
+
+    for value in &values {
+        let (tag, key) = parse_tagged_key(value);
+        println!();
+        println!("[{}]", tag);
+        match describe_key(key) {
+            Ok(info) => {
+                println!("Algorithm:   {}", info.algorithm);
+                match info.bits {
+                    Some(bits) => println!("Bits:        {}", bits),
+                    None => println!("Bits:        unknown"),
+                }
+                println!("Fingerprint: {}", info.fingerprint);
+                if !info.comment.is_empty() {
+                    println!("Comment:     {}", info.comment);
+                }
+            }
+            Err(e) => println!("Invalid key: {}", e),
+        }
+        println!("Key:         {}", key);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1964 @@
+//! Where keys come from, and fetching the keys of every configured account
+//!
+//! The fetch logic is written against [`KeySource`] rather than the kanidm client directly, so
+//! it can be tested without a live server.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use clap::{Args, ValueEnum};
+use kanidm_client::{ClientError, KanidmClient, StatusCode};
+use kanidm_proto::constants::{ATTR_GIDNUMBER, ATTR_SSH_PUBLICKEY};
+use kanidm_proto::internal::OperationError;
+use serde::{Deserialize, Deserializer, Serialize};
+use tracing::{Instrument, debug, info};
+
+use crate::cache::{Cache, DEFAULT_MAX_STALENESS};
+use crate::config::glob_match;
+use crate::diagnostic::Error;
+use crate::ldap::LdapSource;
+use crate::unixd::UnixdSource;
+use crate::{Cli, CommentPolicy, FailurePolicy};
+
+/// How many accounts need fetching before all persons are fetched in one request
+pub const DEFAULT_BATCH_THRESHOLD: usize = 10;
+
+/// Where keys come from besides kanidm, the `[source]` table of the configuration file
+#[derive(Debug, Clone, Default, Args, Serialize, Deserialize)]
+pub struct SourceArgs {
+    /// A command printing extra keys of an account, `%a` is replaced by the account id, can be
+    /// repeated
+    #[arg(long = "source-exec", value_name = "COMMAND")]
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub exec: Vec<String>,
+
+    /// A file, or directory of files, with keys to add to the managed block, e.g. break-glass
+    /// keys, can be repeated
+    #[arg(long = "source-file", value_name = "PATH", value_parser)]
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub file: Vec<PathBuf>,
+
+    /// Add the keys a GitHub user publishes to an account's keys, can be repeated
+    #[arg(long = "source-github", value_name = "ACCOUNT=USER", value_parser = parse_mapping)]
+    #[serde(default, with = "mappings")]
+    pub github: Vec<(String, String)>,
+
+    /// Add the keys a GitLab user publishes to an account's keys, can be repeated
+    #[arg(long = "source-gitlab", value_name = "ACCOUNT=USER", value_parser = parse_mapping)]
+    #[serde(default, with = "mappings")]
+    pub gitlab: Vec<(String, String)>,
+
+    /// The GitLab instance to fetch keys from, defaults to https://gitlab.com
+    #[arg(long = "source-gitlab-url", value_name = "URL")]
+    pub gitlab_url: Option<String>,
+
+    /// The order to ask the sources of an account's keys in, comma separated, sources left out
+    /// are not used, defaults to kanidm,exec,github,gitlab
+    #[arg(
+        long = "source-priority",
+        value_name = "SOURCES",
+        value_delimiter = ','
+    )]
+    #[serde(default)]
+    pub priority: Vec<SourceKind>,
+
+    /// How to combine the keys of the sources, defaults to union
+    #[arg(long = "source-merge", value_name = "STRATEGY")]
+    pub merge: Option<MergeStrategy>,
+
+    /// Priority and merge strategy of single accounts, only in the configuration file
+    #[arg(skip)]
+    #[serde(default)]
+    pub accounts: BTreeMap<String, AccountSources>,
+}
+
+/// The sources an account's keys can come from, LDAP counts as kanidm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    Kanidm,
+    Exec,
+    Github,
+    Gitlab,
+}
+
+/// The default priority of the sources
+const DEFAULT_PRIORITY: &[SourceKind] = &[
+    SourceKind::Kanidm,
+    SourceKind::Exec,
+    SourceKind::Github,
+    SourceKind::Gitlab,
+];
+
+/// How the keys of several sources are combined
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MergeStrategy {
+    /// The keys of all sources
+    #[default]
+    Union,
+    /// The keys of the first source that has any
+    FirstMatch,
+    /// The keys of all sources, but only for accounts that exist in kanidm
+    RequireKanidm,
+}
+
+/// The `[source.accounts.<id>]` tables of the configuration file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AccountSources {
+    pub priority: Option<Vec<SourceKind>>,
+    pub merge: Option<MergeStrategy>,
+}
+
+impl SourceArgs {
+    pub fn or(&mut self, other: &SourceArgs) {
+        self.exec.extend(other.exec.clone());
+        self.file.extend(other.file.clone());
+        self.github.extend(other.github.clone());
+        self.gitlab.extend(other.gitlab.clone());
+        self.gitlab_url = self.gitlab_url.clone().or(other.gitlab_url.clone());
+        if self.priority.is_empty() {
+            self.priority = other.priority.clone();
+        }
+        self.merge = self.merge.or(other.merge);
+        for (account, sources) in &other.accounts {
+            self.accounts
+                .entry(account.clone())
+                .or_insert_with(|| sources.clone());
+        }
+    }
+
+    /// The priority and merge strategy for an account, its own if configured
+    pub fn for_account(&self, account_id: &str) -> (&[SourceKind], MergeStrategy) {
+        let account = self.accounts.get(account_id);
+        let priority = account
+            .and_then(|a| a.priority.as_deref())
+            .or((!self.priority.is_empty()).then_some(self.priority.as_slice()))
+            .unwrap_or(DEFAULT_PRIORITY);
+        let merge = account
+            .and_then(|a| a.merge)
+            .or(self.merge)
+            .unwrap_or_default();
+        (priority, merge)
+    }
+}
+
+/// Parse an `ACCOUNT=USER` mapping
+fn parse_mapping(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((account, user)) if !account.is_empty() && !user.is_empty() => {
+            Ok((account.to_string(), user.to_string()))
+        }
+        _ => Err(format!("expected ACCOUNT=USER, got `{value}`")),
+    }
+}
+
+/// Mappings are a table of accounts to users in the configuration file
+mod mappings {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        mappings: &[(String, String)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(mappings.iter().map(|(account, user)| (account, user)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(String, String)>, D::Error> {
+        Ok(BTreeMap::<String, String>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+/// Accept a single value where a list is expected
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+/// What kind of source keys came from, see [`Origin`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OriginKind {
+    Kanidm,
+    Ldap,
+    Unixd,
+    /// The cache or `--fallback-dir`, when no source was asked or none answered
+    Cache,
+    Exec,
+    Github,
+    Gitlab,
+    File,
+    /// A signed bundle written with `import`
+    Bundle,
+}
+
+/// Where keys came from, for audits of where access is actually granted from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Origin {
+    pub kind: OriginKind,
+    /// The server URL, socket, command, forge user, file or bundle
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+}
+
+impl Origin {
+    fn new(kind: OriginKind, from: impl std::fmt::Display) -> Origin {
+        Origin {
+            kind,
+            from: Some(from.to_string()),
+        }
+    }
+
+    /// The origin an attribution comment before some keys names, e.g. `# From GitHub user
+    /// alice`, `Some(None)` for the keys of the account's kanidm source
+    fn of_attribution(line: &str) -> Option<Option<Origin>> {
+        if line == KANIDM_ATTRIBUTION {
+            return Some(None);
+        }
+        let origin = [
+            (OriginKind::Github, "# From GitHub user "),
+            (OriginKind::Gitlab, "# From GitLab user "),
+            (OriginKind::Exec, "# From command "),
+            (OriginKind::File, "# Static keys from "),
+        ]
+        .into_iter()
+        .find_map(|(kind, prefix)| Some(Origin::new(kind, line.strip_prefix(prefix)?)))?;
+        Some(Some(origin))
+    }
+}
+
+/// The attribution of kanidm's keys when they follow those of other sources
+const KANIDM_ATTRIBUTION: &str = "# From kanidm";
+
+/// Everything a run fetched, which is then written to the configured destinations
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fetched {
+    /// The keys of each account, `None` if they could not be fetched
+    pub fetched: Vec<(String, Option<Vec<String>>)>,
+    /// Whether every group was resolved, see [`resolve_account_ids`]
+    pub complete: bool,
+    /// The lines of the static key files, see [`static_keys`]
+    #[serde(default)]
+    pub static_keys: Vec<String>,
+    /// The POSIX uids of the accounts, see [`posix_ids`]
+    #[serde(default)]
+    pub posix_ids: BTreeMap<String, u32>,
+    /// Where the kanidm keys of each account came from, see [`origins`]
+    #[serde(default)]
+    pub origins: BTreeMap<String, Origin>,
+}
+
+impl Fetched {
+    /// Where each fetched key came from, by the attribution comment before it
+    ///
+    /// Keys before any attribution are the account's kanidm keys. A key several accounts have
+    /// is attributed to the first.
+    pub fn key_origins(&self) -> HashMap<&str, Origin> {
+        let accounts = self.fetched.iter().filter_map(|(id, keys)| {
+            let origin = self.origins.get(id).cloned();
+            Some((origin, keys.as_ref()?))
+        });
+        let mut origins = HashMap::new();
+        for (account_origin, keys) in accounts.chain([(None, &self.static_keys)]) {
+            let mut origin = account_origin.clone();
+            for line in keys {
+                if let Some(attributed) = Origin::of_attribution(line) {
+                    origin = attributed.or_else(|| account_origin.clone());
+                } else if let Some(origin) = origin.as_ref().filter(|_| !line.starts_with('#')) {
+                    origins
+                        .entry(line.as_str())
+                        .or_insert_with(|| origin.clone());
+                }
+            }
+        }
+        origins
+    }
+}
+
+/// Where the kanidm keys of each fetched account came from, the cache if `source` doesn't
+/// know, see [`KeySource::origin`]
+pub fn origins(
+    source: &impl KeySource,
+    fetched: &[(String, Option<Vec<String>>)],
+) -> BTreeMap<String, Origin> {
+    fetched
+        .iter()
+        .filter(|(_, keys)| keys.is_some())
+        .map(|(id, _)| {
+            let origin = source.origin(id).unwrap_or(Origin {
+                kind: OriginKind::Cache,
+                from: None,
+            });
+            (id.clone(), origin)
+        })
+        .collect()
+}
+
+/// The keys in some text, one per line, without empty lines and `#` comments
+pub fn key_lines(content: &str) -> impl Iterator<Item = String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+}
+
+/// The keys of the static key files, those of each file preceded by a comment naming it
+///
+/// Directories contribute every file in them in name order, skipping hidden files. Unreadable
+/// files are skipped, so a broken break-glass file doesn't keep kanidm's keys from being written.
+pub fn static_keys(args: &Cli) -> Vec<String> {
+    let mut files = Vec::new();
+    for path in &args.source.file {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+        match std::fs::read_dir(path) {
+            Ok(entries) => {
+                let mut entries: Vec<PathBuf> = entries
+                    .flatten()
+                    .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+                    .map(|entry| entry.path())
+                    .filter(|path| path.is_file())
+                    .collect();
+                entries.sort();
+                files.extend(entries);
+            }
+            Err(e) => Error::new("static_keys::read", "Failed to read static key directory")
+                .file(path)
+                .cause(e)
+                .report(),
+        }
+    }
+
+    let mut lines = Vec::new();
+    for file in files {
+        match std::fs::read_to_string(&file) {
+            Ok(content) => {
+                lines.push(format!("# Static keys from {}", file.display()));
+                lines.extend(key_lines(&content));
+            }
+            Err(e) => Error::new("static_keys::read", "Failed to read static key file")
+                .file(&file)
+                .cause(e)
+                .report(),
+        }
+    }
+    lines
+}
+
+/// Why a key source could not answer
+#[derive(Debug)]
+pub enum SourceError {
+    /// The account or group doesn't exist
+    NotFound,
+    /// The session expired or the credentials were refused
+    Unauthorized(String),
+    /// Anything else, e.g. the source being unreachable
+    Other(String),
+}
+
+/// Whether the server refused the session or the credentials
+pub fn is_unauthorized(e: &ClientError) -> bool {
+    matches!(
+        e,
+        ClientError::Unauthorized
+            | ClientError::SessionExpired
+            | ClientError::AuthenticationFailed
+            | ClientError::Http(StatusCode::UNAUTHORIZED, _, _)
+    )
+}
+
+/// Whether the server reported that the requested entry doesn't exist
+pub fn is_not_found(e: &ClientError) -> bool {
+    matches!(
+        e,
+        ClientError::Http(StatusCode::NOT_FOUND, _, _)
+            | ClientError::Http(_, Some(OperationError::NoMatchingEntries), _)
+    )
+}
+
+impl std::fmt::Display for SourceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SourceError::NotFound => write!(f, "not found"),
+            SourceError::Unauthorized(e) | SourceError::Other(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<ClientError> for SourceError {
+    fn from(e: ClientError) -> SourceError {
+        if is_not_found(&e) {
+            SourceError::NotFound
+        } else if is_unauthorized(&e) {
+            SourceError::Unauthorized(format!("{e:?}"))
+        } else {
+            SourceError::Other(format!("{e:?}"))
+        }
+    }
+}
+
+/// Something that knows the ssh keys of accounts and the members of groups
+pub trait KeySource {
+    /// The keys of one account
+    async fn account_keys(&self, account_id: &str) -> Result<Vec<String>, SourceError>;
+
+    /// The keys of every account the source can list at once, by every id they are known by
+    ///
+    /// Accounts left out are fetched with [`KeySource::account_keys`] instead.
+    async fn all_account_keys(&self) -> Result<HashMap<String, Vec<String>>, SourceError>;
+
+    /// The members of a group, `None` if it has none
+    async fn group_members(&self, group: &str) -> Result<Option<Vec<String>>, SourceError>;
+
+    /// The POSIX uid of an account, `None` if it has none
+    async fn posix_id(&self, account_id: &str) -> Result<Option<u32>, SourceError>;
+
+    /// The `(tag, key)` pairs of one account, for `--host-tags`
+    async fn tagged_keys(&self, account_id: &str) -> Result<Vec<(String, String)>, SourceError>;
+
+    /// Where the keys last fetched for an account came from, `None` if not known
+    fn origin(&self, _account_id: &str) -> Option<Origin> {
+        None
+    }
+}
+
+impl KeySource for KanidmClient {
+    async fn account_keys(&self, account_id: &str) -> Result<Vec<String>, SourceError> {
+        Ok(self.idm_account_get_ssh_pubkeys(account_id).await?)
+    }
+
+    async fn all_account_keys(&self) -> Result<HashMap<String, Vec<String>>, SourceError> {
+        Ok(crate::keys::get_all_person_keys(self).await?)
+    }
+
+    async fn group_members(&self, group: &str) -> Result<Option<Vec<String>>, SourceError> {
+        Ok(self.idm_group_get_members(group).await?)
+    }
+
+    async fn posix_id(&self, account_id: &str) -> Result<Option<u32>, SourceError> {
+        // kanidm gives POSIX accounts the same uid and gid
+        let values = self
+            .idm_person_account_get_attr(account_id, ATTR_GIDNUMBER)
+            .await?;
+        Ok(values
+            .and_then(|v| v.first().cloned())
+            .and_then(|gid| gid.parse().ok()))
+    }
+
+    async fn tagged_keys(&self, account_id: &str) -> Result<Vec<(String, String)>, SourceError> {
+        // Only the attribute has the tags, and only persons have it
+        let values = self
+            .idm_person_account_get_attr(account_id, ATTR_SSH_PUBLICKEY)
+            .await?;
+        Ok(tagged(values.unwrap_or_default().iter()))
+    }
+}
+
+/// The `(tag, key)` pairs of `tag: key` values of the `ssh_publickey` attribute
+pub fn tagged<'a>(values: impl Iterator<Item = &'a String>) -> Vec<(String, String)> {
+    values
+        .map(|value| {
+            let (tag, key) = crate::keys::parse_tagged_key(value);
+            (tag.to_string(), key.to_string())
+        })
+        .collect()
+}
+
+/// Knows nothing, for runs the cache answers entirely, see [`answered_by_cache`]
+pub struct CacheOnly;
+
+impl KeySource for CacheOnly {
+    async fn account_keys(&self, _account_id: &str) -> Result<Vec<String>, SourceError> {
+        Err(SourceError::Other(
+            "not connected to the server".to_string(),
+        ))
+    }
+
+    async fn all_account_keys(&self) -> Result<HashMap<String, Vec<String>>, SourceError> {
+        Err(SourceError::Other(
+            "not connected to the server".to_string(),
+        ))
+    }
+
+    async fn group_members(&self, _group: &str) -> Result<Option<Vec<String>>, SourceError> {
+        Err(SourceError::Other(
+            "not connected to the server".to_string(),
+        ))
+    }
+
+    async fn posix_id(&self, _account_id: &str) -> Result<Option<u32>, SourceError> {
+        Err(SourceError::Other(
+            "not connected to the server".to_string(),
+        ))
+    }
+
+    async fn tagged_keys(&self, _account_id: &str) -> Result<Vec<(String, String)>, SourceError> {
+        Err(SourceError::Other(
+            "not connected to the server".to_string(),
+        ))
+    }
+}
+
+/// Whether the cache knows every configured account, so a run needn't connect to the server
+///
+/// Groups, local users, and the uids of `--map-by-uid`, always need the server. Cached keys
+/// were already filtered by `--host-tags`.
+pub fn answered_by_cache(args: &Cli, cache: &Cache) -> bool {
+    args.groups.is_empty()
+        && !args.local_users
+        && !args.map_by_uid
+        && !args.account_ids.is_empty()
+        && args.account_ids.iter().all(|id| cache.covers(id))
+}
+
+/// The configured sources: kanidm-unixd if configured, the HTTPS API, and LDAP if configured,
+/// each asked in turn when the previous one fails
+pub struct Sources<'a> {
+    unixd: Option<UnixdSource>,
+    api: Option<&'a KanidmClient>,
+    ldap: Option<LdapSource>,
+    login: Login,
+    /// Whether logging in again didn't help, after which failures are not retried
+    login_refused: AtomicBool,
+    /// Which source answered for the keys of each account
+    answered: Mutex<HashMap<String, Origin>>,
+}
+
+/// How the API client logs in again when the server refuses its session
+enum Login {
+    /// The new session is saved in the path, if any, see [`crate::session`]
+    Anonymous(Option<PathBuf>),
+    /// The token file is read again, it may have been rotated since
+    TokenFile(PathBuf),
+    /// A token given directly is the same every time, logging in again can't help
+    Token,
+}
+
+impl<'a> Sources<'a> {
+    pub fn new(client: &'a KanidmClient, args: &Cli) -> Result<Sources<'a>, ()> {
+        let ldap = LdapSource::from_args(args)?;
+        let unixd = UnixdSource::from_args(args);
+        if args.unixd_only && unixd.is_none() {
+            Error::new("args::conflict", "--unixd-only requires --unixd-socket").report();
+            return Err(());
+        }
+        let api = match (args.ldap_only, &ldap) {
+            _ if args.unixd_only => None,
+            (false, _) => Some(client),
+            (true, Some(_)) => None,
+            (true, None) => {
+                Error::new("args::conflict", "--ldap-only requires --ldap-url").report();
+                return Err(());
+            }
+        };
+        let login = match (&args.token, &args.token_file) {
+            (None, _) => Login::Anonymous(crate::session::session_path(args)),
+            (Some(_), Some(path)) => Login::TokenFile(path.clone()),
+            (Some(_), None) => Login::Token,
+        };
+        Ok(Sources {
+            unixd,
+            api,
+            ldap,
+            login,
+            login_refused: AtomicBool::new(false),
+            answered: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Log in again after the server refused the session, whether it is worth retrying
+    async fn login_again(&self, client: &KanidmClient) -> bool {
+        if self.login_refused.load(Ordering::Relaxed) {
+            return false;
+        }
+        match &self.login {
+            Login::Anonymous(session) => {
+                info!("The session was refused, logging in again");
+                if let Err(e) = client.auth_anonymous().await {
+                    Error::new("auth::anonymous", "Failed to log in again")
+                        .url(client.get_url())
+                        .maybe_help(crate::auth_hint(&e, false))
+                        .cause(e)
+                        .warn();
+                    return false;
+                }
+                if let Some(path) = session {
+                    crate::session::save(path, client).await;
+                }
+            }
+            Login::TokenFile(path) => {
+                info!("The token was refused, reading it again");
+                let Ok(token) = crate::config::read_secret(path) else {
+                    return false;
+                };
+                client.set_token(token).await;
+            }
+            Login::Token => return false,
+        }
+        true
+    }
+
+    /// Remember which source answered for the keys of `account_id`, forgetting it if none did
+    fn answered<T>(
+        &self,
+        account_id: &str,
+        result: Result<(T, Origin), SourceError>,
+    ) -> Result<T, SourceError> {
+        if let Ok(mut answered) = self.answered.lock() {
+            match &result {
+                Ok((_, origin)) => answered.insert(account_id.to_string(), origin.clone()),
+                Err(_) => answered.remove(account_id),
+            };
+        }
+        result.map(|(value, _)| value)
+    }
+
+    /// Ask kanidm-unixd, then the API, and LDAP if the API fails for any reason but the entry
+    /// not existing, returning which of them answered
+    ///
+    /// kanidm-unixd doesn't tell unknown accounts from those without keys, so the API is asked
+    /// about both.
+    ///
+    /// A request the server refuses the session for is retried once after logging in again,
+    /// e.g. when the session expired during a long run or in daemon mode.
+    async fn ask<T>(
+        &self,
+        what: &str,
+        unixd: impl AsyncFnOnce(&UnixdSource) -> Result<T, SourceError>,
+        api: impl AsyncFn(&KanidmClient) -> Result<T, SourceError>,
+        ldap: impl AsyncFnOnce(&LdapSource) -> Result<T, SourceError>,
+    ) -> Result<(T, Origin), SourceError> {
+        if let Some(source) = &self.unixd {
+            match unixd(source).await {
+                Err(e) if self.api.is_some() || self.ldap.is_some() => {
+                    debug!(
+                        "Failed to get {} from kanidm-unixd, asking the server -- {}",
+                        what, e
+                    );
+                }
+                result => {
+                    let origin = Origin::new(OriginKind::Unixd, source.socket().display());
+                    return result.map(|value| (value, origin));
+                }
+            }
+        }
+        if let Some(client) = self.api {
+            let mut result = api(client).await;
+            if let Err(SourceError::Unauthorized(e)) = &result {
+                debug!("The server refused the request for {} -- {}", what, e);
+                if self.login_again(client).await {
+                    result = api(client).await;
+                    if matches!(result, Err(SourceError::Unauthorized(_))) {
+                        self.login_refused.store(true, Ordering::Relaxed);
+                    }
+                }
+            }
+            match result {
+                Err(SourceError::Unauthorized(e) | SourceError::Other(e))
+                    if self.ldap.is_some() =>
+                {
+                    debug!("Failed to get {} from the API, trying LDAP -- {}", what, e);
+                }
+                result => {
+                    let origin = Origin::new(OriginKind::Kanidm, client.get_url());
+                    return result.map(|value| (value, origin));
+                }
+            }
+        }
+        match &self.ldap {
+            Some(source) => {
+                let origin = Origin::new(OriginKind::Ldap, source.url());
+                ldap(source).await.map(|value| (value, origin))
+            }
+            None => unreachable!("Sources::new requires a source"),
+        }
+    }
+}
+
+impl KeySource for Sources<'_> {
+    async fn account_keys(&self, account_id: &str) -> Result<Vec<String>, SourceError> {
+        let result = self
+            .ask(
+                account_id,
+                async |unixd| unixd.account_keys(account_id).await,
+                async |api| api.account_keys(account_id).await,
+                async |ldap| ldap.account_keys(account_id).await,
+            )
+            .await;
+        self.answered(account_id, result)
+    }
+
+    async fn all_account_keys(&self) -> Result<HashMap<String, Vec<String>>, SourceError> {
+        let (keys, origin) = self
+            .ask(
+                "all accounts",
+                async |unixd| unixd.all_account_keys().await,
+                async |api| api.all_account_keys().await,
+                async |ldap| ldap.all_account_keys().await,
+            )
+            .await?;
+        if let Ok(mut answered) = self.answered.lock() {
+            answered.extend(keys.keys().map(|id| (id.clone(), origin.clone())));
+        }
+        Ok(keys)
+    }
+
+    async fn group_members(&self, group: &str) -> Result<Option<Vec<String>>, SourceError> {
+        self.ask(
+            group,
+            async |unixd| unixd.group_members(group).await,
+            async |api| api.group_members(group).await,
+            async |ldap| ldap.group_members(group).await,
+        )
+        .await
+        .map(|(members, _)| members)
+    }
+
+    async fn posix_id(&self, account_id: &str) -> Result<Option<u32>, SourceError> {
+        self.ask(
+            account_id,
+            async |unixd| unixd.posix_id(account_id).await,
+            async |api| api.posix_id(account_id).await,
+            async |ldap| ldap.posix_id(account_id).await,
+        )
+        .await
+        .map(|(uid, _)| uid)
+    }
+
+    async fn tagged_keys(&self, account_id: &str) -> Result<Vec<(String, String)>, SourceError> {
+        let result = self
+            .ask(
+                account_id,
+                async |unixd| unixd.tagged_keys(account_id).await,
+                async |api| api.tagged_keys(account_id).await,
+                async |ldap| ldap.tagged_keys(account_id).await,
+            )
+            .await;
+        self.answered(account_id, result)
+    }
+
+    fn origin(&self, account_id: &str) -> Option<Origin> {
+        self.answered.lock().ok()?.get(account_id).cloned()
+    }
+}
+
+/// Collect the configured account ids, expanding the configured groups into their members
+///
+/// The returned flag is false if any group could not be resolved, in which case the list of
+/// accounts may be incomplete.
+pub async fn resolve_account_ids(source: &impl KeySource, args: &Cli) -> (Vec<String>, bool) {
+    let mut account_ids = args.account_ids.clone();
+    let mut complete = true;
+
+    for group in &args.groups {
+        match source.group_members(group).await {
+            Ok(Some(members)) => {
+                debug!("Group {} has {} members", group, members.len());
+                account_ids.extend(members);
+            }
+            Ok(None) => debug!("Group {} has no members", group),
+            Err(e) => {
+                Error::new("fetch::group", "Failed to get members of group")
+                    .with("group", group)
+                    .cause(e)
+                    .report();
+                complete = false;
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    if args.local_users {
+        match crate::usermap::local_users(args) {
+            Ok(users) => account_ids.extend(users),
+            Err(()) => complete = false,
+        }
+    }
+
+    let mut seen = HashSet::new();
+    account_ids.retain(|id| seen.insert(id.clone()));
+
+    (account_ids, complete)
+}
+
+/// Fetch the keys of every configured account, consulting the cache first
+///
+/// `emit` is called with the keys of each account as soon as they are known, cached accounts
+/// first. Accounts whose keys could not be fetched are returned without keys, the flag is false
+/// if not every group could be resolved. With `--local-users`, accounts kanidm doesn't know are
+/// left out unless they were given by id.
+pub async fn fetch_all(
+    source: &impl KeySource,
+    args: &Cli,
+    cache: Option<&Cache>,
+    mut emit: impl FnMut(&str, &[String]),
+) -> (Vec<(String, Option<Vec<String>>)>, bool) {
+    let fetching = Instant::now();
+    let mut fetched = Vec::new();
+    let mut pending = Vec::new();
+    let forge = if args.source.github.is_empty() && args.source.gitlab.is_empty() {
+        None
+    } else {
+        // Reported by forge::client, and for every account it leaves without keys
+        crate::forge::client().ok()
+    };
+
+    let optional = |id: &str| args.local_users && !args.account_ids.iter().any(|a| a == id);
+    let mut unknown = Vec::new();
+
+    let host = args.host_tags.then(|| crate::config::hostname().ok_or(()));
+
+    let (account_ids, complete) = resolve_account_ids(source, args).await;
+    for id in &account_ids {
+        if let Some(pkeys) = cache.and_then(|c| c.get(id)) {
+            let pkeys = merge_sources(args, forge.as_ref(), id, Some(pkeys), false).await;
+            let pkeys = rewrite_keys(source, args, id, pkeys).await;
+            if let Some(pkeys) = &pkeys {
+                emit(id, pkeys);
+            }
+            fetched.push((id.clone(), pkeys));
+        } else if cache.is_some_and(|c| c.is_missing(id)) {
+            if optional(id) {
+                continue;
+            }
+            let pkeys = merge_sources(args, forge.as_ref(), id, None, true).await;
+            let pkeys = rewrite_keys(source, args, id, pkeys).await;
+            if let Some(pkeys) = &pkeys {
+                emit(id, pkeys);
+            }
+            fetched.push((id.clone(), pkeys));
+        } else {
+            pending.push(fetched.len());
+            fetched.push((id.clone(), None));
+        }
+    }
+
+    // One request for every person beats a round trip per account, but has no tags
+    let threshold = args.batch_threshold.unwrap_or(DEFAULT_BATCH_THRESHOLD);
+    let batch = if threshold > 0 && pending.len() >= threshold && host.is_none() {
+        debug!("Fetching keys of {} accounts in one request", pending.len());
+        source
+            .all_account_keys()
+            .await
+            .map_err(|e| debug!("Failed to fetch all accounts, fetching one by one -- {}", e))
+            .unwrap_or_default()
+    } else {
+        HashMap::new()
+    };
+
+    let mut timings = Vec::new();
+    for index in pending {
+        let id = fetched[index].0.clone();
+        let started = Instant::now();
+        let span = tracing::info_span!("account", account = %id);
+        // `None` for accounts that don't exist and aren't needed
+        let pkeys = async {
+            let result = match (batch.get(&id), &host) {
+                (Some(pkeys), _) => Ok(pkeys.clone()),
+                (None, None) => source.account_keys(&id).await,
+                (None, Some(Ok(host))) => host_keys(source, &id, host).await,
+                (None, Some(Err(()))) => Err(SourceError::Other(
+                    "the hostname to match tags against is unknown".to_string(),
+                )),
+            };
+
+            let pkeys = match result {
+                Ok(pkeys) => {
+                    if let Some(cache) = cache {
+                        let _ = cache.put(&id, &pkeys);
+                    }
+                    if let Some(dir) = &args.fallback_dir {
+                        let _ = crate::cache::save_fallback(dir, &id, &pkeys);
+                    }
+                    merge_sources(args, forge.as_ref(), &id, Some(pkeys), false).await
+                }
+                Err(SourceError::NotFound) => {
+                    debug!("Account {} not found", id);
+                    if let Some(cache) = cache {
+                        let _ = cache.put_missing(&id);
+                    }
+                    if optional(&id) {
+                        return None;
+                    }
+                    merge_sources(args, forge.as_ref(), &id, None, true).await
+                }
+                Err(e) => match on_server_failure(args, cache, &id, e) {
+                    Some(pkeys) => {
+                        merge_sources(args, forge.as_ref(), &id, Some(pkeys), false).await
+                    }
+                    None => None,
+                },
+            };
+            let pkeys = rewrite_keys(source, args, &id, pkeys).await;
+            debug!("Fetched in {}ms", started.elapsed().as_millis());
+            Some(pkeys)
+        }
+        .instrument(span)
+        .await;
+        timings.push((id.clone(), started.elapsed()));
+
+        let Some(pkeys) = pkeys else {
+            unknown.push(index);
+            continue;
+        };
+        if let Some(pkeys) = &pkeys {
+            emit(&id, pkeys);
+        }
+        fetched[index].1 = pkeys;
+    }
+    if let Some(n) = args.slowest_accounts {
+        report_slowest(timings, n);
+    }
+
+    for index in unknown.into_iter().rev() {
+        fetched.remove(index);
+    }
+    crate::timings::record("fetch", fetching.elapsed());
+    (fetched, complete)
+}
+
+/// The `n` accounts that took longest to fetch, slowest first
+fn slowest(mut timings: Vec<(String, Duration)>, n: usize) -> Vec<(String, Duration)> {
+    timings.sort_by(|(_, a), (_, b)| b.cmp(a));
+    timings.truncate(n);
+    timings
+}
+
+/// Log the `n` accounts that took longest to fetch, see `--slowest-accounts`
+fn report_slowest(timings: Vec<(String, Duration)>, n: usize) {
+    let slowest = slowest(timings, n);
+    if slowest.is_empty() {
+        return;
+    }
+    let slowest = slowest
+        .iter()
+        .map(|(id, duration)| format!("{id} {}ms", duration.as_millis()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    info!("Slowest accounts to fetch: {slowest}");
+}
+
+/// The keys of an account tagged `host:<pattern>` with a pattern matching `host`, see
+/// `--host-tags`
+///
+/// The pattern may match the full hostname or its first label, ignoring case.
+async fn host_keys(
+    source: &impl KeySource,
+    account_id: &str,
+    host: &str,
+) -> Result<Vec<String>, SourceError> {
+    let host = host.to_ascii_lowercase();
+    let short = host.split('.').next().unwrap_or_default();
+    let matches = |tag: &str| {
+        tag.strip_prefix("host:").is_some_and(|pattern| {
+            let pattern = pattern.to_ascii_lowercase();
+            glob_match(&pattern, &host) || glob_match(&pattern, short)
+        })
+    };
+
+    let tagged = source.tagged_keys(account_id).await?;
+    let total = tagged.len();
+    let keys: Vec<String> = tagged
+        .into_iter()
+        .filter(|(tag, _)| matches(tag))
+        .map(|(_, key)| key)
+        .collect();
+    debug!(
+        "Using {} of the {} keys of {} tagged for this host",
+        keys.len(),
+        total,
+        account_id
+    );
+    Ok(keys)
+}
+
+/// The POSIX uids of the fetched accounts, for naming their key files with `--map-by-uid`
+///
+/// An account whose uid can't be looked up counts as failed, so its previous key file is kept
+/// rather than written under the wrong name.
+pub async fn posix_ids(
+    source: &impl KeySource,
+    args: &Cli,
+    fetched: &mut [(String, Option<Vec<String>>)],
+) -> BTreeMap<String, u32> {
+    let mut ids = BTreeMap::new();
+    if !args.map_by_uid || args.key_dir.is_none() {
+        return ids;
+    }
+
+    for (id, keys) in fetched.iter_mut().filter(|(_, keys)| keys.is_some()) {
+        match source.posix_id(id).await {
+            Ok(Some(uid)) => {
+                ids.insert(id.clone(), uid);
+            }
+            Ok(None) | Err(SourceError::NotFound) => debug!("Account {} has no POSIX uid", id),
+            Err(e) => {
+                Error::new("user_map::posix_id", "Failed to get the POSIX uid")
+                    .account(&*id)
+                    .cause(e)
+                    .report();
+                *keys = None;
+            }
+        }
+    }
+    ids
+}
+
+/// The accounts given by id that were fetched but have no keys, not counting `#` comments
+///
+/// Members of groups are left out, many of them never log in over ssh.
+pub fn empty_accounts<'a>(
+    args: &Cli,
+    fetched: &'a [(String, Option<Vec<String>>)],
+) -> Vec<&'a str> {
+    fetched
+        .iter()
+        .filter(|(id, _)| args.account_ids.contains(id))
+        .filter(|(_, keys)| {
+            keys.as_ref()
+                .is_some_and(|keys| keys.iter().all(|line| line.starts_with('#')))
+        })
+        .map(|(id, _)| id.as_str())
+        .collect()
+}
+
+/// Normalize the keys of an account, apply `--key-comments` and `--max-keys-per-account`
+///
+/// `#` comments, like the attribution of forge keys, are left alone and don't count as keys.
+/// Keys that don't parse are left out when their comments are rewritten. Over the limit, the
+/// keys ranked first by [`key_ranks`] are kept, in their original order.
+async fn rewrite_keys(
+    source: &impl KeySource,
+    args: &Cli,
+    account_id: &str,
+    pkeys: Option<Vec<String>>,
+) -> Option<Vec<String>> {
+    let comment = match args.key_comments.unwrap_or_default() {
+        CommentPolicy::Keep => None,
+        CommentPolicy::Strip => Some(String::new()),
+        CommentPolicy::Account => Some(format!("{}@kanidm", crate::export::local_name(account_id))),
+    };
+
+    // A key whose comment can't be rewritten is dropped rather than written with its comment
+    let rewrite = |line: String| {
+        if line.starts_with('#') {
+            return Some(line);
+        }
+        let line = crate::keys::normalize_key(&line);
+        let Some(comment) = &comment else {
+            return Some(line);
+        };
+        let rewritten = ssh_key::PublicKey::from_openssh(&line).and_then(|mut key| {
+            key.set_comment(comment.as_str());
+            key.to_openssh()
+        });
+        match rewritten {
+            Ok(key) => Some(key),
+            Err(e) => {
+                Error::new(
+                    "fetch::key_comment",
+                    "Failed to parse key to rewrite its comment, leaving it out",
+                )
+                .account(account_id)
+                .with("key", &line)
+                .cause(e)
+                .help("with --key-comments keep, keys are used as they are")
+                .warn();
+                None
+            }
+        }
+    };
+    let mut keys: Vec<String> = pkeys?.into_iter().filter_map(rewrite).collect();
+
+    if let Some(max) = args.max_keys_per_account {
+        let total = keys.iter().filter(|line| !line.starts_with('#')).count();
+        if total > max {
+            let ranks = key_ranks(source, args, account_id, &keys).await;
+            let mut ranked: Vec<usize> = (0..total).collect();
+            ranked.sort_by_key(|&key| ranks[key]);
+            let kept: HashSet<usize> = ranked.into_iter().take(max).collect();
+
+            // Comments head the keys up to the next comment, and stay if one of those does, so
+            // an attribution doesn't end up over the keys of another
+            let mut sections = Vec::with_capacity(keys.len());
+            let mut kept_sections = HashSet::new();
+            let (mut section, mut index, mut in_comments) = (0, 0, false);
+            for line in &keys {
+                let comment = line.starts_with('#');
+                if comment && !in_comments {
+                    section += 1;
+                }
+                in_comments = comment;
+                if !comment {
+                    if kept.contains(&index) {
+                        kept_sections.insert(section);
+                    }
+                    index += 1;
+                }
+                sections.push(section);
+            }
+            let mut index = 0;
+            let mut sections = sections.into_iter();
+            keys.retain(|line| {
+                let section = sections.next().unwrap_or_default();
+                if line.starts_with('#') {
+                    return kept_sections.contains(&section);
+                }
+                index += 1;
+                kept.contains(&(index - 1))
+            });
+            Error::new(
+                "fetch::max_keys",
+                format!("Only using {max} of {total} keys"),
+            )
+            .account(account_id)
+            .help("raise --max-keys-per-account or remove unused keys from the account")
+            .warn();
+        }
+    }
+    Some(keys)
+}
+
+/// The rank of each key among the `#` comments and keys in `keys`, lower ranks are kept first
+/// with `--max-keys-per-account`
+///
+/// Keys kanidm has tagged with a tag matching one of the `--key-priority` patterns rank by the
+/// first one they match, ignoring case, all others after them. Without patterns, or tags, all
+/// keys rank the same and the first ones are kept.
+async fn key_ranks(
+    source: &impl KeySource,
+    args: &Cli,
+    account_id: &str,
+    keys: &[String],
+) -> Vec<usize> {
+    let keys: Vec<&String> = keys.iter().filter(|line| !line.starts_with('#')).collect();
+    if args.key_priority.is_empty() {
+        return vec![0; keys.len()];
+    }
+    let tagged = match source.tagged_keys(account_id).await {
+        Ok(tagged) => tagged,
+        Err(e) => {
+            Error::new(
+                "fetch::key_priority",
+                "Failed to get the tags of keys, keeping the first ones",
+            )
+            .account(account_id)
+            .cause(e)
+            .warn();
+            return vec![0; keys.len()];
+        }
+    };
+
+    // Keys are matched by algorithm and key, their comments may have been rewritten
+    let material = |key: &str| {
+        crate::keys::normalize_key(key)
+            .split_whitespace()
+            .take(2)
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let rank = |tag: &str| {
+        let tag = tag.to_ascii_lowercase();
+        args.key_priority
+            .iter()
+            .position(|pattern| glob_match(&pattern.to_ascii_lowercase(), &tag))
+            .unwrap_or(args.key_priority.len())
+    };
+    let ranks: HashMap<String, usize> = tagged
+        .iter()
+        .map(|(tag, key)| (material(key), rank(tag)))
+        .collect();
+    keys.iter()
+        .map(|key| {
+            ranks
+                .get(&material(key))
+                .copied()
+                .unwrap_or(args.key_priority.len())
+        })
+        .collect()
+}
+
+/// The cached keys to return for an account the sources failed to answer for, see
+/// [`FailurePolicy`]
+///
+/// Which policy was applied is always reported, so a lockout or stale keys can be explained.
+fn on_server_failure(
+    args: &Cli,
+    cache: Option<&Cache>,
+    account_id: &str,
+    e: SourceError,
+) -> Option<Vec<String>> {
+    let error = Error::new("fetch::account", "Failed to get ssh keys")
+        .account(account_id)
+        .cause(format_args!("{e}"));
+    if args.on_server_failure.unwrap_or_default() == FailurePolicy::Closed {
+        error
+            .with("policy", "fail-closed, returning no keys")
+            .warn();
+        return None;
+    }
+
+    let max_age = args.max_staleness.unwrap_or(DEFAULT_MAX_STALENESS);
+    if let Some((pkeys, age)) = cache.and_then(|c| c.get_stale(account_id, max_age)) {
+        error
+            .with("policy", "fail-open, returning cached keys")
+            .with("age", format!("{age}s"))
+            .warn();
+        return Some(pkeys);
+    }
+    let fallback = args
+        .fallback_dir
+        .as_ref()
+        .and_then(|dir| crate::cache::load_fallback(dir, account_id, max_age));
+    if let Some((pkeys, path, age)) = fallback {
+        error
+            .with("policy", "fail-open, returning the fallback file")
+            .file(path)
+            .with("age", format!("{age}s"))
+            .warn();
+        return Some(pkeys);
+    }
+
+    error
+        .with("policy", "fail-open, but no cached keys are recent enough")
+        .warn();
+    None
+}
+
+/// Merge the keys kanidm has for an account with those of the other sources
+///
+/// The sources are asked in the account's priority order and merged with its strategy, see
+/// [`SourceArgs::for_account`]. An account kanidm doesn't know (`missing`) only gets the other
+/// sources' keys, if any. If any source fails the account counts as failed, so its previous keys
+/// are kept rather than replaced without some of them.
+async fn merge_sources(
+    args: &Cli,
+    forge: Option<&reqwest::Client>,
+    account_id: &str,
+    pkeys: Option<Vec<String>>,
+    missing: bool,
+) -> Option<Vec<String>> {
+    let gitlab_url = crate::forge::gitlab_users_url(args);
+    let forge_users = |kind: SourceKind| {
+        let (name, url, mappings) = match kind {
+            SourceKind::Github => ("GitHub", crate::forge::GITHUB_URL, &args.source.github),
+            _ => ("GitLab", gitlab_url.as_str(), &args.source.gitlab),
+        };
+        mappings
+            .iter()
+            .filter(|(account, _)| account == account_id)
+            .map(move |(_, user)| (name, url, user.as_str()))
+    };
+    let supplemental = !args.source.exec.is_empty()
+        || forge_users(SourceKind::Github).next().is_some()
+        || forge_users(SourceKind::Gitlab).next().is_some();
+    let (priority, merge) = args.source.for_account(account_id);
+    let kanidm_only = !supplemental && priority.contains(&SourceKind::Kanidm);
+    if kanidm_only || (pkeys.is_none() && !missing) {
+        return pkeys;
+    }
+    if missing && merge == MergeStrategy::RequireKanidm {
+        debug!(
+            "Account {} is not in kanidm, ignoring its other sources",
+            account_id
+        );
+        return None;
+    }
+
+    let kanidm = pkeys.unwrap_or_default();
+    let mut merged = Vec::new();
+    for kind in priority {
+        // Keys before the first attribution are kanidm's
+        let kanidm_attribution = (!merged.is_empty()).then(|| KANIDM_ATTRIBUTION.to_string());
+        let mut found = false;
+        let mut add = |keys: Vec<String>, attribution: Option<String>| {
+            found |= !keys.is_empty();
+            let keys: Vec<String> = keys.into_iter().filter(|k| !merged.contains(k)).collect();
+            if !keys.is_empty() {
+                merged.extend(attribution);
+                merged.extend(keys);
+            }
+        };
+
+        match kind {
+            SourceKind::Kanidm => add(kanidm.clone(), kanidm_attribution),
+            SourceKind::Exec => {
+                for command in &args.source.exec {
+                    let keys = crate::exec::keys(command, account_id).await.ok()?;
+                    add(keys, Some(format!("# From command {command}")));
+                }
+            }
+            SourceKind::Github | SourceKind::Gitlab => {
+                for (name, url, user) in forge_users(*kind) {
+                    let Some(forge) = forge else {
+                        Error::new(
+                            "fetch::forge",
+                            "No forge client to fetch keys with, keeping the previous keys",
+                        )
+                        .account(account_id)
+                        .with("source", name)
+                        .warn();
+                        return None;
+                    };
+                    let keys = crate::forge::keys(forge, url, user).await.ok()?;
+                    add(keys, Some(format!("# From {name} user {user}")));
+                }
+            }
+        }
+
+        if found && merge == MergeStrategy::FirstMatch {
+            debug!("Using the keys of account {} from {:?}", account_id, kind);
+            break;
+        }
+    }
+
+    if missing && merged.is_empty() {
+        None
+    } else {
+        Some(merged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use clap::Parser;
+
+    use super::*;
+
+    /// A source answering from fixed data, counting the requests it serves
+    #[derive(Default)]
+    struct MockSource {
+        accounts: HashMap<String, Vec<String>>,
+        groups: HashMap<String, Vec<String>>,
+        listable: bool,
+        posix: HashMap<String, u32>,
+        requests: Cell<usize>,
+    }
+
+    impl MockSource {
+        fn with_account(mut self, id: &str, keys: &[&str]) -> Self {
+            self.accounts
+                .insert(id.to_string(), keys.iter().map(|k| k.to_string()).collect());
+            self
+        }
+
+        fn with_group(mut self, group: &str, members: &[&str]) -> Self {
+            self.groups.insert(
+                group.to_string(),
+                members.iter().map(|m| m.to_string()).collect(),
+            );
+            self
+        }
+    }
+
+    impl KeySource for MockSource {
+        async fn account_keys(&self, account_id: &str) -> Result<Vec<String>, SourceError> {
+            self.requests.set(self.requests.get() + 1);
+            match account_id {
+                "unreachable" => Err(SourceError::Other("unreachable".to_string())),
+                id => self.accounts.get(id).cloned().ok_or(SourceError::NotFound),
+            }
+        }
+
+        async fn all_account_keys(&self) -> Result<HashMap<String, Vec<String>>, SourceError> {
+            self.requests.set(self.requests.get() + 1);
+            if self.listable {
+                Ok(self.accounts.clone())
+            } else {
+                Err(SourceError::Other("forbidden".to_string()))
+            }
+        }
+
+        async fn group_members(&self, group: &str) -> Result<Option<Vec<String>>, SourceError> {
+            self.groups
+                .get(group)
+                .cloned()
+                .map(Some)
+                .ok_or(SourceError::NotFound)
+        }
+
+        async fn posix_id(&self, account_id: &str) -> Result<Option<u32>, SourceError> {
+            Ok(self.posix.get(account_id).copied())
+        }
+
+        async fn tagged_keys(
+            &self,
+            account_id: &str,
+        ) -> Result<Vec<(String, String)>, SourceError> {
+            self.requests.set(self.requests.get() + 1);
+            let values = self.accounts.get(account_id).ok_or(SourceError::NotFound)?;
+            Ok(tagged(values.iter()))
+        }
+    }
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::parse_from(std::iter::once("kanidm_sshkey_fetcher").chain(args.iter().copied()))
+    }
+
+    #[tokio::test]
+    async fn fetches_each_account() {
+        let source = MockSource::default()
+            .with_account("alice", &["ssh-ed25519 AAAA alice"])
+            .with_account("bob", &[]);
+
+        let (fetched, complete) =
+            fetch_all(&source, &cli(&["alice", "bob", "carol"]), None, |_, _| {}).await;
+
+        assert!(complete);
+        assert_eq!(
+            fetched,
+            vec![
+                (
+                    "alice".to_string(),
+                    Some(vec!["ssh-ed25519 AAAA alice".to_string()])
+                ),
+                ("bob".to_string(), Some(vec![])),
+                ("carol".to_string(), None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_accounts_have_no_keys() {
+        let source = MockSource::default().with_account("alice", &["ssh-ed25519 AAAA alice"]);
+
+        let (fetched, _) =
+            fetch_all(&source, &cli(&["unreachable", "alice"]), None, |_, _| {}).await;
+
+        assert_eq!(fetched[0], ("unreachable".to_string(), None));
+        assert!(fetched[1].1.is_some());
+    }
+
+    #[tokio::test]
+    async fn expands_groups_without_duplicates() {
+        let source = MockSource::default()
+            .with_account("alice", &["ssh-ed25519 AAAA alice"])
+            .with_account("bob", &["ssh-ed25519 BBBB bob"])
+            .with_group("admins", &["alice", "bob"]);
+
+        let (fetched, complete) =
+            fetch_all(&source, &cli(&["alice", "-g", "admins"]), None, |_, _| {}).await;
+
+        assert!(complete);
+        let ids: Vec<&str> = fetched.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, ["alice", "bob"]);
+    }
+
+    #[tokio::test]
+    async fn unresolved_groups_are_incomplete() {
+        let source = MockSource::default().with_account("alice", &["ssh-ed25519 AAAA alice"]);
+
+        let (fetched, complete) =
+            fetch_all(&source, &cli(&["alice", "-g", "missing"]), None, |_, _| {}).await;
+
+        assert!(!complete);
+        assert_eq!(fetched.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rewrites_key_comments() {
+        const KEY: &str =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKhtiB5ZKct7eqPkIZBIkubYHjEVoOhM7ufiAisize+o";
+        let source = MockSource::default()
+            .with_account("alice@idm.example.com", &[&format!("{KEY} alice@laptop")]);
+        let fetch = async |comments: &str| {
+            let args = cli(&["alice@idm.example.com", "--key-comments", comments]);
+            fetch_all(&source, &args, None, |_, _| {}).await.0[0]
+                .1
+                .clone()
+        };
+
+        assert_eq!(
+            fetch("keep").await,
+            Some(vec![format!("{KEY} alice@laptop")])
+        );
+        assert_eq!(fetch("strip").await, Some(vec![KEY.to_string()]));
+        assert_eq!(
+            fetch("account").await,
+            Some(vec![format!("{KEY} alice@kanidm")])
+        );
+    }
+
+    #[tokio::test]
+    async fn leaves_out_keys_whose_comments_cannot_be_rewritten() {
+        const KEY: &str =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKhtiB5ZKct7eqPkIZBIkubYHjEVoOhM7ufiAisize+o";
+        let source = MockSource::default().with_account(
+            "alice",
+            &[
+                &format!("{KEY} alice@laptop"),
+                "ssh-ed25519 AAAA alice@desktop",
+            ],
+        );
+        let fetch = async |comments: &str| {
+            let args = cli(&["alice", "--key-comments", comments]);
+            fetch_all(&source, &args, None, |_, _| {}).await.0[0]
+                .1
+                .clone()
+        };
+
+        assert_eq!(fetch("strip").await, Some(vec![KEY.to_string()]));
+        assert_eq!(
+            fetch("keep").await,
+            Some(vec![
+                format!("{KEY} alice@laptop"),
+                "ssh-ed25519 AAAA alice@desktop".to_string()
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_the_keys_of_an_account() {
+        let source = MockSource::default().with_account("alice", &["a", "b", "c"]);
+        let args = cli(&["alice", "--max-keys-per-account", "2"]);
+
+        let (fetched, _) = fetch_all(&source, &args, None, |_, _| {}).await;
+        assert_eq!(fetched[0].1, Some(vec!["a".to_string(), "b".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn keeps_prioritized_keys_over_the_first_ones() {
+        const KEYS: [&str; 3] = [
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKhtiB5ZKct7eqPkIZBIkubYHjEVoOhM7ufiAisize+o",
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIFbWDrkbnYQ6l4QBijYjJbk2LzptMbHbWQkB7mW1T3Og",
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl",
+        ];
+        let source = MockSource::default().with_account(
+            "alice",
+            &[
+                &format!("01-old-laptop: {}", KEYS[0]),
+                &format!("02-desktop: {}", KEYS[1]),
+                &format!("yubikey-2025: {} alice@yubikey", KEYS[2]),
+            ],
+        );
+        let limited = async |args: &[&str]| {
+            let args = cli(&[&["alice", "--max-keys-per-account", "2"], args].concat());
+            let keys = source.accounts["alice"]
+                .iter()
+                .map(|value| crate::keys::parse_tagged_key(value).1.to_string())
+                .collect();
+            rewrite_keys(&source, &args, "alice", Some(keys))
+                .await
+                .unwrap()
+        };
+
+        let keys = limited(&["--key-priority", "YubiKey-*"]).await;
+        assert_eq!(keys.len(), 2);
+        assert!(keys[0].contains(KEYS[0]));
+        assert!(
+            keys[1].contains(KEYS[2]),
+            "the prioritized key past the cap"
+        );
+
+        let keys = limited(&["--key-priority", "02-*", "--key-priority", "yubikey-*"]).await;
+        assert!(keys[0].contains(KEYS[1]) && keys[1].contains(KEYS[2]));
+
+        let keys = limited(&[]).await;
+        assert!(keys[0].contains(KEYS[0]) && keys[1].contains(KEYS[1]));
+    }
+
+    #[tokio::test]
+    async fn drops_the_comments_of_capped_keys() {
+        const KEYS: [&str; 3] = [
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIKhtiB5ZKct7eqPkIZBIkubYHjEVoOhM7ufiAisize+o",
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIFbWDrkbnYQ6l4QBijYjJbk2LzptMbHbWQkB7mW1T3Og",
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl",
+        ];
+        let source = MockSource::default().with_account(
+            "alice",
+            &[
+                &format!("laptop: {}", KEYS[0]),
+                &format!("desktop: {}", KEYS[1]),
+                &format!("yubikey: {}", KEYS[2]),
+            ],
+        );
+        let args = cli(&[
+            "alice",
+            "--max-keys-per-account",
+            "2",
+            "--key-priority",
+            "yubikey",
+        ]);
+        let keys = vec![
+            "# GitHub user alice".to_string(),
+            KEYS[0].to_string(),
+            "# GitLab user alice".to_string(),
+            "# on gitlab.com".to_string(),
+            KEYS[1].to_string(),
+            "# kanidm account alice".to_string(),
+            KEYS[2].to_string(),
+        ];
+
+        let keys = rewrite_keys(&source, &args, "alice", Some(keys))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            keys,
+            vec![
+                "# GitHub user alice".to_string(),
+                KEYS[0].to_string(),
+                "# kanidm account alice".to_string(),
+                KEYS[2].to_string(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn finds_accounts_without_keys() {
+        let source = MockSource::default()
+            .with_account("alice", &["a"])
+            .with_account("bob", &[])
+            .with_account("carol", &[])
+            .with_group("ops", &["carol"]);
+        let args = cli(&["alice", "bob", "mallory", "-g", "ops"]);
+
+        let (fetched, _) = fetch_all(&source, &args, None, |_, _| {}).await;
+        assert_eq!(empty_accounts(&args, &fetched), vec!["bob"]);
+    }
+
+    #[tokio::test]
+    async fn looks_up_posix_ids_for_the_key_dir() {
+        let mut source = MockSource::default()
+            .with_account("alice", &["a"])
+            .with_account("bob", &["b"]);
+        source.posix.insert("alice".to_string(), 1000);
+        let mut fetched = vec![
+            ("alice".to_string(), Some(vec!["a".to_string()])),
+            ("bob".to_string(), Some(vec!["b".to_string()])),
+            ("carol".to_string(), None),
+        ];
+
+        let args = cli(&["--map-by-uid", "alice", "bob", "carol"]);
+        assert!(posix_ids(&source, &args, &mut fetched).await.is_empty());
+
+        let args = cli(&["--map-by-uid", "--key-dir=keys", "alice", "bob", "carol"]);
+        let ids = posix_ids(&source, &args, &mut fetched).await;
+        assert_eq!(ids, BTreeMap::from([("alice".to_string(), 1000)]));
+    }
+
+    #[tokio::test]
+    async fn only_uses_keys_tagged_for_the_host() {
+        let source = MockSource::default().with_account(
+            "alice",
+            &[
+                "host:web-*: a",
+                "host:WEB01: b",
+                "host:db-01: c",
+                "laptop: d",
+            ],
+        );
+
+        let keys = host_keys(&source, "alice", "web01.example.com").await;
+        assert_eq!(keys.ok(), Some(vec!["b".to_string()]));
+        let keys = host_keys(&source, "alice", "web-02").await;
+        assert_eq!(keys.ok(), Some(vec!["a".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn emits_keys_as_they_arrive() {
+        let source = MockSource::default()
+            .with_account("alice", &["ssh-ed25519 AAAA alice"])
+            .with_account("bob", &["ssh-ed25519 BBBB bob"]);
+
+        let mut emitted = Vec::new();
+        fetch_all(
+            &source,
+            &cli(&["alice", "carol", "bob"]),
+            None,
+            |id, keys| emitted.push((id.to_string(), keys.len())),
+        )
+        .await;
+
+        assert_eq!(
+            emitted,
+            vec![("alice".to_string(), 1), ("bob".to_string(), 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn batches_many_accounts() {
+        let source = MockSource {
+            listable: true,
+            ..MockSource::default()
+        }
+        .with_account("alice", &["ssh-ed25519 AAAA alice"])
+        .with_account("bob", &["ssh-ed25519 BBBB bob"]);
+
+        let args = cli(&["alice", "bob", "svc", "--batch-threshold", "2"]);
+        let (fetched, _) = fetch_all(&source, &args, None, |_, _| {}).await;
+
+        // One listing, plus one request for the account missing from it
+        assert_eq!(source.requests.get(), 2);
+        assert!(fetched[0].1.is_some() && fetched[1].1.is_some());
+        assert_eq!(fetched[2].1, None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn merges_exec_source_keys() {
+        let source = MockSource::default().with_account("alice", &["ssh-ed25519 AAAA alice"]);
+
+        let args = cli(&[
+            "alice",
+            "bob",
+            "--source-exec",
+            "echo ssh-ed25519 LEGACY %a",
+        ]);
+        let (fetched, _) = fetch_all(&source, &args, None, |_, _| {}).await;
+
+        assert_eq!(
+            fetched,
+            vec![
+                (
+                    "alice".to_string(),
+                    Some(vec![
+                        "ssh-ed25519 AAAA alice".to_string(),
+                        "# From command echo ssh-ed25519 LEGACY %a".to_string(),
+                        "ssh-ed25519 LEGACY alice".to_string()
+                    ])
+                ),
+                (
+                    "bob".to_string(),
+                    Some(vec![
+                        "# From command echo ssh-ed25519 LEGACY %a".to_string(),
+                        "ssh-ed25519 LEGACY bob".to_string()
+                    ])
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_forge_mapped_accounts_without_a_forge_client() {
+        let args = cli(&["alice", "--source-github", "alice=alice-gh"]);
+        let kanidm = Some(vec!["ssh-ed25519 AAAA alice".to_string()]);
+
+        assert_eq!(
+            merge_sources(&args, None, "alice", kanidm.clone(), false).await,
+            None
+        );
+        assert_eq!(
+            merge_sources(&args, None, "bob", kanidm.clone(), false).await,
+            kanidm
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn merges_in_priority_order() {
+        let source = MockSource::default().with_account("alice", &["ssh-ed25519 AAAA alice"]);
+        let exec = ["--source-exec", "echo ssh-ed25519 LEGACY %a"];
+
+        let args = cli(&[&["alice", "--source-priority", "exec,kanidm"], &exec[..]].concat());
+        let (fetched, _) = fetch_all(&source, &args, None, |_, _| {}).await;
+        assert_eq!(
+            fetched[0].1,
+            Some(vec![
+                "# From command echo ssh-ed25519 LEGACY %a".to_string(),
+                "ssh-ed25519 LEGACY alice".to_string(),
+                "# From kanidm".to_string(),
+                "ssh-ed25519 AAAA alice".to_string()
+            ])
+        );
+
+        let args = cli(&[
+            &["alice", "bob", "--source-merge", "first-match"],
+            &exec[..],
+        ]
+        .concat());
+        let (fetched, _) = fetch_all(&source, &args, None, |_, _| {}).await;
+        assert_eq!(
+            fetched[0].1,
+            Some(vec!["ssh-ed25519 AAAA alice".to_string()])
+        );
+        assert_eq!(
+            fetched[1].1,
+            Some(vec![
+                "# From command echo ssh-ed25519 LEGACY %a".to_string(),
+                "ssh-ed25519 LEGACY bob".to_string()
+            ])
+        );
+
+        let args = cli(&[
+            &["alice", "bob", "--source-merge", "require-kanidm"],
+            &exec[..],
+        ]
+        .concat());
+        let (fetched, _) = fetch_all(&source, &args, None, |_, _| {}).await;
+        assert_eq!(fetched[0].1.as_ref().map(Vec::len), Some(3));
+        assert_eq!(fetched[1].1, None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn attributes_keys_to_their_sources() {
+        let source = MockSource::default().with_account("alice", &["ssh-ed25519 AAAA alice"]);
+        let args = cli(&[
+            "alice",
+            "--source-priority",
+            "exec,kanidm",
+            "--source-exec",
+            "echo ssh-ed25519 LEGACY %a",
+        ]);
+        let (fetched, complete) = fetch_all(&source, &args, None, |_, _| {}).await;
+        let server = Origin::new(OriginKind::Kanidm, "https://idm.example.com/");
+        let results = Fetched {
+            origins: [("alice".to_string(), server.clone())].into(),
+            fetched,
+            complete,
+            static_keys: vec![
+                "# Static keys from /etc/ssh/breakglass.pub".to_string(),
+                "ssh-ed25519 BREAKGLASS".to_string(),
+            ],
+            posix_ids: Default::default(),
+        };
+
+        let keys = results.key_origins();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(keys["ssh-ed25519 AAAA alice"], server);
+        assert_eq!(
+            keys["ssh-ed25519 LEGACY alice"],
+            Origin::new(OriginKind::Exec, "echo ssh-ed25519 LEGACY %a")
+        );
+        assert_eq!(
+            keys["ssh-ed25519 BREAKGLASS"],
+            Origin::new(OriginKind::File, "/etc/ssh/breakglass.pub")
+        );
+
+        // Accounts no source answered for were served from the cache
+        assert_eq!(
+            origins(&source, &results.fetched)["alice"].kind,
+            OriginKind::Cache
+        );
+    }
+
+    #[test]
+    fn account_sources_override_the_defaults() {
+        let args: Cli = toml::from_str(
+            r#"
+            [source]
+            merge = "first-match"
+
+            [source.accounts.alice]
+            priority = ["exec"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            args.source.for_account("alice"),
+            (&[SourceKind::Exec][..], MergeStrategy::FirstMatch)
+        );
+        assert_eq!(
+            args.source.for_account("bob"),
+            (DEFAULT_PRIORITY, MergeStrategy::FirstMatch)
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn failed_exec_sources_fail_the_account() {
+        let source = MockSource::default().with_account("alice", &["ssh-ed25519 AAAA alice"]);
+
+        let args = cli(&["alice", "--source-exec", "false %a"]);
+        let (fetched, _) = fetch_all(&source, &args, None, |_, _| {}).await;
+
+        assert_eq!(fetched, vec![("alice".to_string(), None)]);
+    }
+
+    #[test]
+    fn reads_static_key_files() {
+        let dir = std::env::temp_dir().join(format!(
+            "kanidm_sshkey_fetcher-static-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(dir.join("keys.d")).unwrap();
+        std::fs::write(
+            dir.join("breakglass"),
+            "# on paper in the safe\nssh-ed25519 GLASS\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("keys.d/b"), "ssh-ed25519 B\n").unwrap();
+        std::fs::write(dir.join("keys.d/a"), "\nssh-ed25519 A\n").unwrap();
+        std::fs::write(dir.join("keys.d/.hidden"), "ssh-ed25519 HIDDEN\n").unwrap();
+
+        let args = cli(&[
+            "--source-file",
+            dir.join("breakglass").to_str().unwrap(),
+            "--source-file",
+            dir.join("keys.d").to_str().unwrap(),
+            "--source-file",
+            dir.join("missing").to_str().unwrap(),
+        ]);
+        let lines = static_keys(&args);
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            lines,
+            vec![
+                format!("# Static keys from {}", dir.join("breakglass").display()),
+                "ssh-ed25519 GLASS".to_string(),
+                format!("# Static keys from {}", dir.join("keys.d/a").display()),
+                "ssh-ed25519 A".to_string(),
+                format!("# Static keys from {}", dir.join("keys.d/b").display()),
+                "ssh-ed25519 B".to_string(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back_when_listing_fails() {
+        let source = MockSource::default()
+            .with_account("alice", &["ssh-ed25519 AAAA alice"])
+            .with_account("bob", &["ssh-ed25519 BBBB bob"]);
+
+        let args = cli(&["alice", "bob", "--batch-threshold", "2"]);
+        let (fetched, _) = fetch_all(&source, &args, None, |_, _| {}).await;
+
+        assert_eq!(source.requests.get(), 3);
+        assert!(fetched.iter().all(|(_, keys)| keys.is_some()));
+    }
+
+    #[test]
+    fn reports_the_slowest_accounts_first() {
+        let timings = vec![
+            ("alice".to_string(), Duration::from_millis(20)),
+            ("bob".to_string(), Duration::from_millis(900)),
+            ("carol".to_string(), Duration::from_millis(300)),
+        ];
+        let names: Vec<String> = slowest(timings.clone(), 2)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(names, ["bob", "carol"]);
+        assert_eq!(slowest(timings, 10).len(), 3);
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+
+use crate::diagnostic::Error;
+
+/// Where state is kept if `state_dir` is not configured and neither `$STATE_DIRECTORY` nor
+/// `$XDG_STATE_HOME` is set
+pub const DEFAULT_STATE_DIR: &str = "~/.local/state/kanidm_sshkey_fetcher";
+
+const STATE_FILE: &str = "state.json";
+const LOCK_FILE: &str = "lock";
+
+/// What is remembered between runs
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    /// The checksum of the managed block last written, by authorized_keys path
+    #[serde(default)]
+    pub managed_checksums: BTreeMap<String, String>,
+
+    /// The checksum of the keys last fetched, by account
+    #[serde(default)]
+    pub key_checksums: BTreeMap<String, String>,
+
+    /// The checksum of each key last fetched, by account
+    #[serde(default)]
+    pub key_set_checksums: BTreeMap<String, BTreeSet<String>>,
+
+    /// When the last imported bundle was created, see [`crate::bundle`]
+    #[serde(default)]
+    pub last_bundle: Option<i64>,
+}
+
+/// How the keys of the fetched accounts changed since they were last fetched
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct KeyChanges<'a> {
+    /// The accounts whose keys changed
+    pub accounts: Vec<&'a str>,
+    pub added: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+}
+
+/// The configured state directory with `~` expanded
+pub fn state_dir(args: &crate::Cli) -> PathBuf {
+    let dir = match &args.state_dir {
+        Some(dir) => dir.to_string_lossy().into_owned(),
+        None => default_state_dir(
+            std::env::var("STATE_DIRECTORY").ok(),
+            std::env::var("XDG_STATE_HOME").ok(),
+        ),
+    };
+    PathBuf::from(shellexpand::tilde(&dir).into_owned())
+}
+
+/// The state directory systemd created for the service with `StateDirectory=`, otherwise the
+/// one of the XDG base directories
+///
+/// systemd separates several state directories with `:`, the first one is used.
+fn default_state_dir(state_directory: Option<String>, xdg_state_home: Option<String>) -> String {
+    if let Some(dir) = state_directory
+        .as_deref()
+        .and_then(|dirs| dirs.split(':').next())
+        .filter(|dir| !dir.is_empty())
+    {
+        return dir.to_string();
+    }
+    match xdg_state_home.filter(|dir| Path::new(dir).is_absolute()) {
+        Some(dir) => format!("{dir}/{}", env!("CARGO_PKG_NAME")),
+        None => DEFAULT_STATE_DIR.to_string(),
+    }
+}
+
+/// The configured lock file, `lock` in the state directory by default
+pub fn lock_path(args: &crate::Cli) -> PathBuf {
+    match &args.lock_file {
+        Some(path) => PathBuf::from(shellexpand::tilde(&path.to_string_lossy()).into_owned()),
+        None => state_dir(args).join(LOCK_FILE),
+    }
+}
+
+/// The hex encoded SHA-256 checksum of some content
+pub fn checksum(content: &[u8]) -> String {
+    hex::encode(Sha256::digest(content))
+}
+
+/// Held while a run modifies files, released when dropped
+pub struct Lock {
+    _file: File,
+}
+
+/// Make sure no other instance modifies the same files at the same time
+///
+/// The lock file contains the pid of the holder. If another instance holds it, either wait for
+/// it to finish or give up.
+pub fn lock(path: &Path, wait: bool) -> Result<Lock, ()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(|e| {
+            Error::new(
+                "state::create_dir",
+                "Failed to create the lock file's directory",
+            )
+            .file(dir)
+            .cause(e)
+            .report()
+        })?;
+    }
+
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| {
+            Error::new("state::lock", "Failed to open lock file")
+                .file(path)
+                .cause(e)
+                .report()
+        })?;
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let mut holder = String::new();
+            let _ = file.read_to_string(&mut holder);
+            let holder = match holder.trim() {
+                "" => String::new(),
+                pid => format!(" (pid {pid})"),
+            };
+            if !wait {
+                Error::new(
+                    "state::locked",
+                    format!("Another instance{holder} is running"),
+                )
+                .file(path)
+                .help("use --wait-for-lock to wait for it")
+                .report();
+                return Err(());
+            }
+            info!("Waiting for another instance{holder} to finish");
+            file.lock().map_err(|e| {
+                Error::new("state::lock", "Failed to lock")
+                    .file(path)
+                    .cause(e)
+                    .report()
+            })?;
+        }
+        Err(TryLockError::Error(e)) => {
+            Error::new("state::lock", "Failed to lock")
+                .file(path)
+                .cause(e)
+                .report();
+            return Err(());
+        }
+    }
+    debug!("Locked {path:?}");
+
+    // Record the holder for diagnostics, failing to do so doesn't affect the lock
+    let _ = file
+        .set_len(0)
+        .and_then(|_| file.rewind())
+        .and_then(|_| writeln!(file, "{}", std::process::id()));
+
+    Ok(Lock { _file: file })
+}
+
+impl State {
+    /// Load the state, starting from scratch if there is none or it is unreadable
+    pub fn load(dir: &Path) -> State {
+        let path = dir.join(STATE_FILE);
+        match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                Error::new("state::parse", "Failed to parse state file, starting over")
+                    .file(&path)
+                    .cause(e)
+                    .report();
+                State::default()
+            }),
+            Err(e) => {
+                debug!("No state loaded from {path:?} -- {:?}", e);
+                State::default()
+            }
+        }
+    }
+
+    /// Remember the checksum of the keys of every fetched account
+    ///
+    /// Returns how the keys changed since they were last fetched. Accounts that failed to fetch
+    /// keep their previous checksums.
+    pub fn update_key_checksums<'a>(
+        &mut self,
+        fetched: &'a [(String, Option<Vec<String>>)],
+    ) -> KeyChanges<'a> {
+        let mut changes = KeyChanges::default();
+        for (id, keys) in fetched {
+            let Some(keys) = keys else {
+                continue;
+            };
+            let checksum = checksum(keys.join("\n").as_bytes());
+            if self.key_checksums.get(id) != Some(&checksum) {
+                self.key_checksums.insert(id.clone(), checksum);
+                changes.accounts.push(id.as_str());
+            }
+
+            let current: BTreeSet<String> = keys
+                .iter()
+                .map(|key| self::checksum(key.as_bytes()))
+                .collect();
+            let previous = self
+                .key_set_checksums
+                .insert(id.clone(), current.clone())
+                .unwrap_or_default();
+            changes.added += current.difference(&previous).count();
+            changes.removed += previous.difference(&current).count();
+            changes.unchanged += current.intersection(&previous).count();
+        }
+        changes
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<(), ()> {
+        std::fs::create_dir_all(dir).map_err(|e| {
+            Error::new("state::create_dir", "Failed to create state directory")
+                .file(dir)
+                .cause(e)
+                .report()
+        })?;
+
+        let path = dir.join(STATE_FILE);
+        let tmp_path = dir.join(format!(".{STATE_FILE}.tmp"));
+        let content = serde_json::to_string_pretty(self).map_err(|e| {
+            Error::new("state::write", "Failed to serialize state")
+                .cause(e)
+                .report()
+        })?;
+        std::fs::write(&tmp_path, content).map_err(|e| {
+            Error::new("state::write", "Failed to write state file")
+                .file(&tmp_path)
+                .cause(e)
+                .report()
+        })?;
+        std::fs::rename(&tmp_path, &path).map_err(|e| {
+            Error::new("state::write", "Failed to move state file into place")
+                .file(&path)
+                .cause(e)
+                .report()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_the_directories_of_systemd_and_xdg() {
+        assert_eq!(
+            default_state_dir(
+                Some("/var/lib/kanidm_sshkey_fetcher:/var/lib/other".to_string()),
+                Some("/home/alice/.state".to_string())
+            ),
+            "/var/lib/kanidm_sshkey_fetcher"
+        );
+        assert_eq!(
+            default_state_dir(None, Some("/home/alice/.state".to_string())),
+            "/home/alice/.state/kanidm_sshkey_fetcher"
+        );
+        assert_eq!(
+            default_state_dir(Some(String::new()), Some("relative".to_string())),
+            DEFAULT_STATE_DIR
+        );
+    }
+
+    #[test]
+    fn counts_added_removed_and_unchanged_keys() {
+        let mut state = State::default();
+        let first = vec![
+            (
+                "alice".to_string(),
+                Some(vec!["a1".to_string(), "a2".to_string()]),
+            ),
+            ("bob".to_string(), None),
+        ];
+        let changes = state.update_key_checksums(&first);
+        assert_eq!(
+            (changes.added, changes.removed, changes.unchanged),
+            (2, 0, 0)
+        );
+        assert_eq!(changes.accounts, ["alice"]);
+
+        let second = vec![(
+            "alice".to_string(),
+            Some(vec!["a2".to_string(), "a3".to_string()]),
+        )];
+        let changes = state.update_key_checksums(&second);
+        assert_eq!(
+            (changes.added, changes.removed, changes.unchanged),
+            (1, 1, 1)
+        );
+
+        let changes = state.update_key_checksums(&second);
+        assert_eq!(
+            (changes.added, changes.removed, changes.unchanged),
+            (0, 0, 2)
+        );
+        assert!(changes.accounts.is_empty());
+    }
+}
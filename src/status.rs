@@ -0,0 +1,132 @@
+//! The outcome of the last run, for monitoring, with `--status-file`
+//!
+//! After every run, and every sync of the daemon, a small JSON object is written to the status
+//! file: when the run finished, whether it succeeded, when a run last succeeded, what it wrote
+//! and the first errors and warnings it reported. Zabbix, Telegraf and the like can alarm on a
+//! stale or failing sync by checking the file, without parsing logs.
+
+use std::path::Path;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+use tracing::debug;
+
+use crate::diagnostic::{Error, Reported};
+use crate::summary::Summary;
+
+#[derive(Debug, Serialize)]
+struct Status {
+    /// When the run finished, in RFC 3339
+    finished: String,
+    /// When the run finished, in seconds since the epoch
+    finished_unix: i64,
+    success: bool,
+    /// When the last successful run finished, in seconds since the epoch, if any did
+    last_success_unix: Option<i64>,
+    duration_ms: u128,
+    /// What the run wrote, `null` if it wrote no keys
+    summary: Option<Summary>,
+    errors: usize,
+    warnings: usize,
+    /// The first errors and warnings, with their level, code, account and message
+    problems: Vec<serde_json::Value>,
+}
+
+/// What is read back of the previous status
+#[derive(Deserialize)]
+struct Previous {
+    last_success_unix: Option<i64>,
+}
+
+impl Status {
+    fn new(
+        success: bool,
+        finished: OffsetDateTime,
+        previous: Option<i64>,
+        duration_ms: u128,
+        summary: Option<Summary>,
+        reported: Reported,
+    ) -> Status {
+        let finished_unix = finished.unix_timestamp();
+        Status {
+            finished: finished.format(&Rfc3339).unwrap_or_default(),
+            finished_unix,
+            success,
+            last_success_unix: if success {
+                Some(finished_unix)
+            } else {
+                previous
+            },
+            duration_ms,
+            summary,
+            errors: reported.errors,
+            warnings: reported.warnings,
+            problems: reported.first,
+        }
+    }
+}
+
+/// When the run before the one writing to `path` last succeeded
+fn last_success(path: &Path) -> Option<i64> {
+    let content = std::fs::read_to_string(path).ok()?;
+    serde_json::from_str::<Previous>(&content)
+        .ok()?
+        .last_success_unix
+}
+
+/// Write the status of the run that started at `started` to `path`
+///
+/// A status that can't be written is only logged, it doesn't change the outcome of the run.
+pub fn write(path: &Path, success: bool, started: Instant, summary: Option<Summary>) {
+    let status = Status::new(
+        success,
+        OffsetDateTime::now_utc(),
+        last_success(path),
+        started.elapsed().as_millis(),
+        summary,
+        crate::diagnostic::take_reported(),
+    );
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let written = serde_json::to_string_pretty(&status)
+        .map_err(std::io::Error::other)
+        .and_then(|content| {
+            std::fs::write(&tmp_path, content + "\n")?;
+            std::fs::rename(&tmp_path, path)
+        });
+    match written {
+        Ok(()) => debug!("Wrote the status of the run to {}", path.display()),
+        Err(e) => Error::new("status::write", "Failed to write the status file")
+            .file(path)
+            .cause(e)
+            .warn(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_last_success_across_failures() {
+        let finished = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+        let status = Status::new(true, finished, Some(1000), 42, None, Reported::default());
+        assert_eq!(status.finished, "2023-11-14T22:13:20Z");
+        assert_eq!(status.last_success_unix, Some(1_700_000_000));
+
+        let reported = Reported {
+            errors: 1,
+            warnings: 0,
+            first: vec![serde_json::json!({ "level": "error", "code": "source::fetch" })],
+        };
+        let status = Status::new(false, finished, Some(1000), 42, None, reported);
+        assert_eq!(status.last_success_unix, Some(1000));
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["errors"], 1);
+        assert_eq!(json["problems"][0]["code"], "source::fetch");
+        assert_eq!(json["summary"], serde_json::Value::Null);
+    }
+}
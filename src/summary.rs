@@ -0,0 +1,79 @@
+//! What a run did, printed once it wrote the keys
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+use tracing::info;
+
+use crate::state::KeyChanges;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    /// How many accounts were fetched, including those that failed
+    pub accounts: usize,
+    pub failed: usize,
+    /// The accounts that failed, for metrics
+    #[serde(skip)]
+    pub failed_accounts: Vec<String>,
+    pub keys_added: usize,
+    pub keys_removed: usize,
+    pub keys_unchanged: usize,
+    pub duration_ms: u128,
+}
+
+/// The summary of the last write, for the status file and metrics
+static LAST: Mutex<Option<Summary>> = Mutex::new(None);
+
+/// The summary kept by the last [`Summary::keep`], if any
+pub fn take_last() -> Option<Summary> {
+    LAST.lock().map_or(None, |mut last| last.take())
+}
+
+impl Summary {
+    pub fn new(
+        fetched: &[(String, Option<Vec<String>>)],
+        changes: &KeyChanges,
+        duration: Duration,
+    ) -> Summary {
+        Summary {
+            accounts: fetched.len(),
+            failed: fetched.iter().filter(|(_, keys)| keys.is_none()).count(),
+            failed_accounts: fetched
+                .iter()
+                .filter(|(_, keys)| keys.is_none())
+                .map(|(account, _)| account.clone())
+                .collect(),
+            keys_added: changes.added,
+            keys_removed: changes.removed,
+            keys_unchanged: changes.unchanged,
+            duration_ms: duration.as_millis(),
+        }
+    }
+
+    /// Log the summary, or with `json` print it as one JSON object on stderr
+    ///
+    /// stdout is left alone, it may carry the keys for sshd.
+    pub fn print(&self, json: bool) {
+        if json {
+            eprintln!("{}", serde_json::json!({ "summary": self }));
+            return;
+        }
+        info!(
+            "Fetched {} accounts, {} failed: {} keys added, {} removed, {} unchanged in {}ms",
+            self.accounts,
+            self.failed,
+            self.keys_added,
+            self.keys_removed,
+            self.keys_unchanged,
+            self.duration_ms
+        );
+    }
+
+    /// Keep the summary for [`take_last`]
+    pub fn keep(self) {
+        if let Ok(mut last) = LAST.lock() {
+            *last = Some(self);
+        }
+    }
+}
@@ -0,0 +1,34 @@
+/// Print rows with every column padded to its widest cell, separated by two spaces
+///
+/// The columns listed in `right_aligned` are aligned to the right, e.g. for counts.
+pub fn print_table(rows: &[Vec<String>], right_aligned: &[usize]) {
+    let columns = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+    let widths: Vec<usize> = (0..columns)
+        .map(|c| {
+            rows.iter()
+                .filter_map(|r| r.get(c))
+                .map(|cell| cell.chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    for row in rows {
+        let cells: Vec<String> = row
+            .iter()
+            .enumerate()
+            .map(|(c, cell)| {
+                let width = widths[c];
+                if right_aligned.contains(&c) {
+                    format!("{cell:>width$}")
+                } else if c + 1 == row.len() {
+                    // Avoid trailing whitespace after the last column
+                    cell.clone()
+                } else {
+                    format!("{cell:<width$}")
+                }
+            })
+            .collect();
+        println!("{}", cells.join("  "));
+    }
+}
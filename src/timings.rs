@@ -0,0 +1,74 @@
+//! How long the phases of a run took, printed at its end with `--timings`
+//!
+//! Helps tuning timeouts and concurrency: whether a slow run waits for the server to log in,
+//! for the accounts, or for the disk.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tracing::info;
+
+/// The phases recorded since the last [`print`], in the order they first ran
+static PHASES: Mutex<Vec<(&'static str, Duration)>> = Mutex::new(Vec::new());
+
+/// Add `duration` to the time spent in `phase`
+pub fn record(phase: &'static str, duration: Duration) {
+    let Ok(mut phases) = PHASES.lock() else {
+        return;
+    };
+    match phases.iter_mut().find(|(name, _)| *name == phase) {
+        Some((_, total)) => *total += duration,
+        None => phases.push((phase, duration)),
+    }
+}
+
+/// The breakdown as text, e.g. `config 2ms, auth 180ms, total 190ms`
+fn breakdown(phases: &[(&str, Duration)], total: Duration) -> String {
+    phases
+        .iter()
+        .chain([&("total", total)])
+        .map(|(phase, duration)| format!("{phase} {}ms", duration.as_millis()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Log the phases recorded since the last call and the `total`, or with `json` print them as
+/// one JSON object on stderr like the summary
+pub fn print(total: Duration, json: bool) {
+    let phases = PHASES
+        .lock()
+        .map(|mut phases| std::mem::take(&mut *phases))
+        .unwrap_or_default();
+    if json {
+        let timings: serde_json::Map<String, serde_json::Value> = phases
+            .iter()
+            .chain([&("total", total)])
+            .map(|(phase, duration)| {
+                (
+                    format!("{phase}_ms"),
+                    serde_json::json!(duration.as_millis()),
+                )
+            })
+            .collect();
+        eprintln!("{}", serde_json::json!({ "timings": timings }));
+        return;
+    }
+    info!("Timings: {}", breakdown(&phases, total));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_the_phases_and_the_total() {
+        let phases = [
+            ("config", Duration::from_millis(2)),
+            ("fetch", Duration::from_millis(412)),
+        ];
+        assert_eq!(
+            breakdown(&phases, Duration::from_millis(420)),
+            "config 2ms, fetch 412ms, total 420ms"
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Reading keys from kanidm-unixd over its unix socket
+//!
+//! Hosts running kanidm-unixd already cache the accounts they resolve, and answer from that
+//! cache while the server can't be reached. With `--unixd-socket` the keys of an account are
+//! asked from there first, with the server only asked for what kanidm-unixd can't answer. The
+//! daemon speaks one JSON request and one JSON response per connection, e.g.
+//! `{"SshKey":"alice"}` answered by `{"SshKeys":["ssh-ed25519 ..."]}`.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use tracing::debug;
+
+use crate::Cli;
+use crate::source::{KeySource, SourceError};
+
+/// The socket kanidm-unixd listens on by default
+pub const DEFAULT_SOCKET: &str = "/var/run/kanidm-unixd/sock";
+
+/// How long to wait for kanidm-unixd before asking the server instead
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Responses larger than this are refused rather than buffered
+const MAX_RESPONSE: usize = 1 << 20;
+
+/// The unix socket of kanidm-unixd, connected to for every request
+pub struct UnixdSource {
+    socket: PathBuf,
+}
+
+impl UnixdSource {
+    /// The configured socket, `None` if `--unixd-socket` is not given
+    pub fn from_args(args: &Cli) -> Option<UnixdSource> {
+        args.unixd_socket.as_ref().map(|socket| UnixdSource {
+            socket: socket.clone(),
+        })
+    }
+
+    pub fn socket(&self) -> &std::path::Path {
+        &self.socket
+    }
+
+    /// Send one request and read the JSON response, giving up after `TIMEOUT`
+    #[cfg(unix)]
+    async fn request(&self, request: &serde_json::Value) -> Result<serde_json::Value, SourceError> {
+        tokio::time::timeout(TIMEOUT, self.exchange(request))
+            .await
+            .map_err(|_| {
+                SourceError::Other(format!(
+                    "kanidm-unixd at {} didn't answer within {}s",
+                    self.socket.display(),
+                    TIMEOUT.as_secs()
+                ))
+            })?
+    }
+
+    #[cfg(unix)]
+    async fn exchange(
+        &self,
+        request: &serde_json::Value,
+    ) -> Result<serde_json::Value, SourceError> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::UnixStream;
+
+        let io_error = |e: std::io::Error| {
+            SourceError::Other(format!("kanidm-unixd at {} -- {e}", self.socket.display()))
+        };
+        let mut stream = UnixStream::connect(&self.socket).await.map_err(io_error)?;
+        stream
+            .write_all(request.to_string().as_bytes())
+            .await
+            .map_err(io_error)?;
+
+        // The response isn't delimited, it is complete once it parses
+        let mut response = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let read = stream.read(&mut buf).await.map_err(io_error)?;
+            if read == 0 {
+                return Err(SourceError::Other(
+                    "kanidm-unixd closed the connection before answering".to_string(),
+                ));
+            }
+            response.extend_from_slice(&buf[..read]);
+            if let Ok(value) = serde_json::from_slice(&response) {
+                return Ok(value);
+            }
+            if response.len() > MAX_RESPONSE {
+                return Err(SourceError::Other(
+                    "kanidm-unixd answered more than 1 MiB".to_string(),
+                ));
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn request(
+        &self,
+        _request: &serde_json::Value,
+    ) -> Result<serde_json::Value, SourceError> {
+        Err(SourceError::Other(
+            "kanidm-unixd is only supported on Unix".to_string(),
+        ))
+    }
+}
+
+/// The keys of an `SshKeys` response
+///
+/// kanidm-unixd answers an empty list for an account it doesn't know, so that is reported as
+/// not found to let the server tell the two apart.
+fn response_keys(response: serde_json::Value) -> Result<Vec<String>, SourceError> {
+    let Some(keys) = response.get("SshKeys") else {
+        return Err(SourceError::Other(format!(
+            "kanidm-unixd answered {response}"
+        )));
+    };
+    let keys: Vec<String> = serde_json::from_value(keys.clone())
+        .map_err(|e| SourceError::Other(format!("kanidm-unixd answered {response} -- {e}")))?;
+    if keys.is_empty() {
+        return Err(SourceError::NotFound);
+    }
+    Ok(keys)
+}
+
+/// kanidm-unixd only knows the keys of single accounts
+fn unsupported<T>(what: &str) -> Result<T, SourceError> {
+    Err(SourceError::Other(format!(
+        "kanidm-unixd can't list {what}"
+    )))
+}
+
+impl KeySource for UnixdSource {
+    async fn account_keys(&self, account_id: &str) -> Result<Vec<String>, SourceError> {
+        let response = self
+            .request(&serde_json::json!({ "SshKey": account_id }))
+            .await?;
+        let keys = response_keys(response)?;
+        debug!("kanidm-unixd knows {} keys of {}", keys.len(), account_id);
+        Ok(keys)
+    }
+
+    async fn all_account_keys(&self) -> Result<HashMap<String, Vec<String>>, SourceError> {
+        unsupported("all accounts")
+    }
+
+    async fn group_members(&self, _group: &str) -> Result<Option<Vec<String>>, SourceError> {
+        unsupported("the members of groups")
+    }
+
+    async fn posix_id(&self, _account_id: &str) -> Result<Option<u32>, SourceError> {
+        unsupported("POSIX uids")
+    }
+
+    async fn tagged_keys(&self, _account_id: &str) -> Result<Vec<(String, String)>, SourceError> {
+        unsupported("the tags of keys")
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn asks_for_the_keys_of_an_account() {
+        let socket = std::env::temp_dir().join(format!(
+            "kanidm_sshkey_fetcher-unixd-{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&socket);
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for keys in [
+                r#"{"SshKeys":["ssh-ed25519 AAAA alice"]}"#,
+                r#"{"SshKeys":[]}"#,
+            ] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 64];
+                let read = stream.read(&mut request).unwrap();
+                requests.push(String::from_utf8_lossy(&request[..read]).into_owned());
+                // Split to check that partial responses are read until complete
+                let (start, end) = keys.split_at(5);
+                stream.write_all(start.as_bytes()).unwrap();
+                stream.flush().unwrap();
+                stream.write_all(end.as_bytes()).unwrap();
+            }
+            requests
+        });
+
+        let source = UnixdSource {
+            socket: socket.clone(),
+        };
+        assert_eq!(
+            source.account_keys("alice").await.unwrap(),
+            vec!["ssh-ed25519 AAAA alice".to_string()]
+        );
+        assert!(matches!(
+            source.account_keys("carol").await,
+            Err(SourceError::NotFound)
+        ));
+        assert_eq!(
+            server.join().unwrap(),
+            vec![r#"{"SshKey":"alice"}"#, r#"{"SshKey":"carol"}"#]
+        );
+        let _ = std::fs::remove_file(&socket);
+    }
+}
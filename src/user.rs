@@ -0,0 +1,72 @@
+//! Resolving the local users whose authorized_keys are managed
+
+use std::io::{BufRead, IsTerminal, Write};
+
+use nix::unistd::User;
+use tracing::info;
+
+use crate::diagnostic::Error;
+
+/// Look up a user in the passwd database, including NSS sources like NIS or LDAP
+pub fn lookup(name: &str) -> Result<User, ()> {
+    match User::from_name(name) {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => {
+            Error::new("user::not_found", "User does not exist")
+                .with("user", name)
+                .report();
+            Err(())
+        }
+        Err(e) => {
+            Error::new("user::lookup", "Failed to look up user")
+                .with("user", name)
+                .cause(e)
+                .report();
+            Err(())
+        }
+    }
+}
+
+/// The user who invoked sudo, if running as root through sudo
+fn sudo_user() -> Option<String> {
+    if !nix::unistd::geteuid().is_root() {
+        return None;
+    }
+    std::env::var("SUDO_USER")
+        .ok()
+        .filter(|user| !user.is_empty() && user != "root")
+}
+
+/// Ask whether to manage the keys of the user who invoked sudo instead of root's
+///
+/// Without a terminal to ask on, root's authorized_keys are kept and the situation is reported.
+pub fn offer_sudo_user() -> Option<String> {
+    let sudo_user = sudo_user()?;
+
+    if !std::io::stdin().is_terminal() {
+        Error::new(
+            "user::sudo",
+            "Running under sudo, managing root's authorized_keys",
+        )
+        .help(format!("pass --user {sudo_user} to manage theirs"))
+        .warn();
+        return None;
+    }
+
+    eprint!(
+        "Running under sudo, manage the authorized_keys of {sudo_user} instead of root? [Y/n] "
+    );
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer).is_err() {
+        return None;
+    }
+
+    match answer.trim().to_lowercase().as_str() {
+        "" | "y" | "yes" => {
+            info!("Managing the authorized_keys of {sudo_user}");
+            Some(sudo_user)
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,243 @@
+//! Which local user the keys of a kanidm account are for, when maintaining `--key-dir`
+//!
+//! By default a key file is named after the account without its domain. A mapping file given
+//! with `--user-map` renames accounts whose names don't match a local user, one per line:
+//!
+//! ```text
+//! # kanidm account -> local user
+//! alice_corp -> alice
+//! bob@idm.example.com -> bsmith
+//! ```
+//!
+//! With `--map-by-uid`, accounts with a POSIX uid are instead named after the local user with
+//! that uid, unless the mapping file names them.
+//!
+//! `--local-users` goes the other way and fetches the keys of the local users, see
+//! [`local_users`].
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+
+use tracing::debug;
+
+use crate::Cli;
+use crate::diagnostic::Error;
+
+/// The uid range `useradd` gives human users by default, see `--local-uid-min`
+pub const DEFAULT_LOCAL_UIDS: (u32, u32) = (1000, 60000);
+
+/// The local users `--local-users` fetches the keys of, read from /etc/passwd
+///
+/// These are the users in the uid range with a login shell, users only known through NSS
+/// modules are not found.
+#[cfg(unix)]
+pub fn local_users(args: &Cli) -> Result<Vec<String>, ()> {
+    const PASSWD: &str = "/etc/passwd";
+
+    let content = std::fs::read_to_string(PASSWD).map_err(|e| {
+        Error::new("user_map::passwd", "Failed to read the local users")
+            .file(PASSWD)
+            .cause(e)
+            .report()
+    })?;
+    let uids = (
+        args.local_uid_min.unwrap_or(DEFAULT_LOCAL_UIDS.0),
+        args.local_uid_max.unwrap_or(DEFAULT_LOCAL_UIDS.1),
+    );
+    let users = passwd_users(&content, uids);
+    debug!("Found {} local users", users.len());
+    Ok(users)
+}
+
+/// The users of passwd lines with a uid in `uids` and a shell that allows logging in
+#[cfg(unix)]
+fn passwd_users(content: &str, (first, last): (u32, u32)) -> Vec<String> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            let [name, _, uid, _, _, _, shell] = fields[..] else {
+                return None;
+            };
+            let uid: u32 = uid.parse().ok()?;
+            let login = !shell.ends_with("/nologin") && !shell.ends_with("/false");
+            ((first..=last).contains(&uid) && login).then(|| name.to_string())
+        })
+        .collect()
+}
+
+/// The local users of the accounts that don't share their name
+#[derive(Debug, Default)]
+pub struct UserMap {
+    /// By the account id or name of the mapping file
+    users: HashMap<String, String>,
+    /// By the account id, found by uid
+    by_uid: HashMap<String, String>,
+}
+
+impl UserMap {
+    /// Read the mapping file of `--user-map` and look up the users of `posix_ids`
+    pub fn load(args: &Cli, posix_ids: &BTreeMap<String, u32>) -> Result<UserMap, ()> {
+        let map = UserMap::read(args)?;
+        #[cfg(unix)]
+        let map = map.with_posix_ids(posix_ids);
+        #[cfg(not(unix))]
+        let _ = posix_ids;
+        Ok(map)
+    }
+
+    /// Read the mapping file of `--user-map`, an empty map if none is configured
+    fn read(args: &Cli) -> Result<UserMap, ()> {
+        let Some(path) = &args.user_map else {
+            return Ok(UserMap::default());
+        };
+
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            Error::new("user_map::read", "Failed to read user map")
+                .file(path)
+                .cause(e)
+                .report()
+        })?;
+        let users = parse(&content).map_err(|(span, problem)| {
+            Error::new("user_map::parse", "Failed to parse user map")
+                .file(path)
+                .span(path.display().to_string(), &content, span, problem)
+                .report()
+        })?;
+
+        Ok(UserMap {
+            users,
+            by_uid: HashMap::new(),
+        })
+    }
+
+    /// Add the local users with the uids of accounts, skipping uids no local user has
+    #[cfg(unix)]
+    fn with_posix_ids(mut self, posix_ids: &BTreeMap<String, u32>) -> UserMap {
+        for (account_id, uid) in posix_ids {
+            match nix::unistd::User::from_uid(nix::unistd::Uid::from_raw(*uid)) {
+                Ok(Some(user)) => {
+                    self.by_uid.insert(account_id.clone(), user.name);
+                }
+                Ok(None) => debug!("No local user has the uid {} of {}", uid, account_id),
+                Err(e) => Error::new("user_map::passwd", "Failed to look up the local user")
+                    .account(account_id)
+                    .with("uid", uid)
+                    .cause(e)
+                    .warn(),
+            }
+        }
+        self
+    }
+
+    /// The local user of an account, mapped by its full id or its name without the domain
+    ///
+    /// The mapping file comes first, then the uid, then the name of the account itself.
+    pub fn local_user<'a>(&'a self, account_id: &'a str) -> &'a str {
+        let name = crate::export::local_name(account_id);
+        self.users
+            .get(account_id)
+            .or_else(|| self.users.get(name))
+            .or_else(|| self.by_uid.get(account_id))
+            .map_or(name, String::as_str)
+    }
+}
+
+/// The `account -> user` lines of a mapping file, or where and why one is wrong
+fn parse(content: &str) -> Result<HashMap<String, String>, (Range<usize>, &'static str)> {
+    let mut users = HashMap::new();
+    let mut offset = 0;
+    for line in content.split_inclusive('\n') {
+        let span = offset..offset + line.trim_end().len();
+        offset += line.len();
+
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((account, user)) = line.split_once("->") else {
+            return Err((span, "expected `account -> user`"));
+        };
+        let (account, user) = (account.trim(), user.trim());
+        if account.is_empty() || user.is_empty() {
+            return Err((span, "expected `account -> user`"));
+        }
+        if users
+            .insert(account.to_string(), user.to_string())
+            .is_some()
+        {
+            return Err((span, "account is mapped more than once"));
+        }
+    }
+    Ok(users)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_accounts_by_id_or_name() {
+        let users = parse("# comment\nalice_corp -> alice\n\nbob@idm.example.com->bsmith\n")
+            .expect("the map is valid");
+        let map = UserMap {
+            users,
+            by_uid: HashMap::new(),
+        };
+
+        assert_eq!(map.local_user("alice_corp@idm.example.com"), "alice");
+        assert_eq!(map.local_user("bob@idm.example.com"), "bsmith");
+        assert_eq!(map.local_user("bob@other.example.com"), "bob");
+        assert_eq!(map.local_user("carol"), "carol");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn maps_accounts_by_uid() {
+        let users = parse("alice_corp -> alice").expect("the map is valid");
+        let posix_ids = BTreeMap::from([
+            ("admin@idm.example.com".to_string(), 0),
+            ("alice_corp".to_string(), 0),
+        ]);
+        let map = UserMap {
+            users,
+            by_uid: HashMap::new(),
+        }
+        .with_posix_ids(&posix_ids);
+
+        assert_eq!(map.local_user("admin@idm.example.com"), "root");
+        assert_eq!(map.local_user("alice_corp"), "alice");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn finds_human_users_in_passwd() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\n\
+                      nobody:x:65534:65534:nobody:/:/usr/sbin/nologin\n\
+                      alice:x:1000:1000:Alice:/home/alice:/bin/bash\n\
+                      svc:x:1001:1001::/srv:/bin/false\n\
+                      broken line\n\
+                      bob:x:2000:2000::/home/bob:/bin/zsh\n";
+
+        assert_eq!(
+            passwd_users(passwd, DEFAULT_LOCAL_UIDS),
+            vec!["alice".to_string(), "bob".to_string()]
+        );
+        assert_eq!(
+            passwd_users(passwd, (1000, 1999)),
+            vec!["alice".to_string()]
+        );
+    }
+
+    #[test]
+    fn points_at_bad_lines() {
+        assert_eq!(
+            parse("alice -> a\nbob\n"),
+            Err((11..14, "expected `account -> user`"))
+        );
+        assert_eq!(
+            parse("alice -> a\nalice -> b"),
+            Err((11..21, "account is mapped more than once"))
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! The version of this build, and checking that the server runs a kanidm release the bundled
+//! client can talk to
+//!
+//! kanidm only promises compatibility between a client and a server of the same minor release,
+//! a mismatch otherwise shows up as opaque protocol errors later on.
+
+use kanidm_client::{KanidmClient, KanidmClientBuilder};
+use kanidm_proto::constants::KVERSION;
+use serde::Serialize;
+use tracing::debug;
+
+use crate::diagnostic::Error;
+
+/// What `--version --json` prints, recorded by build.rs
+#[derive(Debug, Serialize)]
+struct BuildInfo {
+    version: &'static str,
+    git_sha: &'static str,
+    kanidm_client: &'static str,
+    kanidm_proto: &'static str,
+    tls_backend: &'static str,
+    target: &'static str,
+}
+
+/// Print the version, as JSON with all the details of the build if `json` is set
+pub fn print(json: bool) -> Result<(), ()> {
+    if !json {
+        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+
+    let info = BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("GIT_SHA"),
+        kanidm_client: client_version(),
+        kanidm_proto: env!("KANIDM_PROTO_VERSION"),
+        // kanidm_client, reqwest and ldap3 are all built with rustls on ring
+        tls_backend: "rustls-ring",
+        target: env!("BUILD_TARGET"),
+    };
+    let json = serde_json::to_string(&info).map_err(|e| {
+        Error::new("version::serialize", "Failed to serialize the version")
+            .cause(e)
+            .report()
+    })?;
+    println!("{}", json);
+
+    Ok(())
+}
+
+/// The version of the bundled kanidm_client
+pub fn client_version() -> &'static str {
+    KanidmClientBuilder::user_agent()
+        .rsplit_once('/')
+        .map_or("unknown", |(_, version)| version)
+}
+
+/// The version the server reports in its responses, `None` if it reports none
+///
+/// Asks with the underlying HTTP client, kanidm_client exits on a mismatch in debug builds.
+pub async fn server_version(client: &KanidmClient) -> Result<Option<String>, reqwest::Error> {
+    let response = client
+        .client()
+        .get(client.make_url("/status"))
+        .send()
+        .await?;
+    Ok(response
+        .headers()
+        .get(KVERSION)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from))
+}
+
+/// The major and minor number of a version like `1.8.1` or `1.9.0-dev`
+fn minor_release(version: &str) -> Option<(u64, u64)> {
+    let mut numbers = version.split(['.', '-']).map(str::parse);
+    Some((numbers.next()?.ok()?, numbers.next()?.ok()?))
+}
+
+/// Whether a server of `version` can be talked to with the bundled client
+pub fn is_supported(version: &str) -> bool {
+    minor_release(version).is_some() && minor_release(version) == minor_release(client_version())
+}
+
+/// Warn about, or with `strict` refuse, a server the bundled client doesn't support
+///
+/// An unreachable server is not reported here, the requests that follow report it.
+pub async fn check(client: &KanidmClient, strict: bool) -> Result<(), ()> {
+    let version = match server_version(client).await {
+        Ok(version) => version,
+        Err(e) => {
+            debug!("Failed to ask the server for its version -- {:?}", e);
+            return Ok(());
+        }
+    };
+
+    let problem = match version {
+        Some(version) if is_supported(&version) => {
+            debug!("Server version {} is supported", version);
+            return Ok(());
+        }
+        Some(version) => format!(
+            "Server version {} is not supported by kanidm_client {}",
+            version,
+            client_version()
+        ),
+        None => "The server did not report its version, it may be behind a proxy".to_string(),
+    };
+
+    if strict {
+        Error::new("version::unsupported", problem)
+            .help("drop --strict-version to continue anyway")
+            .report();
+        return Err(());
+    }
+    Error::new("version::unsupported", format!("{problem}, expect errors"))
+        .help("upgrade the server or this tool to the same kanidm release")
+        .warn();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supports_the_same_minor_release() {
+        let (major, minor) = minor_release(client_version()).expect("the client has a version");
+
+        assert!(is_supported(&format!("{major}.{minor}.0")));
+        assert!(is_supported(&format!("{major}.{minor}.99-dev")));
+        assert!(!is_supported(&format!("{major}.{}.0", minor + 1)));
+        assert!(!is_supported(&format!("{}.{minor}.0", major + 1)));
+        assert!(!is_supported("unknown"));
+    }
+}
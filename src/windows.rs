@@ -0,0 +1,66 @@
+//! Support for the Windows port of OpenSSH Server
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use tracing::debug;
+
+use crate::diagnostic::Error;
+
+/// The well-known SID of the local Administrators group
+const SID_ADMINISTRATORS: &str = "*S-1-5-32-544";
+/// The well-known SID of the SYSTEM account
+const SID_SYSTEM: &str = "*S-1-5-18";
+
+/// The file sshd reads for members of the Administrators group
+pub fn administrators_authorized_keys_path() -> PathBuf {
+    let program_data = std::env::var_os("ProgramData").unwrap_or_else(|| r"C:\ProgramData".into());
+    PathBuf::from(program_data)
+        .join("ssh")
+        .join("administrators_authorized_keys")
+}
+
+/// The authorized_keys file in the profile of the current user
+pub fn user_authorized_keys_path() -> PathBuf {
+    let profile = std::env::var_os("USERPROFILE").unwrap_or_default();
+    PathBuf::from(profile).join(".ssh").join("authorized_keys")
+}
+
+/// Replace the inherited ACL of a written file with the one sshd's StrictModes expects
+///
+/// administrators_authorized_keys may only be accessible by Administrators and SYSTEM, a user's
+/// authorized_keys additionally by that user.
+pub fn restrict_acl(path: &Path, administrators: bool) -> Result<(), ()> {
+    let mut grants = vec![format!("{SID_ADMINISTRATORS}:F"), format!("{SID_SYSTEM}:F")];
+    if !administrators {
+        let user = std::env::var("USERNAME").map_err(|e| {
+            Error::new("windows::user", "Failed to determine the current user")
+                .cause(e)
+                .report()
+        })?;
+        grants.push(format!("{user}:F"));
+    }
+
+    let mut command = Command::new("icacls");
+    command.arg(path).arg("/inheritance:r");
+    for grant in &grants {
+        command.arg("/grant:r").arg(grant);
+    }
+
+    debug!("Restricting ACL of {path:?} -- {command:?}");
+    let status = command.status().map_err(|e| {
+        Error::new("windows::acl", "Failed to run icacls")
+            .file(path)
+            .cause(e)
+            .report()
+    })?;
+    if !status.success() {
+        Error::new("windows::acl", "icacls failed to restrict the ACL")
+            .file(path)
+            .with("status", status)
+            .report();
+        return Err(());
+    }
+
+    Ok(())
+}